@@ -1,48 +1,855 @@
 use std::{
-    io::{BufRead, BufReader, Error, ErrorKind},
-    net::{self, TcpStream},
+    cell::Cell,
+    io::{self, Error, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::internal::{request, response::Response};
+use crate::{
+    debug, error, info,
+    internal::{
+        accesslog::AccessEntry,
+        body::{
+            self, BodyLength,
+            stream::{BodyDecoder, BodyReader, IncomingBody},
+        },
+        compression,
+        config::Config,
+        connection::{ClientStream, ConnectionGuard, ConnectionTracker, TimedStream},
+        headers::Headers,
+        http2,
+        limits::Limits,
+        listener::{self, Listener, ListenerConfig},
+        metrics::CountingWriter,
+        reload::{Live, Snapshot},
+        request::{self, Request, RequestMethod},
+        response::{Response, StatusCode},
+        router::Router,
+        tracing::{self, Span, SpanKind, TraceContext},
+        websocket,
+    },
+    trace,
+};
+
+// How long the listener sleeps when no connection is waiting
+const ACCEPT_POLL: Duration = Duration::from_millis(10);
+// How long it waits after an accept failed before trying again
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// The go ahead for a client that sent `Expect: 100-continue`
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+// Where the connection is before a request is read
+enum Phase {
+    // a new connection, the client is expected to send right away
+    New,
+    // a kept-alive connection waiting for its next request
+    Idle,
+}
+
+// What came of reading a request, with when its first byte arrived
+enum Incoming {
+    Request(Request, Instant),
+    // answered from the head, the client waited for 100 Continue and the
+    // body was never sent
+    Refused(Request, Instant, Box<Response>),
+    // for a proxy, which reads the body off the connection as it sends it
+    // upstream
+    Streamed(Request, Instant, Box<PendingBody>),
+}
+
+// The body of a streamed request, still on the connection
+struct PendingBody {
+    decoder: BodyDecoder,
+    // read past the head
+    rest: Vec<u8>,
+    // the client waits for 100 Continue before it sends the body
+    expects_continue: bool,
+}
+
+fn process_request_data(
+    timed: &mut TimedStream,
+    leftover: &mut Vec<u8>,
+    phase: Phase,
+    config: &Config,
+    router: &Router,
+) -> Result<Option<Incoming>, Error> {
+    let limits = &config.limits;
+    let timeouts = &config.timeouts;
+    // the minimum rate and the idle timeout only apply to bodies
+    timed.set_min_rate(0, Duration::ZERO);
+    timed.set_idle_timeout(None);
+
+    let (mut request, rest, first_byte) = match read_request_head(timed, leftover, phase, config)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let client = timed.get_ref();
+    request.peer_addr = client.peer_addr();
+
+    // a client that already started on the body is not waiting
+    let expects_continue = request.expects_continue() && rest.is_empty();
+    if expects_continue && let Some(response) = router.precheck(&request) {
+        return Ok(Some(Incoming::Refused(
+            request,
+            first_byte,
+            Box::new(response),
+        )));
+    }
+
+    timed.clear_deadline();
+    timed.set_idle_timeout(Some(timeouts.body));
+    timed.set_min_rate(timeouts.min_body_rate, timeouts.min_body_rate_grace);
+    let decode = config.compression.decompress_requests
+        && request
+            .headers
+            .as_ref()
+            .is_some_and(|h| h.get("Content-Encoding").is_some());
+    let length = match &request.headers {
+        Some(headers) => body::body_length(headers)?,
+        None => BodyLength::Empty,
+    };
+
+    // a proxy gets the body as it arrives and holds none of it longer than
+    // it has to, so max_body_size does not apply. A body to decode is read
+    // whole like any other, a few KiB can inflate to gigabytes.
+    if router.streams(&request)
+        && !decode
+        && !http2::is_upgrade(&request)
+        && !matches!(length, BodyLength::Empty | BodyLength::Fixed(0))
+    {
+        let unlimited = Limits {
+            max_body_size: usize::MAX,
+            ..limits.clone()
+        };
+        // the 100 waits until the proxy asks for the body, which is once
+        // the upstream wants it
+        let pending = PendingBody {
+            decoder: BodyDecoder::new(length, &unlimited)?,
+            rest,
+            expects_continue,
+        };
+        return Ok(Some(Incoming::Streamed(
+            request,
+            first_byte,
+            Box::new(pending),
+        )));
+    }
+
+    // the other routes get the body read whole, the decoder keeps it within
+    // max_body_size. A Content-Length over the limit is refused here, before
+    // the 100.
+    let mut body = BodyReader::for_request(&request, &rest, &mut *timed, limits)?;
+    if expects_continue {
+        let mut client = client;
+        client.write_all(CONTINUE)?;
+    }
+    let mut received = Vec::new();
+    match &request.headers {
+        Some(headers) if decode => {
+            compression::decode_request_body(headers, &mut body, limits.max_body_size)?
+                .read_to_end(&mut received)?;
+        }
+        _ => {
+            body.read_to_end(&mut received)?;
+        }
+    };
+    trace!("received body of {} bytes", received.len());
+    request.body = received;
+    // the start of a pipelined request
+    *leftover = body.leftover().to_vec();
+
+    // what is passed on is the decoded body
+    if config.compression.decompress_requests
+        && let Some(headers) = request.headers.as_mut()
+    {
+        headers.remove("Content-Encoding");
+    }
+
+    Ok(Some(Incoming::Request(request, first_byte)))
+}
+
+// The body of a streamed request as the proxy reads it, what it took off the
+// connection and whether it got to the end are seen by the listener meanwhile
+struct StreamedBody<'s, 'c> {
+    body: BodyReader<&'s mut TimedStream<'c>>,
+    // the 100 Continue is still to be sent, on the first read
+    expects_continue: bool,
+    // everything read off the connection so far
+    read: &'s Cell<u64>,
+    done: &'s Cell<bool>,
+}
+
+impl Read for StreamedBody<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.expects_continue {
+            self.expects_continue = false;
+            self.body.get_ref().get_ref().write_all(CONTINUE)?;
+        }
+        let result = self.body.read(buf);
+        self.read.set(self.body.get_ref().total_read());
+        self.done.set(self.body.is_done());
+        result
+    }
+}
+
+// Reads until the request line and headers are complete, returns the request,
+// the bytes that were read past the head and when the first byte came. None
+// when the connection was closed, or stayed silent, before the request started.
+fn read_request_head(
+    stream: &mut TimedStream,
+    leftover: &mut Vec<u8>,
+    phase: Phase,
+    config: &Config,
+) -> Result<Option<(Request, Vec<u8>, Instant)>, Error> {
+    let timeouts = &config.timeouts;
+    let mut received: Vec<u8> = std::mem::take(leftover);
+    let mut buf = [0u8; 8 * 1024];
+
+    let wait = match phase {
+        Phase::New => timeouts.request_line,
+        Phase::Idle => timeouts.keep_alive,
+    };
+    stream.set_deadline(Instant::now() + wait);
+    let mut started: Option<Instant> = None;
+
+    // keep reading until the parser has a complete head, the limits
+    // make sure this cannot grow without bounds
+    loop {
+        if !received.is_empty() {
+            let first_byte = *started.get_or_insert_with(Instant::now);
+            let line_done = received.windows(2).any(|w| w == b"\r\n");
+            // slowloris: each part of the head has to be complete in time,
+            // no matter how often a byte trickles in
+            stream.set_deadline(
+                first_byte
+                    + match line_done {
+                        true => timeouts.headers,
+                        false => timeouts.request_line,
+                    },
+            );
+
+            match request::parse_head_with_limits(&received, &config.limits) {
+                Ok((request, read)) => {
+                    return Ok(Some((request, received.split_off(read), first_byte)));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let n = match stream.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::TimedOut && received.is_empty() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            if received.is_empty() {
+                return Ok(None);
+            }
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before the request was complete",
+            ));
+        }
+        received.extend_from_slice(&buf[..n]);
+        trace!("received {n} bytes, {} in total", received.len());
+    }
+}
+
+// HTTP/1.1 connections persist unless either side says close, HTTP/1.0 ones
+// only when the client asks for keep-alive, RFC 9112 section 9.3
+fn keep_alive(request: &Request) -> bool {
+    let connection = request
+        .headers
+        .as_ref()
+        .and_then(|h| h.get("Connection"))
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let has = |option: &str| connection.split(',').any(|o| o.trim() == option);
+
+    match request.version.as_deref() {
+        Some("HTTP/1.1") => !has("close"),
+        _ => has("keep-alive"),
+    }
+}
+
+// Whether a new connection starts with the HTTP/2 preface, reading as much
+// of it as it takes to tell. None when the client went, or said nothing.
+fn starts_with_preface(
+    timed: &mut TimedStream,
+    leftover: &mut Vec<u8>,
+    config: &Config,
+) -> Option<bool> {
+    timed.set_deadline(Instant::now() + config.timeouts.request_line);
+    let mut buf = [0u8; 1024];
+    while leftover.len() < http2::PREFACE.len() && http2::PREFACE.starts_with(leftover) {
+        match timed.read(&mut buf) {
+            Ok(0) | Err(_) if leftover.is_empty() => return None,
+            Ok(0) | Err(_) => break,
+            Ok(n) => leftover.extend_from_slice(&buf[..n]),
+        }
+    }
+    Some(leftover.starts_with(http2::PREFACE))
+}
+
+fn handle_connection(stream: ClientStream, live: &Live, connection: &ConnectionGuard, http2: bool) {
+    let mut timed = TimedStream::new(&stream);
+    let mut leftover = Vec::new();
+    let mut phase = Phase::New;
+
+    // h2 picked with ALPN, or h2c with prior knowledge
+    if http2 {
+        let current = live.load();
+        let preface = starts_with_preface(&mut timed, &mut leftover, &current.config);
+        match preface {
+            None => return,
+            Some(preface) if stream.is_h2() || (preface && !stream.is_tls()) => {
+                serve_http2(&stream, &leftover, None, live, connection);
+                return;
+            }
+            Some(_) => {}
+        }
+    }
+
+    loop {
+        // on shutdown the connection is closed instead of waiting for another request
+        if !connection.idle() {
+            break;
+        }
+
+        // each request is served with the config current when it starts, a
+        // reload does not change it halfway
+        let current = live.load();
+        let config = &current.config;
+        let metrics = current.router.metrics();
+        let _ = stream.tcp().set_write_timeout(Some(config.timeouts.write));
+
+        let read_before = timed.total_read();
+        let waiting = SystemTime::now();
+        let result =
+            process_request_data(&mut timed, &mut leftover, phase, config, &current.router);
+        let head_read = timed.total_read();
+        if let Some(metrics) = metrics {
+            metrics.add_bytes_received(head_read - read_before);
+        }
+        // a streamed body is counted as the proxy reads it
+        let read = Cell::new(head_read);
+        let bytes_received = || read.get() - read_before;
+
+        match result {
+            Ok(Some(Incoming::Request(request, _)))
+                if http2 && !stream.is_tls() && http2::is_upgrade(&request) =>
+            {
+                connection.busy();
+                if (&stream).write_all(http2::SWITCHING).is_ok() {
+                    serve_http2(&stream, &leftover, Some(request), live, connection);
+                }
+                break;
+            }
+            Ok(Some(incoming)) => {
+                connection.busy();
+                let mut persistent = match &incoming {
+                    Incoming::Request(request, _) | Incoming::Streamed(request, ..) => {
+                        keep_alive(request)
+                    }
+                    // the body the client held back would be taken for the next request
+                    Incoming::Refused(..) => false,
+                };
+                let done = Cell::new(true);
+                let (incoming, mut streamed) = match incoming {
+                    Incoming::Streamed(request, first_byte, pending) => {
+                        done.set(false);
+                        let PendingBody {
+                            decoder,
+                            rest,
+                            expects_continue,
+                        } = *pending;
+                        let length = decoder.remaining();
+                        let body = StreamedBody {
+                            body: BodyReader::new(decoder, &rest, &mut timed),
+                            expects_continue,
+                            read: &read,
+                            done: &done,
+                        };
+                        (Incoming::Request(request, first_byte), Some((body, length)))
+                    }
+                    incoming => (incoming, None),
+                };
+                let body = streamed.as_mut().map(|(reader, length)| IncomingBody {
+                    reader,
+                    length: *length,
+                });
+                let mut upgrade = None;
+                let sent = serve_request(
+                    &stream,
+                    &current,
+                    connection,
+                    incoming,
+                    body,
+                    bytes_received,
+                    |mut response| {
+                        // the client learns about a shutdown from the response,
+                        // a body left unread would be taken for the next request
+                        persistent = persistent
+                            && done.get()
+                            && !connection.is_stopping()
+                            && !response.ends_with_close();
+                        upgrade = response.upgrade.take();
+                        response.set_header(
+                            "Connection",
+                            match (&upgrade, persistent) {
+                                (Some(_), _) => "upgrade",
+                                (None, true) => "keep-alive",
+                                (None, false) => "close",
+                            },
+                        );
+                        let mut out = CountingWriter::new(&stream);
+                        let sent = response.send(&mut out);
+                        (sent, out.written())
+                    },
+                );
+                if let Some((streamed, _)) = streamed {
+                    // the start of a pipelined request
+                    leftover = streamed.body.leftover().to_vec();
+                    if let Some(metrics) = metrics {
+                        metrics.add_bytes_received(read.get() - head_read);
+                    }
+                }
+
+                // the connection is the WebSocket's from here on, until it closes
+                if let Some(upgraded) = upgrade {
+                    if sent.is_ok() {
+                        let address = upgraded.address().to_string();
+                        let pending = std::mem::take(&mut leftover);
+                        let stats = websocket::tunnel(
+                            &stream, upgraded, &pending, config, connection, metrics,
+                        );
+                        debug!(
+                            "WebSocket to {address} closed, {} messages in, {} out",
+                            stats.messages_received, stats.messages_sent
+                        );
+                    }
+                    break;
+                }
+                if sent.is_err() || !persistent {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            // nothing can be answered without a TLS session
+            Err(e) if stream.is_handshaking() => {
+                debug!("TLS handshake failed: {e}");
+                break;
+            }
+            Err(e) => {
+                serve_error(
+                    &stream,
+                    &current,
+                    &e,
+                    waiting,
+                    bytes_received(),
+                    |response| {
+                        let mut out = CountingWriter::new(&stream);
+                        let sent = response.send(&mut out);
+                        (sent, out.written())
+                    },
+                );
+                break;
+            }
+        }
+        phase = Phase::Idle;
+    }
+}
 
-fn process_request_data(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
-    // read the stream into a buffer
-    let mut reader = BufReader::new(stream);
+// Serves the streams of an HTTP/2 connection, each request with the config
+// current when it arrived
+fn serve_http2(
+    stream: &ClientStream,
+    pending: &[u8],
+    upgrade: Option<Request>,
+    live: &Live,
+    connection: &ConnectionGuard,
+) {
+    let current = live.load();
+    let _ = stream
+        .tcp()
+        .set_write_timeout(Some(current.config.timeouts.write));
+    http2::serve(
+        stream,
+        pending,
+        upgrade,
+        &current.config,
+        connection,
+        &|request, responder| {
+            let current = live.load();
+            let (first_byte, received) = (responder.received_at, responder.bytes_received);
+            if let Some(metrics) = current.router.metrics() {
+                metrics.add_bytes_received(received);
+            }
+            let send = |response| {
+                let sent = responder.send(response);
+                (sent, responder.written())
+            };
+            match request {
+                Ok(request) => {
+                    let incoming = Incoming::Request(request, first_byte);
+                    let _ = serve_request(
+                        stream,
+                        &current,
+                        connection,
+                        incoming,
+                        None,
+                        || received,
+                        send,
+                    );
+                }
+                Err(e) => {
+                    let waiting = SystemTime::now() - first_byte.elapsed();
+                    serve_error(stream, &current, &e, waiting, received, send);
+                }
+            }
+        },
+    );
+}
+
+// Routes a request and sends the response with `send`, which returns the
+// result and the bytes written. The request is counted, logged and traced.
+// A refused request is answered with its response instead of being routed,
+// one with a `body` still to read goes to the router with it.
+// `bytes_received` is asked once the body was read.
+fn serve_request(
+    stream: &ClientStream,
+    current: &Snapshot,
+    connection: &ConnectionGuard,
+    incoming: Incoming,
+    body: Option<IncomingBody>,
+    bytes_received: impl Fn() -> u64,
+    send: impl FnOnce(Response) -> (Result<(), Error>, u64),
+) -> Result<(), Error> {
+    let (mut request, first_byte, refused) = match incoming {
+        Incoming::Request(request, first_byte) | Incoming::Streamed(request, first_byte, ..) => {
+            (request, first_byte, None)
+        }
+        Incoming::Refused(request, first_byte, response) => (request, first_byte, Some(*response)),
+    };
+    let (router, config) = (&current.router, &current.config);
+    let metrics = router.metrics();
+    let access_log = router.access_log();
+    let client = stream.peer_addr().map(|addr| addr.ip());
+    let started = Instant::now();
+    let time = SystemTime::now();
+    let received_at = time - first_byte.elapsed();
+
+    // the request is passed on with its id, and with the route
+    // span as the parent of whatever the upstream does
+    let request_id = tracing::request_id(request.headers.as_ref());
+    let headers = request.headers.get_or_insert_with(Headers::default);
+    headers.insert("X-Request-Id", &request_id);
+    headers.insert(
+        "X-Forwarded-Proto",
+        match stream.is_tls() {
+            true => "https",
+            false => "http",
+        },
+    );
+    let parent = TraceContext::from_headers(headers);
+    let mut server =
+        Span::start("request", SpanKind::Server, parent.as_ref()).started_at(received_at);
+    Span::start("parse", SpanKind::Internal, Some(&server.context()))
+        .started_at(received_at)
+        .end();
+    let mut route = Span::start("route", SpanKind::Internal, Some(&server.context()));
+    route.context().set_headers(headers);
+    route.set_attribute("lb.route", router.route_name(&request).unwrap_or("none"));
+    let handled = refused.or_else(|| match body {
+        Some(body) => router.handle_streaming(&request, body),
+        None => router.handle(&request),
+    });
+    route.end();
+
+    let mut response = match (handled, &request.headers) {
+        (Some(response), Some(headers)) => {
+            compression::compress_response(headers, response, &config.compression)
+        }
+        (Some(response), None) => response,
+        (None, _) => Response::builder(StatusCode::OK)
+            .body(StatusCode::OK.reason())
+            .build(),
+    };
+    response.set_header("X-Request-Id", &request_id);
+    // answered in the version it was asked in, so an HTTP/1.0 client gets no chunked body
+    if request.version.as_deref() == Some("HTTP/1.0") {
+        response.protocol = "HTTP/1.0".to_string();
+    }
+    // whatever answered a HEAD request, the body stays here
+    if request.method == Some(RequestMethod::Head) {
+        response = response.without_body();
+    }
+
+    let status = response.status;
+    let upstream = response.upstream.take();
+    let (sent, written) = send(response);
+    connection.served();
+    if let Some(metrics) = metrics {
+        metrics.add_bytes_sent(written);
+        let method = request.method.as_ref().map_or("", |m| m.as_str());
+        let route = router.route_name(&request).unwrap_or("none");
+        metrics.record_request(method, route, status, started.elapsed());
+    }
+    if let Some(access_log) = access_log {
+        access_log.log(&AccessEntry {
+            time,
+            request: Some(&request),
+            client,
+            status,
+            bytes_sent: written,
+            bytes_received: bytes_received(),
+            duration: started.elapsed(),
+            upstream: upstream.as_ref(),
+            request_id: Some(&request_id),
+        });
+    }
 
-    // read the data  into a byte array
-    let received: Vec<u8> = reader.fill_buf()?.to_vec();
+    if let Some(method) = &request.method {
+        server.set_attribute("http.request.method", method.as_str());
+    }
+    server.set_attribute("url.path", request.path.as_deref().unwrap_or_default());
+    server.set_attribute("http.response.status_code", status.as_u16());
+    if let Some(upstream) = &upstream {
+        server.set_attribute("lb.upstream", &upstream.address);
+    }
+    if status.is_server_error() || sent.is_err() {
+        server.set_error();
+    }
+    server.end();
+    sent
+}
 
-    reader.consume(received.len());
+// Answers a request that could not be read, `send` as for serve_request
+fn serve_error(
+    stream: &ClientStream,
+    current: &Snapshot,
+    e: &Error,
+    waiting: SystemTime,
+    bytes_received: u64,
+    send: impl FnOnce(Response) -> (Result<(), Error>, u64),
+) {
+    debug!("invalid request: {e}");
+    let metrics = current.router.metrics();
+    let access_log = current.router.access_log();
+    let status = request::error_status(e);
+    let request_id = tracing::new_request_id();
+    let response = Response::builder(status)
+        .header("X-Request-Id", &request_id)
+        .body(e.to_string())
+        .build();
+    let (_, written) = send(response);
+    if let Some(metrics) = metrics {
+        metrics.add_bytes_sent(written);
+        metrics.record_parse_error(e);
+    }
+    if let Some(access_log) = access_log {
+        access_log.log(&AccessEntry {
+            time: waiting,
+            request: None,
+            client: stream.peer_addr().map(|addr| addr.ip()),
+            status,
+            bytes_sent: written,
+            bytes_received,
+            duration: waiting.elapsed().unwrap_or_default(),
+            upstream: None,
+            request_id: Some(&request_id),
+        });
+    }
 
-    println!("received VALUE => {received:?}");
-    Ok(received)
+    let mut server = Span::start("request", SpanKind::Server, None).started_at(waiting);
+    server.set_attribute("http.response.status_code", status.as_u16());
+    server.set_attribute("error.message", e);
+    server.set_error();
+    server.end();
 }
 
 pub fn listen_for_http() -> Result<(), Error> {
-    let socket_url = "127.0.0.1:8080";
-    let listener = net::TcpListener::bind(&socket_url)?;
+    serve("127.0.0.1:8080", &Router::new(), &Config::default())
+}
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(mut data) => {
-                println!("====================");
-                println!("stream data received ");
+pub fn serve(socket_url: &str, router: &Router, config: &Config) -> Result<(), Error> {
+    let address = listener::parse_address(socket_url)?;
+    let listener = Listener::bind(ListenerConfig::new(address))?;
+    let live = Live::new(Snapshot {
+        router: router.clone(),
+        config: config.clone(),
+    });
+    let connections = ConnectionTracker::new(config.connections.clone());
+    serve_live(&listener, &live, &connections)
+}
+
+// Serves with whatever router and config `live` holds at the time, the open
+// connections are kept where others can see them. Returns once
+// `connections.stop()` was called and the connections are drained, with
+// ErrorKind::TimedOut when some had to be closed before their response.
+pub fn serve_live(
+    listener: &Listener,
+    live: &Live,
+    connections: &ConnectionTracker,
+) -> Result<(), Error> {
+    for socket in listener.sockets() {
+        // polled, so that a stop is noticed without another connection coming in
+        socket.set_nonblocking(true)?;
+    }
 
-                let request_data = process_request_data(&mut data)?;
+    // every connection gets its own thread, the scope lets them borrow the
+    // live config and the tracker
+    thread::scope(|scope| {
+        let accepting: Vec<_> = listener
+            .sockets()
+            .iter()
+            .map(|socket| scope.spawn(move || accept(scope, socket, listener, live, connections)))
+            .collect();
+        for thread in accepting {
+            let _ = thread.join();
+        }
+
+        let drain = live.load().config.timeouts.drain;
+        info!(
+            "{} stopped accepting, draining {} connections",
+            listener.config().address,
+            connections.open()
+        );
+        match connections.drain(drain) {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::TimedOut,
+                "Connections were closed before their response",
+            )),
+        }
+    })
+}
 
-                let _ = request::parse(&request_data);
-                let _ = Response::ok(&mut data, None);
-                println!("Stream done processing");
+// Takes the connections of one socket until the server stops
+fn accept<'scope>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    socket: &'scope TcpListener,
+    listener: &'scope Listener,
+    live: &'scope Live,
+    connections: &'scope ConnectionTracker,
+) {
+    while !connections.is_stopping() {
+        match socket.accept() {
+            Ok((mut data, peer)) => {
+                let peer = Some(SocketAddr::new(peer.ip().to_canonical(), peer.port()));
+                debug!("connection from {peer:?}");
+                let _ = data.set_nonblocking(false);
+
+                let guard = match connections.acquire(peer) {
+                    Some(guard) => guard,
+                    None => {
+                        // over TLS the answer would need a handshake first,
+                        // the accept thread does not wait for one
+                        if listener.tls().is_none() {
+                            let write = live.load().config.timeouts.write;
+                            let _ = data.set_write_timeout(Some(write));
+                            let _ =
+                                Response::respond(&mut data, StatusCode::SERVICE_UNAVAILABLE, None);
+                        }
+                        continue;
+                    }
+                };
+                guard.attach(&data);
+
+                scope.spawn(move || {
+                    // closed on the metrics it was opened on, whatever a
+                    // reload does in between
+                    let metrics = live.load().router.metrics().cloned();
+                    if let Some(metrics) = &metrics {
+                        metrics.connection_opened();
+                    }
+                    // the TLS handshake is left to the connection's thread
+                    let stream = match listener.tls() {
+                        Some(tls) => tls.accept(data).map(|s| ClientStream::Tls(Box::new(s))),
+                        None => Ok(ClientStream::Plain(data)),
+                    };
+                    match stream {
+                        Ok(stream) => {
+                            handle_connection(stream, live, &guard, listener.config().http2)
+                        }
+                        Err(e) => debug!("TLS setup for {peer:?} failed: {e}"),
+                    }
+                    if let Some(metrics) = &metrics {
+                        metrics.connection_closed();
+                    }
+                    drop(guard);
+                    debug!("connection from {peer:?} closed");
+                });
             }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            // out of file descriptors or memory, or a connection reset
+            // before it was taken: the listener itself is fine
             Err(e) => {
-                println!("Error occured on stream: {}", e);
-                return Err(Error::new(
-                    ErrorKind::NetworkUnreachable,
-                    "Something went wrong",
-                ));
+                error!("accepting a connection failed: {e}");
+                thread::sleep(ACCEPT_BACKOFF);
             }
         }
     }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpStream};
+
+    // Serves `router` while `client` talks to it, then stops
+    fn with_server(router: Router, client: impl FnOnce(TcpStream)) {
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let listener = Listener::bind(ListenerConfig::new(address)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = Config::default();
+        let connections = ConnectionTracker::new(config.connections.clone());
+        let live = Live::new(Snapshot { router, config });
+
+        thread::scope(|scope| {
+            let server = scope.spawn(|| serve_live(&listener, &live, &connections));
+            let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            client(stream);
+            connections.stop();
+            server.join().unwrap().unwrap();
+        });
+    }
 
-    Ok(())
+    // Reads one response head, the bytes after it are left on the stream
+    fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            assert_eq!(stream.read(&mut byte).unwrap(), 1, "closed early");
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[test]
+    fn test_head_keeps_connection() {
+        with_server(Router::new(), |mut stream| {
+            stream
+                .write_all(b"HEAD / HTTP/1.1\r\nHost: lb\r\n\r\nGET / HTTP/1.1\r\nHost: lb\r\nConnection: close\r\n\r\n")
+                .unwrap();
+
+            // the length GET gets, but no body before the next response
+            let head = read_head(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(head.contains("Content-Length: 2\r\n"));
+            assert!(head.contains("Connection: keep-alive\r\n"));
+
+            let head = read_head(&mut stream);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+            let mut body = String::new();
+            stream.read_to_string(&mut body).unwrap();
+            assert_eq!(body, "OK");
+        });
+    }
 }
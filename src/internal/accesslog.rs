@@ -0,0 +1,403 @@
+use std::{
+    fmt::{self, Write as _},
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::internal::{
+    httpdate,
+    request::Request,
+    response::{StatusCode, Upstream},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    // host ident authuser [date] "request" status bytes
+    Common,
+    // Common plus "referer" "user-agent"
+    Combined,
+    // one object per line with the configured fields
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log format {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Time,
+    ClientIp,
+    Method,
+    Path,
+    Protocol,
+    Status,
+    // everything written for the response, head included
+    BytesSent,
+    BytesReceived,
+    Duration,
+    Upstream,
+    UpstreamDuration,
+    RequestId,
+    Referer,
+    UserAgent,
+}
+
+const FIELDS: [(Field, &str); 14] = [
+    (Field::Time, "time"),
+    (Field::ClientIp, "client_ip"),
+    (Field::Method, "method"),
+    (Field::Path, "path"),
+    (Field::Protocol, "protocol"),
+    (Field::Status, "status"),
+    (Field::BytesSent, "bytes_sent"),
+    (Field::BytesReceived, "bytes_received"),
+    (Field::Duration, "duration_ms"),
+    (Field::Upstream, "upstream"),
+    (Field::UpstreamDuration, "upstream_duration_ms"),
+    (Field::RequestId, "request_id"),
+    (Field::Referer, "referer"),
+    (Field::UserAgent, "user_agent"),
+];
+
+impl Field {
+    pub fn name(&self) -> &'static str {
+        FIELDS
+            .iter()
+            .find(|(f, _)| f == self)
+            .map(|(_, n)| *n)
+            .unwrap()
+    }
+
+    pub fn all() -> Vec<Field> {
+        FIELDS.iter().map(|(f, _)| *f).collect()
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FIELDS
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(f, _)| *f)
+            .ok_or_else(|| format!("Invalid log field {s}"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogOutput {
+    Stdout,
+    // appended to, reopened on request so it can be rotated
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogConfig {
+    pub format: LogFormat,
+    // only used by the JSON format, the others are fixed
+    pub fields: Vec<Field>,
+    pub output: LogOutput,
+    // share of the requests that are logged, server errors always are
+    pub sample: f64,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            format: LogFormat::Combined,
+            fields: Field::all(),
+            output: LogOutput::Stdout,
+            sample: 1.0,
+        }
+    }
+}
+
+// What is known about a request once its response was sent. The request is
+// None when it could not be parsed.
+pub struct AccessEntry<'a> {
+    pub time: SystemTime,
+    pub request: Option<&'a Request>,
+    pub client: Option<IpAddr>,
+    pub status: StatusCode,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duration: Duration,
+    pub upstream: Option<&'a Upstream>,
+    // also given to requests that could not be parsed
+    pub request_id: Option<&'a str>,
+}
+
+impl AccessEntry<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.request?.headers.as_ref()?.get(name)
+    }
+
+    fn method(&self) -> Option<&str> {
+        Some(self.request?.method.as_ref()?.as_str())
+    }
+
+    fn path(&self) -> Option<&str> {
+        self.request?.path.as_deref()
+    }
+
+    fn protocol(&self) -> Option<&str> {
+        self.request?.version.as_deref()
+    }
+}
+
+pub struct AccessLog {
+    config: AccessLogConfig,
+    out: Mutex<Box<dyn Write + Send>>,
+    // requests seen, for sampling
+    seen: AtomicU64,
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl AccessLog {
+    pub fn new(config: AccessLogConfig) -> io::Result<AccessLog> {
+        let out = open(&config.output)?;
+        Ok(AccessLog {
+            config,
+            out: Mutex::new(out),
+            seen: AtomicU64::new(0),
+        })
+    }
+
+    pub fn config(&self) -> &AccessLogConfig {
+        &self.config
+    }
+
+    // Opens the file again, after it was moved away by log rotation
+    pub fn reopen(&self) -> io::Result<()> {
+        let out = open(&self.config.output)?;
+        *self.out.lock().unwrap() = out;
+        Ok(())
+    }
+
+    pub fn log(&self, entry: &AccessEntry) {
+        if !self.sampled(entry.status) {
+            return;
+        }
+        let mut line = self.format(entry);
+        line.push('\n');
+        let _ = self.out.lock().unwrap().write_all(line.as_bytes());
+    }
+
+    // Keeps an even share: the n-th request is logged when it moves
+    // floor(n * sample) up by one
+    fn sampled(&self, status: StatusCode) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed);
+        let sample = self.config.sample.clamp(0.0, 1.0);
+        status.is_server_error() || ((n + 1) as f64 * sample).floor() > (n as f64 * sample).floor()
+    }
+
+    pub fn format(&self, entry: &AccessEntry) -> String {
+        match self.config.format {
+            LogFormat::Common => common(entry),
+            LogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                common(entry),
+                clf_escape(entry.header("Referer").unwrap_or("-")),
+                clf_escape(entry.header("User-Agent").unwrap_or("-"))
+            ),
+            LogFormat::Json => self.json(entry),
+        }
+    }
+
+    fn json(&self, entry: &AccessEntry) -> String {
+        let mut out = String::from("{");
+        for (i, field) in self.config.fields.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "\"{}\":", field.name());
+
+            let text = |out: &mut String, value: Option<&str>| match value {
+                Some(value) => {
+                    let _ = write!(out, "\"{}\"", json_escape(value));
+                }
+                None => out.push_str("null"),
+            };
+            let millis = |d: Duration| format!("{:.3}", d.as_secs_f64() * 1000.0);
+
+            match field {
+                Field::Time => text(&mut out, Some(&httpdate::format_rfc3339(entry.time))),
+                Field::ClientIp => text(&mut out, entry.client.map(|ip| ip.to_string()).as_deref()),
+                Field::Method => text(&mut out, entry.method()),
+                Field::Path => text(&mut out, entry.path()),
+                Field::Protocol => text(&mut out, entry.protocol()),
+                Field::Status => out.push_str(&entry.status.as_u16().to_string()),
+                Field::BytesSent => out.push_str(&entry.bytes_sent.to_string()),
+                Field::BytesReceived => out.push_str(&entry.bytes_received.to_string()),
+                Field::Duration => out.push_str(&millis(entry.duration)),
+                Field::Upstream => text(&mut out, entry.upstream.map(|u| u.address.as_str())),
+                Field::UpstreamDuration => match entry.upstream {
+                    Some(upstream) => out.push_str(&millis(upstream.took)),
+                    None => out.push_str("null"),
+                },
+                Field::RequestId => text(&mut out, entry.request_id),
+                Field::Referer => text(&mut out, entry.header("Referer")),
+                Field::UserAgent => text(&mut out, entry.header("User-Agent")),
+            }
+        }
+        out.push('}');
+        out
+    }
+}
+
+fn open(output: &LogOutput) -> io::Result<Box<dyn Write + Send>> {
+    Ok(match output {
+        LogOutput::Stdout => Box::new(io::stdout()),
+        LogOutput::File(path) => {
+            let file: File = OpenOptions::new().create(true).append(true).open(path)?;
+            Box::new(file)
+        }
+    })
+}
+
+fn common(entry: &AccessEntry) -> String {
+    let request = match (entry.method(), entry.path(), entry.protocol()) {
+        (Some(method), Some(path), Some(protocol)) => format!("{method} {path} {protocol}"),
+        _ => "-".to_string(),
+    };
+    format!(
+        "{} - - [{}] \"{}\" {} {}",
+        entry.client.map_or("-".to_string(), |ip| ip.to_string()),
+        httpdate::format_clf(entry.time),
+        clf_escape(&request),
+        entry.status.as_u16(),
+        entry.bytes_sent
+    )
+}
+
+// Quotes and control characters would break the line apart
+fn clf_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\x{:02x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn json_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::request::parse;
+    use std::time::UNIX_EPOCH;
+
+    fn entry<'a>(request: &'a Request, upstream: Option<&'a Upstream>) -> AccessEntry<'a> {
+        AccessEntry {
+            time: UNIX_EPOCH + Duration::from_secs(784111777),
+            request: Some(request),
+            client: Some("10.0.0.1".parse().unwrap()),
+            status: StatusCode::OK,
+            bytes_sent: 120,
+            bytes_received: 80,
+            duration: Duration::from_micros(1500),
+            upstream,
+            request_id: Some("abc"),
+        }
+    }
+
+    #[test]
+    fn test_log_formats() {
+        let request = parse(
+            b"GET /a?b=1 HTTP/1.1\r\nHost: h\r\nUser-Agent: curl \"x\"\r\nX-Request-Id: abc\r\n\r\n",
+        )
+        .unwrap();
+        let upstream = Upstream {
+            address: "127.0.0.1:9000".to_string(),
+            took: Duration::from_millis(1),
+        };
+
+        let log = AccessLog::new(AccessLogConfig::default()).unwrap();
+        assert_eq!(
+            log.format(&entry(&request, None)),
+            "10.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /a?b=1 HTTP/1.1\" 200 120 \"-\" \"curl \\\"x\\\"\""
+        );
+
+        let log = AccessLog::new(AccessLogConfig {
+            format: LogFormat::Json,
+            fields: "client_ip,method,status,duration_ms,upstream,upstream_duration_ms,request_id,referer"
+                .split(',')
+                .map(|f| f.parse().unwrap())
+                .collect(),
+            ..AccessLogConfig::default()
+        })
+        .unwrap();
+        assert_eq!(
+            log.format(&entry(&request, Some(&upstream))),
+            "{\"client_ip\":\"10.0.0.1\",\"method\":\"GET\",\"status\":200,\"duration_ms\":1.500,\
+             \"upstream\":\"127.0.0.1:9000\",\"upstream_duration_ms\":1.000,\"request_id\":\"abc\",\"referer\":null}"
+        );
+
+        let unparsed = AccessEntry {
+            request: None,
+            status: StatusCode::BAD_REQUEST,
+            ..entry(&request, None)
+        };
+        assert!(common(&unparsed).ends_with("\"-\" 400 120"));
+    }
+
+    #[test]
+    fn test_sampling() {
+        let log = AccessLog::new(AccessLogConfig {
+            sample: 0.25,
+            ..AccessLogConfig::default()
+        })
+        .unwrap();
+
+        let logged = (0..100).filter(|_| log.sampled(StatusCode::OK)).count();
+        assert_eq!(logged, 25);
+        assert!(log.sampled(StatusCode::BAD_GATEWAY));
+    }
+}
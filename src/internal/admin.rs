@@ -0,0 +1,502 @@
+use std::{
+    fmt::{self, Write as _},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use crate::internal::{
+    accesslog::json_escape,
+    breaker::CircuitState,
+    connection::ConnectionTracker,
+    httpdate,
+    metrics::Metrics,
+    proxy::{Backend, Proxy},
+    reload::{Live, Snapshot},
+    request::{Request, RequestMethod},
+    response::{Response, StatusCode},
+    router::{RouteAction, Router},
+};
+
+// Reloads the configuration, with a summary of what changed
+pub type ReloadFn = dyn Fn() -> Result<String, String> + Send + Sync;
+
+// Inspects and changes the running server, meant for a listener of its own
+// on a private address:
+//
+//   GET  /backends                      backends of every route of every listener,
+//                                       with health and pools
+//   GET  /pools                         upstream connection pools
+//   POST /backends/<host:port>/drain    no new requests for the backend
+//   POST /backends/<host:port>/enable   takes requests again
+//   POST /backends/<host:port>/weight   body or ?value= is the new weight
+//   GET  /connections                   open client connections
+//   GET  /config                        the effective configuration
+//   POST /reload                        reloads the configuration
+//   GET  /metrics                       Prometheus metrics, when they are collected
+pub struct Admin {
+    listeners: Vec<Watched>,
+    metrics: Option<Arc<Metrics>>,
+    // expected as `Authorization: Bearer <token>` on every request
+    token: Option<String>,
+    reload: Option<Box<ReloadFn>>,
+}
+
+// A listener and what it serves with
+#[derive(Debug)]
+struct Watched {
+    name: String,
+    live: Arc<Live>,
+    connections: Arc<ConnectionTracker>,
+}
+
+impl fmt::Debug for Admin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Admin")
+            .field("listeners", &self.listeners)
+            .field("token", &self.token.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
+impl Admin {
+    // `live` holds what the listener named `name` serves with, the backends
+    // are shared with it
+    pub fn new(
+        name: impl Into<String>,
+        live: Arc<Live>,
+        connections: Arc<ConnectionTracker>,
+    ) -> Admin {
+        Admin {
+            listeners: vec![Watched {
+                name: name.into(),
+                live,
+                connections,
+            }],
+            metrics: None,
+            token: None,
+            reload: None,
+        }
+    }
+
+    pub fn with_listener(
+        mut self,
+        name: impl Into<String>,
+        live: Arc<Live>,
+        connections: Arc<ConnectionTracker>,
+    ) -> Self {
+        self.listeners.push(Watched {
+            name: name.into(),
+            live,
+            connections,
+        });
+        self
+    }
+
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_reload(
+        mut self,
+        reload: impl Fn() -> Result<String, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.reload = Some(Box::new(reload));
+        self
+    }
+
+    pub fn serve(&self, request: &Request, path: &str) -> Response {
+        if let Some(response) = self.unauthorized(request) {
+            return response;
+        }
+
+        let current: Vec<(&str, Arc<Snapshot>)> = self
+            .listeners
+            .iter()
+            .map(|l| (l.name.as_str(), l.live.load()))
+            .collect();
+        let routers: Vec<(&str, &Router)> = current
+            .iter()
+            .map(|(name, snapshot)| (*name, &snapshot.router))
+            .collect();
+        let router = routers.as_slice();
+        let method = request.method.clone().unwrap_or(RequestMethod::Get);
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (RequestMethod::Get, ["backends"]) => json(StatusCode::OK, backends_json(router)),
+            (RequestMethod::Get, ["pools"]) => json(StatusCode::OK, pools_json(router)),
+            (RequestMethod::Post, ["backends", address, "drain"]) => {
+                update(router, address, |b| b.set_draining(true))
+            }
+            (RequestMethod::Post, ["backends", address, "enable"]) => {
+                update(router, address, |b| b.set_draining(false))
+            }
+            (RequestMethod::Post | RequestMethod::Put, ["backends", address, "weight"]) => {
+                match weight(request) {
+                    Some(weight) => update(router, address, |b| b.set_weight(weight)),
+                    None => text(StatusCode::BAD_REQUEST, "The weight must be a number\n"),
+                }
+            }
+            (RequestMethod::Get, ["connections"]) => json(StatusCode::OK, self.connections_json()),
+            (RequestMethod::Get, ["config"]) => text(StatusCode::OK, &config_text(&current)),
+            (RequestMethod::Post, ["reload"]) => match &self.reload {
+                Some(reload) => match reload() {
+                    Ok(changes) => text(StatusCode::OK, &changes),
+                    Err(e) => text(StatusCode::INTERNAL_SERVER_ERROR, &format!("{e}\n")),
+                },
+                None => text(
+                    StatusCode::NOT_IMPLEMENTED,
+                    "There is no configuration file to reload\n",
+                ),
+            },
+            (RequestMethod::Get, ["metrics"]) if self.metrics.is_some() => {
+                self.metrics.as_ref().unwrap().response()
+            }
+            (
+                _,
+                ["backends" | "pools" | "connections" | "config" | "reload" | "metrics"]
+                | ["backends", _, "drain" | "enable" | "weight"],
+            ) => text(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed\n"),
+            _ => text(StatusCode::NOT_FOUND, "Resource Not Found\n"),
+        }
+    }
+
+    // The 401 for a request without the token, None when it may go on
+    pub fn unauthorized(&self, request: &Request) -> Option<Response> {
+        if self.authorized(request) {
+            return None;
+        }
+        let response = Response::builder(StatusCode::UNAUTHORIZED)
+            .header("WWW-Authenticate", "Bearer")
+            .body(StatusCode::UNAUTHORIZED.reason())
+            .build();
+        Some(response)
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        let given = request
+            .headers
+            .as_ref()
+            .and_then(|h| h.get("Authorization"))
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        // the time taken should not tell how much of the token was right
+        given.len() == token.len()
+            && given
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    fn connections_json(&self) -> String {
+        let now = SystemTime::now();
+        let mut out = String::from("[");
+        let active = self.listeners.iter().flat_map(|l| {
+            let name = l.name.as_str();
+            l.connections.active().into_iter().map(move |c| (name, c))
+        });
+        for (i, (listener, connection)) in active.enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"listener\":\"{}\",\"id\":{},\"peer\":{},\"opened\":\"{}\",\"age_secs\":{:.3},\"requests\":{}}}",
+                json_escape(listener),
+                connection.id,
+                connection
+                    .peer
+                    .map_or("null".to_string(), |p| format!("\"{p}\"")),
+                httpdate::format_rfc3339(connection.opened),
+                now.duration_since(connection.opened)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+                connection.requests
+            );
+        }
+        out.push(']');
+        out
+    }
+}
+
+// The listeners by name, with the router each serves with
+type Routers<'a> = [(&'a str, &'a Router)];
+
+// The proxies of every route, with their fallbacks, by listener and route
+fn proxies<'a>(routers: &Routers<'a>) -> Vec<(&'a str, &'a str, &'a Proxy)> {
+    let mut proxies = Vec::new();
+    for (listener, router) in routers {
+        for route in router.routes() {
+            if let RouteAction::Proxy(proxy) = &route.action {
+                proxies.push((*listener, route.name(), proxy));
+                if let Some(fallback) = proxy.fallback() {
+                    proxies.push((*listener, route.name(), fallback));
+                }
+            }
+        }
+    }
+    proxies
+}
+
+// Applies to the backend in every route it serves
+fn update(router: &Routers, address: &str, change: impl Fn(&Backend)) -> Response {
+    let mut found = false;
+    for (_, _, proxy) in proxies(router) {
+        for backend in proxy.backends().iter().filter(|b| b.address() == address) {
+            change(backend);
+            found = true;
+        }
+    }
+    match found {
+        true => json(StatusCode::OK, backends_json(router)),
+        false => text(StatusCode::NOT_FOUND, &format!("No backend at {address}\n")),
+    }
+}
+
+fn backends_json(router: &Routers) -> String {
+    let mut out = String::from("[");
+    for (i, (listener, route, proxy)) in proxies(router).into_iter().enumerate() {
+        for (j, backend) in proxy.backends().iter().enumerate() {
+            if i + j > 0 {
+                out.push(',');
+            }
+            let circuit = backend.breaker().map(|b| b.state());
+            let healthy = !backend.is_draining()
+                && backend.weight() > 0
+                && backend.is_healthy()
+                && circuit != Some(CircuitState::Open);
+            let _ = write!(
+                out,
+                "{{\"listener\":\"{}\",\"route\":\"{}\",\"address\":\"{}\",\"healthy\":{healthy},\"draining\":{},\"weight\":{},\"circuit\":{},\"idle\":{},\"in_flight\":{}}}",
+                json_escape(listener),
+                json_escape(route),
+                json_escape(backend.address()),
+                backend.is_draining(),
+                backend.weight(),
+                match circuit {
+                    Some(CircuitState::Closed) => "\"closed\"",
+                    Some(CircuitState::Open) => "\"open\"",
+                    Some(CircuitState::HalfOpen) => "\"half_open\"",
+                    None => "null",
+                },
+                backend.pool().idle(),
+                backend.pool().in_flight()
+            );
+        }
+    }
+    out.push(']');
+    out
+}
+
+fn pools_json(router: &Routers) -> String {
+    let mut out = String::from("[");
+    let pools = proxies(router)
+        .into_iter()
+        .flat_map(|(listener, route, proxy)| {
+            proxy
+                .backends()
+                .iter()
+                .map(move |b| (listener, route, b.pool()))
+        });
+    for (i, (listener, route, pool)) in pools.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let config = pool.config();
+        let _ = write!(
+            out,
+            "{{\"listener\":\"{}\",\"route\":\"{}\",\"address\":\"{}\",\"idle\":{},\"in_flight\":{},\"max_idle\":{},\"max_in_flight\":{}}}",
+            json_escape(listener),
+            json_escape(route),
+            json_escape(pool.address()),
+            pool.idle(),
+            pool.in_flight(),
+            config.max_idle,
+            config.max_in_flight
+        );
+    }
+    out.push(']');
+    out
+}
+
+fn config_text(current: &[(&str, Arc<Snapshot>)]) -> String {
+    let mut out = String::new();
+    for (listener, snapshot) in current {
+        let _ = writeln!(out, "listener {listener}\nroutes:");
+        for route in snapshot.router.routes() {
+            let _ = writeln!(out, "  {} => {}", route.name(), route.describe());
+        }
+        let redirects = snapshot.router.redirects();
+        if !redirects.is_empty() {
+            let _ = writeln!(out, "redirect map: {} paths", redirects.len());
+        }
+        let _ = writeln!(out, "{:#?}", snapshot.config);
+    }
+    out
+}
+
+// From the query, ?value=3, or else the body
+fn weight(request: &Request) -> Option<u32> {
+    let from_query = request.query().and_then(|q| {
+        q.split('&')
+            .find_map(|pair| pair.strip_prefix("value="))
+            .map(|v| v.to_string())
+    });
+    let value = from_query.unwrap_or_else(|| String::from_utf8_lossy(&request.body).to_string());
+    value.trim().parse().ok()
+}
+
+fn json(status: StatusCode, body: String) -> Response {
+    Response::builder(status)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(body)
+        .build()
+}
+
+fn text(status: StatusCode, body: &str) -> Response {
+    Response::builder(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .header("Cache-Control", "no-store")
+        .body(body)
+        .build()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::{config::Config, connection::ConnectionLimits, request::parse};
+
+    fn admin() -> (Admin, Proxy) {
+        let proxy = Proxy::new("127.0.0.1:1").with_backend("127.0.0.1:2");
+        let live = Arc::new(Live::new(Snapshot {
+            router: Router::new().route("/api", RouteAction::Proxy(proxy.clone())),
+            config: Config::default(),
+        }));
+        let connections = Arc::new(ConnectionTracker::new(ConnectionLimits::default()));
+        let admin = Admin::new("127.0.0.1:8080", live, connections).with_token("secret");
+        (admin, proxy)
+    }
+
+    fn call(admin: &Admin, head: &str) -> (StatusCode, String) {
+        let request = parse(head.as_bytes()).unwrap();
+        let path = request.target_path().unwrap().to_string();
+        let response = admin.serve(&request, &path);
+        let mut out = Vec::new();
+        let status = response.status;
+        response.send(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let body = out.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status, body)
+    }
+
+    #[test]
+    fn test_admin_backends() {
+        let (admin, proxy) = admin();
+        let auth = "Authorization: Bearer secret\r\n";
+
+        let (status, _) = call(&admin, "GET /backends HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(
+            &admin,
+            "GET /backends HTTP/1.1\r\nHost: a\r\nAuthorization: Bearer secreT\r\n\r\n",
+        );
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = call(&admin, &format!("GET /backends HTTP/1.1\r\n{auth}\r\n"));
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with(
+            "[{\"listener\":\"127.0.0.1:8080\",\"route\":\"/api\",\"address\":\"127.0.0.1:1\",\"healthy\":true,\"draining\":false,\"weight\":1,\"circuit\":null,"
+        ));
+
+        // the change reaches the proxy the main listener serves with
+        let (status, _) = call(
+            &admin,
+            &format!(
+                "POST /backends/127.0.0.1:2/drain HTTP/1.1\r\n{auth}Content-Length: 0\r\n\r\n"
+            ),
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert!(proxy.backends()[1].is_draining());
+        call(
+            &admin,
+            &format!(
+                "POST /backends/127.0.0.1:2/enable HTTP/1.1\r\n{auth}Content-Length: 0\r\n\r\n"
+            ),
+        );
+        assert!(!proxy.backends()[1].is_draining());
+
+        call(
+            &admin,
+            &format!(
+                "POST /backends/127.0.0.1:1/weight HTTP/1.1\r\n{auth}Content-Length: 1\r\n\r\n5"
+            ),
+        );
+        call(
+            &admin,
+            &format!("PUT /backends/127.0.0.1:2/weight?value=0 HTTP/1.1\r\n{auth}\r\n"),
+        );
+        assert_eq!(proxy.backends()[0].weight(), 5);
+        assert_eq!(proxy.backends()[1].weight(), 0);
+
+        let (status, _) = call(
+            &admin,
+            &format!(
+                "POST /backends/127.0.0.1:3/drain HTTP/1.1\r\n{auth}Content-Length: 0\r\n\r\n"
+            ),
+        );
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(&admin, &format!("DELETE /backends HTTP/1.1\r\n{auth}\r\n"));
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_admin_inspection() {
+        let (admin, _) = admin();
+        let auth = "Authorization: Bearer secret\r\n";
+
+        let guard = admin.listeners[0]
+            .connections
+            .acquire(Some("10.0.0.1:5000".parse().unwrap()))
+            .unwrap();
+        guard.served();
+        let (_, body) = call(&admin, &format!("GET /connections HTTP/1.1\r\n{auth}\r\n"));
+        assert!(
+            body.starts_with(
+                "[{\"listener\":\"127.0.0.1:8080\",\"id\":0,\"peer\":\"10.0.0.1:5000\","
+            )
+        );
+        assert!(body.ends_with("\"requests\":1}]"));
+
+        let (_, body) = call(&admin, &format!("GET /config HTTP/1.1\r\n{auth}\r\n"));
+        assert!(body.starts_with(
+            "listener 127.0.0.1:8080\nroutes:\n  /api => proxy 127.0.0.1:1,127.0.0.1:2\nConfig {"
+        ));
+
+        let (status, _) = call(
+            &admin,
+            &format!("POST /reload HTTP/1.1\r\n{auth}Content-Length: 0\r\n\r\n"),
+        );
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        drop(guard);
+        let admin = admin.with_reload(|| Ok("1 route changed\n".to_string()));
+        let (status, body) = call(
+            &admin,
+            &format!("POST /reload HTTP/1.1\r\n{auth}Content-Length: 0\r\n\r\n"),
+        );
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::OK, "1 route changed\n")
+        );
+    }
+}
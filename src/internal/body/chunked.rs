@@ -1,103 +1,391 @@
+use crate::internal::headers::{Headers, field_section_len, is_token_char, parse_field_lines};
+use crate::internal::{limits::Limits, request::ErrorMsg};
 use std::{
-    io::{Error, ErrorKind},
+    io::{Error, ErrorKind, Write},
     str,
 };
 const CRLF: &[u8; 2] = b"\r\n";
-const MAX_LENGTH: usize = 1024;
 
-pub fn parse_chunked_message(msg: &[u8], body: &mut Vec<u8>) -> Result<usize, Error> {
-    enum ChunkState {
-        GetSize,
-        GetData,
+// chunked-body   = *chunk
+//                  last-chunk
+//                  trailer-section
+//                  CRLF
+//
+// chunk          = chunk-size [ chunk-ext ] CRLF
+//                  chunk-data CRLF
+// last-chunk     = 1*("0") [ chunk-ext ] CRLF
+// chunk-ext      = *( BWS ";" BWS chunk-ext-name
+//                     [ BWS "=" BWS chunk-ext-val ] )
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkExtension {
+    // index of the chunk the extension was attached to, the last-chunk included
+    pub chunk: usize,
+    pub name: String,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum ChunkState {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+// Incremental decoder: bytes can be fed as they arrive off the wire and the
+// decoded chunk-data is written out without waiting for the whole body.
+#[derive(Debug)]
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    limits: Limits,
+    chunks: usize,
+    decoded: usize,
+    extensions: Vec<ChunkExtension>,
+    trailers: Option<Headers>,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    let mut state: ChunkState = ChunkState::GetSize;
-    let mut size: usize = 0;
-    let mut read: usize = 0;
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
 
-    if msg.len() >= MAX_LENGTH {
-        return Err(Error::new(ErrorKind::InvalidInput, "Exceeded max length"));
+    pub fn with_limits(limits: Limits) -> Self {
+        ChunkedDecoder {
+            state: ChunkState::Size,
+            limits,
+            chunks: 0,
+            decoded: 0,
+            extensions: Vec::new(),
+            trailers: None,
+        }
     }
 
-    loop {
-        let chunk_idx = match msg[read..].windows(2).position(|b| b == CRLF) {
-            Some(c) => c,
-            None => return Ok(read),
-        };
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    pub fn extensions(&self) -> &[ChunkExtension] {
+        &self.extensions
+    }
+
+    pub fn trailers(&self) -> Option<&Headers> {
+        self.trailers.as_ref()
+    }
+
+    pub fn take_trailers(&mut self) -> Option<Headers> {
+        self.trailers.take()
+    }
+
+    // Consumes as much of `msg` as possible and writes the decoded data into `out`.
+    // Returns the number of bytes consumed; if the decoder is not done yet the
+    // caller has to feed the remaining bytes again together with the next read.
+    pub fn decode(&mut self, msg: &[u8], out: &mut impl Write) -> Result<usize, Error> {
+        let mut read: usize = 0;
 
-        let chunk = &msg[read..read + chunk_idx];
+        loop {
+            match self.state {
+                ChunkState::Size => {
+                    let line_idx = match msg[read..].windows(2).position(|b| b == CRLF) {
+                        Some(i) => i,
+                        // a size line with extensions is bounded like a field line
+                        None if msg.len() - read > self.limits.max_header_size => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                ErrorMsg::HEADER_TOO_LARGE,
+                            ));
+                        }
+                        None => return Ok(read),
+                    };
 
-        match state {
-            ChunkState::GetSize => {
-                let chunk_str = str::from_utf8(chunk)
-                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid utf-8 in chunks"))?;
+                    let size = self.parse_size_line(&msg[read..read + line_idx])?;
+                    // the size is known before the data arrives, no need to wait for it
+                    self.decoded = self
+                        .decoded
+                        .checked_add(size)
+                        .filter(|d| *d <= self.limits.max_body_size)
+                        .ok_or_else(|| {
+                            Error::new(ErrorKind::InvalidData, ErrorMsg::BODY_TOO_LARGE)
+                        })?;
 
-                let chunk_usize = usize::from_str_radix(chunk_str, 16).map_err(|_| {
-                    Error::new(ErrorKind::InvalidInput, "Invalid chunk size number")
-                })?;
+                    self.chunks += 1;
+                    read += line_idx + CRLF.len();
 
-                if chunk_usize == 0 {
-                    return Ok(read + chunk_idx + 4);
+                    self.state = if size == 0 {
+                        ChunkState::Trailers
+                    } else {
+                        ChunkState::Data(size)
+                    };
                 }
-                size = chunk_usize;
+                ChunkState::Data(remaining) => {
+                    let available = msg.len() - read;
+                    if available == 0 {
+                        return Ok(read);
+                    }
 
-                state = ChunkState::GetData;
-                read += chunk_idx + 2;
-            }
+                    let n = remaining.min(available);
+                    out.write_all(&msg[read..read + n])?;
+                    read += n;
+
+                    self.state = if n == remaining {
+                        ChunkState::DataEnd
+                    } else {
+                        ChunkState::Data(remaining - n)
+                    };
+                }
+                ChunkState::DataEnd => {
+                    if msg[read..].len() < CRLF.len() {
+                        return Ok(read);
+                    }
+
+                    if &msg[read..read + CRLF.len()] != CRLF {
+                        return Err(Error::new(ErrorKind::InvalidData, "Chunk missing CRLF"));
+                    }
+
+                    read += CRLF.len();
+                    self.state = ChunkState::Size;
+                }
+                ChunkState::Trailers => {
+                    // the trailer section is only parsed once it is complete
+                    let rest = &msg[read..];
+                    let section_end = match field_section_len(rest) {
+                        Some(end) => end,
+                        None => {
+                            // only checks the limits on what has arrived so far
+                            parse_field_lines(rest, &self.limits)?;
+                            return Ok(read);
+                        }
+                    };
 
-            ChunkState::GetData => {
-                let required_byte = size + 2;
+                    let (trailers, _) = parse_field_lines(&rest[..section_end], &self.limits)?;
 
-                if msg[read..].len() < required_byte {
-                    return Ok(read);
+                    self.trailers = Some(trailers);
+                    read += section_end;
+                    self.state = ChunkState::Done;
                 }
+                ChunkState::Done => return Ok(read),
+            }
+        }
+    }
+
+    fn parse_size_line(&mut self, line: &[u8]) -> Result<usize, Error> {
+        let line = str::from_utf8(line)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid utf-8 in chunks"))?;
+
+        let (size, ext) = match line.split_once(';') {
+            Some((s, e)) => (s.trim_end_matches([' ', '\t']), Some(e)),
+            None => (line, None),
+        };
+
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid chunk size number",
+            ));
+        }
+
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid chunk size number"))?;
+
+        if let Some(ext) = ext {
+            self.parse_extensions(ext)?;
+        }
+
+        Ok(size)
+    }
 
-                //  Unneccessary check;
-                // if msg[read..required_byte + read].len() != size {
-                //     println!("This is it");
-                //     return Err(Error::new(ErrorKind::InvalidInput, "Invalid chunk size"));
-                // }
+    fn parse_extensions(&mut self, ext: &str) -> Result<(), Error> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, "Invalid chunk extension");
+        let is_token = |s: &str| !s.is_empty() && s.bytes().all(|b| is_token_char(&b));
+        let bws: &[char] = &[' ', '\t'];
 
-                let data = &msg[read..read + required_byte];
+        let mut rest = ext;
+        loop {
+            let (name, after_name) = match rest.find(['=', ';']) {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, ""),
+            };
+            let name = name.trim_matches(bws);
+            if !is_token(name) {
+                return Err(invalid());
+            }
+
+            let mut value = None;
+            rest = after_name;
 
-                body.extend_from_slice(data);
+            if let Some(v) = rest.strip_prefix('=') {
+                let v = v.trim_start_matches(bws);
 
-                if &msg[read + size..read + required_byte] != b"\r\n" {
-                    return Err(Error::new(ErrorKind::InvalidData, "Chunk missing CRLF"));
+                if let Some(quoted) = v.strip_prefix('"') {
+                    let (unquoted, consumed) = parse_quoted_string(quoted).ok_or_else(invalid)?;
+                    value = Some(unquoted);
+                    rest = quoted[consumed..].trim_start_matches(bws);
+                } else {
+                    let end = v.find(';').unwrap_or(v.len());
+                    let token = v[..end].trim_end_matches(bws);
+                    if !is_token(token) {
+                        return Err(invalid());
+                    }
+                    value = Some(token.to_string());
+                    rest = &v[end..];
                 }
+            }
 
-                state = ChunkState::GetSize;
-                read += required_byte
+            self.extensions.push(ChunkExtension {
+                chunk: self.chunks,
+                name: name.to_string(),
+                value,
+            });
+
+            match rest.strip_prefix(';') {
+                Some(r) => rest = r,
+                None if rest.is_empty() => return Ok(()),
+                None => return Err(invalid()),
             }
         }
     }
 }
 
+// Parses the inside of a quoted-string (opening DQUOTE already stripped), returns
+// the unescaped value and the number of bytes consumed including the closing DQUOTE
+fn parse_quoted_string(s: &str) -> Option<(String, usize)> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, i + 1)),
+            '\\' => value.push(chars.next()?.1),
+            _ => value.push(c),
+        }
+    }
+
+    None
+}
+
+pub fn parse_chunked_message(
+    msg: &[u8],
+    body: &mut Vec<u8>,
+    trailers: &mut Option<Headers>,
+    limits: &Limits,
+) -> Result<usize, Error> {
+    let mut decoder = ChunkedDecoder::with_limits(limits.clone());
+    let read = decoder.decode(msg, body)?;
+
+    if !decoder.is_done() {
+        return Err(Error::new(
+            ErrorKind::WouldBlock,
+            ErrorMsg::INCOMPLETE_MESSAGE,
+        ));
+    }
+
+    *trailers = decoder.take_trailers();
+    Ok(read)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[test]
     fn test_chunk_body() {
+        let limits = Limits::default();
         let mut body: Vec<u8> = Vec::new();
+        let mut trailers = None;
         let mut input: &[u8] = b"6\r\nHello \r\n5\r\nWorld\r\n0\r\n\r\n";
 
-        println!("Ox");
-        let result = parse_chunked_message(input, &mut body).unwrap();
+        let result = parse_chunked_message(input, &mut body, &mut trailers, &limits).unwrap();
 
         let hello = b"Hello";
         assert_eq!(result, 26);
         let result_slice = &body[0..5];
         assert_eq!(result_slice, hello);
-        println!("O");
+        assert_eq!(body, b"Hello World");
 
         input = b"6\r\nHello \r\n%\r\nWorld\r\n0\r\n\r\n";
         body = Vec::new();
-        let result_err = parse_chunked_message(input, &mut body).unwrap_err();
-
-        println!("result_err = {result_err:?}");
+        let result_err =
+            parse_chunked_message(input, &mut body, &mut trailers, &limits).unwrap_err();
 
         assert_eq!(result_err.kind(), ErrorKind::InvalidInput);
         assert_eq!(result_err.to_string().as_str(), "Invalid chunk size number");
+
+        input = b"6\r\nHello \r\n5\r\nWorld";
+        body = Vec::new();
+        let result_err =
+            parse_chunked_message(input, &mut body, &mut trailers, &limits).unwrap_err();
+
+        assert_eq!(result_err.kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_chunk_extensions_and_trailers() {
+        let mut decoder = ChunkedDecoder::new();
+        let mut body: Vec<u8> = Vec::new();
+        let input: &[u8] = b"5;name=val\r\nHello\r\n6 ; a ; b=\"x \\\"y\\\"\"\r\n World\r\n0\r\ngrpc-status: 0\r\nGrpc-Message: ok\r\n\r\n";
+
+        let read = decoder.decode(input, &mut body).unwrap();
+
+        assert!(decoder.is_done());
+        assert_eq!(read, input.len());
+        assert_eq!(body, b"Hello World");
+
+        let ext = decoder.extensions();
+        assert_eq!(ext.len(), 3);
+        assert_eq!(ext[0].chunk, 0);
+        assert_eq!(ext[0].name, "name");
+        assert_eq!(ext[0].value.as_deref(), Some("val"));
+        assert_eq!(ext[1].name, "a");
+        assert_eq!(ext[1].value, None);
+        assert_eq!(ext[2].chunk, 1);
+        assert_eq!(ext[2].value.as_deref(), Some("x \"y\""));
+
+        let trailers = decoder.trailers().unwrap();
+        assert_eq!(trailers.get("grpc-status"), Some("0"));
+        assert_eq!(trailers.get("grpc-message"), Some("ok"));
+
+        let mut decoder = ChunkedDecoder::new();
+        let error = decoder.decode(b"5;=val\r\n", &mut body).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "Invalid chunk extension");
+    }
+
+    #[test]
+    fn test_chunk_streaming_decode() {
+        let input: &[u8] = b"a\r\n0123456789\r\n3\r\nabc\r\n0\r\nx-checksum: 42\r\n\r\n";
+        let mut decoder = ChunkedDecoder::new();
+        let mut body: Vec<u8> = Vec::new();
+        let mut pending: Vec<u8> = Vec::new();
+
+        // feed the message a few bytes at a time, as a socket would
+        for piece in input.chunks(3) {
+            pending.extend_from_slice(piece);
+            let read = decoder.decode(&pending, &mut body).unwrap();
+            pending.drain(..read);
+        }
+
+        assert!(decoder.is_done());
+        assert!(pending.is_empty());
+        assert_eq!(body, b"0123456789abc");
+        assert_eq!(decoder.trailers().unwrap().get("X-Checksum"), Some("42"));
+
+        let mut decoder = ChunkedDecoder::new();
+        let error = decoder.decode(b"3\r\nabcX\r\n", &mut body).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let limits = Limits {
+            max_body_size: 12,
+            ..Limits::default()
+        };
+        let mut decoder = ChunkedDecoder::with_limits(limits);
+        let error = decoder.decode(input, &mut body).unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::BODY_TOO_LARGE);
     }
 }
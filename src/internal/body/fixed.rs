@@ -1,27 +1,28 @@
+use crate::internal::{limits::Limits, request::ErrorMsg};
 use std::io::{Error, ErrorKind};
-const MAX_LENGTH: usize = 1024;
 
-pub fn parse_fixed_message(msg: &[u8], size: usize, body: &mut Vec<u8>) -> Result<usize, Error> {
-    if msg.len() >= MAX_LENGTH {
-        return Err(Error::new(ErrorKind::InvalidInput, "Exceeded max length"));
+pub fn parse_fixed_message(
+    msg: &[u8],
+    size: usize,
+    body: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<usize, Error> {
+    // the declared length is checked up front, before any of the body arrives
+    if size > limits.max_body_size {
+        return Err(Error::new(ErrorKind::InvalidData, ErrorMsg::BODY_TOO_LARGE));
     }
 
     if msg.len() < size {
         return Err(Error::new(
             ErrorKind::WouldBlock,
-            "Incomplete message: waiting for data",
+            ErrorMsg::INCOMPLETE_MESSAGE,
         ));
     }
 
-    let request_body = &msg[0..size];
+    body.extend_from_slice(&msg[0..size]);
 
-    if msg.len() < size {
-        return Err(Error::new(ErrorKind::InvalidData, "Waiting for Data.."));
-    };
-
-    body.extend_from_slice(request_body);
-
-    Ok(body.len())
+    // the bytes taken from `msg`, whatever `body` held before
+    Ok(size)
 }
 
 #[cfg(test)]
@@ -30,11 +31,12 @@ mod test {
 
     #[test]
     fn test_parse_fixed_message() {
+        let limits = Limits::default();
         let mut body: Vec<u8> = Vec::new();
         let mut input: &[u8] = b"This is a valid input, okay";
         let mut content_length: usize = 27;
 
-        let mut result = parse_fixed_message(input, content_length, &mut body).unwrap();
+        let mut result = parse_fixed_message(input, content_length, &mut body, &limits).unwrap();
 
         assert_eq!(result, 27);
         assert_eq!(&body[0..4], b"This");
@@ -44,15 +46,19 @@ mod test {
         content_length = 13;
 
         body = Vec::new();
-        result = parse_fixed_message(input, content_length, &mut body).unwrap();
+        result = parse_fixed_message(input, content_length, &mut body, &limits).unwrap();
 
         assert_eq!(result, 13);
         assert_eq!(&body[body.len() - 4..], b" cut");
 
-        println!("This one");
+        // what is counted is the part of the input taken, not the whole body
+        result = parse_fixed_message(input, content_length, &mut body, &limits).unwrap();
+        assert_eq!(result, 13);
+        assert_eq!(body.len(), 26);
+
         input = b"shorter one";
         content_length = 13;
-        let result = parse_fixed_message(input, content_length, &mut body).unwrap_err();
+        let result = parse_fixed_message(input, content_length, &mut body, &limits).unwrap_err();
 
         assert_eq!(result.kind(), ErrorKind::WouldBlock);
         assert_eq!(
@@ -60,4 +66,21 @@ mod test {
             "Incomplete message: waiting for data"
         );
     }
+
+    #[test]
+    fn test_fixed_message_limits() {
+        let mut limits = Limits::default();
+        let mut body: Vec<u8> = Vec::new();
+
+        // uploads larger than a single read buffer are fine
+        let input = vec![b'a'; 64 * 1024];
+        let result = parse_fixed_message(&input, input.len(), &mut body, &limits).unwrap();
+        assert_eq!(result, 64 * 1024);
+
+        limits.max_body_size = 10;
+        body = Vec::new();
+        let error = parse_fixed_message(b"short", 11, &mut body, &limits).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), ErrorMsg::BODY_TOO_LARGE);
+    }
 }
@@ -1,30 +1,57 @@
 use crate::internal::body::{chunked::parse_chunked_message, fixed::parse_fixed_message};
 
+use super::headers::Headers;
+use super::limits::Limits;
 use super::request::Request;
 use std::io::{Error, ErrorKind};
 
-mod chunked;
+pub mod chunked;
 mod fixed;
+pub mod stream;
 
-pub fn parse_request_body(bytes: &[u8], request: &mut Request) -> Result<usize, Error> {
-    let header = request
-        .headers
-        .as_ref()
-        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Headers missing during body parse"))?;
+// How the length of a message body is determined, RFC 9112 section 6.3
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyLength {
+    Empty,
+    Fixed(usize),
+    Chunked,
+    // responses without a length are delimited by the server closing the connection
+    UntilClose,
+}
 
-    if let Some(te) = header.get("Transfer-Encoding") {
-        if te.to_lowercase().contains("chunked") {
-            return parse_chunked_message(bytes, &mut  request.body);
-        }
+pub fn body_length(headers: &Headers) -> Result<BodyLength, Error> {
+    if let Some(te) = headers.get("Transfer-Encoding")
+        && te.to_lowercase().contains("chunked")
+    {
+        return Ok(BodyLength::Chunked);
     }
 
-    if let Some(cl) = header.get("Content-Length") {
+    if let Some(cl) = headers.get("Content-Length") {
         let length = cl
             .parse::<usize>()
             .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid Content-Length"))?;
 
-        return parse_fixed_message(bytes, length, &mut request.body);
+        return Ok(BodyLength::Fixed(length));
     }
 
-    Ok(0)
+    Ok(BodyLength::Empty)
+}
+
+pub fn parse_request_body(
+    bytes: &[u8],
+    request: &mut Request,
+    limits: &Limits,
+) -> Result<usize, Error> {
+    let header = request
+        .headers
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Headers missing during body parse"))?;
+
+    match body_length(header)? {
+        BodyLength::Chunked => {
+            parse_chunked_message(bytes, &mut request.body, &mut request.trailers, limits)
+        }
+        BodyLength::Fixed(length) => parse_fixed_message(bytes, length, &mut request.body, limits),
+        BodyLength::Empty | BodyLength::UntilClose => Ok(0),
+    }
 }
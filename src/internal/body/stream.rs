@@ -0,0 +1,457 @@
+use super::{BodyLength, body_length, chunked::ChunkedDecoder};
+use crate::internal::{
+    headers::Headers,
+    limits::Limits,
+    request::{ErrorMsg, Request, RequestMethod},
+    response::{ParsedResponse, response_body_length},
+};
+use std::io::{self, Error, ErrorKind, Read, Write};
+
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, ReadBuf};
+
+const READ_SIZE: usize = 8 * 1024;
+
+#[derive(Debug)]
+enum BodyKind {
+    Fixed(usize),
+    Chunked(ChunkedDecoder),
+    UntilClose { read: usize, closed: bool },
+}
+
+// Sans-io body decoder: raw message bytes go in, decoded body bytes come out.
+// Shared by the blocking and the async readers below.
+#[derive(Debug)]
+pub struct BodyDecoder {
+    kind: BodyKind,
+    max_body_size: usize,
+}
+
+impl BodyDecoder {
+    pub fn new(length: BodyLength, limits: &Limits) -> Result<Self, Error> {
+        let kind = match length {
+            BodyLength::Empty => BodyKind::Fixed(0),
+            BodyLength::Fixed(size) if size > limits.max_body_size => {
+                return Err(Error::new(ErrorKind::InvalidData, ErrorMsg::BODY_TOO_LARGE));
+            }
+            BodyLength::Fixed(size) => BodyKind::Fixed(size),
+            BodyLength::Chunked => BodyKind::Chunked(ChunkedDecoder::with_limits(limits.clone())),
+            BodyLength::UntilClose => BodyKind::UntilClose {
+                read: 0,
+                closed: false,
+            },
+        };
+
+        Ok(BodyDecoder {
+            kind,
+            max_body_size: limits.max_body_size,
+        })
+    }
+
+    // Returns the number of bytes of `input` consumed, the decoded data is
+    // appended to `out`
+    pub fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<usize, Error> {
+        match &mut self.kind {
+            BodyKind::Fixed(remaining) => {
+                let n = input.len().min(*remaining);
+                out.extend_from_slice(&input[..n]);
+                *remaining -= n;
+                Ok(n)
+            }
+            BodyKind::Chunked(decoder) => decoder.decode(input, out),
+            BodyKind::UntilClose { read, .. } => {
+                *read += input.len();
+                if *read > self.max_body_size {
+                    return Err(Error::new(ErrorKind::InvalidData, ErrorMsg::BODY_TOO_LARGE));
+                }
+                out.extend_from_slice(input);
+                Ok(input.len())
+            }
+        }
+    }
+
+    // Called once the peer closed the connection, only a close-delimited body
+    // can end that way
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if let BodyKind::UntilClose { closed, .. } = &mut self.kind {
+            *closed = true;
+        }
+
+        match self.is_done() {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before the body was complete",
+            )),
+        }
+    }
+
+    // What is still to come of a body whose length is known
+    pub fn remaining(&self) -> Option<u64> {
+        match &self.kind {
+            BodyKind::Fixed(remaining) => Some(*remaining as u64),
+            _ => None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        match &self.kind {
+            BodyKind::Fixed(remaining) => *remaining == 0,
+            BodyKind::Chunked(decoder) => decoder.is_done(),
+            BodyKind::UntilClose { closed, .. } => *closed,
+        }
+    }
+
+    pub fn trailers(&self) -> Option<&Headers> {
+        match &self.kind {
+            BodyKind::Chunked(decoder) => decoder.trailers(),
+            _ => None,
+        }
+    }
+}
+
+enum Step {
+    Ready,
+    NeedInput,
+}
+
+// Streams a message body straight off the connection. Nothing is read from the
+// source until the caller asks for more data, so a slow consumer (e.g. the
+// upstream connection) holds back the client instead of the body piling up here.
+pub struct BodyReader<R> {
+    source: R,
+    decoder: BodyDecoder,
+    // raw bytes read off the source that the decoder has not consumed yet
+    pending: Vec<u8>,
+    // decoded bytes that have not been handed out yet
+    decoded: Vec<u8>,
+    pos: usize,
+}
+
+impl<R> BodyReader<R> {
+    // `leftover` are the bytes that were read together with the head
+    pub fn new(decoder: BodyDecoder, leftover: &[u8], source: R) -> Self {
+        BodyReader {
+            source,
+            decoder,
+            pending: leftover.to_vec(),
+            decoded: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn for_request(
+        request: &Request,
+        leftover: &[u8],
+        source: R,
+        limits: &Limits,
+    ) -> Result<Self, Error> {
+        let length = match request.headers.as_ref() {
+            Some(headers) => body_length(headers)?,
+            None => BodyLength::Empty,
+        };
+
+        Ok(Self::new(
+            BodyDecoder::new(length, limits)?,
+            leftover,
+            source,
+        ))
+    }
+
+    // `method` is the method of the request the response answers
+    pub fn for_response(
+        response: &ParsedResponse,
+        method: &RequestMethod,
+        leftover: &[u8],
+        source: R,
+        limits: &Limits,
+    ) -> Result<Self, Error> {
+        let length = response_body_length(method, response.status, &response.headers)?;
+
+        Ok(Self::new(
+            BodyDecoder::new(length, limits)?,
+            leftover,
+            source,
+        ))
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.decoder.is_done() && self.pos == self.decoded.len()
+    }
+
+    pub fn trailers(&self) -> Option<&Headers> {
+        self.decoder.trailers()
+    }
+
+    // Bytes read off the source past the end of the body, i.e. the start of the
+    // next pipelined request
+    pub fn leftover(&self) -> &[u8] {
+        if self.decoder.is_done() {
+            &self.pending
+        } else {
+            &[]
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    fn copy_decoded(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.decoded.len() - self.pos);
+        buf[..n].copy_from_slice(&self.decoded[self.pos..self.pos + n]);
+        self.pos += n;
+        n
+    }
+
+    // Decodes whatever is pending, tells if there is something to hand out or
+    // if more input is needed
+    fn step(&mut self) -> Result<Step, Error> {
+        if self.pos < self.decoded.len() || self.decoder.is_done() {
+            return Ok(Step::Ready);
+        }
+
+        self.decoded.clear();
+        self.pos = 0;
+
+        let consumed = self.decoder.decode(&self.pending, &mut self.decoded)?;
+        self.pending.drain(..consumed);
+
+        if !self.decoded.is_empty() || self.decoder.is_done() {
+            Ok(Step::Ready)
+        } else {
+            Ok(Step::NeedInput)
+        }
+    }
+
+    fn feed(&mut self, input: &[u8]) -> Result<(), Error> {
+        if input.is_empty() {
+            return self.decoder.finish();
+        }
+
+        self.pending.extend_from_slice(input);
+        Ok(())
+    }
+}
+
+impl<R: Read> BodyReader<R> {
+    // Copies the body into `upstream` as it arrives, returns the decoded length
+    pub fn pipe(&mut self, upstream: &mut impl Write) -> Result<u64, Error> {
+        io::copy(self, upstream)
+    }
+}
+
+impl<R: Read> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut chunk = [0u8; READ_SIZE];
+        while let Step::NeedInput = self.step()? {
+            let n = self.source.read(&mut chunk)?;
+            self.feed(&chunk[..n])?;
+        }
+
+        Ok(self.copy_decoded(buf))
+    }
+}
+
+// A request body still on its way from the client, for a proxy to stream
+// upstream. The `length` is known when the client sent a Content-Length and
+// the body is passed on as it came.
+pub struct IncomingBody<'a> {
+    pub reader: &'a mut dyn Read,
+    pub length: Option<u64>,
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin> AsyncRead for BodyReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut chunk = [0u8; READ_SIZE];
+        while let Step::NeedInput = this.step()? {
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.source).poll_read(cx, &mut chunk_buf) {
+                Poll::Ready(Ok(())) => this.feed(chunk_buf.filled())?,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = this.copy_decoded(buf.initialize_unfilled());
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::request::parse_head_with_limits;
+    use crate::internal::response::parse_head_with_limits as parse_response_head;
+
+    // hands out at most `step` bytes per read, like a slow socket would
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+        reads: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            self.reads += 1;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_stream_fixed_body() {
+        let limits = Limits::default();
+        let input: &[u8] = b"POST /upload HTTP/1.1\r\nContent-Length: 11\r\n\r\nHello";
+        let (request, read) = parse_head_with_limits(input, &limits).unwrap();
+        assert!(request.body.is_empty());
+
+        let source = Trickle {
+            data: b" WorldGET / HTTP/1.1\r\n",
+            step: 2,
+            reads: 0,
+        };
+        let mut reader =
+            BodyReader::for_request(&request, &input[read..], source, &limits).unwrap();
+
+        let mut upstream: Vec<u8> = Vec::new();
+        let copied = reader.pipe(&mut upstream).unwrap();
+
+        assert_eq!(copied, 11);
+        assert_eq!(upstream, b"Hello World");
+        assert!(reader.is_done());
+        // the next request is left on the connection
+        let source = reader.into_inner();
+        assert_eq!(source.data, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_stream_chunked_body() {
+        let limits = Limits::default();
+        let input: &[u8] = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let (request, read) = parse_head_with_limits(input, &limits).unwrap();
+
+        let source = Trickle {
+            data: b"5\r\nHello\r\n6\r\n World\r\n0\r\nx-sum: 1\r\n\r\nGET",
+            step: 4,
+            reads: 0,
+        };
+        let mut reader =
+            BodyReader::for_request(&request, &input[read..], source, &limits).unwrap();
+
+        // nothing is pulled off the source before the caller asks for data
+        let mut buf = [0u8; 3];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Hel");
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+
+        assert_eq!(rest, "lo World");
+        assert_eq!(reader.trailers().unwrap().get("x-sum"), Some("1"));
+        // the decoder stopped right at the end of the body
+        assert!(reader.leftover().is_empty());
+        assert_eq!(reader.into_inner().data, b"GET");
+
+        let source = Trickle {
+            data: b"5\r\nHel",
+            step: 4,
+            reads: 0,
+        };
+        let mut reader = BodyReader::for_request(&request, &[], source, &limits).unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_stream_backpressure() {
+        let limits = Limits::default();
+        let body = vec![b'x'; 64 * 1024];
+        let source = Trickle {
+            data: &body,
+            step: READ_SIZE,
+            reads: 0,
+        };
+        let decoder = BodyDecoder::new(BodyLength::Fixed(body.len()), &limits).unwrap();
+        let mut reader = BodyReader::new(decoder, &[], source);
+
+        let mut buf = [0u8; 100];
+        reader.read_exact(&mut buf).unwrap();
+
+        // only one read worth of data was taken from the client
+        assert_eq!(reader.source.reads, 1);
+        assert_eq!(reader.pending.len() + reader.decoded.len(), READ_SIZE);
+
+        let too_large = Limits {
+            max_body_size: 10,
+            ..Limits::default()
+        };
+        let error = BodyDecoder::new(BodyLength::Fixed(11), &too_large).unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::BODY_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_stream_response_until_close() {
+        let limits = Limits::default();
+        let input: &[u8] = b"HTTP/1.0 200 OK\r\nServer: old\r\n\r\nsome";
+        let (response, read) = parse_response_head(input, &RequestMethod::Get, &limits).unwrap();
+
+        let source: &[u8] = b" more data";
+        let mut reader = BodyReader::for_response(
+            &response,
+            &RequestMethod::Get,
+            &input[read..],
+            source,
+            &limits,
+        )
+        .unwrap();
+
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "some more data");
+        assert!(reader.is_done());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_stream_async_read() {
+        let limits = Limits::default();
+        let decoder = BodyDecoder::new(BodyLength::Chunked, &limits).unwrap();
+        let source: &[u8] = b"3\r\nabc\r\n0\r\n\r\n";
+        let mut reader = BodyReader::new(decoder, &[], source);
+
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        let mut out = [0u8; 8];
+        let mut buf = ReadBuf::new(&mut out);
+
+        let poll = Pin::new(&mut reader).poll_read(&mut cx, &mut buf);
+        assert!(matches!(poll, Poll::Ready(Ok(()))));
+        assert_eq!(buf.filled(), b"abc");
+    }
+}
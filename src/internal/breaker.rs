@@ -0,0 +1,394 @@
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::internal::request::ErrorMsg;
+
+// Outcomes kept for the rolling window, older ones are dropped first
+const MAX_SAMPLES: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BreakerConfig {
+    // outcomes older than this no longer count
+    pub window: Duration,
+    // no decision is taken on fewer outcomes than this
+    pub min_requests: usize,
+    // share of failures (connection errors, timeouts, 5xx) that opens the circuit
+    pub error_rate: f64,
+    // the circuit also opens when this percentile of the latency exceeds `slow_threshold`
+    pub slow_percentile: f64,
+    pub slow_threshold: Option<Duration>,
+    // how long an open circuit fails fast before letting probes through
+    pub open_for: Duration,
+    // probes let through at once while half open, all of them have to succeed
+    pub half_open_probes: u32,
+    pub max_concurrent: usize,
+    // requests waiting for one of the concurrent slots, for at most `pending_timeout`
+    pub max_pending: usize,
+    pub pending_timeout: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            window: Duration::from_secs(10),
+            min_requests: 20,
+            error_rate: 0.5,
+            slow_percentile: 0.99,
+            slow_threshold: None,
+            open_for: Duration::from_secs(30),
+            half_open_probes: 5,
+            max_concurrent: 1024,
+            max_pending: 256,
+            pending_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum Circuit {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probes: u32, successes: u32 },
+}
+
+#[derive(Debug)]
+struct Sample {
+    at: Instant,
+    success: bool,
+    latency: Duration,
+}
+
+#[derive(Debug)]
+struct BreakerState {
+    circuit: Circuit,
+    samples: VecDeque<Sample>,
+    active: usize,
+    pending: usize,
+}
+
+// Stops sending requests to an upstream that keeps failing, and caps how many
+// requests it gets at once
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: Mutex<BreakerState>,
+    released: Condvar,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Arc<CircuitBreaker> {
+        Arc::new(CircuitBreaker {
+            config,
+            state: Mutex::new(BreakerState {
+                circuit: Circuit::Closed,
+                samples: VecDeque::new(),
+                active: 0,
+                pending: 0,
+            }),
+            released: Condvar::new(),
+        })
+    }
+
+    pub fn config(&self) -> &BreakerConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let mut state = self.state.lock().unwrap();
+        match self.advance(&mut state) {
+            Circuit::Closed => CircuitState::Closed,
+            Circuit::Open { .. } => CircuitState::Open,
+            Circuit::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.state.lock().unwrap().active
+    }
+
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().pending
+    }
+
+    // Whether a request would be let through right now
+    pub fn allows(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.admits(&mut state)
+    }
+
+    // Takes one of the concurrent slots, waiting for one when they are all in
+    // use. Fails right away while the circuit is open.
+    pub fn try_acquire(self: &Arc<Self>) -> Result<Permit, Error> {
+        let mut state = self.state.lock().unwrap();
+        if !self.admits(&mut state) {
+            return Err(circuit_open());
+        }
+
+        if state.active >= self.config.max_concurrent {
+            let too_many = || Error::new(ErrorKind::ResourceBusy, ErrorMsg::TOO_MANY_PENDING);
+            if state.pending >= self.config.max_pending {
+                return Err(too_many());
+            }
+
+            state.pending += 1;
+            let deadline = Instant::now() + self.config.pending_timeout;
+            while state.active >= self.config.max_concurrent {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    state.pending -= 1;
+                    return Err(too_many());
+                }
+                state = self.released.wait_timeout(state, left).unwrap().0;
+            }
+            state.pending -= 1;
+        }
+
+        state.active += 1;
+        let probe = match &mut state.circuit {
+            Circuit::HalfOpen { probes, .. } => {
+                *probes += 1;
+                true
+            }
+            _ => false,
+        };
+
+        Ok(Permit(Arc::new(PermitInner {
+            breaker: Arc::clone(self),
+            probe,
+        })))
+    }
+
+    fn admits(&self, state: &mut BreakerState) -> bool {
+        match self.advance(state) {
+            Circuit::Closed => true,
+            Circuit::Open { .. } => false,
+            Circuit::HalfOpen { probes, .. } => *probes < self.config.half_open_probes,
+        }
+    }
+
+    // An open circuit turns half open once its time is up
+    fn advance<'a>(&self, state: &'a mut BreakerState) -> &'a Circuit {
+        if let Circuit::Open { until } = state.circuit
+            && Instant::now() >= until
+        {
+            state.circuit = Circuit::HalfOpen {
+                probes: 0,
+                successes: 0,
+            };
+        }
+        &state.circuit
+    }
+
+    fn record(&self, probe: bool, success: bool, latency: Duration) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let now = Instant::now();
+
+        while state
+            .samples
+            .front()
+            .is_some_and(|s| now.duration_since(s.at) > self.config.window)
+            || state.samples.len() >= MAX_SAMPLES
+        {
+            state.samples.pop_front();
+        }
+        state.samples.push_back(Sample {
+            at: now,
+            success,
+            latency,
+        });
+
+        let open = Circuit::Open {
+            until: now + self.config.open_for,
+        };
+        match &mut state.circuit {
+            // requests let through before the circuit opened say nothing about now
+            Circuit::HalfOpen { .. } if !probe => {}
+            Circuit::HalfOpen { .. } if !success => state.circuit = open,
+            Circuit::HalfOpen { successes, .. } => {
+                *successes += 1;
+                if *successes >= self.config.half_open_probes {
+                    state.circuit = Circuit::Closed;
+                    state.samples.clear();
+                }
+            }
+            Circuit::Closed if self.tripped(&state.samples) => state.circuit = open,
+            Circuit::Closed | Circuit::Open { .. } => {}
+        }
+    }
+
+    fn tripped(&self, samples: &VecDeque<Sample>) -> bool {
+        if samples.is_empty() || samples.len() < self.config.min_requests {
+            return false;
+        }
+
+        let failures = samples.iter().filter(|s| !s.success).count();
+        if failures as f64 / samples.len() as f64 >= self.config.error_rate {
+            return true;
+        }
+
+        match self.config.slow_threshold {
+            Some(threshold) => {
+                let mut latencies: Vec<Duration> = samples.iter().map(|s| s.latency).collect();
+                latencies.sort();
+                let rank = (self.config.slow_percentile * latencies.len() as f64).ceil() as usize;
+                latencies[rank.clamp(1, latencies.len()) - 1] > threshold
+            }
+            None => false,
+        }
+    }
+}
+
+pub fn circuit_open() -> Error {
+    Error::new(ErrorKind::ResourceBusy, ErrorMsg::CIRCUIT_OPEN)
+}
+
+// Failing fast because of a breaker, rather than because of the upstream
+pub fn is_rejection(e: &Error) -> bool {
+    e.kind() == ErrorKind::ResourceBusy
+        && matches!(
+            e.to_string().as_str(),
+            ErrorMsg::CIRCUIT_OPEN | ErrorMsg::TOO_MANY_PENDING
+        )
+}
+
+#[derive(Debug)]
+struct PermitInner {
+    breaker: Arc<CircuitBreaker>,
+    probe: bool,
+}
+
+impl Drop for PermitInner {
+    fn drop(&mut self) {
+        let mut state = self.breaker.state.lock().unwrap();
+        state.active -= 1;
+        if self.probe
+            && let Circuit::HalfOpen { probes, .. } = &mut state.circuit
+        {
+            *probes = probes.saturating_sub(1);
+        }
+        drop(state);
+        self.breaker.released.notify_one();
+    }
+}
+
+// A concurrent slot, given back when the last clone is dropped
+#[derive(Debug, Clone)]
+pub struct Permit(Arc<PermitInner>);
+
+impl Permit {
+    pub fn record(&self, success: bool, latency: Duration) {
+        self.0.breaker.record(self.0.probe, success, latency);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    fn breaker(config: BreakerConfig) -> Arc<CircuitBreaker> {
+        CircuitBreaker::new(BreakerConfig {
+            min_requests: 4,
+            open_for: Duration::from_millis(50),
+            half_open_probes: 2,
+            ..config
+        })
+    }
+
+    #[test]
+    fn test_circuit_states() {
+        let breaker = breaker(BreakerConfig::default());
+
+        for success in [true, false, true] {
+            breaker
+                .try_acquire()
+                .unwrap()
+                .record(success, Duration::ZERO);
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.try_acquire().unwrap().record(false, Duration::ZERO);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        let error = breaker.try_acquire().unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::CIRCUIT_OPEN);
+
+        // half open lets two probes through, a failed one opens it again
+        thread::sleep(Duration::from_millis(60));
+        let probe = breaker.try_acquire().unwrap();
+        let second = breaker.try_acquire().unwrap();
+        assert!(!breaker.allows());
+        probe.record(false, Duration::ZERO);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        drop(second);
+
+        thread::sleep(Duration::from_millis(60));
+        for _ in 0..2 {
+            breaker.try_acquire().unwrap().record(true, Duration::ZERO);
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_slow_circuit() {
+        let breaker = breaker(BreakerConfig {
+            slow_percentile: 0.5,
+            slow_threshold: Some(Duration::from_millis(100)),
+            ..BreakerConfig::default()
+        });
+
+        for ms in [10, 20, 300, 400] {
+            breaker
+                .try_acquire()
+                .unwrap()
+                .record(true, Duration::from_millis(ms));
+        }
+        // the median is 20ms
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker
+            .try_acquire()
+            .unwrap()
+            .record(true, Duration::from_millis(500));
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_concurrency_limits() {
+        let breaker = breaker(BreakerConfig {
+            max_concurrent: 1,
+            max_pending: 1,
+            pending_timeout: Duration::from_millis(500),
+            ..BreakerConfig::default()
+        });
+
+        let first = breaker.try_acquire().unwrap();
+        let waiter = {
+            let breaker = Arc::clone(&breaker);
+            thread::spawn(move || breaker.try_acquire().is_ok())
+        };
+        while breaker.pending() == 0 {
+            thread::yield_now();
+        }
+        // the one pending place is taken
+        let error = breaker.try_acquire().unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::TOO_MANY_PENDING);
+
+        // clones share the slot
+        let clone = first.clone();
+        drop(first);
+        assert_eq!(breaker.active(), 1);
+        drop(clone);
+        assert!(waiter.join().unwrap());
+        assert_eq!(breaker.active(), 0);
+    }
+}
@@ -0,0 +1,116 @@
+// Cache-Control directives, RFC 9111 section 5.2. Unknown directives are
+// ignored, the qualified forms of no-cache and private are taken as the
+// unqualified ones which is the stricter reading.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub proxy_revalidate: bool,
+    pub no_transform: bool,
+    pub only_if_cached: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    // max-stale without a value accepts any staleness
+    pub max_stale: Option<u64>,
+    pub min_fresh: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn parse(value: &str) -> CacheControl {
+        let mut cc = CacheControl::default();
+
+        for directive in split_directives(value) {
+            let (name, arg) = match directive.split_once('=') {
+                Some((name, arg)) => (name.trim(), Some(arg.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            // delta-seconds that do not fit are taken as the largest value, RFC 9111 section 1.2.2
+            let seconds = arg.and_then(|a| {
+                (!a.is_empty() && a.chars().all(|c| c.is_ascii_digit()))
+                    .then(|| a.parse::<u64>().unwrap_or(u64::MAX))
+            });
+
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "public" => cc.public = true,
+                "must-revalidate" => cc.must_revalidate = true,
+                "proxy-revalidate" => cc.proxy_revalidate = true,
+                "no-transform" => cc.no_transform = true,
+                "only-if-cached" => cc.only_if_cached = true,
+                "max-age" => cc.max_age = seconds,
+                "s-maxage" => cc.s_maxage = seconds,
+                "max-stale" => cc.max_stale = arg.map_or(Some(u64::MAX), |_| seconds),
+                "min-fresh" => cc.min_fresh = seconds,
+                "stale-while-revalidate" => cc.stale_while_revalidate = seconds,
+                "stale-if-error" => cc.stale_if_error = seconds,
+                _ => {}
+            }
+        }
+
+        cc
+    }
+
+    // A shared cache may not serve the response stale without revalidating,
+    // s-maxage implies proxy-revalidate
+    pub fn forbids_stale(&self) -> bool {
+        self.must_revalidate || self.proxy_revalidate || self.s_maxage.is_some()
+    }
+}
+
+// Splits on commas outside of quoted strings, no-cache="a, b" is one directive
+fn split_directives(value: &str) -> Vec<&str> {
+    let mut directives = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                directives.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    directives.push(&value[start..]);
+
+    directives
+        .into_iter()
+        .filter(|d| !d.trim().is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache_control() {
+        let cc = CacheControl::parse(
+            "public, max-age=60, s-maxage=\"120\", no-cache=\"Set-Cookie, X-Foo\", stale-while-revalidate=30",
+        );
+        assert!(cc.public);
+        assert!(cc.no_cache);
+        assert_eq!(cc.max_age, Some(60));
+        assert_eq!(cc.s_maxage, Some(120));
+        assert_eq!(cc.stale_while_revalidate, Some(30));
+        assert!(cc.forbids_stale());
+
+        let cc = CacheControl::parse("NO-STORE, max-stale, max-age=abc");
+        assert!(cc.no_store);
+        assert_eq!(cc.max_stale, Some(u64::MAX));
+        assert_eq!(cc.max_age, None);
+
+        let cc = CacheControl::parse("max-age=99999999999999999999999");
+        assert_eq!(cc.max_age, Some(u64::MAX));
+        assert_eq!(CacheControl::parse(""), CacheControl::default());
+    }
+}
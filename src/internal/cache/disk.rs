@@ -0,0 +1,218 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::Entry;
+use crate::internal::response::StatusCode;
+
+// Second tier for entries evicted from memory. Every primary key gets one file
+// with all of its variants, an entry is moved back to memory when it is hit.
+//
+// file    = "key " key LF *( variant )
+// variant = "entry " status SP request-time SP response-time SP body-length LF
+//           *( "h " name ": " value LF ) *( "v " name [ ": " value ] LF ) LF body
+#[derive(Debug)]
+pub(super) struct DiskStore {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl DiskStore {
+    pub(super) fn new(dir: PathBuf, max_bytes: u64) -> DiskStore {
+        DiskStore { dir, max_bytes }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.entry", hasher.finish()))
+    }
+
+    // Failing to write only loses the entry, the response was already sent
+    pub(super) fn save(&self, key: &str, variants: &[Entry]) {
+        if fs::create_dir_all(&self.dir).is_err() {
+            return;
+        }
+
+        let mut data = format!("key {key}\n").into_bytes();
+        for entry in variants {
+            encode(entry, &mut data);
+        }
+        if fs::write(self.path(key), data).is_ok() {
+            self.trim();
+        }
+    }
+
+    // Reads the variants stored for the key and removes the file
+    pub(super) fn take(&self, key: &str) -> Option<Vec<Entry>> {
+        let path = self.path(key);
+        let data = fs::read(&path).ok()?;
+        let _ = fs::remove_file(&path);
+
+        let mut reader = BufReader::new(&data[..]);
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        // a hash collision, the other key is just dropped
+        if line.trim_end_matches('\n') != format!("key {key}") {
+            return None;
+        }
+
+        let mut variants = Vec::new();
+        while let Some(entry) = decode(&mut reader) {
+            variants.push(entry);
+        }
+        Some(variants)
+    }
+
+    pub(super) fn remove(&self, key: &str) -> bool {
+        fs::remove_file(self.path(key)).is_ok()
+    }
+
+    // Deletes the least recently written files until the directory fits the budget
+    fn trim(&self) {
+        let mut files: Vec<(SystemTime, u64, PathBuf)> = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().extension().is_some_and(|ext| ext == "entry"))
+                .filter_map(|e| {
+                    let meta = e.metadata().ok()?;
+                    Some((meta.modified().ok()?, meta.len(), e.path()))
+                })
+                .collect(),
+            Err(_) => return,
+        };
+        files.sort();
+
+        let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+        for (_, len, path) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(path).is_ok() {
+                total -= len;
+            }
+        }
+    }
+}
+
+fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+fn from_nanos(nanos: &str) -> Option<SystemTime> {
+    let nanos = nanos.parse::<u128>().ok()?;
+    let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+    Some(UNIX_EPOCH + Duration::new(secs, (nanos % 1_000_000_000) as u32))
+}
+
+fn encode(entry: &Entry, out: &mut Vec<u8>) {
+    let _ = writeln!(
+        out,
+        "entry {} {} {} {}",
+        entry.status.as_u16(),
+        nanos(entry.request_time),
+        nanos(entry.response_time),
+        entry.body.len()
+    );
+    for (name, value) in &entry.headers {
+        let _ = writeln!(out, "h {name}: {value}");
+    }
+    for (name, value) in &entry.vary {
+        let _ = match value {
+            Some(value) => writeln!(out, "v {name}: {value}"),
+            None => writeln!(out, "v {name}"),
+        };
+    }
+    out.push(b'\n');
+    out.extend_from_slice(&entry.body);
+}
+
+fn decode(reader: &mut impl BufRead) -> Option<Entry> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+
+    let mut fields = line.trim_end().strip_prefix("entry ")?.split(' ');
+    let status = StatusCode::from_u16(fields.next()?.parse().ok()?).ok()?;
+    let request_time = from_nanos(fields.next()?)?;
+    let response_time = from_nanos(fields.next()?)?;
+    let body_len = fields.next()?.parse::<usize>().ok()?;
+
+    let mut headers = Vec::new();
+    let mut vary = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        let line = line.strip_suffix('\n')?;
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(header) = line.strip_prefix("h ") {
+            let (name, value) = header.split_once(": ")?;
+            headers.push((name.to_string(), value.to_string()));
+        } else if let Some(field) = line.strip_prefix("v ") {
+            match field.split_once(": ") {
+                Some((name, value)) => vary.push((name.to_string(), Some(value.to_string()))),
+                None => vary.push((field.to_string(), None)),
+            }
+        }
+    }
+
+    let mut body = vec![0u8; body_len];
+    reader.read_exact(&mut body).ok()?;
+
+    Some(Entry {
+        status,
+        headers,
+        body,
+        vary,
+        request_time,
+        response_time,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::cache::{Cache, CacheConfig, Lookup};
+    use crate::internal::request::parse;
+
+    #[test]
+    fn test_disk_tier() {
+        let dir = std::env::temp_dir().join(format!("lb-cache-{}", std::process::id()));
+        let config = CacheConfig {
+            max_bytes: 150,
+            disk_dir: Some(dir.clone()),
+            ..CacheConfig::default()
+        };
+        let cache = Cache::new(config);
+        let now = SystemTime::now();
+
+        let a = parse(b"GET /a HTTP/1.1\r\nHost: h\r\nAccept: text/html\r\n\r\n").unwrap();
+        let b = parse(b"GET /b HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let headers = vec![
+            ("Cache-Control".to_string(), "max-age=60".to_string()),
+            ("Vary".to_string(), "Accept, Accept-Language".to_string()),
+        ];
+        let mut entry = Entry::new(&a, StatusCode::OK, &headers, now, now);
+        entry.body = b"binary\n\0body".repeat(5);
+        cache.store(&a, entry.clone());
+
+        // b pushes a out of memory and onto disk
+        let mut other = Entry::new(&b, StatusCode::OK, &[], now, now);
+        other.body = vec![b'x'; 100];
+        cache.store(&b, other);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert_eq!(cache.lookup(&a, now), Lookup::Hit(entry));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,919 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::internal::{
+    headers::Headers,
+    httpdate,
+    request::{Request, RequestMethod},
+    response::{Response, ResponseBody, StatusCode},
+};
+
+pub mod control;
+mod disk;
+
+pub use control::CacheControl;
+use disk::DiskStore;
+
+// Statuses that can be stored without explicit freshness, RFC 9110 section 15.1
+const HEURISTICALLY_CACHEABLE: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+// Fields that belong to the connection or are recomputed when the entry is sent,
+// Age is kept since the age computation starts from it
+const NOT_STORED: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "trailer",
+    "upgrade",
+    "content-length",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    // memory budget for all entries, least recently used ones are evicted past it
+    pub max_bytes: usize,
+    // larger responses are passed through without being stored
+    pub max_entry_bytes: usize,
+    // share of the time since Last-Modified a response without explicit
+    // freshness is considered fresh for, RFC 9111 section 4.2.2
+    pub heuristic_fraction: f64,
+    pub max_heuristic: Duration,
+    // PURGE is only accepted from client addresses in these blocks
+    pub purge_allowed: Vec<Cidr>,
+    // entries evicted from memory go here when set, instead of being dropped
+    pub disk_dir: Option<PathBuf>,
+    pub max_disk_bytes: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_bytes: 64 * 1024 * 1024,
+            max_entry_bytes: 8 * 1024 * 1024,
+            heuristic_fraction: 0.1,
+            max_heuristic: Duration::from_secs(24 * 60 * 60),
+            purge_allowed: vec![
+                Cidr::from(IpAddr::from([127, 0, 0, 1])),
+                Cidr::from(IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])),
+            ],
+            disk_dir: None,
+            max_disk_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+// A block of addresses such as 10.0.0.0/8, a single address without the prefix
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // a shift by the full width would overflow
+        let matches = |network: u128, addr: u128, bits: u32| {
+            let host_bits = bits - self.prefix;
+            host_bits == bits || network >> host_bits == addr >> host_bits
+        };
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                matches(u32::from(network).into(), u32::from(addr).into(), 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                matches(u128::from(network), u128::from(addr), 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    fn from(addr: IpAddr) -> Self {
+        let prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Cidr {
+            network: addr,
+            prefix,
+        }
+    }
+}
+
+// <address>[/<prefix length>]
+impl FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid address block {s}");
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let mut cidr = Cidr::from(addr.parse::<IpAddr>().map_err(|_| invalid())?);
+        if let Some(prefix) = prefix {
+            match prefix.parse() {
+                Ok(prefix) if prefix <= cidr.prefix => cidr.prefix = prefix,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(cidr)
+    }
+}
+
+// A stored response together with what is needed to compute its age and to
+// match it against later requests
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // values of the request fields named by Vary, None when the field was absent
+    pub vary: Vec<(String, Option<String>)>,
+    pub request_time: SystemTime,
+    pub response_time: SystemTime,
+}
+
+impl Entry {
+    pub fn new(
+        request: &Request,
+        status: StatusCode,
+        headers: &[(String, String)],
+        request_time: SystemTime,
+        response_time: SystemTime,
+    ) -> Entry {
+        let headers: Vec<(String, String)> = headers
+            .iter()
+            .filter(|(k, _)| !NOT_STORED.contains(&k.to_ascii_lowercase().as_str()))
+            .cloned()
+            .collect();
+
+        let vary = vary_fields(&headers)
+            .into_iter()
+            .map(|name| {
+                let value = request
+                    .headers
+                    .as_ref()
+                    .and_then(|h| h.get(&name))
+                    .map(normalize);
+                (name, value)
+            })
+            .collect();
+
+        Entry {
+            status,
+            headers,
+            body: Vec::new(),
+            vary,
+            request_time,
+            response_time,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn control(&self) -> CacheControl {
+        CacheControl::parse(self.header("Cache-Control").unwrap_or_default())
+    }
+
+    pub fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(k, v)| k.len() + v.len() + 4)
+                .sum::<usize>()
+    }
+
+    fn date(&self) -> SystemTime {
+        self.header("Date")
+            .and_then(httpdate::parse)
+            .unwrap_or(self.response_time)
+    }
+
+    // RFC 9111 section 4.2.3
+    pub fn current_age(&self, now: SystemTime) -> Duration {
+        let age_value = self
+            .header("Age")
+            .and_then(|a| a.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        let apparent_age = elapsed(self.date(), self.response_time);
+        let response_delay = elapsed(self.request_time, self.response_time);
+        let corrected_initial_age = apparent_age.max(age_value + response_delay);
+
+        corrected_initial_age + elapsed(self.response_time, now)
+    }
+
+    // RFC 9111 section 4.2.1, for a shared cache
+    pub fn freshness_lifetime(&self, config: &CacheConfig) -> Duration {
+        let cc = self.control();
+        if let Some(seconds) = cc.s_maxage.or(cc.max_age) {
+            return Duration::from_secs(seconds);
+        }
+
+        if let Some(expires) = self.header("Expires") {
+            // an invalid Expires, "0" included, means already expired
+            return httpdate::parse(expires)
+                .map(|e| elapsed(self.date(), e))
+                .unwrap_or_default();
+        }
+
+        match self.header("Last-Modified").and_then(httpdate::parse) {
+            Some(modified) if HEURISTICALLY_CACHEABLE.contains(&self.status.as_u16()) => {
+                let since = elapsed(modified, self.date());
+                since
+                    .mul_f64(config.heuristic_fraction)
+                    .min(config.max_heuristic)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    // A stored response can only be used for a request with the same values
+    // for the fields its Vary names, RFC 9111 section 4.1
+    pub fn matches(&self, request: &Request) -> bool {
+        self.vary.iter().all(|(name, value)| {
+            let current = request
+                .headers
+                .as_ref()
+                .and_then(|h| h.get(name))
+                .map(normalize);
+            current == *value
+        })
+    }
+
+    // Builds the response sent to the client, the body is left out for HEAD
+    pub fn to_response(&self, method: &RequestMethod, now: SystemTime) -> Response {
+        let mut response = Response::new(self.status);
+        // the stored Server replaces ours
+        if self.header("Server").is_some() {
+            response.remove_header("Server");
+        }
+        for (name, value) in &self.headers {
+            response.append_header(name, value);
+        }
+        response.set_header("Age", &self.current_age(now).as_secs().to_string());
+
+        if *method == RequestMethod::Head {
+            response.set_header("Content-Length", &self.body.len().to_string());
+        } else {
+            response.body = ResponseBody::Bytes(self.body.clone());
+        }
+        response
+    }
+
+    // Merges the fields of a 304 into the stored ones, RFC 9111 section 3.2
+    pub fn freshen(
+        &mut self,
+        headers: &[(String, String)],
+        request_time: SystemTime,
+        response_time: SystemTime,
+    ) {
+        for (name, value) in headers {
+            let lower = name.to_ascii_lowercase();
+            if NOT_STORED.contains(&lower.as_str()) || lower == "content-encoding" {
+                continue;
+            }
+            self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+            self.headers.push((name.clone(), value.clone()));
+        }
+        self.request_time = request_time;
+        self.response_time = response_time;
+    }
+}
+
+fn elapsed(from: SystemTime, to: SystemTime) -> Duration {
+    to.duration_since(from).unwrap_or_default()
+}
+
+fn normalize(value: &str) -> String {
+    value
+        .split(',')
+        .map(|v| v.trim())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn vary_fields(headers: &[(String, String)]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("Vary"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(|f| f.trim().to_ascii_lowercase())
+        .filter(|f| !f.is_empty())
+        .collect()
+}
+
+fn request_control(request: &Request) -> CacheControl {
+    let headers = match &request.headers {
+        Some(headers) => headers,
+        None => return CacheControl::default(),
+    };
+
+    match headers.get("Cache-Control") {
+        Some(cc) => CacheControl::parse(cc),
+        // Pragma is only looked at without Cache-Control, RFC 9111 section 5.4
+        None => CacheControl {
+            no_cache: headers
+                .get("Pragma")
+                .is_some_and(|p| p.to_ascii_lowercase().contains("no-cache")),
+            ..CacheControl::default()
+        },
+    }
+}
+
+// Primary cache key, HEAD is answered from the stored GET
+pub fn cache_key(request: &Request) -> Option<String> {
+    let method = match request.method.as_ref()? {
+        RequestMethod::Get | RequestMethod::Head => RequestMethod::Get,
+        other => other.clone(),
+    };
+    let host = request.host().unwrap_or_default().to_ascii_lowercase();
+
+    Some(format!(
+        "{} {}{}",
+        method.as_str(),
+        host,
+        request.path.as_deref()?
+    ))
+}
+
+// Whether a response to the request may be stored, RFC 9111 section 3
+pub fn is_storable(request: &Request, status: StatusCode, headers: &[(String, String)]) -> bool {
+    if request.method != Some(RequestMethod::Get)
+        || status.is_informational()
+        // partial and not modified responses are not complete representations
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || request_control(request).no_store
+    {
+        return false;
+    }
+
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+    let cc = CacheControl::parse(header("Cache-Control").unwrap_or_default());
+    if cc.no_store || cc.private || vary_fields(headers).iter().any(|f| f == "*") {
+        return false;
+    }
+
+    let authorized = request
+        .headers
+        .as_ref()
+        .is_some_and(|h| h.get("Authorization").is_some());
+    if authorized && !(cc.public || cc.must_revalidate || cc.s_maxage.is_some()) {
+        return false;
+    }
+
+    cc.public
+        || cc.max_age.is_some()
+        || cc.s_maxage.is_some()
+        || header("Expires").is_some()
+        || HEURISTICALLY_CACHEABLE.contains(&status.as_u16())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    Miss,
+    // can be sent as is
+    Hit(Entry),
+    // has to be validated with the origin first, kept to answer a 304 with
+    Stale(Entry),
+    // stale-while-revalidate allows sending it while validating in the background
+    Revalidate(Entry),
+}
+
+#[derive(Debug)]
+struct Slot {
+    variants: Vec<Entry>,
+    last_used: u64,
+    size: usize,
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    slots: HashMap<String, Slot>,
+    // last_used -> key, the first one is the next to evict
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    size: usize,
+    revalidating: HashSet<String>,
+}
+
+impl Store {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(slot) = self.slots.get_mut(key) {
+            self.lru.remove(&slot.last_used);
+            slot.last_used = self.tick;
+            self.lru.insert(self.tick, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Slot> {
+        let slot = self.slots.remove(key)?;
+        self.lru.remove(&slot.last_used);
+        self.size -= slot.size;
+        Some(slot)
+    }
+
+    fn insert(&mut self, key: &str, slot: Slot) {
+        self.size += slot.size;
+        self.slots.insert(key.to_string(), slot);
+        self.touch(key);
+    }
+}
+
+// Shared response cache (RFC 9111), meant to be put in an Arc and handed to
+// every proxy route that should use it
+#[derive(Debug)]
+pub struct Cache {
+    config: CacheConfig,
+    store: Mutex<Store>,
+    disk: Option<DiskStore>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Cache {
+        let disk = config
+            .disk_dir
+            .as_ref()
+            .map(|dir| DiskStore::new(dir.clone(), config.max_disk_bytes));
+
+        Cache {
+            config,
+            store: Mutex::new(Store::default()),
+            disk,
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    // Size of the entries held in memory
+    pub fn size(&self) -> usize {
+        self.store.lock().unwrap().size
+    }
+
+    pub fn lookup(&self, request: &Request, now: SystemTime) -> Lookup {
+        let key = match cache_key(request) {
+            Some(key) => key,
+            None => return Lookup::Miss,
+        };
+
+        let mut store = self.store.lock().unwrap();
+        if !store.slots.contains_key(&key)
+            && let Some(disk) = &self.disk
+            && let Some(variants) = disk.take(&key)
+        {
+            let size = variants.iter().map(|e| e.size()).sum();
+            store.insert(
+                &key,
+                Slot {
+                    variants,
+                    last_used: 0,
+                    size,
+                },
+            );
+            self.evict(&mut store);
+        }
+
+        let entry = match store
+            .slots
+            .get(&key)
+            .and_then(|slot| slot.variants.iter().find(|e| e.matches(request)))
+        {
+            Some(entry) => entry.clone(),
+            None => return Lookup::Miss,
+        };
+        store.touch(&key);
+        drop(store);
+
+        self.classify(request, entry, now)
+    }
+
+    // RFC 9111 section 4.2 and the request directives of section 5.2.1
+    fn classify(&self, request: &Request, entry: Entry, now: SystemTime) -> Lookup {
+        let req = request_control(request);
+        let res = entry.control();
+        let age = entry.current_age(now);
+        let lifetime = entry.freshness_lifetime(&self.config);
+
+        if req.no_cache || res.no_cache {
+            return Lookup::Stale(entry);
+        }
+        if req
+            .max_age
+            .is_some_and(|max| age > Duration::from_secs(max))
+        {
+            return Lookup::Stale(entry);
+        }
+
+        let min_fresh = Duration::from_secs(req.min_fresh.unwrap_or(0));
+        if age + min_fresh < lifetime {
+            return Lookup::Hit(entry);
+        }
+
+        let staleness = age.saturating_sub(lifetime).as_secs();
+        if res.forbids_stale() {
+            return Lookup::Stale(entry);
+        }
+        if req.max_stale.is_some_and(|max| staleness <= max) {
+            return Lookup::Hit(entry);
+        }
+        if res
+            .stale_while_revalidate
+            .is_some_and(|swr| staleness <= swr)
+        {
+            return Lookup::Revalidate(entry);
+        }
+        Lookup::Stale(entry)
+    }
+
+    // Whether a stale entry may answer the request when the origin fails,
+    // RFC 5861 section 4
+    pub fn can_serve_stale_on_error(
+        &self,
+        request: &Request,
+        entry: &Entry,
+        now: SystemTime,
+    ) -> bool {
+        let res = entry.control();
+        if res.forbids_stale() || res.no_cache {
+            return false;
+        }
+
+        let staleness = entry
+            .current_age(now)
+            .saturating_sub(entry.freshness_lifetime(&self.config))
+            .as_secs();
+        request_control(request)
+            .stale_if_error
+            .or(res.stale_if_error)
+            .is_some_and(|max| staleness <= max)
+    }
+
+    // Replaces the variant of the entry, evicting the least recently used
+    // entries until the cache is back within its budget
+    pub fn store(&self, request: &Request, entry: Entry) {
+        let key = match cache_key(request) {
+            Some(key) => key,
+            None => return,
+        };
+        if entry.size() > self.config.max_entry_bytes {
+            return;
+        }
+
+        let mut store = self.store.lock().unwrap();
+        let mut slot = store.remove(&key).unwrap_or(Slot {
+            variants: Vec::new(),
+            last_used: 0,
+            size: 0,
+        });
+        slot.variants.retain(|e| e.vary != entry.vary);
+        slot.variants.push(entry);
+        slot.size = slot.variants.iter().map(|e| e.size()).sum();
+
+        store.insert(&key, slot);
+        self.evict(&mut store);
+    }
+
+    fn evict(&self, store: &mut Store) {
+        while store.size > self.config.max_bytes {
+            let key = match store.lru.first_key_value() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            if let Some(slot) = store.remove(&key)
+                && let Some(disk) = &self.disk
+            {
+                disk.save(&key, &slot.variants);
+            }
+        }
+    }
+
+    // Drops every variant stored for the request target, returns whether there was any
+    pub fn invalidate(&self, request: &Request) -> bool {
+        let key = match cache_key(request) {
+            Some(key) => key,
+            None => return false,
+        };
+
+        let in_memory = self.store.lock().unwrap().remove(&key).is_some();
+        let on_disk = self.disk.as_ref().is_some_and(|disk| disk.remove(&key));
+        in_memory || on_disk
+    }
+
+    // PURGE removes the entry for the same target as a GET would use
+    pub fn purge(&self, request: &Request) -> Response {
+        let allowed = request.peer_addr.is_some_and(|addr| {
+            let ip = addr.ip().to_canonical();
+            self.config
+                .purge_allowed
+                .iter()
+                .any(|cidr| cidr.contains(ip))
+        });
+        if !allowed {
+            return Response::builder(StatusCode::FORBIDDEN)
+                .body(StatusCode::FORBIDDEN.reason())
+                .build();
+        }
+
+        let mut get = request.clone();
+        get.method = Some(RequestMethod::Get);
+        match self.invalidate(&get) {
+            true => Response::builder(StatusCode::OK).body("Purged").build(),
+            false => Response::builder(StatusCode::NOT_FOUND)
+                .body(StatusCode::NOT_FOUND.reason())
+                .build(),
+        }
+    }
+
+    // Only one background revalidation per key at a time
+    pub fn begin_revalidation(&self, request: &Request) -> bool {
+        match cache_key(request) {
+            Some(key) => self.store.lock().unwrap().revalidating.insert(key),
+            None => false,
+        }
+    }
+
+    pub fn end_revalidation(&self, request: &Request) {
+        if let Some(key) = cache_key(request) {
+            self.store.lock().unwrap().revalidating.remove(&key);
+        }
+    }
+}
+
+// Copies the request fields a conditional request to the origin needs
+pub fn validators(entry: &Entry) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    if let Some(etag) = entry.header("ETag") {
+        fields.push(("If-None-Match".to_string(), etag.to_string()));
+    }
+    if let Some(modified) = entry.header("Last-Modified") {
+        fields.push(("If-Modified-Since".to_string(), modified.to_string()));
+    }
+    fields
+}
+
+// Answers the client's own conditional request from a stored entry
+pub fn not_modified(request_headers: &Headers, entry: &Entry) -> bool {
+    let strip = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+
+    if let Some(inm) = request_headers.get("If-None-Match") {
+        return match entry.header("ETag") {
+            Some(etag) => inm
+                .split(',')
+                .any(|t| t.trim() == "*" || strip(t) == strip(etag)),
+            None => false,
+        };
+    }
+
+    match (
+        request_headers
+            .get("If-Modified-Since")
+            .and_then(httpdate::parse),
+        entry.header("Last-Modified").and_then(httpdate::parse),
+    ) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::request::parse;
+
+    fn request(raw: &str) -> Request {
+        parse(raw.as_bytes()).unwrap()
+    }
+
+    fn entry(request: &Request, headers: &[(&str, &str)], body: &str, at: SystemTime) -> Entry {
+        let headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut entry = Entry::new(request, StatusCode::OK, &headers, at, at);
+        entry.body = body.as_bytes().to_vec();
+        entry
+    }
+
+    #[test]
+    fn test_freshness() {
+        let now = SystemTime::now();
+        let config = CacheConfig::default();
+        let get = request("GET /a HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        let date = httpdate::format(now);
+
+        let e = entry(
+            &get,
+            &[("Cache-Control", "max-age=60, s-maxage=10")],
+            "",
+            now,
+        );
+        assert_eq!(e.freshness_lifetime(&config), Duration::from_secs(10));
+
+        let expires = httpdate::format(now + Duration::from_secs(30));
+        let e = entry(&get, &[("Date", &date), ("Expires", &expires)], "", now);
+        assert_eq!(e.freshness_lifetime(&config).as_secs(), 30);
+
+        let e = entry(&get, &[("Expires", "0")], "", now);
+        assert_eq!(e.freshness_lifetime(&config), Duration::ZERO);
+
+        // 10% of the ten days since the last modification
+        let modified = httpdate::format(now - Duration::from_secs(10 * 24 * 3600));
+        let e = entry(
+            &get,
+            &[("Date", &date), ("Last-Modified", &modified)],
+            "",
+            now,
+        );
+        assert_eq!(e.freshness_lifetime(&config).as_secs(), 24 * 3600);
+
+        // the Age received from upstream counts
+        let e = entry(&get, &[("Date", &date), ("Age", "100")], "", now);
+        assert_eq!(e.current_age(now + Duration::from_secs(5)).as_secs(), 105);
+    }
+
+    #[test]
+    fn test_storable() {
+        let get = request("GET /a HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        let ok = StatusCode::OK;
+        let h = |k: &str, v: &str| vec![(k.to_string(), v.to_string())];
+
+        assert!(is_storable(&get, ok, &h("Cache-Control", "max-age=60")));
+        assert!(is_storable(&get, ok, &[]));
+        assert!(!is_storable(
+            &get,
+            ok,
+            &h("Cache-Control", "private, max-age=60")
+        ));
+        assert!(!is_storable(&get, ok, &h("Cache-Control", "no-store")));
+        assert!(!is_storable(&get, ok, &h("Vary", "*")));
+        assert!(!is_storable(&get, StatusCode::CREATED, &[]));
+        assert!(is_storable(&get, StatusCode::CREATED, &h("Expires", "0")));
+
+        let post = request("POST /a HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n");
+        assert!(!is_storable(&post, ok, &h("Cache-Control", "max-age=60")));
+
+        let auth = request("GET /a HTTP/1.1\r\nAuthorization: Basic eA==\r\n\r\n");
+        assert!(!is_storable(&auth, ok, &h("Cache-Control", "max-age=60")));
+        assert!(is_storable(
+            &auth,
+            ok,
+            &h("Cache-Control", "public, max-age=60")
+        ));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let now = SystemTime::now();
+        let cache = Cache::new(CacheConfig::default());
+        let get = request("GET /a HTTP/1.1\r\nHost: example.com\r\nAccept-Encoding: gzip\r\n\r\n");
+
+        assert_eq!(cache.lookup(&get, now), Lookup::Miss);
+
+        let e = entry(
+            &get,
+            &[
+                ("Cache-Control", "max-age=60, stale-while-revalidate=30"),
+                ("Vary", "Accept-Encoding"),
+            ],
+            "gzip body",
+            now,
+        );
+        cache.store(&get, e.clone());
+
+        assert_eq!(cache.lookup(&get, now), Lookup::Hit(e.clone()));
+        let head =
+            request("HEAD /a HTTP/1.1\r\nHost: EXAMPLE.com\r\nAccept-Encoding: gzip\r\n\r\n");
+        assert_eq!(cache.lookup(&head, now), Lookup::Hit(e.clone()));
+
+        // another Accept-Encoding is another variant
+        let plain = request("GET /a HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(cache.lookup(&plain, now), Lookup::Miss);
+
+        let later = now + Duration::from_secs(70);
+        assert_eq!(cache.lookup(&get, later), Lookup::Revalidate(e.clone()));
+        let much_later = now + Duration::from_secs(100);
+        assert_eq!(cache.lookup(&get, much_later), Lookup::Stale(e.clone()));
+
+        let no_cache = request(
+            "GET /a HTTP/1.1\r\nHost: example.com\r\nAccept-Encoding: gzip\r\nCache-Control: no-cache\r\n\r\n",
+        );
+        assert_eq!(cache.lookup(&no_cache, now), Lookup::Stale(e.clone()));
+        let max_stale = request(
+            "GET /a HTTP/1.1\r\nHost: example.com\r\nAccept-Encoding: gzip\r\nCache-Control: max-stale=60\r\n\r\n",
+        );
+        assert_eq!(cache.lookup(&max_stale, much_later), Lookup::Hit(e));
+
+        assert!(cache.invalidate(&get));
+        assert_eq!(cache.lookup(&get, now), Lookup::Miss);
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let now = SystemTime::now();
+        let config = CacheConfig {
+            max_bytes: 250,
+            ..CacheConfig::default()
+        };
+        let cache = Cache::new(config);
+        let body = "x".repeat(100);
+
+        let a = request("GET /a HTTP/1.1\r\nHost: h\r\n\r\n");
+        let b = request("GET /b HTTP/1.1\r\nHost: h\r\n\r\n");
+        let c = request("GET /c HTTP/1.1\r\nHost: h\r\n\r\n");
+        cache.store(&a, entry(&a, &[], &body, now));
+        cache.store(&b, entry(&b, &[], &body, now));
+        // a is now the most recently used one, so b goes
+        assert_ne!(cache.lookup(&a, now), Lookup::Miss);
+        cache.store(&c, entry(&c, &[], &body, now));
+
+        assert_ne!(cache.lookup(&a, now), Lookup::Miss);
+        assert_eq!(cache.lookup(&b, now), Lookup::Miss);
+        assert_ne!(cache.lookup(&c, now), Lookup::Miss);
+        assert_eq!(cache.size(), 200);
+    }
+
+    #[test]
+    fn test_purge() {
+        let now = SystemTime::now();
+        let cache = Cache::new(CacheConfig::default());
+        let get = request("GET /a HTTP/1.1\r\nHost: h\r\n\r\n");
+        cache.store(&get, entry(&get, &[], "body", now));
+
+        let mut purge = request("PURGE /a HTTP/1.1\r\nHost: h\r\n\r\n");
+        purge.peer_addr = Some("10.0.0.1:4000".parse().unwrap());
+        assert_eq!(cache.purge(&purge).status, StatusCode::FORBIDDEN);
+        assert_ne!(cache.lookup(&get, now), Lookup::Miss);
+
+        purge.peer_addr = Some("127.0.0.1:4000".parse().unwrap());
+        assert_eq!(cache.purge(&purge).status, StatusCode::OK);
+        assert_eq!(cache.lookup(&get, now), Lookup::Miss);
+        assert_eq!(cache.purge(&purge).status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_purge_allowed() {
+        let now = SystemTime::now();
+        let cache = Cache::new(CacheConfig {
+            purge_allowed: vec!["10.1.0.0/16".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            ..CacheConfig::default()
+        });
+        let get = request("GET /a HTTP/1.1\r\nHost: h\r\n\r\n");
+        let mut purge = request("PURGE /a HTTP/1.1\r\nHost: h\r\n\r\n");
+
+        // loopback is no longer allowed once blocks are given
+        for peer in ["127.0.0.1:4000", "10.2.0.1:4000", "[fe80::1]:4000"] {
+            purge.peer_addr = Some(peer.parse().unwrap());
+            assert_eq!(cache.purge(&purge).status, StatusCode::FORBIDDEN, "{peer}");
+        }
+        for peer in [
+            "10.1.200.3:4000",
+            "[::ffff:10.1.0.9]:4000",
+            "[fd12::1]:4000",
+        ] {
+            cache.store(&get, entry(&get, &[], "body", now));
+            purge.peer_addr = Some(peer.parse().unwrap());
+            assert_eq!(cache.purge(&purge).status, StatusCode::OK, "{peer}");
+        }
+
+        assert_eq!(
+            "0.0.0.0/0".parse::<Cidr>().unwrap(),
+            Cidr {
+                network: IpAddr::from([0, 0, 0, 0]),
+                prefix: 0
+            }
+        );
+        assert!(
+            "0.0.0.0/0"
+                .parse::<Cidr>()
+                .unwrap()
+                .contains(IpAddr::from([1, 2, 3, 4]))
+        );
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use std::io::{self, Error, ErrorKind, Read};
+
+use brotli::{CompressorReader, Decompressor};
+use flate2::{
+    Compression,
+    read::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder},
+};
+
+use crate::internal::{
+    headers::Headers,
+    request::ErrorMsg,
+    response::{Response, ResponseBody, StatusCode},
+};
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    // "deflate" in HTTP is the zlib format, RFC 9110 section 8.4.1.2
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    pub fn from_token(token: &str) -> Option<Encoding> {
+        match token.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn encoder(&self, reader: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+        match self {
+            Encoding::Brotli => Box::new(CompressorReader::new(reader, BUFFER_SIZE, 5, 22)),
+            Encoding::Gzip => Box::new(GzEncoder::new(reader, Compression::default())),
+            Encoding::Deflate => Box::new(ZlibEncoder::new(reader, Compression::default())),
+        }
+    }
+
+    pub fn decoder<'a>(&self, reader: impl Read + 'a) -> Box<dyn Read + 'a> {
+        match self {
+            Encoding::Brotli => Box::new(Decompressor::new(reader, BUFFER_SIZE)),
+            Encoding::Gzip => Box::new(GzDecoder::new(reader)),
+            Encoding::Deflate => Box::new(ZlibDecoder::new(reader)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionConfig {
+    // compress responses at all
+    pub enabled: bool,
+    // in order of preference when the client weighs several the same
+    pub encodings: Vec<Encoding>,
+    // responses with a known length below this are not worth it
+    pub min_size: u64,
+    // Content-Type prefixes that are compressed, images and archives usually are already
+    pub content_types: Vec<String>,
+    // decode request bodies sent with a Content-Encoding
+    pub decompress_requests: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: false,
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            min_size: 1024,
+            content_types: [
+                "text/",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "application/wasm",
+                "image/svg+xml",
+            ]
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+            decompress_requests: false,
+        }
+    }
+}
+
+// Picks the coding to use from Accept-Encoding, RFC 9110 section 12.5.3.
+// None means identity.
+pub fn negotiate(accept_encoding: &str, supported: &[Encoding]) -> Option<Encoding> {
+    let mut weights: Vec<(String, f32)> = Vec::new();
+
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+
+        let mut q: f32 = 1.0;
+        for param in params {
+            if let Some((name, value)) = param.split_once('=')
+                && name.trim().eq_ignore_ascii_case("q")
+            {
+                q = value.trim().parse::<f32>().unwrap_or(0.0).clamp(0.0, 1.0);
+            }
+        }
+        weights.push((coding, q));
+    }
+
+    let weight = |encoding: &Encoding| -> f32 {
+        let names: &[&str] = match encoding {
+            Encoding::Gzip => &["gzip", "x-gzip"],
+            other => &[other.as_str()],
+        };
+        weights
+            .iter()
+            .find(|(c, _)| names.contains(&c.as_str()))
+            .or_else(|| weights.iter().find(|(c, _)| c == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0)
+    };
+
+    // first highest wins, so the server's order breaks ties
+    supported
+        .iter()
+        .map(|e| (*e, weight(e)))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(None, |best: Option<(Encoding, f32)>, (e, q)| match best {
+            Some((_, bq)) if bq >= q => best,
+            _ => Some((e, q)),
+        })
+        .map(|(e, _)| e)
+}
+
+// Compresses the response body on the fly when the client accepts it. The
+// compressed output has no known length, so it is streamed as chunked.
+pub fn compress_response(
+    request_headers: &Headers,
+    mut response: Response,
+    config: &CompressionConfig,
+) -> Response {
+    if !config.enabled
+        || !response.status.allows_body()
+        // a range of the compressed representation would not match the Content-Range
+        || response.status == StatusCode::PARTIAL_CONTENT
+        || response.header("Content-Encoding").is_some()
+        || response.header("Content-Range").is_some()
+    {
+        return response;
+    }
+
+    let content_type = response.header("Content-Type").unwrap_or_default();
+    if !config
+        .content_types
+        .iter()
+        .any(|t| content_type.to_ascii_lowercase().starts_with(t.as_str()))
+    {
+        return response;
+    }
+    if response
+        .header("Cache-Control")
+        .is_some_and(|cc| cc.to_ascii_lowercase().contains("no-transform"))
+    {
+        return response;
+    }
+
+    // the representation depends on Accept-Encoding from here on, even if it ends
+    // up not being compressed, caches have to know
+    add_vary(&mut response, "Accept-Encoding");
+
+    let size = match &response.body {
+        ResponseBody::Empty => return response,
+        ResponseBody::Bytes(b) => Some(b.len() as u64),
+        ResponseBody::Fixed(_, len) => Some(*len),
+        ResponseBody::Stream(_) => None,
+    };
+    if size.is_some_and(|s| s < config.min_size) {
+        return response;
+    }
+
+    let encoding = match request_headers
+        .get("Accept-Encoding")
+        .and_then(|ae| negotiate(ae, &config.encodings))
+    {
+        Some(e) => e,
+        None => return response,
+    };
+
+    let reader: Box<dyn Read + Send> =
+        match std::mem::replace(&mut response.body, ResponseBody::Empty) {
+            ResponseBody::Bytes(b) => Box::new(io::Cursor::new(b)),
+            ResponseBody::Fixed(r, len) => Box::new(r.take(len)),
+            ResponseBody::Stream(r) => r,
+            ResponseBody::Empty => unreachable!(),
+        };
+    response.body = ResponseBody::Stream(encoding.encoder(reader));
+    response.set_header("Content-Encoding", encoding.as_str());
+
+    // a strong validator only holds for the identity representation
+    if let Some(etag) = response.header("ETag")
+        && !etag.starts_with("W/")
+    {
+        let weak = format!("W/{etag}");
+        response.set_header("ETag", &weak);
+    }
+
+    response
+}
+
+fn add_vary(response: &mut Response, field: &str) {
+    match response.header("Vary") {
+        Some(vary)
+            if vary
+                .split(',')
+                .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case(field)) => {}
+        Some(vary) => {
+            let vary = format!("{vary}, {field}");
+            response.set_header("Vary", &vary);
+        }
+        None => response.set_header("Vary", field),
+    }
+}
+
+// Wraps a request body so it is read decoded. Codings are listed in the order
+// they were applied, so they are undone back to front.
+pub fn decode_request_body<'a>(
+    headers: &Headers,
+    body: impl Read + 'a,
+    max_size: usize,
+) -> Result<Box<dyn Read + 'a>, Error> {
+    let mut reader: Box<dyn Read + 'a> = Box::new(body);
+
+    let codings = headers.get("Content-Encoding").unwrap_or_default();
+    for coding in codings
+        .rsplit(',')
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty())
+    {
+        if coding.eq_ignore_ascii_case("identity") {
+            continue;
+        }
+        let encoding = Encoding::from_token(coding).ok_or_else(|| {
+            Error::new(
+                ErrorKind::Unsupported,
+                ErrorMsg::UNSUPPORTED_CONTENT_ENCODING,
+            )
+        })?;
+        reader = encoding.decoder(reader);
+    }
+
+    // a few KiB of compressed input can inflate to gigabytes
+    Ok(Box::new(LimitedReader {
+        inner: reader,
+        remaining: max_size as u64,
+    }))
+}
+
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n as u64 > self.remaining {
+            return Err(Error::new(ErrorKind::InvalidData, ErrorMsg::BODY_TOO_LARGE));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::request::parse;
+    use std::io::Write;
+
+    fn request_headers(raw: &str) -> Headers {
+        parse(raw.as_bytes()).unwrap().headers.unwrap()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let all = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+        assert_eq!(negotiate("gzip, deflate, br", &all), Some(Encoding::Brotli));
+        assert_eq!(
+            negotiate("gzip;q=1.0, br;q=0.5", &all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            negotiate("deflate, gzip;q=0.9", &all),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate("x-gzip", &all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0.5, br;q=0", &all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity", &all), None);
+        assert_eq!(negotiate("gzip;q=0", &all), None);
+        assert_eq!(negotiate("", &all), None);
+        assert_eq!(negotiate("br", &[Encoding::Gzip]), None);
+    }
+
+    #[test]
+    fn test_compress_response() {
+        let config = CompressionConfig {
+            enabled: true,
+            min_size: 10,
+            ..CompressionConfig::default()
+        };
+        let text = "compress me please ".repeat(100);
+        let headers = request_headers("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+
+        let response = Response::builder(StatusCode::OK)
+            .header("Content-Type", "text/plain")
+            .header("ETag", "\"v1\"")
+            .header("Vary", "Origin")
+            .body(text.clone())
+            .build();
+        let mut out = Vec::new();
+        compress_response(&headers, response, &config)
+            .send(&mut out)
+            .unwrap();
+
+        let parsed =
+            crate::internal::response::parse(&out, &crate::internal::request::RequestMethod::Get)
+                .unwrap();
+        assert_eq!(parsed.headers.get("content-encoding"), Some("gzip"));
+        assert_eq!(parsed.headers.get("transfer-encoding"), Some("chunked"));
+        assert_eq!(parsed.headers.get("vary"), Some("Origin, Accept-Encoding"));
+        assert_eq!(parsed.headers.get("etag"), Some("W/\"v1\""));
+        assert!(parsed.body.len() < text.len());
+
+        let mut decoded = String::new();
+        GzDecoder::new(&parsed.body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        // images are left alone, small bodies too but they still vary
+        let image = Response::builder(StatusCode::OK)
+            .header("Content-Type", "image/png")
+            .body(text.clone())
+            .build();
+        let image = compress_response(&headers, image, &config);
+        assert_eq!(image.header("Content-Encoding"), None);
+        assert_eq!(image.header("Vary"), None);
+
+        let small = Response::builder(StatusCode::OK)
+            .header("Content-Type", "text/plain")
+            .body("tiny")
+            .build();
+        let small = compress_response(&headers, small, &config);
+        assert_eq!(small.header("Content-Encoding"), None);
+        assert_eq!(small.header("Vary"), Some("Accept-Encoding"));
+
+        let disabled = Response::builder(StatusCode::OK)
+            .header("Content-Type", "text/plain")
+            .body(text)
+            .build();
+        let disabled = compress_response(&headers, disabled, &CompressionConfig::default());
+        assert_eq!(disabled.header("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn test_decode_request_body() {
+        let text = b"a compressed upload";
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(text).unwrap();
+        let gz = gz.finish().unwrap();
+
+        let headers = request_headers("POST / HTTP/1.1\r\nContent-Encoding: gzip\r\n\r\n");
+        let mut decoded = Vec::new();
+        decode_request_body(&headers, &gz[..], 1024)
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        let mut br = Vec::new();
+        CompressorReader::new(&text[..], BUFFER_SIZE, 5, 22)
+            .read_to_end(&mut br)
+            .unwrap();
+        let headers = request_headers("POST / HTTP/1.1\r\nContent-Encoding: br\r\n\r\n");
+        let mut decoded = Vec::new();
+        decode_request_body(&headers, &br[..], 1024)
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        // decompression bombs are cut off at the body limit
+        let headers = request_headers("POST / HTTP/1.1\r\nContent-Encoding: gzip\r\n\r\n");
+        let error = decode_request_body(&headers, &gz[..], 5)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let headers = request_headers("POST / HTTP/1.1\r\nContent-Encoding: compress\r\n\r\n");
+        let error = decode_request_body(&headers, &gz[..], 1024).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+}
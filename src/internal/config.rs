@@ -0,0 +1,23 @@
+use crate::internal::{
+    compression::CompressionConfig,
+    connection::{ConnectionLimits, Timeouts},
+    http2::Http2Config,
+    limits::Limits,
+    pool::PoolConfig,
+    retry::RetryPolicy,
+    websocket::WebSocketConfig,
+};
+
+// Everything a listener needs besides the routes
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Config {
+    pub limits: Limits,
+    pub compression: CompressionConfig,
+    pub timeouts: Timeouts,
+    pub connections: ConnectionLimits,
+    // applies to each proxied upstream
+    pub upstream: PoolConfig,
+    pub retry: RetryPolicy,
+    pub websocket: WebSocketConfig,
+    pub http2: Http2Config,
+}
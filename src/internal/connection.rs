@@ -0,0 +1,581 @@
+use std::{
+    collections::HashMap,
+    io::{self, Error, ErrorKind, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpStream},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::internal::{request::ErrorMsg, tls::TlsStream};
+
+// How long each phase of reading a request may take. A client that sends
+// nothing, or one byte at a time, would otherwise hold its connection forever.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeouts {
+    // from the first byte until the request line is complete, also the wait
+    // for the first byte on a new connection
+    pub request_line: Duration,
+    // from the first byte until the header section is complete
+    pub headers: Duration,
+    // between two reads of the body, a long upload at a steady rate has no
+    // limit, a slow one is left to `min_body_rate`
+    pub body: Duration,
+    // wait for the next request on a kept-alive connection
+    pub keep_alive: Duration,
+    // a body that arrives slower than this, after the grace period, is given up on
+    pub min_body_rate: u64,
+    pub min_body_rate_grace: Duration,
+    // for each write of the response
+    pub write: Duration,
+    // for the requests in flight to finish on shutdown
+    pub drain: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            request_line: Duration::from_secs(10),
+            headers: Duration::from_secs(20),
+            body: Duration::from_secs(60),
+            keep_alive: Duration::from_secs(5),
+            min_body_rate: 1024,
+            min_body_rate_grace: Duration::from_secs(5),
+            write: Duration::from_secs(30),
+            drain: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionLimits {
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_connections: 1024,
+            max_connections_per_ip: 64,
+        }
+    }
+}
+
+// How often a drain checks whether the connections are closed
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+// Counts open connections, globally and per client address
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    // changed on reload, connections over the new limits are not closed
+    limits: Mutex<ConnectionLimits>,
+    open: Mutex<(usize, HashMap<IpAddr, usize>)>,
+    // the open connections by id, for the admin API
+    active: Mutex<HashMap<u64, ActiveConnection>>,
+    next_id: AtomicU64,
+    // set on shutdown, no new requests are taken after it
+    stopping: AtomicBool,
+    // a handle on each connection, to close it from outside
+    streams: Mutex<HashMap<u64, TcpStream>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveConnection {
+    pub id: u64,
+    pub peer: Option<SocketAddr>,
+    pub opened: SystemTime,
+    pub requests: u64,
+    // a request is being served, otherwise it waits for the next one
+    pub busy: bool,
+}
+
+impl ConnectionTracker {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        ConnectionTracker {
+            limits: Mutex::new(limits),
+            open: Mutex::new((0, HashMap::new())),
+            active: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            stopping: AtomicBool::new(false),
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // None when either limit is reached, the slot is given back when the guard is dropped
+    pub fn acquire(&self, peer: Option<SocketAddr>) -> Option<ConnectionGuard<'_>> {
+        let ip = peer.map(|addr| addr.ip());
+        let limits = self.limits.lock().unwrap().clone();
+        let mut open = self.open.lock().unwrap();
+        let (total, per_ip) = &mut *open;
+
+        if *total >= limits.max_connections {
+            return None;
+        }
+        if let Some(ip) = ip {
+            let count = per_ip.entry(ip).or_insert(0);
+            if *count >= limits.max_connections_per_ip {
+                return None;
+            }
+            *count += 1;
+        }
+        *total += 1;
+        drop(open);
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.active.lock().unwrap().insert(
+            id,
+            ActiveConnection {
+                id,
+                peer,
+                opened: SystemTime::now(),
+                requests: 0,
+                busy: false,
+            },
+        );
+        Some(ConnectionGuard {
+            tracker: self,
+            ip,
+            id,
+        })
+    }
+
+    pub fn set_limits(&self, limits: ConnectionLimits) {
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.lock().unwrap().0
+    }
+
+    // Oldest first
+    pub fn active(&self) -> Vec<ActiveConnection> {
+        let mut active: Vec<_> = self.active.lock().unwrap().values().cloned().collect();
+        active.sort_by_key(|c| c.id);
+        active
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    // Takes no new requests: idle connections are closed now, busy ones after
+    // their response
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let active = self.active.lock().unwrap();
+        let streams = self.streams.lock().unwrap();
+        for (id, stream) in streams.iter() {
+            if active.get(id).is_none_or(|c| !c.busy) {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    // Waits for the open connections to close, the ones still open after
+    // `timeout` are closed. False when any had to be.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.open() > 0 {
+            if Instant::now() >= deadline {
+                for stream in self.streams.lock().unwrap().values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                return false;
+            }
+            thread::sleep(DRAIN_POLL);
+        }
+        true
+    }
+
+    fn release(&self, ip: Option<IpAddr>, id: u64) {
+        self.streams.lock().unwrap().remove(&id);
+        self.active.lock().unwrap().remove(&id);
+        let mut open = self.open.lock().unwrap();
+        let (total, per_ip) = &mut *open;
+
+        *total -= 1;
+        if let Some(ip) = ip
+            && let Some(count) = per_ip.get_mut(&ip)
+        {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+pub struct ConnectionGuard<'a> {
+    tracker: &'a ConnectionTracker,
+    ip: Option<IpAddr>,
+    id: u64,
+}
+
+impl ConnectionGuard<'_> {
+    // Keeps a handle on the stream, so that shutdown can close it
+    pub fn attach(&self, stream: &TcpStream) {
+        if let Ok(stream) = stream.try_clone() {
+            self.tracker.streams.lock().unwrap().insert(self.id, stream);
+        }
+    }
+
+    // Counts a request answered on the connection
+    pub fn served(&self) {
+        if let Some(connection) = self.tracker.active.lock().unwrap().get_mut(&self.id) {
+            connection.requests += 1;
+        }
+    }
+
+    pub fn busy(&self) {
+        self.set_busy(true);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.tracker.is_stopping()
+    }
+
+    // Marks the connection as waiting for a request, false when it should be
+    // closed instead because the server is stopping
+    pub fn idle(&self) -> bool {
+        !self.set_busy(false)
+    }
+
+    // Returns whether the server is stopping, read under the same lock `stop` takes
+    fn set_busy(&self, busy: bool) -> bool {
+        let mut active = self.tracker.active.lock().unwrap();
+        if let Some(connection) = active.get_mut(&self.id) {
+            connection.busy = busy;
+        }
+        self.tracker.is_stopping()
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.tracker.release(self.ip, self.id);
+    }
+}
+
+// A client connection, in the clear or through TLS
+pub enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+}
+
+impl ClientStream {
+    // The socket underneath, for its addresses, timeouts and shutdown
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(stream) => stream,
+            ClientStream::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    // IPv4 clients of a dual-stack listener come as IPv4 addresses, not
+    // IPv4-mapped IPv6 ones
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        let peer = self.tcp().peer_addr().ok()?;
+        Some(SocketAddr::new(peer.ip().to_canonical(), peer.port()))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, ClientStream::Tls(_))
+    }
+
+    // Whether the client picked h2 with ALPN
+    pub fn is_h2(&self) -> bool {
+        match self {
+            ClientStream::Plain(_) => false,
+            ClientStream::Tls(stream) => stream.alpn_protocol().as_deref() == Some(b"h2"),
+        }
+    }
+
+    // A TLS client that did not get through the handshake
+    pub fn is_handshaking(&self) -> bool {
+        match self {
+            ClientStream::Plain(_) => false,
+            ClientStream::Tls(stream) => stream.is_handshaking(),
+        }
+    }
+}
+
+impl From<TcpStream> for ClientStream {
+    fn from(stream: TcpStream) -> Self {
+        ClientStream::Plain(stream)
+    }
+}
+
+impl Read for &ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => (&*stream).read(buf),
+            ClientStream::Tls(stream) => (&**stream).read(buf),
+        }
+    }
+}
+
+impl Write for &ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(stream) => (&*stream).write(buf),
+            ClientStream::Tls(stream) => (&**stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(stream) => (&*stream).flush(),
+            ClientStream::Tls(stream) => (&**stream).flush(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MinRate {
+    bytes_per_second: u64,
+    start: Instant,
+    grace: Duration,
+}
+
+// Reads from the connection with a deadline, an idle timeout between reads
+// and a minimum transfer rate, each optional. Running out of any fails the
+// read with ErrorKind::TimedOut.
+pub struct TimedStream<'a> {
+    stream: &'a ClientStream,
+    deadline: Option<Instant>,
+    idle: Option<Duration>,
+    min_rate: Option<MinRate>,
+    read: u64,
+    // everything read so far, the rate counts from the last `set_min_rate`
+    total: u64,
+}
+
+impl<'a> TimedStream<'a> {
+    pub fn new(stream: &'a ClientStream) -> Self {
+        TimedStream {
+            stream,
+            deadline: None,
+            idle: None,
+            min_rate: None,
+            read: 0,
+            total: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &'a ClientStream {
+        self.stream
+    }
+
+    pub fn total_read(&self) -> u64 {
+        self.total
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    pub fn clear_deadline(&mut self) {
+        self.deadline = None;
+    }
+
+    // How long each read may wait for data, None for as long as the deadline allows
+    pub fn set_idle_timeout(&mut self, idle: Option<Duration>) {
+        self.idle = idle;
+    }
+
+    // Counts from now, what was read before does not count towards the rate.
+    // 0 turns the minimum off.
+    pub fn set_min_rate(&mut self, bytes_per_second: u64, grace: Duration) {
+        self.read = 0;
+        self.min_rate = (bytes_per_second > 0).then(|| MinRate {
+            bytes_per_second,
+            start: Instant::now(),
+            grace,
+        });
+    }
+
+    // The earliest point by which the next byte has to have arrived
+    fn next_deadline(&self) -> Option<Instant> {
+        let rate_deadline = self.min_rate.as_ref().map(|rate| {
+            let allowed =
+                Duration::from_secs_f64((self.read + 1) as f64 / rate.bytes_per_second as f64);
+            rate.start + rate.grace + allowed
+        });
+
+        let idle_deadline = self.idle.map(|idle| Instant::now() + idle);
+
+        [self.deadline, rate_deadline, idle_deadline]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+}
+
+pub fn timed_out() -> Error {
+    Error::new(ErrorKind::TimedOut, ErrorMsg::REQUEST_TIMEOUT)
+}
+
+impl Read for TimedStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.next_deadline() {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(timed_out());
+                }
+                Some(left)
+            }
+            None => None,
+        };
+        self.stream.tcp().set_read_timeout(timeout)?;
+
+        match self.stream.read(buf) {
+            Ok(n) => {
+                self.read += n as u64;
+                self.total += n as u64;
+                Ok(n)
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Err(timed_out())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn test_connection_tracker() {
+        let tracker = ConnectionTracker::new(ConnectionLimits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+        });
+        let a: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+
+        let first = tracker.acquire(Some(a)).unwrap();
+        let _second = tracker.acquire(Some(a)).unwrap();
+        assert!(tracker.acquire(Some(a)).is_none());
+        let _third = tracker.acquire(Some(b)).unwrap();
+        assert!(tracker.acquire(Some(b)).is_none());
+        assert_eq!(tracker.open(), 3);
+
+        first.served();
+        let active = tracker.active();
+        assert_eq!(active.len(), 3);
+        assert_eq!((active[0].peer, active[0].requests), (Some(a), 1));
+
+        drop(first);
+        assert_eq!(tracker.open(), 2);
+        assert_eq!(tracker.active().len(), 2);
+        assert!(tracker.acquire(Some(a)).is_some());
+    }
+
+    #[test]
+    fn test_connection_drain() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _idle_client = TcpStream::connect(addr).unwrap();
+        let _busy_client = TcpStream::connect(addr).unwrap();
+        let (idle_stream, _) = listener.accept().unwrap();
+        let (busy_stream, _) = listener.accept().unwrap();
+
+        let tracker = ConnectionTracker::new(ConnectionLimits::default());
+        let idle = tracker.acquire(None).unwrap();
+        idle.attach(&idle_stream);
+        assert!(idle.idle());
+        let busy = tracker.acquire(None).unwrap();
+        busy.attach(&busy_stream);
+        busy.busy();
+
+        // the idle connection is closed right away, the busy one is left alone
+        tracker.stop();
+        assert!(tracker.is_stopping());
+        let mut buf = [0u8; 1];
+        assert_eq!((&idle_stream).read(&mut buf).unwrap(), 0);
+        busy_stream
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        assert!((&busy_stream).read(&mut buf).is_err());
+        assert!(!busy.idle());
+
+        drop(idle);
+        assert!(!tracker.drain(Duration::from_millis(30)));
+        assert_eq!((&busy_stream).read(&mut buf).unwrap(), 0);
+        drop(busy);
+        assert!(tracker.drain(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn test_timed_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET").unwrap();
+            thread::sleep(Duration::from_millis(300));
+        });
+
+        let stream = ClientStream::from(listener.accept().unwrap().0);
+        let mut timed = TimedStream::new(&stream);
+        timed.set_deadline(Instant::now() + Duration::from_millis(100));
+
+        let mut buf = [0u8; 16];
+        assert_eq!(timed.read(&mut buf).unwrap(), 3);
+        let error = timed.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert_eq!(error.to_string(), ErrorMsg::REQUEST_TIMEOUT);
+
+        // at 100 bytes a second the 4th byte is due after 40ms
+        timed.deadline = None;
+        timed.set_min_rate(100, Duration::ZERO);
+        timed.read = 3;
+        let started = Instant::now();
+        assert_eq!(
+            timed.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+        assert!(started.elapsed() < Duration::from_millis(250));
+
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            for _ in 0..6 {
+                stream.write_all(b"x").unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+            thread::sleep(Duration::from_millis(300));
+        });
+
+        let stream = ClientStream::from(listener.accept().unwrap().0);
+        let mut timed = TimedStream::new(&stream);
+        timed.set_idle_timeout(Some(Duration::from_millis(150)));
+
+        // a body that keeps coming may take longer than the idle timeout
+        let started = Instant::now();
+        let mut buf = [0u8; 16];
+        let mut received = 0;
+        while received < 6 {
+            received += timed.read(&mut buf).unwrap();
+        }
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        let error = timed.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+
+        client.join().unwrap();
+    }
+}
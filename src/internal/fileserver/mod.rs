@@ -0,0 +1,585 @@
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::internal::{
+    httpdate,
+    request::{Request, RequestMethod},
+    response::{Response, StatusCode},
+};
+
+mod mime;
+mod range;
+
+pub use mime::content_type;
+pub use range::{ByteRange, Ranges, parse_range};
+
+// Serves files from a directory on disk. Paths are resolved below `root` only,
+// anything that would end up outside of it is answered with 404.
+#[derive(Debug, Clone)]
+pub struct FileServer {
+    root: PathBuf,
+    // serve index.html for directory requests
+    index: bool,
+    // generate a listing for directories without an index
+    listing: bool,
+}
+
+impl FileServer {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FileServer {
+            root: root.into(),
+            index: true,
+            listing: false,
+        }
+    }
+
+    pub fn with_index(mut self, index: bool) -> Self {
+        self.index = index;
+        self
+    }
+
+    pub fn with_listing(mut self, listing: bool) -> Self {
+        self.listing = listing;
+        self
+    }
+
+    // The directory and options, for reports
+    pub fn describe(&self) -> String {
+        let mut out = format!("static {}", self.root.display());
+        if !self.index {
+            out.push_str(" without index");
+        }
+        if self.listing {
+            out.push_str(" with listing");
+        }
+        out
+    }
+
+    // `path` is the part of the request path below the route prefix
+    pub fn serve(&self, request: &Request, path: &str) -> Response {
+        match request.method {
+            Some(RequestMethod::Get) | Some(RequestMethod::Head) => {}
+            _ => {
+                return Response::builder(StatusCode::METHOD_NOT_ALLOWED)
+                    .header("Allow", "GET, HEAD")
+                    .body(StatusCode::METHOD_NOT_ALLOWED.reason())
+                    .build();
+            }
+        }
+
+        let file_path = match self.resolve(path) {
+            Ok(p) => p,
+            Err(status) => return error_response(status),
+        };
+
+        let meta = match fs::metadata(&file_path) {
+            Ok(m) => m,
+            Err(e) => return error_response(io_error_status(&e)),
+        };
+
+        if !meta.is_dir() {
+            return serve_file(request, &file_path, &meta);
+        }
+
+        // relative links in the index and listing only work with the trailing slash
+        if !path.ends_with('/') {
+            let mut location = format!("{}/", request.target_path().unwrap_or("/"));
+            if let Some(query) = request.query() {
+                location.push('?');
+                location.push_str(query);
+            }
+
+            return Response::builder(StatusCode::MOVED_PERMANENTLY)
+                .header("Location", &location)
+                .body(StatusCode::MOVED_PERMANENTLY.reason())
+                .build();
+        }
+
+        let index_path = file_path.join("index.html");
+        if self.index
+            && let Ok(meta) = fs::metadata(&index_path)
+            && meta.is_file()
+        {
+            return serve_file(request, &index_path, &meta);
+        }
+
+        if self.listing {
+            return match directory_listing(&file_path, request.target_path().unwrap_or("/")) {
+                Ok(html) => Response::builder(StatusCode::OK)
+                    .header("Content-Type", "text/html; charset=utf-8")
+                    .body(html)
+                    .build(),
+                Err(e) => error_response(io_error_status(&e)),
+            };
+        }
+
+        error_response(StatusCode::FORBIDDEN)
+    }
+
+    // Maps the request path onto the file system. The segments are checked before
+    // touching the disk and the canonical result again, so neither `..` nor a
+    // symlink can lead out of the root.
+    fn resolve(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        let decoded = percent_decode(path).ok_or(StatusCode::BAD_REQUEST)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        let mut resolved = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return Err(StatusCode::NOT_FOUND),
+                s if s.contains(['\\', '\0']) || Path::new(s).has_root() => {
+                    return Err(StatusCode::NOT_FOUND);
+                }
+                s => resolved.push(s),
+            }
+        }
+
+        let root = self.root.canonicalize().map_err(|e| io_error_status(&e))?;
+        let canonical = resolved.canonicalize().map_err(|e| io_error_status(&e))?;
+        if !canonical.starts_with(&root) {
+            return Err(StatusCode::NOT_FOUND);
+        }
+
+        Ok(canonical)
+    }
+}
+
+fn serve_file(request: &Request, path: &Path, meta: &Metadata) -> Response {
+    let length = meta.len();
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok());
+    // HTTP-dates only have second precision
+    let last_modified = modified.map(|m| UNIX_EPOCH + Duration::from_secs(m.as_secs()));
+    let etag = format!(
+        "\"{:x}-{:x}\"",
+        modified.map(|m| m.as_nanos()).unwrap_or(0),
+        length
+    );
+    let headers = request.headers.clone().unwrap_or_default();
+
+    let not_modified = match headers.get("If-None-Match") {
+        Some(inm) => etag_matches(inm, &etag, true),
+        None => match (headers.get("If-Modified-Since"), last_modified) {
+            (Some(ims), Some(lm)) => httpdate::parse(ims).is_some_and(|ims| lm <= ims),
+            _ => false,
+        },
+    };
+
+    let mut builder = Response::builder(StatusCode::OK)
+        .header("Content-Type", content_type(path))
+        .header("ETag", &etag)
+        .header("Accept-Ranges", "bytes");
+    if let Some(lm) = last_modified {
+        builder = builder.header("Last-Modified", &httpdate::format(lm));
+    }
+
+    if not_modified {
+        return builder.status(StatusCode::NOT_MODIFIED).build();
+    }
+
+    // a stale If-Range means the client's partial copy is useless, send it all
+    let range_applies = match headers.get("If-Range") {
+        Some(ir) if ir.trim_start().starts_with(['"', 'W']) => etag_matches(ir, &etag, false),
+        Some(ir) => httpdate::parse(ir).is_some() && httpdate::parse(ir) == last_modified,
+        None => true,
+    };
+    let ranges = match headers.get("Range") {
+        Some(r) if range_applies && request.method == Some(RequestMethod::Get) => {
+            parse_range(r, length)
+        }
+        _ => Ranges::Ignore,
+    };
+
+    let result = match ranges {
+        Ranges::Ignore => File::open(path).map(|file| builder.sized(file, length).build()),
+        Ranges::Unsatisfiable => Ok(builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("Content-Range", &format!("bytes */{length}"))
+            .body(StatusCode::RANGE_NOT_SATISFIABLE.reason())
+            .build()),
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            open_range(path, &range).map(|part| {
+                builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header("Content-Range", &range.content_range(length))
+                    .sized(part, range.size())
+                    .build()
+            })
+        }
+        Ranges::Satisfiable(ranges) => {
+            multipart_byteranges(path, &ranges, length, content_type(path)).map(
+                |(boundary, body, body_len)| {
+                    builder
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header(
+                            "Content-Type",
+                            &format!("multipart/byteranges; boundary={boundary}"),
+                        )
+                        .sized(body, body_len)
+                        .build()
+                },
+            )
+        }
+    };
+
+    result.unwrap_or_else(|e| error_response(io_error_status(&e)))
+}
+
+// RFC 9110 section 13.1.2, If-None-Match uses the weak comparison and
+// If-Range the strong one
+fn etag_matches(header: &str, etag: &str, weak: bool) -> bool {
+    let strip = |t: &str| t.trim().trim_start_matches("W/").to_string();
+
+    header.split(',').map(|t| t.trim()).any(|candidate| {
+        if candidate == "*" {
+            return weak;
+        }
+        if weak {
+            strip(candidate) == strip(etag)
+        } else {
+            !candidate.starts_with("W/") && candidate == etag
+        }
+    })
+}
+
+fn open_range(path: &Path, range: &ByteRange) -> io::Result<io::Take<File>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(range.start))?;
+    Ok(file.take(range.size()))
+}
+
+type Part = Box<dyn Read + Send>;
+
+// Builds a multipart/byteranges body, RFC 9110 section 14.6. The parts are
+// read straight from the file, only the part headers are kept in memory.
+fn multipart_byteranges(
+    path: &Path,
+    ranges: &[ByteRange],
+    length: u64,
+    content_type: &str,
+) -> io::Result<(String, MultiReader, u64)> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let boundary = format!("lb-byteranges-{nanos:x}");
+
+    let mut parts: Vec<Part> = Vec::new();
+    let mut total: u64 = 0;
+
+    for range in ranges {
+        let head = format!(
+            "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+            range.content_range(length)
+        );
+        total += head.len() as u64 + range.size();
+        parts.push(Box::new(Cursor::new(head.into_bytes())));
+        parts.push(Box::new(open_range(path, range)?));
+    }
+
+    let tail = format!("\r\n--{boundary}--\r\n");
+    total += tail.len() as u64;
+    parts.push(Box::new(Cursor::new(tail.into_bytes())));
+
+    Ok((boundary, MultiReader { parts, current: 0 }, total))
+}
+
+// Reads a list of readers one after the other
+pub struct MultiReader {
+    parts: Vec<Part>,
+    current: usize,
+}
+
+impl Read for MultiReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current < self.parts.len() {
+            let n = self.parts[self.current].read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.current += 1;
+        }
+
+        Ok(0)
+    }
+}
+
+fn directory_listing(dir: &Path, request_path: &str) -> io::Result<String> {
+    let mut entries: Vec<(String, bool)> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| {
+            let is_dir = e.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            (e.file_name().to_string_lossy().to_string(), is_dir)
+        })
+        .collect();
+    entries.sort();
+
+    let title = html_escape(&String::from_utf8_lossy(
+        &percent_decode(request_path).unwrap_or_default(),
+    ));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n<body>\n<h1>Index of {title}</h1>\n<ul>\n"
+    );
+    if request_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let suffix = if is_dir { "/" } else { "" };
+        html.push_str(&format!(
+            "<li><a href=\"{}{suffix}\">{}{suffix}</a></li>\n",
+            percent_encode(&name),
+            html_escape(&name)
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    Ok(html)
+}
+
+fn error_response(status: StatusCode) -> Response {
+    Response::builder(status)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(status.reason())
+        .build()
+}
+
+fn io_error_status(e: &io::Error) -> StatusCode {
+    match e.kind() {
+        ErrorKind::NotFound | ErrorKind::NotADirectory => StatusCode::NOT_FOUND,
+        ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            let hex = std::str::from_utf8(hex).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Some(out)
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::request::parse;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("lb-fileserver-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("hello.txt"), b"Hello, static world!").unwrap();
+        fs::write(root.join("index.html"), b"<h1>home</h1>").unwrap();
+        fs::write(root.join("docs").join("a b.css"), b"body {}").unwrap();
+        root
+    }
+
+    fn send(server: &FileServer, raw: &str) -> String {
+        let request = parse(raw.as_bytes()).unwrap();
+        let path = request.target_path().unwrap().to_string();
+        let mut out = Vec::new();
+        server.serve(&request, &path).send(&mut out).unwrap();
+        String::from_utf8_lossy(&out).to_string()
+    }
+
+    #[test]
+    fn test_serve_files() {
+        let root = temp_root("files");
+        let server = FileServer::new(&root).with_listing(true);
+
+        let output = send(&server, "GET /hello.txt HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(output.contains("Content-Length: 20\r\n"));
+        assert!(output.ends_with("\r\n\r\nHello, static world!"));
+
+        // the listener drops the body of a HEAD response
+        let request = parse(b"HEAD /hello.txt HTTP/1.1\r\n\r\n").unwrap();
+        let mut out = Vec::new();
+        let response = server.serve(&request, "/hello.txt").without_body();
+        response.send(&mut out).unwrap();
+        let output = String::from_utf8(out).unwrap();
+        assert!(output.contains("Content-Length: 20\r\n"));
+        assert!(output.ends_with("\r\n\r\n"));
+
+        let output = send(&server, "GET /docs/a%20b.css HTTP/1.1\r\n\r\n");
+        assert!(output.contains("text/css"));
+
+        // index.html for the root, listing for a directory without one
+        let output = send(&server, "GET / HTTP/1.1\r\n\r\n");
+        assert!(output.ends_with("<h1>home</h1>"));
+        let output = send(&server, "GET /docs/ HTTP/1.1\r\n\r\n");
+        assert!(output.contains("<a href=\"a%20b.css\">a b.css</a>"));
+        let output = send(&server, "GET /docs?x=1 HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(output.contains("Location: /docs/?x=1\r\n"));
+
+        let server = FileServer::new(&root).with_index(false);
+        let output = send(&server, "GET /docs/ HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+        // traversal never leaves the root
+        for target in [
+            "/../etc/passwd",
+            "/docs/../../etc/passwd",
+            "/%2e%2e/etc/passwd",
+            "/docs/..%2f..%2fetc",
+        ] {
+            let output = send(&server, &format!("GET {target} HTTP/1.1\r\n\r\n"));
+            assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"), "{target}");
+        }
+
+        let output = send(&server, "DELETE /hello.txt HTTP/1.1\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(output.contains("Allow: GET, HEAD\r\n"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_conditional_requests() {
+        let root = temp_root("conditional");
+        let server = FileServer::new(&root);
+
+        let output = send(&server, "GET /hello.txt HTTP/1.1\r\n\r\n");
+        let etag = output
+            .lines()
+            .find_map(|l| l.strip_prefix("ETag: "))
+            .unwrap()
+            .to_string();
+        let last_modified = output
+            .lines()
+            .find_map(|l| l.strip_prefix("Last-Modified: "))
+            .unwrap()
+            .to_string();
+
+        let output = send(
+            &server,
+            &format!("GET /hello.txt HTTP/1.1\r\nIf-None-Match: \"x\", W/{etag}\r\n\r\n"),
+        );
+        assert!(output.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(output.ends_with("\r\n\r\n"));
+
+        let output = send(
+            &server,
+            &format!("GET /hello.txt HTTP/1.1\r\nIf-Modified-Since: {last_modified}\r\n\r\n"),
+        );
+        assert!(output.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+
+        let output = send(
+            &server,
+            "GET /hello.txt HTTP/1.1\r\nIf-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // If-None-Match wins over If-Modified-Since
+        let output = send(
+            &server,
+            &format!(
+                "GET /hello.txt HTTP/1.1\r\nIf-None-Match: \"other\"\r\nIf-Modified-Since: {last_modified}\r\n\r\n"
+            ),
+        );
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_range_requests() {
+        let root = temp_root("range");
+        let server = FileServer::new(&root);
+
+        let output = send(
+            &server,
+            "GET /hello.txt HTTP/1.1\r\nRange: bytes=0-4\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(output.contains("Content-Range: bytes 0-4/20\r\n"));
+        assert!(output.contains("Content-Length: 5\r\n"));
+        assert!(output.ends_with("\r\n\r\nHello"));
+
+        let output = send(
+            &server,
+            "GET /hello.txt HTTP/1.1\r\nRange: bytes=-6\r\n\r\n",
+        );
+        assert!(output.ends_with("world!"));
+
+        let output = send(
+            &server,
+            "GET /hello.txt HTTP/1.1\r\nRange: bytes=50-\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(output.contains("Content-Range: bytes */20\r\n"));
+
+        // If-Range with an outdated validator falls back to the full file
+        let output = send(
+            &server,
+            "GET /hello.txt HTTP/1.1\r\nRange: bytes=0-4\r\nIf-Range: \"stale\"\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let output = send(
+            &server,
+            "GET /hello.txt HTTP/1.1\r\nRange: bytes=0-4, 14-19\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        let boundary = output
+            .lines()
+            .find_map(|l| l.strip_prefix("Content-Type: multipart/byteranges; boundary="))
+            .unwrap()
+            .to_string();
+        let (head, body) = output.split_once("\r\n\r\n").unwrap();
+        let length: usize = head
+            .lines()
+            .find_map(|l| l.strip_prefix("Content-Length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(body.len(), length);
+        assert!(body.contains("Content-Range: bytes 0-4/20\r\n\r\nHello\r\n"));
+        assert!(body.contains("Content-Range: bytes 14-19/20\r\n\r\nworld!\r\n"));
+        assert!(body.ends_with(&format!("\r\n--{boundary}--\r\n")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
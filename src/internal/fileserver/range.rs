@@ -0,0 +1,148 @@
+// Range requests, RFC 9110 section 14
+//
+// Range         = ranges-specifier
+// ranges-specifier = range-unit "=" range-set
+// range-set     = 1#range-spec
+// range-spec    = int-range / suffix-range
+// int-range     = first-pos "-" [ last-pos ]
+// suffix-range  = "-" suffix-length
+
+// more ranges than this in one request is treated as abuse and the whole
+// representation is sent instead
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    // inclusive
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, complete_length: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, complete_length)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    // invalid or unsupported Range header, the full representation is sent
+    Ignore,
+    Unsatisfiable,
+    Satisfiable(Vec<ByteRange>),
+}
+
+pub fn parse_range(header: &str, length: u64) -> Ranges {
+    let set = match header.trim().split_once('=') {
+        Some((unit, set)) if unit.trim().eq_ignore_ascii_case("bytes") => set,
+        _ => return Ranges::Ignore,
+    };
+
+    let specs: Vec<&str> = set
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return Ranges::Ignore;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = match spec.split_once('-') {
+            Some(p) => p,
+            None => return Ranges::Ignore,
+        };
+
+        let parse = |s: &str| s.parse::<u64>().ok();
+        let range = match (first.is_empty(), last.is_empty()) {
+            // suffix-range, the last n bytes
+            (true, false) => match parse(last) {
+                Some(0) => None,
+                Some(n) if length > 0 => Some(ByteRange {
+                    start: length.saturating_sub(n),
+                    end: length - 1,
+                }),
+                Some(_) => None,
+                None => return Ranges::Ignore,
+            },
+            (false, _) => {
+                let start = match parse(first) {
+                    Some(s) => s,
+                    None => return Ranges::Ignore,
+                };
+                let end = match last.is_empty() {
+                    true => u64::MAX,
+                    false => match parse(last) {
+                        Some(e) if e >= start => e,
+                        _ => return Ranges::Ignore,
+                    },
+                };
+
+                (start < length).then(|| ByteRange {
+                    start,
+                    end: end.min(length - 1),
+                })
+            }
+            (true, true) => return Ranges::Ignore,
+        };
+
+        // unsatisfiable ranges are skipped as long as one of them can be served
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Satisfiable(ranges)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        let r = |start, end| ByteRange { start, end };
+
+        assert_eq!(
+            parse_range("bytes=0-499", 10000),
+            Ranges::Satisfiable(vec![r(0, 499)])
+        );
+        assert_eq!(
+            parse_range("bytes=9500-", 10000),
+            Ranges::Satisfiable(vec![r(9500, 9999)])
+        );
+        assert_eq!(
+            parse_range("bytes=-500", 10000),
+            Ranges::Satisfiable(vec![r(9500, 9999)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-0, -1 ,9000-20000", 10000),
+            Ranges::Satisfiable(vec![r(0, 0), r(9999, 9999), r(9000, 9999)])
+        );
+        assert_eq!(
+            parse_range("bytes=-20000", 10000),
+            Ranges::Satisfiable(vec![r(0, 9999)])
+        );
+
+        assert_eq!(parse_range("bytes=10000-", 10000), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 10000), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Ranges::Unsatisfiable);
+
+        assert_eq!(parse_range("items=0-5", 10000), Ranges::Ignore);
+        assert_eq!(parse_range("bytes=5-1", 10000), Ranges::Ignore);
+        assert_eq!(parse_range("bytes=a-b", 10000), Ranges::Ignore);
+        assert_eq!(parse_range("bytes=", 10000), Ranges::Ignore);
+
+        let many = format!("bytes={}", vec!["0-1"; 17].join(","));
+        assert_eq!(parse_range(&many, 10000), Ranges::Ignore);
+    }
+}
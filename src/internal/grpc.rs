@@ -0,0 +1,305 @@
+use std::{
+    io::{Error, ErrorKind, Read},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::internal::{
+    http2::client,
+    request::Request,
+    response::{Response, StatusCode},
+};
+
+// Status codes, https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+pub const OK: u32 = 0;
+pub const UNKNOWN: u32 = 2;
+pub const DEADLINE_EXCEEDED: u32 = 4;
+pub const PERMISSION_DENIED: u32 = 7;
+pub const UNIMPLEMENTED: u32 = 12;
+pub const INTERNAL: u32 = 13;
+pub const UNAVAILABLE: u32 = 14;
+pub const UNAUTHENTICATED: u32 = 16;
+
+const HEALTH_PATH: &str = "/grpc.health.v1.Health/Check";
+// HealthCheckResponse.ServingStatus
+const SERVING: u64 = 1;
+
+fn is_grpc_type(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|t| {
+        let t = t.trim().to_ascii_lowercase();
+        t == "application/grpc"
+            || t.starts_with("application/grpc+")
+            || t.starts_with("application/grpc;")
+    })
+}
+
+// A gRPC call, which is told about failures with a status instead of an
+// HTTP error page
+pub fn is_grpc(request: &Request) -> bool {
+    is_grpc_type(request.headers.as_ref().and_then(|h| h.get("Content-Type")))
+}
+
+// The status of a call that got a plain HTTP response, as gRPC clients map
+// them: https://github.com/grpc/grpc/blob/master/doc/http-grpc-status-mapping.md
+pub fn status_for(status: StatusCode) -> u32 {
+    match status.as_u16() {
+        400 => INTERNAL,
+        401 => UNAUTHENTICATED,
+        403 => PERMISSION_DENIED,
+        404 => UNIMPLEMENTED,
+        429 | 502 | 503 | 504 => UNAVAILABLE,
+        _ => UNKNOWN,
+    }
+}
+
+// A call that failed without reaching a backend, or timed out waiting for it
+pub fn failure(e: &Error) -> Response {
+    let code = match e.kind() {
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => DEADLINE_EXCEEDED,
+        _ => UNAVAILABLE,
+    };
+    error_response(code, &e.to_string())
+}
+
+// Passes a gRPC response on, anything else becomes the status a client would
+// make of it
+pub fn answer(response: Response) -> Response {
+    if is_grpc_type(response.header("Content-Type")) {
+        return response;
+    }
+    let code = match response.status {
+        StatusCode::OK => UNKNOWN,
+        status => status_for(status),
+    };
+    let message = format!(
+        "upstream answered HTTP {} {}",
+        response.status.as_u16(),
+        response.status.reason()
+    );
+    error_response(code, &message)
+}
+
+// A response without messages, the status in the trailers
+pub fn error_response(code: u32, message: &str) -> Response {
+    Response::builder(StatusCode::OK)
+        .header("Content-Type", "application/grpc")
+        .trailer("grpc-status", &code.to_string())
+        .trailer("grpc-message", &percent_encode(message))
+        .build()
+}
+
+// grpc-message is percent-encoded UTF-8, printable ASCII other than % as is
+fn percent_encode(message: &str) -> String {
+    let mut out = String::new();
+    for byte in message.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'%' => out.push(byte as char),
+            _ => out.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub interval: Duration,
+    // the service asked about, empty for the server as a whole
+    pub service: String,
+    pub timeout: Duration,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            interval: Duration::from_secs(10),
+            service: String::new(),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+// Asks a backend with the gRPC health checking protocol whether it serves,
+// https://github.com/grpc/grpc/blob/master/doc/health-checking.md
+pub fn probe(address: &str, check: &HealthCheck) -> Result<(), Error> {
+    let mut last_error = Error::new(ErrorKind::NotFound, "Upstream address did not resolve");
+    let mut connected = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, check.timeout) {
+            Ok(stream) => {
+                connected = Some(stream);
+                break;
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    let stream = connected.ok_or(last_error)?;
+    stream.set_read_timeout(Some(check.timeout))?;
+    stream.set_write_timeout(Some(check.timeout))?;
+
+    let fields = [
+        (":method", "POST"),
+        (":scheme", "http"),
+        (":path", HEALTH_PATH),
+        (":authority", address),
+        ("content-type", "application/grpc"),
+        ("te", "trailers"),
+    ]
+    .map(|(n, v)| (n.to_string(), v.to_string()));
+    let request = health_request(&check.service);
+    let mut response = client::send(&stream, &fields, Some(&mut &request[..]), 16 * 1024)?;
+    if response.status != 200 {
+        return Err(unhealthy(format!("HTTP status {}", response.status)));
+    }
+    let mut body = Vec::new();
+    response.body.read_to_end(&mut body)?;
+
+    // a response without messages has the status among its headers
+    let status = response
+        .body
+        .trailers()
+        .iter()
+        .chain(&response.headers)
+        .find(|(name, _)| name == "grpc-status")
+        .map(|(_, value)| value.as_str());
+    match status {
+        Some("0") if is_serving(&body) => Ok(()),
+        Some("0") => Err(unhealthy("not serving".to_string())),
+        Some(status) => Err(unhealthy(format!("grpc-status {status}"))),
+        None => Err(unhealthy("no grpc-status".to_string())),
+    }
+}
+
+fn unhealthy(reason: String) -> Error {
+    Error::other(format!("health check failed: {reason}"))
+}
+
+// HealthCheckRequest { string service = 1; } as a length-prefixed message
+fn health_request(service: &str) -> Vec<u8> {
+    let mut message = Vec::new();
+    if !service.is_empty() {
+        message.push(0x0a);
+        varint(&mut message, service.len() as u64);
+        message.extend_from_slice(service.as_bytes());
+    }
+    let mut out = vec![0];
+    out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    out.extend_from_slice(&message);
+    out
+}
+
+// HealthCheckResponse { ServingStatus status = 1; } says SERVING
+fn is_serving(body: &[u8]) -> bool {
+    let Some(message) = body.get(5..) else {
+        return false;
+    };
+    if body[0] != 0
+        || u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize != message.len()
+    {
+        return false;
+    }
+    let mut input = message;
+    let mut status = 0;
+    while !input.is_empty() {
+        let Ok(key) = read_varint(&mut input) else {
+            return false;
+        };
+        let skipped = match key & 7 {
+            0 => read_varint(&mut input).map(|value| {
+                if key >> 3 == 1 {
+                    status = value;
+                }
+            }),
+            1 => skip(&mut input, 8),
+            2 => read_varint(&mut input).and_then(|len| skip(&mut input, len as usize)),
+            5 => skip(&mut input, 4),
+            _ => Err(()),
+        };
+        if skipped.is_err() {
+            return false;
+        }
+    }
+    status == SERVING
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> Result<u64, ()> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first().ok_or(())?;
+        *input = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(())
+}
+
+fn skip(input: &mut &[u8], n: usize) -> Result<(), ()> {
+    *input = input.get(n..).ok_or(())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::headers::Headers;
+
+    #[test]
+    fn test_status_mapping() {
+        let mut request = Request::new();
+        let mut headers = Headers::default();
+        headers.insert("Content-Type", "application/grpc+proto");
+        request.headers = Some(headers);
+        assert!(is_grpc(&request));
+        assert!(!is_grpc(&Request::new()));
+
+        assert_eq!(status_for(StatusCode::NOT_FOUND), UNIMPLEMENTED);
+        assert_eq!(status_for(StatusCode::SERVICE_UNAVAILABLE), UNAVAILABLE);
+        assert_eq!(status_for(StatusCode::CONFLICT), UNKNOWN);
+
+        let response = answer(Response::new(StatusCode::BAD_GATEWAY));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.header("Content-Type"), Some("application/grpc"));
+        assert_eq!(
+            response.trailers[0],
+            ("grpc-status".to_string(), "14".to_string())
+        );
+        assert_eq!(
+            response.trailers[1].1,
+            "upstream answered HTTP 502 Bad Gateway"
+        );
+
+        let passed = Response::builder(StatusCode::OK)
+            .header("Content-Type", "application/grpc")
+            .trailer("grpc-status", "5")
+            .build();
+        assert_eq!(answer(passed).trailers[0].1, "5");
+
+        let timeout = failure(&Error::new(ErrorKind::TimedOut, "50% done\n"));
+        assert_eq!(timeout.trailers[0].1, "4");
+        assert_eq!(timeout.trailers[1].1, "50%25 done%0A");
+    }
+
+    #[test]
+    fn test_health_messages() {
+        assert_eq!(health_request(""), [0, 0, 0, 0, 0]);
+        assert_eq!(
+            health_request("echo"),
+            [0, 0, 0, 0, 6, 0x0a, 4, b'e', b'c', b'h', b'o']
+        );
+        assert!(is_serving(&[0, 0, 0, 0, 2, 0x08, 1]));
+        assert!(!is_serving(&[0, 0, 0, 0, 2, 0x08, 2]));
+        // unknown fields are skipped
+        assert!(is_serving(&[0, 0, 0, 0, 5, 0x12, 1, b'x', 0x08, 1]));
+        assert!(!is_serving(&[0, 0, 0, 0, 0]));
+        assert!(!is_serving(&[0, 0, 0, 0, 3, 0x08, 1]));
+    }
+}
@@ -1,4 +1,6 @@
+use super::limits::Limits;
 use super::request::ErrorMsg;
+use crate::debug;
 use core::str;
 use std::collections::hash_map::Iter;
 // use std::str::FromStr;
@@ -16,9 +18,18 @@ pub struct Headers {
 }
 impl Headers {
     fn new() -> Headers {
-        return Headers {
+        Headers {
             inner: HashMap::new(),
-        };
+        }
+    }
+
+    // Repeated fields are joined as `set` does
+    pub fn from_fields(fields: &[(String, String)]) -> Headers {
+        let mut headers = Headers::new();
+        for (name, value) in fields {
+            headers.set(name, value);
+        }
+        headers
     }
 
     pub fn get(&self, k: &str) -> Option<&str> {
@@ -37,40 +48,91 @@ impl Headers {
             .or_insert_with(|| v.to_string());
     }
 
-    pub fn iter(&self) -> Iter<String, String> {
+    // Replaces any value the field had
+    pub fn insert(&mut self, k: &str, v: &str) {
+        self.inner.insert(k.to_lowercase(), v.to_string());
+    }
+
+    pub fn remove(&mut self, k: &str) -> Option<String> {
+        self.inner.remove(&k.to_lowercase())
+    }
+
+    pub fn iter(&self) -> Iter<'_, String, String> {
         self.inner.iter()
     }
 }
 
-fn is_token_char(byte: &u8) -> bool {
-    match byte {
-        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => true,
-        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' | b'^' | b'_'
-        | b'`' | b'|' | b'~' => true,
-        _ => false,
+pub(crate) fn is_token_char(byte: &u8) -> bool {
+    matches!(
+        byte,
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9'
+            | b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' | b'^'
+            | b'_' | b'`' | b'|' | b'~'
+    )
+}
+// Length of the field section at the start of `bytes`, including the empty line
+// that terminates it, or None while that empty line has not arrived yet
+pub fn field_section_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.starts_with(CRLF) {
+        return Some(CRLF.len());
     }
+
+    bytes
+        .windows(4)
+        .position(|b| b == b"\r\n\r\n")
+        .map(|i| i + 4)
+}
+
+pub fn parse_field_lines(bytes: &[u8], limits: &Limits) -> Result<(Headers, usize), Error> {
+    let (fields, read) = parse_field_list(bytes, limits)?;
+    Ok((Headers::from_fields(&fields), read))
 }
-pub fn parse_field_lines(bytes: &[u8]) -> Result<(Headers, usize), Error> {
+
+// The field lines in the order they came, a repeated field stays repeated.
+// Names are lowercased.
+pub fn parse_field_list(
+    bytes: &[u8],
+    limits: &Limits,
+) -> Result<(Vec<(String, String)>, usize), Error> {
     // Field line syntax -> field-name: field-value
     //
     // RULES
     // There should no whitespace between the field name and :
     // A field-value can have OWS as prefix and suffix
 
-    let mut headers = Headers::new();
+    let mut fields = Vec::new();
     let mut bytes_to_read: &[u8] = bytes;
     let mut read: usize = 0;
+    let mut count: usize = 0;
+
+    while let Some(field_line_idx) = bytes_to_read.windows(CRLF.len()).position(|b| b == CRLF) {
+        if read + field_line_idx + CRLF.len() > limits.max_header_bytes {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                ErrorMsg::HEADERS_TOO_LARGE,
+            ));
+        }
+
+        // an empty line ends the field section, consume it as well
+        if field_line_idx == 0 {
+            return Ok((fields, read + CRLF.len()));
+        }
+
+        if field_line_idx > limits.max_header_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                ErrorMsg::HEADER_TOO_LARGE,
+            ));
+        }
+
+        count += 1;
+        if count > limits.max_header_count {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                ErrorMsg::TOO_MANY_HEADERS,
+            ));
+        }
 
-    loop {
-        let field_line_idx = match bytes_to_read.windows(CRLF.len()).position(|b| b == CRLF) {
-            Some(idx) => {
-                if idx == 0 {
-                    return Ok((headers, read));
-                }
-                idx
-            }
-            None => break,
-        };
         let field_line = &bytes_to_read[0..field_line_idx];
 
         let mut x = field_line.splitn(2, |b| *b == b':');
@@ -86,8 +148,8 @@ pub fn parse_field_lines(bytes: &[u8]) -> Result<(Headers, usize), Error> {
         // .all breaks if it encounters a false value
         let is_valid_field_name = field_name.iter().all(is_token_char);
         if !is_valid_field_name {
-            println!(
-                "Invalid field name {:?}",
+            debug!(
+                "invalid field name {:?}",
                 String::from_utf8_lossy(field_name)
             );
             return Err(Error::new(
@@ -105,16 +167,30 @@ pub fn parse_field_lines(bytes: &[u8]) -> Result<(Headers, usize), Error> {
         let field_name_name_str = bytes_to_string(field_name)?;
         let field_name_value_str = bytes_to_string(field_value)?;
 
-        headers.set(
-            &field_name_name_str.to_lowercase(),
-            &field_name_value_str.trim().to_string(),
-        );
+        fields.push((
+            field_name_name_str.to_lowercase(),
+            field_name_value_str.trim().to_string(),
+        ));
 
         read += field_line_idx + CRLF.len();
         bytes_to_read = &bytes_to_read[field_line_idx + CRLF.len()..];
     }
-    println!("DONE => {:?}", headers);
-    Ok((headers, read))
+
+    // the rest is an unfinished field line, it can already be over the limits
+    if bytes_to_read.len() > limits.max_header_size {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            ErrorMsg::HEADER_TOO_LARGE,
+        ));
+    }
+    if read + bytes_to_read.len() > limits.max_header_bytes {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            ErrorMsg::HEADERS_TOO_LARGE,
+        ));
+    }
+
+    Ok((fields, read))
 }
 
 #[cfg(test)]
@@ -123,26 +199,63 @@ mod test {
 
     #[test]
     fn test_parse_field_lines() {
+        let limits = Limits::default();
         let mut input: &[u8] = b"Authorization: mytoken \r\nContent-type: application/json \r\n";
 
-        let (headers, read) = parse_field_lines(input).unwrap();
+        let (headers, read) = parse_field_lines(input, &limits).unwrap();
         let authorization = headers.get("authorization");
         assert_eq!(authorization, Some("mytoken"));
-        println!("After firs");
 
         let content_type = headers.get("content-type");
         assert_eq!(content_type, Some("application/json"));
         assert_eq!(read, 58);
 
         input = b" Authorization: my token \r\nContent-type: application/json \r\n";
-        let mut x = parse_field_lines(input);
+        let mut x = parse_field_lines(input, &limits);
         let mut error = x.unwrap_err();
 
         assert_eq!(error.kind(), ErrorKind::InvalidData);
 
         input = b"A/uthorization: my token \r\nContent-type: application/json \r\n";
-        x = parse_field_lines(input);
+        x = parse_field_lines(input, &limits);
         error = x.unwrap_err();
         assert_eq!(error.kind(), ErrorKind::InvalidData);
     }
+
+    #[test]
+    fn test_field_lines_limits() {
+        let input: &[u8] = b"Host: lb\r\nAccept: */*\r\nX-Long: aaaaaaaaaaaaaaaa\r\n\r\n";
+        let (_, read) = parse_field_lines(input, &Limits::default()).unwrap();
+        assert_eq!(read, input.len());
+        assert_eq!(field_section_len(input), Some(input.len()));
+
+        let mut limits = Limits {
+            max_header_count: 2,
+            ..Limits::default()
+        };
+        let error = parse_field_lines(input, &limits).unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::TOO_MANY_HEADERS);
+
+        limits = Limits {
+            max_header_size: 20,
+            ..Limits::default()
+        };
+        let error = parse_field_lines(input, &limits).unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::HEADER_TOO_LARGE);
+
+        limits = Limits {
+            max_header_bytes: 30,
+            ..Limits::default()
+        };
+        let error = parse_field_lines(input, &limits).unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::HEADERS_TOO_LARGE);
+
+        // an unterminated field line is rejected before its CRLF arrives
+        limits = Limits {
+            max_header_size: 8,
+            ..Limits::default()
+        };
+        let error = parse_field_lines(b"Host: lb\r\nX-Endless: aaaaaa", &limits).unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::HEADER_TOO_LARGE);
+    }
 }
@@ -0,0 +1,399 @@
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind, Read, Write},
+};
+
+use super::{frame::*, hpack};
+
+// The one stream of a connection
+const STREAM: u32 = 1;
+
+// What the server may send ahead of our reading, for the stream and the
+// connection
+const WINDOW: u32 = 1024 * 1024;
+
+// A response from a server spoken to over HTTP/2 with prior knowledge (h2c),
+// as gRPC backends are. Every request has a connection of its own.
+pub struct ClientResponse<S> {
+    pub status: u16,
+    // the pseudo-header fields taken off
+    pub headers: Vec<(String, String)>,
+    pub body: ClientBody<S>,
+}
+
+// The response body, read off the connection frame by frame. The trailers
+// are there once it was read to its end.
+pub struct ClientBody<S> {
+    client: Client<S>,
+    data: Vec<u8>,
+    position: usize,
+    trailers: Vec<(String, String)>,
+    ended: bool,
+}
+
+impl<S> ClientBody<S> {
+    // The connection it is read from
+    pub fn get_ref(&self) -> &S {
+        &self.client.stream
+    }
+
+    pub fn trailers(&self) -> &[(String, String)] {
+        &self.trailers
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended && self.position == self.data.len()
+    }
+}
+
+impl<S: Read + Write> Read for ClientBody<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.position == self.data.len() {
+            if self.ended {
+                return Ok(0);
+            }
+            let frame = self.client.next()?;
+            match frame.kind {
+                DATA => {
+                    self.client.consumed(frame.payload.len())?;
+                    self.data = frame.content()?.to_vec();
+                    self.position = 0;
+                    self.ended = frame.has(END_STREAM);
+                }
+                HEADERS => {
+                    let (fields, end_stream) = self.client.header_block(frame)?;
+                    if !end_stream {
+                        return Err(error(PROTOCOL_ERROR, "trailers without END_STREAM"));
+                    }
+                    self.trailers = fields;
+                    self.ended = true;
+                }
+                RST_STREAM => return Err(reset(&frame)),
+                _ => return Err(error(PROTOCOL_ERROR, "CONTINUATION out of place")),
+            }
+            if self.ended {
+                let _ = self.client.write(&[Frame::goaway(0, NO_ERROR)]);
+            }
+        }
+        let n = buf.len().min(self.data.len() - self.position);
+        buf[..n].copy_from_slice(&self.data[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+struct Client<S> {
+    stream: S,
+    reader: FrameReader,
+    decoder: hpack::Decoder,
+    max_header_bytes: usize,
+    // what we may still send, and the server's SETTINGS
+    connection_window: i64,
+    stream_window: i64,
+    initial: i64,
+    max_frame_size: usize,
+    // response body bytes not given back with a WINDOW_UPDATE yet
+    consumed: u32,
+    // frames of the stream that came while the request body was being sent
+    early: VecDeque<Frame>,
+}
+
+// Sends the request on a new connection and reads the response head, the
+// body is left to be read. `fields` start with the pseudo-header fields, the
+// request `body` is read through as the server's windows allow.
+pub fn send<S: Read + Write>(
+    stream: S,
+    fields: &[(String, String)],
+    body: Option<&mut dyn Read>,
+    max_header_bytes: usize,
+) -> Result<ClientResponse<S>, Error> {
+    let mut client = Client {
+        stream,
+        reader: FrameReader::new(&[]),
+        decoder: hpack::Decoder::default(),
+        max_header_bytes,
+        connection_window: DEFAULT_WINDOW as i64,
+        stream_window: DEFAULT_WINDOW as i64,
+        initial: DEFAULT_WINDOW as i64,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        consumed: 0,
+        early: VecDeque::new(),
+    };
+
+    let block = hpack::encode(fields.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+    let mut frames = vec![
+        Frame::settings(&[(ENABLE_PUSH, 0), (INITIAL_WINDOW_SIZE, WINDOW)]),
+        Frame::window_update(0, WINDOW - DEFAULT_WINDOW),
+    ];
+    frames.extend(header_frames(
+        STREAM,
+        &block,
+        body.is_none(),
+        DEFAULT_MAX_FRAME_SIZE,
+    ));
+    client.stream.write_all(PREFACE)?;
+    client.write(&frames)?;
+    if let Some(body) = body {
+        client.send_body(body)?;
+    }
+    client.response()
+}
+
+fn reset(frame: &Frame) -> Error {
+    let code = frame.u31().unwrap_or(INTERNAL_ERROR);
+    Error::new(
+        ErrorKind::ConnectionReset,
+        format!("Upstream reset the HTTP/2 stream with error code {code:#x}"),
+    )
+}
+
+impl<S: Read + Write> Client<S> {
+    fn write(&mut self, frames: &[Frame]) -> Result<(), Error> {
+        let mut out = Vec::new();
+        for frame in frames {
+            frame.encode(&mut out);
+        }
+        self.stream.write_all(&out)?;
+        self.stream.flush()
+    }
+
+    // DATA as the server's windows allow, an empty frame ends the stream. A
+    // response that ends the stream before the body is through stops it.
+    fn send_body(&mut self, body: &mut dyn Read) -> Result<(), Error> {
+        let mut buf = vec![0u8; DEFAULT_MAX_FRAME_SIZE];
+        loop {
+            let n = body.read(&mut buf)?;
+            if n == 0 {
+                return self.write(&[Frame::new(DATA, END_STREAM, STREAM, [])]);
+            }
+            let mut chunk = &buf[..n];
+            while !chunk.is_empty() {
+                let available = self
+                    .connection_window
+                    .min(self.stream_window)
+                    .min(self.max_frame_size as i64);
+                if available <= 0 {
+                    if let Some(frame) = self.receive()? {
+                        let ends = frame.kind == RST_STREAM || frame.has(END_STREAM);
+                        self.early.push_back(frame);
+                        if ends {
+                            return Ok(());
+                        }
+                    }
+                    continue;
+                }
+                let n = (available as usize).min(chunk.len());
+                self.write(&[Frame::new(DATA, 0, STREAM, &chunk[..n])])?;
+                self.connection_window -= n as i64;
+                self.stream_window -= n as i64;
+                chunk = &chunk[n..];
+            }
+        }
+    }
+
+    // The final response head, interim 1xx responses are skipped
+    fn response(mut self) -> Result<ClientResponse<S>, Error> {
+        loop {
+            let frame = self.next()?;
+            match frame.kind {
+                HEADERS => {
+                    let (fields, end_stream) = self.header_block(frame)?;
+                    let status = fields
+                        .iter()
+                        .find(|(name, _)| name == ":status")
+                        .and_then(|(_, value)| value.parse::<u16>().ok())
+                        .ok_or(error(PROTOCOL_ERROR, "response without :status"))?;
+                    if (100..200).contains(&status) {
+                        continue;
+                    }
+                    let headers = fields
+                        .into_iter()
+                        .filter(|(name, _)| !name.starts_with(':'))
+                        .collect();
+                    let mut body = ClientBody {
+                        client: self,
+                        data: Vec::new(),
+                        position: 0,
+                        trailers: Vec::new(),
+                        ended: end_stream,
+                    };
+                    if end_stream {
+                        let _ = body.client.write(&[Frame::goaway(0, NO_ERROR)]);
+                    }
+                    return Ok(ClientResponse {
+                        status,
+                        headers,
+                        body,
+                    });
+                }
+                RST_STREAM => return Err(reset(&frame)),
+                _ => return Err(error(PROTOCOL_ERROR, "DATA before the response head")),
+            }
+        }
+    }
+
+    // The next frame of the stream, the ones about the connection are handled
+    // on the way
+    fn next(&mut self) -> Result<Frame, Error> {
+        if let Some(frame) = self.early.pop_front() {
+            return Ok(frame);
+        }
+        loop {
+            if let Some(frame) = self.receive()? {
+                return Ok(frame);
+            }
+        }
+    }
+
+    // Reads one frame, None when it was about the connection
+    fn receive(&mut self) -> Result<Option<Frame>, Error> {
+        let frame = self.reader.next(&mut self.stream)?.ok_or(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Upstream closed the HTTP/2 connection",
+        ))?;
+        self.control(frame)
+    }
+
+    fn control(&mut self, frame: Frame) -> Result<Option<Frame>, Error> {
+        match frame.kind {
+            SETTINGS if frame.has(ACK) => {}
+            SETTINGS => {
+                for (id, value) in frame.settings_values()? {
+                    match id {
+                        INITIAL_WINDOW_SIZE if value > MAX_WINDOW => {
+                            return Err(error(FLOW_CONTROL_ERROR, "initial window too large"));
+                        }
+                        INITIAL_WINDOW_SIZE => {
+                            self.stream_window += value as i64 - self.initial;
+                            self.initial = value as i64;
+                        }
+                        MAX_FRAME_SIZE => {
+                            if !(DEFAULT_MAX_FRAME_SIZE..=1 << 24).contains(&(value as usize)) {
+                                return Err(error(
+                                    PROTOCOL_ERROR,
+                                    "invalid SETTINGS_MAX_FRAME_SIZE",
+                                ));
+                            }
+                            self.max_frame_size = value as usize;
+                        }
+                        _ => {}
+                    }
+                }
+                self.write(&[Frame::new(SETTINGS, ACK, 0, [])])?;
+            }
+            PING if !frame.has(ACK) => self.write(&[Frame::new(PING, ACK, 0, frame.payload)])?,
+            WINDOW_UPDATE if frame.stream == 0 => self.connection_window += frame.u31()? as i64,
+            WINDOW_UPDATE if frame.stream == STREAM => self.stream_window += frame.u31()? as i64,
+            GOAWAY if frame.u31()? < STREAM => {
+                return Err(Error::new(
+                    ErrorKind::ConnectionRefused,
+                    "Upstream refused the HTTP/2 stream",
+                ));
+            }
+            PUSH_PROMISE => return Err(error(PROTOCOL_ERROR, "push though disabled")),
+            DATA | HEADERS | CONTINUATION | RST_STREAM if frame.stream == STREAM => {
+                return Ok(Some(frame));
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    // A header block with the CONTINUATION frames it takes, and whether it
+    // ends the stream
+    fn header_block(&mut self, first: Frame) -> Result<(Vec<(String, String)>, bool), Error> {
+        let end_stream = first.has(END_STREAM);
+        let mut complete = first.has(END_HEADERS);
+        let mut block = first.content()?.to_vec();
+        while !complete {
+            let frame = self.next()?;
+            if frame.kind != CONTINUATION {
+                return Err(error(PROTOCOL_ERROR, "header block interrupted"));
+            }
+            complete = frame.has(END_HEADERS);
+            block.extend_from_slice(&frame.payload);
+        }
+        let fields = self.decoder.decode(&block, self.max_header_bytes)?;
+        Ok((fields, end_stream))
+    }
+
+    // Gives the windows back once half was used, so the server keeps sending
+    fn consumed(&mut self, n: usize) -> Result<(), Error> {
+        self.consumed += n as u32;
+        if self.consumed >= WINDOW / 2 {
+            let n = std::mem::take(&mut self.consumed);
+            self.write(&[Frame::window_update(0, n), Frame::window_update(STREAM, n)])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::{
+        config::Config,
+        connection::{ClientStream, ConnectionLimits, ConnectionTracker},
+        http2::{Responder, serve},
+        request::Request,
+        response::{Response, StatusCode},
+    };
+    use std::{
+        io::Cursor,
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    fn handler(request: Result<Request, Error>, responder: &mut Responder) {
+        let request = request.unwrap();
+        let response = Response::builder(StatusCode::OK)
+            .header("Content-Type", "application/grpc")
+            .stream(Cursor::new(request.body))
+            .trailer("grpc-status", "0")
+            .build();
+        let _ = responder.send(response);
+    }
+
+    #[test]
+    fn test_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let stream = ClientStream::from(listener.accept().unwrap().0);
+            let tracker = ConnectionTracker::new(ConnectionLimits::default());
+            let guard = tracker.acquire(None).unwrap();
+            serve(&stream, &[], None, &Config::default(), &guard, &handler);
+        });
+
+        let fields = [
+            (":method", "POST"),
+            (":scheme", "http"),
+            (":path", "/echo.Echo/Say"),
+            (":authority", "example.com"),
+            ("te", "trailers"),
+        ]
+        .map(|(n, v)| (n.to_string(), v.to_string()));
+        // more than the default window, sent as the server opens it
+        let body = vec![7u8; 200_000];
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut response = send(&stream, &fields, Some(&mut &body[..]), 64 * 1024).unwrap();
+        assert_eq!(response.status, 200);
+        assert!(
+            response
+                .headers
+                .contains(&("content-type".to_string(), "application/grpc".to_string()))
+        );
+        assert!(response.body.trailers().is_empty());
+
+        let mut received = Vec::new();
+        response.body.read_to_end(&mut received).unwrap();
+        assert_eq!(received, body);
+        assert!(response.body.is_ended());
+        assert_eq!(
+            response.body.trailers(),
+            [("grpc-status".to_string(), "0".to_string())]
+        );
+        drop(response);
+        server.join().unwrap();
+    }
+}
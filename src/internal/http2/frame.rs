@@ -0,0 +1,321 @@
+use std::{
+    error, fmt,
+    io::{Error, ErrorKind, Read},
+};
+
+// What a client sends first on an HTTP/2 connection, RFC 9113 section 3.4
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const HEADER_LEN: usize = 9;
+
+// Until SETTINGS say otherwise, RFC 9113 section 6.5.2
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024;
+pub const DEFAULT_WINDOW: u32 = 65_535;
+pub const MAX_WINDOW: u32 = (1 << 31) - 1;
+
+// Frame types, RFC 9113 section 6
+pub const DATA: u8 = 0x0;
+pub const HEADERS: u8 = 0x1;
+pub const PRIORITY: u8 = 0x2;
+pub const RST_STREAM: u8 = 0x3;
+pub const SETTINGS: u8 = 0x4;
+pub const PUSH_PROMISE: u8 = 0x5;
+pub const PING: u8 = 0x6;
+pub const GOAWAY: u8 = 0x7;
+pub const WINDOW_UPDATE: u8 = 0x8;
+pub const CONTINUATION: u8 = 0x9;
+
+// Flags, ACK is on SETTINGS and PING only
+pub const END_STREAM: u8 = 0x1;
+pub const ACK: u8 = 0x1;
+pub const END_HEADERS: u8 = 0x4;
+pub const PADDED: u8 = 0x8;
+pub const PRIORITY_FLAG: u8 = 0x20;
+
+// Settings, RFC 9113 section 6.5.2
+pub const ENABLE_PUSH: u16 = 0x2;
+pub const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub const INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub const MAX_FRAME_SIZE: u16 = 0x5;
+pub const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// Error codes, RFC 9113 section 7
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const INTERNAL_ERROR: u32 = 0x2;
+pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const REFUSED_STREAM: u32 = 0x7;
+pub const COMPRESSION_ERROR: u32 = 0x9;
+pub const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// An error that ends the connection with a GOAWAY carrying `code`, or only a
+// stream with a RST_STREAM
+#[derive(Debug)]
+pub struct Http2Error {
+    pub code: u32,
+    pub message: &'static str,
+}
+
+impl fmt::Display for Http2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP/2 error {:#x}: {}", self.code, self.message)
+    }
+}
+
+impl error::Error for Http2Error {}
+
+pub fn error(code: u32, message: &'static str) -> Error {
+    Error::new(ErrorKind::InvalidData, Http2Error { code, message })
+}
+
+// The code to tell the peer, None for errors that are not about the protocol
+// such as a connection that broke
+pub fn error_code(e: &Error) -> Option<u32> {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<Http2Error>())
+        .map(|e| e.code)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: u8, flags: u8, stream: u32, payload: impl Into<Vec<u8>>) -> Frame {
+        Frame {
+            kind,
+            flags,
+            stream,
+            payload: payload.into(),
+        }
+    }
+
+    pub fn settings(values: &[(u16, u32)]) -> Frame {
+        let mut payload = Vec::with_capacity(values.len() * 6);
+        for (id, value) in values {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        Frame::new(SETTINGS, 0, 0, payload)
+    }
+
+    pub fn window_update(stream: u32, increment: u32) -> Frame {
+        Frame::new(WINDOW_UPDATE, 0, stream, increment.to_be_bytes())
+    }
+
+    pub fn rst_stream(stream: u32, code: u32) -> Frame {
+        Frame::new(RST_STREAM, 0, stream, code.to_be_bytes())
+    }
+
+    pub fn goaway(last_stream: u32, code: u32) -> Frame {
+        let mut payload = last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        Frame::new(GOAWAY, 0, 0, payload)
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    // What a 31 bit stream id or increment at the start of the payload says,
+    // the reserved bit ignored
+    pub fn u31(&self) -> Result<u32, Error> {
+        let bytes = self
+            .payload
+            .get(..4)
+            .ok_or(error(FRAME_SIZE_ERROR, "short frame"))?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()) & MAX_WINDOW)
+    }
+
+    // The identifiers and values of a SETTINGS frame
+    pub fn settings_values(&self) -> Result<Vec<(u16, u32)>, Error> {
+        if !self.payload.len().is_multiple_of(6) {
+            return Err(error(FRAME_SIZE_ERROR, "SETTINGS length"));
+        }
+        Ok(self
+            .payload
+            .chunks(6)
+            .map(|s| {
+                let id = u16::from_be_bytes([s[0], s[1]]);
+                (id, u32::from_be_bytes([s[2], s[3], s[4], s[5]]))
+            })
+            .collect())
+    }
+
+    // The payload of DATA or HEADERS without the padding, and for HEADERS
+    // the priority fields
+    pub fn content(&self) -> Result<&[u8], Error> {
+        let mut content = &self.payload[..];
+        let mut padding = 0;
+        if self.has(PADDED) {
+            let (&pad, rest) = content
+                .split_first()
+                .ok_or(error(PROTOCOL_ERROR, "padding length missing"))?;
+            padding = pad as usize;
+            content = rest;
+        }
+        if self.kind == HEADERS && self.has(PRIORITY_FLAG) {
+            content = content
+                .get(5..)
+                .ok_or(error(FRAME_SIZE_ERROR, "priority fields missing"))?;
+        }
+        if padding > content.len() {
+            return Err(error(PROTOCOL_ERROR, "padding longer than the frame"));
+        }
+        Ok(&content[..content.len() - padding])
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.payload.len() as u32).to_be_bytes()[1..]);
+        out.push(self.kind);
+        out.push(self.flags);
+        out.extend_from_slice(&self.stream.to_be_bytes());
+        out.extend_from_slice(&self.payload);
+    }
+}
+
+// A header block as HEADERS and the CONTINUATION frames it takes
+pub fn header_frames(stream: u32, block: &[u8], end_stream: bool, max: usize) -> Vec<Frame> {
+    let mut parts: Vec<&[u8]> = block.chunks(max).collect();
+    // an empty block still needs its HEADERS frame
+    if parts.is_empty() {
+        parts.push(&[]);
+    }
+    let last = parts.len() - 1;
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| {
+            let mut flags = if i == last { END_HEADERS } else { 0 };
+            if i == 0 && end_stream {
+                flags |= END_STREAM;
+            }
+            let kind = if i == 0 { HEADERS } else { CONTINUATION };
+            Frame::new(kind, flags, stream, part)
+        })
+        .collect()
+}
+
+// Reads frames off a connection. What arrived of a frame is kept when a read
+// times out, so the next call goes on where this one stopped.
+#[derive(Debug, Default)]
+pub struct FrameReader {
+    buf: Vec<u8>,
+    // frames larger than our SETTINGS_MAX_FRAME_SIZE are an error
+    max_frame_size: usize,
+}
+
+impl FrameReader {
+    // `pending` is what was read before, the preface already taken off
+    pub fn new(pending: &[u8]) -> FrameReader {
+        FrameReader {
+            buf: pending.to_vec(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    // None once the peer closed the connection between frames
+    pub fn next(&mut self, mut stream: impl Read) -> Result<Option<Frame>, Error> {
+        loop {
+            if let Some(frame) = self.take()? {
+                return Ok(Some(frame));
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            match stream.read(&mut chunk)? {
+                0 if self.buf.is_empty() => return Ok(None),
+                0 => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Connection closed in the middle of a frame",
+                    ));
+                }
+                n => self.buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    fn take(&mut self) -> Result<Option<Frame>, Error> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([0, self.buf[0], self.buf[1], self.buf[2]]) as usize;
+        if len > self.max_frame_size {
+            return Err(error(
+                FRAME_SIZE_ERROR,
+                "frame larger than SETTINGS_MAX_FRAME_SIZE",
+            ));
+        }
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+        let stream = u32::from_be_bytes(self.buf[5..9].try_into().unwrap()) & MAX_WINDOW;
+        let frame = Frame::new(
+            self.buf[3],
+            self.buf[4],
+            stream,
+            &self.buf[HEADER_LEN..HEADER_LEN + len],
+        );
+        self.buf.drain(..HEADER_LEN + len);
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Hands out its data a few bytes at a time, then times out
+    struct Trickle(Vec<u8>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(Error::new(ErrorKind::WouldBlock, "no data"));
+            }
+            let n = buf.len().min(self.0.len()).min(4);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_frame_reader() {
+        let mut data = Vec::new();
+        Frame::settings(&[(MAX_CONCURRENT_STREAMS, 100)]).encode(&mut data);
+        let mut padded = Frame::new(DATA, PADDED | END_STREAM, 3, [2, b'h', b'i', 0, 0]);
+        padded.encode(&mut data);
+        let split = data.len() - 3;
+
+        let mut reader = FrameReader::new(&data[..5]);
+        let mut stream = Trickle(data[5..split].to_vec());
+        let settings = reader.next(&mut stream).unwrap().unwrap();
+        assert_eq!(settings.settings_values().unwrap(), [(3, 100)]);
+
+        // a timeout in the middle of a frame loses nothing
+        let e = reader.next(&mut stream).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+        let frame = reader
+            .next(Trickle(data[split..].to_vec()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.stream, 3);
+        assert_eq!(frame.content().unwrap(), b"hi");
+        assert!(reader.next(&[][..]).unwrap().is_none());
+
+        padded.payload[0] = 9;
+        assert_eq!(
+            error_code(&padded.content().unwrap_err()),
+            Some(PROTOCOL_ERROR)
+        );
+        let mut large = Vec::new();
+        Frame::new(DATA, 0, 1, vec![0; DEFAULT_MAX_FRAME_SIZE + 1]).encode(&mut large);
+        let e = FrameReader::new(&large).next(&[][..]).unwrap_err();
+        assert_eq!(error_code(&e), Some(FRAME_SIZE_ERROR));
+    }
+}
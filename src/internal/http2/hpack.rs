@@ -0,0 +1,409 @@
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+};
+
+use super::huffman;
+use crate::internal::request::ErrorMsg;
+
+// RFC 7541 Appendix A, index 1 is the first entry
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// Per entry on top of the name and value, RFC 7541 section 4.1
+const ENTRY_OVERHEAD: usize = 32;
+
+// SETTINGS_HEADER_TABLE_SIZE until the peer says otherwise
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+fn compression_error(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("HPACK: {msg}"))
+}
+
+// Decodes the header blocks of one direction of a connection, the dynamic
+// table carries over from one block to the next
+#[derive(Debug)]
+pub struct Decoder {
+    // newest first
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    // what the decoder's side allowed in SETTINGS_HEADER_TABLE_SIZE
+    allowed: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new(DEFAULT_TABLE_SIZE)
+    }
+}
+
+impl Decoder {
+    pub fn new(allowed: usize) -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: allowed,
+            allowed,
+        }
+    }
+
+    // The fields of a complete header block in order. A list over
+    // `max_list_size`, counted as SETTINGS_MAX_HEADER_LIST_SIZE does, is
+    // decoded to the end all the same, so that the table stays in step, and
+    // fails with ErrorKind::InvalidInput. Any other error leaves the table
+    // out of step with the peer's, which is a connection error.
+    pub fn decode(
+        &mut self,
+        mut block: &[u8],
+        max_list_size: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        let mut keep = |field: (String, String)| {
+            list_size += field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+            if list_size <= max_list_size {
+                fields.push(field);
+            }
+        };
+        let mut first = true;
+        while let Some(&byte) = block.first() {
+            if byte & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                keep(self.get(index)?);
+            } else if byte & 0xc0 == 0x40 {
+                let field = self.literal(&mut block, 6)?;
+                self.insert(field.clone());
+                keep(field);
+            } else if byte & 0xe0 == 0x20 {
+                // only at the start of a block, RFC 7541 section 4.2
+                if !first {
+                    return Err(compression_error("table size update after a field"));
+                }
+                let size = integer(&mut block, 5)?;
+                if size > self.allowed {
+                    return Err(compression_error("table size over the limit"));
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // without indexing, or never indexed
+                keep(self.literal(&mut block, 4)?);
+            }
+            first = false;
+        }
+        if list_size > max_list_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                ErrorMsg::HEADERS_TOO_LARGE,
+            ));
+        }
+        Ok(fields)
+    }
+
+    fn get(&self, index: usize) -> Result<(String, String), Error> {
+        match index {
+            0 => Err(compression_error("index 0")),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            _ => self
+                .table
+                .get(index - 62)
+                .cloned()
+                .ok_or_else(|| compression_error("index out of the table")),
+        }
+    }
+
+    fn literal(&self, block: &mut &[u8], prefix: u8) -> Result<(String, String), Error> {
+        let name = match integer(block, prefix)? {
+            0 => text(string(block)?),
+            index => self.get(index)?.0,
+        };
+        let value = text(string(block)?);
+        Ok((name, value))
+    }
+
+    fn insert(&mut self, field: (String, String)) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // an entry larger than the table empties it and is not added
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    // Drops the oldest entries until `room` more fits
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+// Field values are bytes, anything but UTF-8 is kept lossily
+fn text(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+// RFC 7541 section 5.1, the value in the low `prefix` bits of the first byte
+// and 7 bits of each byte after it
+fn integer(block: &mut &[u8], prefix: u8) -> Result<usize, Error> {
+    let truncated = || compression_error("truncated integer");
+    let (&first, rest) = block.split_first().ok_or_else(truncated)?;
+    *block = rest;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or_else(truncated)?;
+        *block = rest;
+        // anything past 28 bits is far over every limit
+        if shift > 21 {
+            return Err(compression_error("integer too large"));
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn string(block: &mut &[u8]) -> Result<Vec<u8>, Error> {
+    let huffman = block.first().is_some_and(|b| b & 0x80 != 0);
+    let len = integer(block, 7)?;
+    if len > block.len() {
+        return Err(compression_error("truncated string"));
+    }
+    let (data, rest) = block.split_at(len);
+    *block = rest;
+    match huffman {
+        true => huffman::decode(data),
+        false => Ok(data.to_vec()),
+    }
+}
+
+fn encode_integer(out: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn encode_string(out: &mut Vec<u8>, data: &[u8]) {
+    let coded = huffman::encoded_len(data);
+    if coded < data.len() {
+        encode_integer(out, 0x80, 7, coded);
+        out.extend_from_slice(&huffman::encode(data));
+    } else {
+        encode_integer(out, 0, 7, data.len());
+        out.extend_from_slice(data);
+    }
+}
+
+// Encodes header blocks without a dynamic table: a field is a static table
+// index, or a literal that is not indexed. That keeps nothing to agree on
+// with the peer, whatever table size it allows.
+pub fn encode<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, value) in fields {
+        let exact = STATIC_TABLE.iter().position(|&f| f == (name, value));
+        if let Some(index) = exact {
+            encode_integer(&mut out, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(index) => encode_integer(&mut out, 0, 4, index + 1),
+            None => {
+                out.push(0);
+                encode_string(&mut out, name.as_bytes());
+            }
+        }
+        encode_string(&mut out, value.as_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fields(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_decoder() {
+        // RFC 7541 Appendix C.4, three requests on one connection
+        let mut decoder = Decoder::default();
+        let first = [
+            0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab,
+            0x90, 0xf4, 0xff,
+        ];
+        assert_eq!(
+            decoder.decode(&first, 1024).unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        let second = [
+            0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf,
+        ];
+        assert_eq!(
+            decoder.decode(&second, 1024).unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+        let third = [
+            0x82, 0x87, 0x85, 0xbf, 0x40, 0x88, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xa9, 0x7d, 0x7f,
+            0x89, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xb8, 0xe8, 0xb4, 0xbf,
+        ];
+        assert_eq!(
+            decoder.decode(&third, 1024).unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.size, 164);
+
+        // a size update only opens a block, and stays within the setting
+        assert!(decoder.decode(&[0x82, 0x20], 1024).is_err());
+        assert!(
+            Decoder::default()
+                .decode(&[0x3f, 0xe2, 0x1f], 1024)
+                .is_err()
+        );
+        let mut decoder = Decoder::default();
+        assert!(decoder.decode(&[0x20, 0x82], 1024).is_ok());
+        assert_eq!(decoder.max_size, 0);
+
+        assert!(Decoder::default().decode(&[0x80], 1024).is_err());
+        assert!(Decoder::default().decode(&[0xbe], 1024).is_err());
+        assert!(
+            Decoder::default()
+                .decode(&[0x41, 0x85, 0x61], 1024)
+                .is_err()
+        );
+
+        // the list is too large, the table is updated all the same
+        let mut decoder = Decoder::default();
+        let e = decoder.decode(&first, 100).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert_eq!(decoder.size, 57);
+    }
+
+    #[test]
+    fn test_encoder() {
+        let list = [
+            (":status", "200"),
+            (":status", "302"),
+            ("content-type", "text/html; charset=utf-8"),
+            ("x-request-id", "abc"),
+            ("location", &"/x".repeat(100)),
+        ];
+        let block = encode(list);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(
+            Decoder::default().decode(&block, 4096).unwrap(),
+            fields(&list)
+        );
+
+        let mut out = Vec::new();
+        encode_integer(&mut out, 0, 5, 1337);
+        assert_eq!(out, [0x1f, 0x9a, 0x0a]);
+        assert_eq!(integer(&mut &out[..], 5).unwrap(), 1337);
+    }
+}
@@ -0,0 +1,1220 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Read, Write},
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub mod client;
+mod frame;
+mod hpack;
+mod huffman;
+
+pub use frame::PREFACE;
+
+use crate::internal::{
+    compression,
+    config::Config,
+    connection::{ClientStream, ConnectionGuard},
+    headers::Headers,
+    limits::Limits,
+    request::{self, ErrorMsg, Request, RequestMethod},
+    response::{Response, ResponseBody},
+};
+use crate::{debug, trace};
+use frame::*;
+
+// How often the connection looks at its idle time and at a shutdown
+const TICK: Duration = Duration::from_millis(500);
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// Fields that only mean something on one HTTP/1 connection, RFC 9113 section 8.2.2
+const CONNECTION_SPECIFIC: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const MALFORMED: &str = "malformed request";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Http2Config {
+    // streams a client may have open at once, more are refused
+    pub max_concurrent_streams: u32,
+    // how much of a request body a client may send ahead, per stream and
+    // for the whole connection
+    pub initial_window_size: u32,
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Http2Config {
+            max_concurrent_streams: 100,
+            initial_window_size: 1024 * 1024,
+        }
+    }
+}
+
+// What the server may still send, RFC 9113 section 6.9
+#[derive(Debug)]
+struct Flow {
+    connection: i64,
+    // the streams being answered
+    streams: HashMap<u32, i64>,
+    // the client's SETTINGS_INITIAL_WINDOW_SIZE and SETTINGS_MAX_FRAME_SIZE
+    initial: i64,
+    max_frame_size: usize,
+    // streams whose handler has not returned yet
+    running: usize,
+    closed: bool,
+}
+
+// The state the reading side shares with the threads answering streams
+struct Connection<'a> {
+    stream: &'a ClientStream,
+    guard: &'a ConnectionGuard<'a>,
+    // frames are written whole, one writer at a time
+    writes: Mutex<()>,
+    flow: Mutex<Flow>,
+    flow_changed: Condvar,
+    write_timeout: Duration,
+}
+
+impl Connection<'_> {
+    fn write(&self, frames: &[Frame]) -> Result<u64, Error> {
+        let mut out = Vec::new();
+        for frame in frames {
+            frame.encode(&mut out);
+        }
+        let _writing = self.writes.lock().unwrap();
+        let mut stream = self.stream;
+        stream.write_all(&out)?;
+        stream.flush()?;
+        Ok(out.len() as u64)
+    }
+
+    // Waits until some of `wanted` bytes may be sent on `stream`, and takes
+    // them off both windows
+    fn reserve(&self, stream: u32, wanted: usize) -> Result<usize, Error> {
+        let deadline = Instant::now() + self.write_timeout;
+        let mut flow = self.flow.lock().unwrap();
+        loop {
+            if flow.closed {
+                return Err(Error::new(
+                    ErrorKind::BrokenPipe,
+                    "HTTP/2 connection closed",
+                ));
+            }
+            let max_frame_size = flow.max_frame_size as i64;
+            let connection = flow.connection;
+            let Some(window) = flow.streams.get_mut(&stream) else {
+                return Err(Error::new(
+                    ErrorKind::ConnectionReset,
+                    "Stream reset by the client",
+                ));
+            };
+            let available = (*window).min(connection).min(max_frame_size);
+            if available > 0 {
+                let n = (available as usize).min(wanted);
+                *window -= n as i64;
+                flow.connection -= n as i64;
+                return Ok(n);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Timed out waiting for the client's flow control window",
+                ));
+            }
+            flow = self.flow_changed.wait_timeout(flow, left).unwrap().0;
+        }
+    }
+
+    // A stream starts being answered, the connection is busy while any is
+    fn start(&self, stream: u32) {
+        let mut flow = self.flow.lock().unwrap();
+        let initial = flow.initial;
+        flow.streams.insert(stream, initial);
+        flow.running += 1;
+        if flow.running == 1 {
+            self.guard.busy();
+        }
+    }
+
+    fn finish(&self, stream: u32) {
+        let mut flow = self.flow.lock().unwrap();
+        flow.streams.remove(&stream);
+        flow.running -= 1;
+        if flow.running == 0 {
+            self.guard.idle();
+        }
+    }
+
+    fn running(&self) -> usize {
+        self.flow.lock().unwrap().running
+    }
+
+    fn reset(&self, stream: u32) {
+        self.flow.lock().unwrap().streams.remove(&stream);
+        self.flow_changed.notify_all();
+    }
+
+    fn close(&self) {
+        self.flow.lock().unwrap().closed = true;
+        self.flow_changed.notify_all();
+    }
+
+    // A WINDOW_UPDATE from the client
+    fn add_window(&self, stream: u32, increment: u32) -> Result<(), Error> {
+        let mut flow = self.flow.lock().unwrap();
+        let window = match stream {
+            0 => Some(&mut flow.connection),
+            _ => flow.streams.get_mut(&stream),
+        };
+        if let Some(window) = window {
+            *window += increment as i64;
+            if *window > MAX_WINDOW as i64 {
+                return Err(error(FLOW_CONTROL_ERROR, "window over 2^31-1"));
+            }
+        }
+        self.flow_changed.notify_all();
+        Ok(())
+    }
+
+    fn apply_settings(&self, settings: &[(u16, u32)]) -> Result<(), Error> {
+        let mut flow = self.flow.lock().unwrap();
+        for &(id, value) in settings {
+            match id {
+                ENABLE_PUSH if value > 1 => {
+                    return Err(error(PROTOCOL_ERROR, "SETTINGS_ENABLE_PUSH over 1"));
+                }
+                INITIAL_WINDOW_SIZE => {
+                    if value > MAX_WINDOW {
+                        return Err(error(FLOW_CONTROL_ERROR, "initial window over 2^31-1"));
+                    }
+                    // applies to the streams already open too, section 6.9.2
+                    let delta = value as i64 - flow.initial;
+                    flow.initial = value as i64;
+                    for window in flow.streams.values_mut() {
+                        *window += delta;
+                        if *window > MAX_WINDOW as i64 {
+                            return Err(error(FLOW_CONTROL_ERROR, "window over 2^31-1"));
+                        }
+                    }
+                }
+                MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE as u32..=(1 << 24) - 1).contains(&value) {
+                        return Err(error(PROTOCOL_ERROR, "invalid SETTINGS_MAX_FRAME_SIZE"));
+                    }
+                    flow.max_frame_size = value as usize;
+                }
+                // the encoder keeps no dynamic table, the table size does not matter
+                _ => {}
+            }
+        }
+        self.flow_changed.notify_all();
+        Ok(())
+    }
+
+    // HEADERS and as many CONTINUATION frames as the block needs
+    fn header_frames(&self, stream: u32, block: &[u8], end_stream: bool) -> Vec<Frame> {
+        let max = self.flow.lock().unwrap().max_frame_size;
+        header_frames(stream, block, end_stream, max)
+    }
+}
+
+// Answers the request of one stream, the handler gets it with the request
+pub struct Responder<'a> {
+    connection: &'a Connection<'a>,
+    stream: u32,
+    head: bool,
+    // the request was not read to its end, the client is told to stop sending
+    incomplete: bool,
+    sent: bool,
+    written: u64,
+    // when the request's first frame arrived, and its frames' bytes
+    pub received_at: Instant,
+    pub bytes_received: u64,
+}
+
+impl Responder<'_> {
+    pub fn send(&mut self, response: Response) -> Result<(), Error> {
+        self.sent = true;
+        let result = self.write_response(response);
+        let code = match &result {
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => None,
+            Err(_) => Some(INTERNAL_ERROR),
+            // RFC 9113 section 8.1, a response before the end of the request
+            Ok(()) if self.incomplete => Some(NO_ERROR),
+            Ok(()) => None,
+        };
+        if let Some(code) = code {
+            let _ = self
+                .connection
+                .write(&[Frame::rst_stream(self.stream, code)]);
+        }
+        result
+    }
+
+    // Frame bytes sent for the response
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    fn write(&mut self, frames: &[Frame]) -> Result<(), Error> {
+        self.written += self.connection.write(frames)?;
+        Ok(())
+    }
+
+    fn write_response(&mut self, response: Response) -> Result<(), Error> {
+        let with_body = response.status.allows_body() && !self.head;
+        let length = match &response.body {
+            ResponseBody::Empty => response.header("Content-Length").map(str::to_string),
+            ResponseBody::Bytes(b) => Some(b.len().to_string()),
+            ResponseBody::Fixed(_, len) => Some(len.to_string()),
+            ResponseBody::Stream(_) => None,
+        };
+
+        let status = response.status.as_u16().to_string();
+        let mut fields = vec![(":status".to_string(), status)];
+        for (name, value) in &response.headers {
+            let name = name.to_ascii_lowercase();
+            if !CONNECTION_SPECIFIC.contains(&name.as_str()) && name != "content-length" {
+                fields.push((name, value.clone()));
+            }
+        }
+        if let Some(length) = length.filter(|_| response.status.allows_body()) {
+            fields.push(("content-length".to_string(), length));
+        }
+        let body = match with_body {
+            true => response.body,
+            false => ResponseBody::Empty,
+        };
+        let mut trailers = response.trailers;
+        let ends = matches!(body, ResponseBody::Empty) && trailers.is_empty();
+
+        let block = hpack::encode(fields.iter().map(|(n, v)| (n.as_str(), v.as_str())));
+        let frames = self.connection.header_frames(self.stream, &block, ends);
+        self.write(&frames)?;
+        if ends {
+            return Ok(());
+        }
+
+        match body {
+            ResponseBody::Empty => {}
+            ResponseBody::Bytes(body) => self.data(&body)?,
+            ResponseBody::Fixed(reader, len) => {
+                if self.copy(reader.take(len))? != len {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Response body shorter than its Content-Length",
+                    ));
+                }
+            }
+            ResponseBody::Stream(reader) => {
+                self.copy(reader)?;
+            }
+        }
+        if let Some(late) = response.late_trailers {
+            trailers.extend(late.lock().unwrap().iter().cloned());
+        }
+
+        match trailers.is_empty() {
+            true => self.write(&[Frame::new(DATA, END_STREAM, self.stream, [])]),
+            false => {
+                let fields = trailers
+                    .iter()
+                    .map(|(n, v)| (n.to_ascii_lowercase(), v.as_str()))
+                    .collect::<Vec<_>>();
+                let block = hpack::encode(fields.iter().map(|(n, v)| (n.as_str(), *v)));
+                let frames = self.connection.header_frames(self.stream, &block, true);
+                self.write(&frames)
+            }
+        }
+    }
+
+    fn copy(&mut self, mut reader: impl Read) -> Result<u64, Error> {
+        let mut buf = [0u8; CHUNK_SIZE];
+        let mut copied = 0;
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => return Ok(copied),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.data(&buf[..n])?;
+            copied += n as u64;
+        }
+    }
+
+    // DATA frames as the windows allow
+    fn data(&mut self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            let n = self.connection.reserve(self.stream, data.len())?;
+            self.write(&[Frame::new(DATA, 0, self.stream, &data[..n])])?;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Responder<'_> {
+    // a handler that gave up on the stream
+    fn drop(&mut self) {
+        if !self.sent {
+            let _ = self
+                .connection
+                .write(&[Frame::rst_stream(self.stream, INTERNAL_ERROR)]);
+        }
+    }
+}
+
+// A stream whose request is still coming in
+struct Incoming {
+    request: Request,
+    // what the client may still send on it
+    window: i64,
+    received_at: Instant,
+    bytes_received: u64,
+}
+
+// A header block that continues in CONTINUATION frames
+struct Block {
+    stream: u32,
+    end_stream: bool,
+    data: Vec<u8>,
+    received_at: Instant,
+    bytes_received: u64,
+}
+
+type Handler<'h> = dyn Fn(Result<Request, Error>, &mut Responder) + Sync + 'h;
+
+// Reads frames and hands each complete request to a thread of its own
+struct Reader<'c, 'h> {
+    connection: &'c Connection<'c>,
+    handler: &'h Handler<'h>,
+    config: &'c Config,
+    decoder: hpack::Decoder,
+    incoming: HashMap<u32, Incoming>,
+    block: Option<Block>,
+    // the highest stream the client opened
+    last_stream: u32,
+    // what the client may still send on the connection
+    window: i64,
+    goaway: bool,
+}
+
+// Serves HTTP/2 on `stream` until the client goes, the connection is idle for
+// the keep-alive timeout, or the server stops. `pending` holds what was read
+// before, starting with the preface. With `upgrade`, the request that asked
+// for h2c, it is answered on stream 1 after the 101 response.
+pub fn serve(
+    stream: &ClientStream,
+    pending: &[u8],
+    upgrade: Option<Request>,
+    config: &Config,
+    guard: &ConnectionGuard,
+    handler: &Handler,
+) {
+    let settings = &config.http2;
+    let _ = stream.tcp().set_read_timeout(Some(TICK));
+    // a response is several frames written one after the other
+    let _ = stream.tcp().set_nodelay(true);
+    let connection = Connection {
+        stream,
+        guard,
+        writes: Mutex::new(()),
+        flow: Mutex::new(Flow {
+            connection: DEFAULT_WINDOW as i64,
+            streams: HashMap::new(),
+            initial: DEFAULT_WINDOW as i64,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            running: 0,
+            closed: false,
+        }),
+        flow_changed: Condvar::new(),
+        write_timeout: config.timeouts.write,
+    };
+    let window = settings
+        .initial_window_size
+        .clamp(DEFAULT_WINDOW, MAX_WINDOW);
+    let mut reader = Reader {
+        connection: &connection,
+        handler,
+        config,
+        decoder: hpack::Decoder::default(),
+        incoming: HashMap::new(),
+        block: None,
+        last_stream: 0,
+        window: window as i64,
+        goaway: false,
+    };
+
+    thread::scope(|scope| {
+        if let Err(e) = reader.run(scope, pending, upgrade, window) {
+            debug!("HTTP/2 connection ended: {e}");
+            if let Some(code) = error_code(&e) {
+                let _ = connection.write(&[Frame::goaway(reader.last_stream, code)]);
+            }
+        }
+        // what is still being answered cannot be sent any more
+        connection.close();
+    });
+}
+
+fn is_timeout(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+impl<'c, 'h> Reader<'c, 'h> {
+    fn run<'s>(
+        &mut self,
+        scope: &'s thread::Scope<'s, '_>,
+        pending: &[u8],
+        upgrade: Option<Request>,
+        window: u32,
+    ) -> Result<(), Error>
+    where
+        'c: 's,
+        'h: 's,
+    {
+        let settings = &self.config.http2;
+        let rest = read_preface(self.connection.stream, pending, self.config)?;
+        let mut frames = FrameReader::new(&rest);
+
+        let mut ours = vec![
+            (MAX_CONCURRENT_STREAMS, settings.max_concurrent_streams),
+            (INITIAL_WINDOW_SIZE, window),
+        ];
+        let limits = &self.config.limits;
+        ours.push((MAX_HEADER_LIST_SIZE, limits.max_header_bytes as u32));
+        let mut first = vec![Frame::settings(&ours)];
+        if window > DEFAULT_WINDOW {
+            first.push(Frame::window_update(0, window - DEFAULT_WINDOW));
+        }
+        self.connection.write(&first)?;
+
+        if let Some(request) = upgrade {
+            self.last_stream = 1;
+            let settings = request
+                .headers
+                .as_ref()
+                .and_then(|h| h.get("HTTP2-Settings"))
+                .and_then(base64url)
+                .map(|payload| Frame::new(SETTINGS, 0, 0, payload))
+                .ok_or(error(PROTOCOL_ERROR, "invalid HTTP2-Settings"))?;
+            self.connection
+                .apply_settings(&settings.settings_values()?)?;
+            let request = upgraded_request(request);
+            self.dispatch(scope, 1, Ok(request), Instant::now(), 0, false);
+        }
+
+        let keep_alive = self.config.timeouts.keep_alive;
+        let mut last_frame = Instant::now();
+        let mut settings_seen = false;
+        loop {
+            let quiet = self.incoming.is_empty() && self.connection.running() == 0;
+            if self.connection.guard.is_stopping() && !self.goaway {
+                self.connection
+                    .write(&[Frame::goaway(self.last_stream, NO_ERROR)])?;
+                self.goaway = true;
+            }
+            if self.goaway && quiet {
+                return Ok(());
+            }
+
+            let frame = match frames.next(self.connection.stream) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(e) if is_timeout(&e) => {
+                    if quiet && self.block.is_none() && last_frame.elapsed() >= keep_alive {
+                        self.connection
+                            .write(&[Frame::goaway(self.last_stream, NO_ERROR)])?;
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            last_frame = Instant::now();
+            trace!(
+                "HTTP/2 frame type {} flags {:#x} on stream {}, {} bytes",
+                frame.kind,
+                frame.flags,
+                frame.stream,
+                frame.payload.len()
+            );
+
+            // the preface ends with the client's SETTINGS, section 3.4
+            if !settings_seen && frame.kind != SETTINGS {
+                return Err(error(PROTOCOL_ERROR, "preface without SETTINGS"));
+            }
+            settings_seen = true;
+            if self
+                .block
+                .as_ref()
+                .is_some_and(|b| frame.kind != CONTINUATION || frame.stream != b.stream)
+            {
+                return Err(error(PROTOCOL_ERROR, "header block interrupted"));
+            }
+            self.frame(scope, frame)?;
+        }
+    }
+
+    fn frame<'s>(&mut self, scope: &'s thread::Scope<'s, '_>, frame: Frame) -> Result<(), Error>
+    where
+        'c: 's,
+        'h: 's,
+    {
+        let connection = self.connection;
+        match frame.kind {
+            DATA => self.data(scope, frame)?,
+            HEADERS => {
+                if frame.stream == 0 || frame.stream.is_multiple_of(2) {
+                    return Err(error(PROTOCOL_ERROR, "HEADERS on an invalid stream"));
+                }
+                let block = Block {
+                    stream: frame.stream,
+                    end_stream: frame.has(END_STREAM),
+                    data: frame.content()?.to_vec(),
+                    received_at: Instant::now(),
+                    bytes_received: 9 + frame.payload.len() as u64,
+                };
+                self.block = Some(block);
+                if frame.has(END_HEADERS) {
+                    self.headers(scope)?;
+                }
+            }
+            CONTINUATION => {
+                let block = self
+                    .block
+                    .as_mut()
+                    .ok_or(error(PROTOCOL_ERROR, "CONTINUATION without HEADERS"))?;
+                block.data.extend_from_slice(&frame.payload);
+                block.bytes_received += 9 + frame.payload.len() as u64;
+                if block.data.len() > self.config.limits.max_header_bytes {
+                    return Err(error(ENHANCE_YOUR_CALM, "header block too large"));
+                }
+                if frame.has(END_HEADERS) {
+                    self.headers(scope)?;
+                }
+            }
+            PRIORITY => {
+                if frame.stream == 0 {
+                    return Err(error(PROTOCOL_ERROR, "PRIORITY on stream 0"));
+                }
+                if frame.payload.len() != 5 {
+                    connection.write(&[Frame::rst_stream(frame.stream, FRAME_SIZE_ERROR)])?;
+                }
+            }
+            RST_STREAM => {
+                if frame.stream == 0 || frame.stream > self.last_stream {
+                    return Err(error(PROTOCOL_ERROR, "RST_STREAM on an idle stream"));
+                }
+                if frame.payload.len() != 4 {
+                    return Err(error(FRAME_SIZE_ERROR, "RST_STREAM length"));
+                }
+                self.incoming.remove(&frame.stream);
+                connection.reset(frame.stream);
+            }
+            SETTINGS => {
+                if frame.stream != 0 {
+                    return Err(error(PROTOCOL_ERROR, "SETTINGS on a stream"));
+                }
+                if frame.has(ACK) {
+                    if !frame.payload.is_empty() {
+                        return Err(error(FRAME_SIZE_ERROR, "SETTINGS ack with a payload"));
+                    }
+                    return Ok(());
+                }
+                connection.apply_settings(&frame.settings_values()?)?;
+                connection.write(&[Frame::new(SETTINGS, ACK, 0, [])])?;
+            }
+            PUSH_PROMISE => return Err(error(PROTOCOL_ERROR, "PUSH_PROMISE from a client")),
+            PING => {
+                if frame.stream != 0 {
+                    return Err(error(PROTOCOL_ERROR, "PING on a stream"));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(error(FRAME_SIZE_ERROR, "PING length"));
+                }
+                if !frame.has(ACK) {
+                    connection.write(&[Frame::new(PING, ACK, 0, frame.payload)])?;
+                }
+            }
+            GOAWAY => {
+                if frame.stream != 0 {
+                    return Err(error(PROTOCOL_ERROR, "GOAWAY on a stream"));
+                }
+                // the streams it opened are answered, then the connection closes
+                self.goaway = true;
+            }
+            WINDOW_UPDATE => {
+                if frame.payload.len() != 4 {
+                    return Err(error(FRAME_SIZE_ERROR, "WINDOW_UPDATE length"));
+                }
+                let increment = frame.u31()?;
+                match (increment, frame.stream) {
+                    (0, 0) => return Err(error(PROTOCOL_ERROR, "WINDOW_UPDATE of 0")),
+                    (0, stream) => {
+                        self.incoming.remove(&stream);
+                        connection.reset(stream);
+                        connection.write(&[Frame::rst_stream(stream, PROTOCOL_ERROR)])?;
+                    }
+                    (increment, stream) => {
+                        if let Err(e) = connection.add_window(stream, increment) {
+                            if stream == 0 {
+                                return Err(e);
+                            }
+                            connection.reset(stream);
+                            connection.write(&[Frame::rst_stream(stream, FLOW_CONTROL_ERROR)])?;
+                        }
+                    }
+                }
+            }
+            // unknown types are ignored, section 5.5
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn data<'s>(&mut self, scope: &'s thread::Scope<'s, '_>, frame: Frame) -> Result<(), Error>
+    where
+        'c: 's,
+        'h: 's,
+    {
+        let connection = self.connection;
+        let id = frame.stream;
+        if id == 0 || id > self.last_stream {
+            return Err(error(PROTOCOL_ERROR, "DATA on an idle stream"));
+        }
+        let len = frame.payload.len() as i64;
+        self.window -= len;
+        if self.window < 0 {
+            return Err(error(
+                FLOW_CONTROL_ERROR,
+                "DATA beyond the connection window",
+            ));
+        }
+        // the body is kept in memory, what came in can be sent again at once
+        let full = self
+            .config
+            .http2
+            .initial_window_size
+            .clamp(DEFAULT_WINDOW, MAX_WINDOW);
+        if self.window < full as i64 / 2 {
+            connection.write(&[Frame::window_update(0, full - self.window as u32)])?;
+            self.window = full as i64;
+        }
+
+        // frames on a stream that was reset or answered are ignored
+        let Some(incoming) = self.incoming.get_mut(&id) else {
+            return Ok(());
+        };
+        incoming.window -= len;
+        incoming.bytes_received += 9 + len as u64;
+        if incoming.window < 0 {
+            self.incoming.remove(&id);
+            connection.write(&[Frame::rst_stream(id, FLOW_CONTROL_ERROR)])?;
+            return Ok(());
+        }
+        let content = match frame.content() {
+            Ok(content) => content,
+            Err(_) => {
+                self.incoming.remove(&id);
+                connection.write(&[Frame::rst_stream(id, PROTOCOL_ERROR)])?;
+                return Ok(());
+            }
+        };
+        incoming.request.body.extend_from_slice(content);
+
+        let end_stream = frame.has(END_STREAM);
+        if incoming.request.body.len() > self.config.limits.max_body_size {
+            let incoming = self.incoming.remove(&id).unwrap();
+            let too_large = Error::new(ErrorKind::InvalidData, ErrorMsg::BODY_TOO_LARGE);
+            let (received_at, bytes) = (incoming.received_at, incoming.bytes_received);
+            self.dispatch(scope, id, Err(too_large), received_at, bytes, !end_stream);
+        } else if end_stream {
+            let incoming = self.incoming.remove(&id).unwrap();
+            let (received_at, bytes) = (incoming.received_at, incoming.bytes_received);
+            self.dispatch(scope, id, Ok(incoming.request), received_at, bytes, false);
+        } else if incoming.window < full as i64 / 2 {
+            let increment = full - incoming.window as u32;
+            incoming.window = full as i64;
+            connection.write(&[Frame::window_update(id, increment)])?;
+        }
+        Ok(())
+    }
+
+    // A header block is complete: a new request, or the trailers of one
+    fn headers<'s>(&mut self, scope: &'s thread::Scope<'s, '_>) -> Result<(), Error>
+    where
+        'c: 's,
+        'h: 's,
+    {
+        let block = self.block.take().unwrap();
+        let id = block.stream;
+        let limits = &self.config.limits;
+        // decoded whatever becomes of the stream, to keep the table in step
+        let fields = match self.decoder.decode(&block.data, limits.max_header_bytes) {
+            Err(e) if e.kind() == ErrorKind::InvalidInput => Err(e),
+            Err(e) => {
+                debug!("HTTP/2 header block on stream {id}: {e}");
+                return Err(error(COMPRESSION_ERROR, "header block cannot be decoded"));
+            }
+            Ok(fields) => Ok(fields),
+        };
+
+        if let Some(mut incoming) = self.incoming.remove(&id) {
+            incoming.bytes_received += block.bytes_received;
+            let (received_at, bytes) = (incoming.received_at, incoming.bytes_received);
+            let trailers = fields.and_then(|f| trailers(f, limits));
+            let result = match trailers {
+                Ok(_) if !block.end_stream => {
+                    Err(error(PROTOCOL_ERROR, "trailers without END_STREAM"))
+                }
+                Ok(trailers) => {
+                    incoming.request.trailers = Some(trailers);
+                    Ok(incoming.request)
+                }
+                Err(e) => Err(e),
+            };
+            return self.answer(scope, id, result, received_at, bytes, false);
+        }
+        if id <= self.last_stream {
+            // a stream that is closed, or one that was refused
+            return Ok(());
+        }
+        self.last_stream = id;
+        if self.goaway {
+            return Ok(());
+        }
+
+        let open = self.incoming.len() + self.connection.running();
+        if open >= self.config.http2.max_concurrent_streams as usize {
+            debug!("HTTP/2 stream {id} refused, {open} streams open");
+            self.connection
+                .write(&[Frame::rst_stream(id, REFUSED_STREAM)])?;
+            return Ok(());
+        }
+
+        let request = fields.and_then(|f| request_from_fields(f, limits));
+        let request = request.map(|mut request| {
+            request.peer_addr = self.connection.stream.peer_addr();
+            request
+        });
+        match request {
+            Ok(request) if !block.end_stream => {
+                // the body is taken as it comes, the client need not wait
+                if request.expects_continue() {
+                    let block = hpack::encode([(":status", "100")]);
+                    let frames = self.connection.header_frames(id, &block, false);
+                    self.connection.write(&frames)?;
+                }
+                let window = self
+                    .config
+                    .http2
+                    .initial_window_size
+                    .clamp(DEFAULT_WINDOW, MAX_WINDOW);
+                self.incoming.insert(
+                    id,
+                    Incoming {
+                        request,
+                        window: window as i64,
+                        received_at: block.received_at,
+                        bytes_received: block.bytes_received,
+                    },
+                );
+                Ok(())
+            }
+            result => self.answer(
+                scope,
+                id,
+                result,
+                block.received_at,
+                block.bytes_received,
+                !block.end_stream,
+            ),
+        }
+    }
+
+    // A malformed request gets a RST_STREAM, any other request, or error, is
+    // for the handler
+    fn answer<'s>(
+        &mut self,
+        scope: &'s thread::Scope<'s, '_>,
+        id: u32,
+        result: Result<Request, Error>,
+        received_at: Instant,
+        bytes_received: u64,
+        incomplete: bool,
+    ) -> Result<(), Error>
+    where
+        'c: 's,
+        'h: 's,
+    {
+        if let Err(e) = &result
+            && let Some(code) = error_code(e)
+        {
+            debug!("HTTP/2 stream {id}: {e}");
+            self.connection.write(&[Frame::rst_stream(id, code)])?;
+            return Ok(());
+        }
+        self.dispatch(scope, id, result, received_at, bytes_received, incomplete);
+        Ok(())
+    }
+
+    fn dispatch<'s>(
+        &mut self,
+        scope: &'s thread::Scope<'s, '_>,
+        id: u32,
+        result: Result<Request, Error>,
+        received_at: Instant,
+        bytes_received: u64,
+        incomplete: bool,
+    ) where
+        'c: 's,
+        'h: 's,
+    {
+        let connection = self.connection;
+        let handler = self.handler;
+        let limits = self.config.limits.clone();
+        let decompress = self.config.compression.decompress_requests;
+        connection.start(id);
+        scope.spawn(move || {
+            let head = result
+                .as_ref()
+                .is_ok_and(|r| r.method == Some(RequestMethod::Head));
+            let result = result.and_then(|r| finish_request(r, &limits, decompress));
+            let mut responder = Responder {
+                connection,
+                stream: id,
+                head,
+                incomplete,
+                sent: false,
+                written: 0,
+                received_at,
+                bytes_received,
+            };
+            match &result {
+                Err(e) if let Some(code) = error_code(e) => {
+                    debug!("HTTP/2 stream {id}: {e}");
+                    responder.sent = true;
+                    let _ = connection.write(&[Frame::rst_stream(id, code)]);
+                }
+                _ => handler(result, &mut responder),
+            }
+            drop(responder);
+            connection.finish(id);
+        });
+    }
+}
+
+// The preface, read to its end when only part of it came with `pending`
+fn read_preface(stream: &ClientStream, pending: &[u8], config: &Config) -> Result<Vec<u8>, Error> {
+    let mut received = pending.to_vec();
+    let deadline = Instant::now() + config.timeouts.request_line;
+    let mut buf = [0u8; 1024];
+    while received.len() < PREFACE.len() && PREFACE.starts_with(&received) {
+        if Instant::now() >= deadline {
+            return Err(Error::new(ErrorKind::TimedOut, ErrorMsg::REQUEST_TIMEOUT));
+        }
+        match (&*stream).read(&mut buf) {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "no HTTP/2 preface")),
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if !received.starts_with(PREFACE) {
+        return Err(error(PROTOCOL_ERROR, "invalid preface"));
+    }
+    Ok(received.split_off(PREFACE.len()))
+}
+
+// RFC 9113 section 8.3.1: the pseudo-header fields come first and once
+// each, field names are lowercase, and no field is connection-specific
+fn request_from_fields(fields: Vec<(String, String)>, limits: &Limits) -> Result<Request, Error> {
+    let malformed = || error(PROTOCOL_ERROR, MALFORMED);
+    let mut request = Request::new();
+    let mut headers = Headers::default();
+    let (mut scheme, mut regular, mut count) = (false, false, 0);
+    for (name, value) in fields {
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if regular {
+                return Err(malformed());
+            }
+            let slot = match pseudo {
+                "method" => request.method.is_some(),
+                "path" => request.path.is_some(),
+                "scheme" => std::mem::replace(&mut scheme, true),
+                "authority" => headers.get("host").is_some(),
+                _ => return Err(malformed()),
+            };
+            if slot {
+                return Err(malformed());
+            }
+            match pseudo {
+                "method" => {
+                    let method = value.parse::<RequestMethod>().map_err(|_| {
+                        Error::new(ErrorKind::Unsupported, ErrorMsg::UNSUPPORTED_METHOD)
+                    })?;
+                    request.method = Some(method);
+                }
+                "path" if value.is_empty() => return Err(malformed()),
+                "path" if value.len() > limits.max_request_line => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        ErrorMsg::REQUEST_LINE_TOO_LONG,
+                    ));
+                }
+                "path" => request.path = Some(value),
+                "authority" => headers.insert("host", &value),
+                _ => {}
+            }
+            continue;
+        }
+        regular = true;
+        check_field(&name, &value, limits, &mut count)?;
+        // :authority wins over a host field
+        if name == "host" && headers.get("host").is_some() {
+            continue;
+        }
+        add_field(&mut headers, &name, &value);
+    }
+    if request.method.is_none() || request.path.is_none() || !scheme {
+        return Err(malformed());
+    }
+    request::check_expectation(&headers)?;
+    request.version = Some("HTTP/2.0".to_string());
+    request.headers = Some(headers);
+    Ok(request)
+}
+
+fn trailers(fields: Vec<(String, String)>, limits: &Limits) -> Result<Headers, Error> {
+    let mut trailers = Headers::default();
+    let mut count = 0;
+    for (name, value) in fields {
+        if name.starts_with(':') {
+            return Err(error(PROTOCOL_ERROR, MALFORMED));
+        }
+        check_field(&name, &value, limits, &mut count)?;
+        add_field(&mut trailers, &name, &value);
+    }
+    Ok(trailers)
+}
+
+fn check_field(name: &str, value: &str, limits: &Limits, count: &mut usize) -> Result<(), Error> {
+    let malformed = || error(PROTOCOL_ERROR, MALFORMED);
+    if name.is_empty() || name.bytes().any(|b| b.is_ascii_uppercase()) {
+        return Err(malformed());
+    }
+    if CONNECTION_SPECIFIC.contains(&name) || (name == "te" && value != "trailers") {
+        return Err(malformed());
+    }
+    if value.bytes().any(|b| matches!(b, b'\0' | b'\r' | b'\n')) {
+        return Err(malformed());
+    }
+    *count += 1;
+    if *count > limits.max_header_count {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            ErrorMsg::TOO_MANY_HEADERS,
+        ));
+    }
+    if name.len() + value.len() + 2 > limits.max_header_size {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            ErrorMsg::HEADER_TOO_LARGE,
+        ));
+    }
+    Ok(())
+}
+
+// Repeated fields are combined as HTTP/1 would have them, cookies with "; ",
+// RFC 9113 section 8.2.3
+fn add_field(headers: &mut Headers, name: &str, value: &str) {
+    let combined = match (headers.get(name), name) {
+        (Some(previous), "cookie") => format!("{previous}; {value}"),
+        (Some(previous), _) => format!("{previous}, {value}"),
+        (None, _) => value.to_string(),
+    };
+    headers.insert(name, &combined);
+}
+
+// Checks the body against content-length and decodes it as HTTP/1 bodies are
+fn finish_request(
+    mut request: Request,
+    limits: &Limits,
+    decompress: bool,
+) -> Result<Request, Error> {
+    let Some(headers) = request.headers.as_mut() else {
+        return Ok(request);
+    };
+    if let Some(length) = headers.get("content-length")
+        && length.trim().parse::<usize>().ok() != Some(request.body.len())
+    {
+        return Err(error(PROTOCOL_ERROR, "body does not match content-length"));
+    }
+    if decompress && headers.get("content-encoding").is_some() {
+        let mut decoded = Vec::new();
+        compression::decode_request_body(headers, &request.body[..], limits.max_body_size)?
+            .read_to_end(&mut decoded)?;
+        headers.remove("content-encoding");
+        headers.insert("content-length", &decoded.len().to_string());
+        request.body = decoded;
+    }
+    Ok(request)
+}
+
+// The request that asked for h2c, as stream 1 has it
+fn upgraded_request(mut request: Request) -> Request {
+    if let Some(headers) = request.headers.as_mut() {
+        for name in ["connection", "upgrade", "http2-settings", "keep-alive"] {
+            headers.remove(name);
+        }
+    }
+    request.version = Some("HTTP/2.0".to_string());
+    request
+}
+
+// A plain request asking to switch to h2c, RFC 7540 section 3.2
+pub fn is_upgrade(request: &Request) -> bool {
+    let Some(headers) = request.headers.as_ref() else {
+        return false;
+    };
+    let has = |field: &str, token: &str| {
+        headers
+            .get(field)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    request.version.as_deref() == Some("HTTP/1.1")
+        && has("Upgrade", "h2c")
+        && has("Connection", "upgrade")
+        && has("Connection", "http2-settings")
+        && headers.get("HTTP2-Settings").is_some()
+}
+
+// The 101 that switches an h2c upgrade over
+pub const SWITCHING: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+// base64url without padding, as HTTP2-Settings is encoded
+fn base64url(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for byte in text.trim().trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6 | value as u32) & 0xfff;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::{
+        connection::{ConnectionLimits, ConnectionTracker},
+        response::StatusCode,
+    };
+    use std::net::{TcpListener, TcpStream};
+
+    fn handler(request: Result<Request, Error>, responder: &mut Responder) {
+        let response = match request {
+            Ok(request) => Response::builder(StatusCode::OK)
+                .header("Connection", "keep-alive")
+                .body(format!(
+                    "{} {}",
+                    request.path.unwrap_or_default(),
+                    request.body.len()
+                ))
+                .build(),
+            Err(_) => Response::new(StatusCode::BAD_REQUEST),
+        };
+        let _ = responder.send(response);
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let stream = ClientStream::from(listener.accept().unwrap().0);
+            let tracker = ConnectionTracker::new(ConnectionLimits::default());
+            let guard = tracker.acquire(None).unwrap();
+            serve(&stream, &[], None, &Config::default(), &guard, &handler);
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let request = hpack::encode([
+            (":method", "POST"),
+            (":scheme", "http"),
+            (":path", "/upload"),
+            (":authority", "example.com"),
+        ]);
+        let uppercase = hpack::encode([
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/"),
+            ("X-Upper", "1"),
+        ]);
+        let mut out = PREFACE.to_vec();
+        Frame::settings(&[]).encode(&mut out);
+        Frame::new(HEADERS, END_HEADERS, 1, request).encode(&mut out);
+        Frame::new(DATA, 0, 1, "hello ").encode(&mut out);
+        Frame::new(DATA, END_STREAM, 1, "world").encode(&mut out);
+        Frame::new(HEADERS, END_HEADERS | END_STREAM, 3, uppercase).encode(&mut out);
+        client.write_all(&out).unwrap();
+
+        let mut reader = FrameReader::new(&[]);
+        let mut decoder = hpack::Decoder::default();
+        let mut fields = Vec::new();
+        let mut body = Vec::new();
+        let mut reset = None;
+        while reset.is_none() || body.len() < 10 {
+            let frame = reader.next(&client).unwrap().unwrap();
+            match (frame.kind, frame.stream) {
+                (SETTINGS, 0) if !frame.has(ACK) => {
+                    assert!(
+                        frame
+                            .settings_values()
+                            .unwrap()
+                            .contains(&(MAX_CONCURRENT_STREAMS, 100))
+                    );
+                }
+                (HEADERS, 1) => fields = decoder.decode(&frame.payload, 4096).unwrap(),
+                (DATA, 1) => body.extend_from_slice(&frame.payload),
+                (RST_STREAM, 3) => reset = Some(frame.u31().unwrap()),
+                _ => {}
+            }
+        }
+        assert_eq!(fields[0], (":status".to_string(), "200".to_string()));
+        assert!(fields.iter().all(|(name, _)| name != "connection"));
+        assert!(fields.contains(&("content-length".to_string(), "10".to_string())));
+        assert_eq!(body, b"/upload 11");
+        assert_eq!(reset, Some(PROTOCOL_ERROR));
+
+        // the connection ends with the client
+        drop(client);
+        server.join().unwrap();
+    }
+}
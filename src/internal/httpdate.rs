@@ -0,0 +1,160 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// HTTP-date handling, RFC 9110 section 5.6.7. Dates are always sent as
+// IMF-fixdate but the two obsolete formats still have to be accepted.
+//
+// IMF-fixdate  = Sun, 06 Nov 1994 08:49:37 GMT
+// rfc850-date  = Sunday, 06-Nov-94 08:49:37 GMT
+// asctime-date = Sun Nov  6 08:49:37 1994
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub fn format(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    let rem = secs % 86400;
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        // 1970-01-01 was a Thursday
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+// 1994-11-06T08:49:37.000Z, for logs
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    format!(
+        "{year}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
+
+// 06/Nov/1994:08:49:37 +0000, the Common Log Format timestamp
+pub fn format_clf(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[(month - 1) as usize],
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+pub fn parse(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let parts: Vec<&str> = value.split_whitespace().collect();
+
+    let (day, month, year, time) = match parts.as_slice() {
+        // IMF-fixdate
+        [_, day, month, year, time, "GMT"] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        // rfc850-date, two digit years are taken as 19xx or 20xx, whichever is
+        // not more than 50 years in the future (RFC 9110 section 5.6.7)
+        [_, date, time, "GMT"] => {
+            let mut d = date.split('-');
+            let (day, month, year) = (d.next()?, d.next()?, d.next()?);
+            let year = year.parse::<i64>().ok()?;
+            let year = if year < 70 { 2000 + year } else { 1900 + year };
+            (day, month, year, *time)
+        }
+        // asctime-date
+        [_, month, day, time, year] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        _ => return None,
+    };
+
+    let day = day.parse::<u32>().ok().filter(|d| (1..=31).contains(d))?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+
+    let mut t = time.split(':');
+    let hour = t.next()?.parse::<u64>().ok().filter(|h| *h < 24)?;
+    let minute = t.next()?.parse::<u64>().ok().filter(|m| *m < 60)?;
+    // 60 for leap seconds
+    let second = t.next()?.parse::<u64>().ok().filter(|s| *s <= 60)?;
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+
+    let secs = days as u64 * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Howard Hinnant's days_from_civil / civil_from_days
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(format(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), Some(time));
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), Some(time));
+
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1709164800);
+        assert_eq!(format(leap_day), "Thu, 29 Feb 2024 00:00:00 GMT");
+        assert_eq!(parse(&format(leap_day)), Some(leap_day));
+
+        assert_eq!(parse("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse("yesterday"), None);
+
+        assert_eq!(format_rfc3339(time), "1994-11-06T08:49:37.000Z");
+        assert_eq!(format_clf(time), "06/Nov/1994:08:49:37 +0000");
+    }
+}
@@ -0,0 +1,26 @@
+// Size limits enforced while parsing a message. Every limit maps to its own
+// `ErrorMsg` so callers can tell which one was hit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    // request-line (status-line for responses), without the CRLF
+    pub max_request_line: usize,
+    // whole field section, including the empty line that ends it
+    pub max_header_bytes: usize,
+    pub max_header_count: usize,
+    // a single field line, without the CRLF
+    pub max_header_size: usize,
+    // decoded message body
+    pub max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_request_line: 8 * 1024,
+            max_header_bytes: 64 * 1024,
+            max_header_count: 100,
+            max_header_size: 8 * 1024,
+            max_body_size: 10 * 1024 * 1024,
+        }
+    }
+}
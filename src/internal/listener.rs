@@ -0,0 +1,163 @@
+use std::{
+    io::{Error, ErrorKind},
+    net::{Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::internal::tls::{TlsAcceptor, TlsConfig};
+
+// Pending connections the kernel queues for each socket
+const BACKLOG: i32 = 1024;
+
+// Where and how a listener accepts connections
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    // accept threads, each on its own socket sharing the port with SO_REUSEPORT
+    pub accept_threads: usize,
+    pub tls: Option<TlsConfig>,
+    // HTTP/2 besides HTTP/1.1: h2 over ALPN with TLS, h2c otherwise
+    pub http2: bool,
+}
+
+impl ListenerConfig {
+    pub fn new(address: SocketAddr) -> Self {
+        ListenerConfig {
+            address,
+            accept_threads: 1,
+            tls: None,
+            http2: false,
+        }
+    }
+}
+
+// A port alone, `443` or `:443`, is every address, IPv4 and IPv6. Anything
+// else is resolved and the first address taken.
+pub fn parse_address(address: &str) -> Result<SocketAddr, Error> {
+    if let Ok(port) = address.trim_start_matches(':').parse::<u16>() {
+        return Ok(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)));
+    }
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{address} does not resolve to an address"),
+        )
+    })
+}
+
+// The sockets of a listener, bound and listening
+#[derive(Debug)]
+pub struct Listener {
+    config: ListenerConfig,
+    sockets: Vec<TcpListener>,
+    tls: Option<TlsAcceptor>,
+}
+
+impl Listener {
+    // Fails when the address is taken, or the certificate cannot be loaded
+    pub fn bind(config: ListenerConfig) -> Result<Listener, Error> {
+        let tls = config
+            .tls
+            .as_ref()
+            .map(|tls| TlsAcceptor::new(tls, config.http2))
+            .transpose()?;
+        let mut address = config.address;
+        let mut sockets = Vec::new();
+        for _ in 0..config.accept_threads.max(1) {
+            let socket = bind(address, config.accept_threads > 1)?;
+            // port 0 is picked once, the other sockets share it
+            address = socket.local_addr()?;
+            sockets.push(socket);
+        }
+        Ok(Listener {
+            config,
+            sockets,
+            tls,
+        })
+    }
+
+    pub fn config(&self) -> &ListenerConfig {
+        &self.config
+    }
+
+    pub fn sockets(&self) -> &[TcpListener] {
+        &self.sockets
+    }
+
+    pub fn tls(&self) -> Option<&TlsAcceptor> {
+        self.tls.as_ref()
+    }
+
+    // With port 0 the one the system picked
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.sockets[0].local_addr()
+    }
+}
+
+fn bind(address: SocketAddr, reuse_port: bool) -> Result<TcpListener, Error> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    // an IPv6 socket takes IPv4 connections too, whatever the system default
+    if address.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpStream};
+
+    #[test]
+    fn test_parse_address() {
+        let any = SocketAddr::from((Ipv6Addr::UNSPECIFIED, 443));
+        assert_eq!(parse_address("443").unwrap(), any);
+        assert_eq!(parse_address(":443").unwrap(), any);
+        assert_eq!(
+            parse_address("127.0.0.1:80").unwrap(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 80))
+        );
+        assert_eq!(
+            parse_address("[::1]:8443").unwrap(),
+            SocketAddr::from((Ipv6Addr::LOCALHOST, 8443))
+        );
+        assert!(parse_address("127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_bind() {
+        // both IPv4 and IPv6 clients reach a listener on every address
+        let listener = Listener::bind(ListenerConfig::new(parse_address("0").unwrap())).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        if TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).is_ok() {
+            TcpStream::connect((Ipv6Addr::LOCALHOST, port)).unwrap();
+        }
+
+        // several sockets on one port
+        let shared = Listener::bind(ListenerConfig {
+            accept_threads: 3,
+            ..ListenerConfig::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        })
+        .unwrap();
+        let port = shared.local_addr().unwrap().port();
+        assert_eq!(shared.sockets().len(), 3);
+        assert!(
+            shared
+                .sockets()
+                .iter()
+                .all(|s| s.local_addr().unwrap().port() == port)
+        );
+    }
+}
@@ -0,0 +1,472 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Error, ErrorKind, Write},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::internal::{
+    breaker::CircuitState,
+    proxy::Backend,
+    request::ErrorMsg,
+    response::{Response, StatusCode},
+};
+
+// Upper bounds in seconds, Prometheus' default buckets
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Label for each parse error, by `ErrorMsg`
+const PARSE_ERRORS: [(&str, &str); 16] = [
+    (ErrorMsg::MALFROMED_START_LINE, "malformed_start_line"),
+    (ErrorMsg::UNSUPPORTED_METHOD, "unsupported_method"),
+    (
+        ErrorMsg::INVALID_HTTP_SPECIFICATION,
+        "invalid_http_specification",
+    ),
+    (ErrorMsg::INVALID_HTTP_VERSION, "invalid_http_version"),
+    (ErrorMsg::INVALID_FIELD_LINE, "invalid_field_line"),
+    (ErrorMsg::INCOMPLETE_MESSAGE, "incomplete_message"),
+    (ErrorMsg::REQUEST_LINE_TOO_LONG, "request_line_too_long"),
+    (ErrorMsg::HEADERS_TOO_LARGE, "headers_too_large"),
+    (ErrorMsg::TOO_MANY_HEADERS, "too_many_headers"),
+    (ErrorMsg::HEADER_TOO_LARGE, "header_too_large"),
+    (ErrorMsg::BODY_TOO_LARGE, "body_too_large"),
+    (ErrorMsg::MALFORMED_STATUS_LINE, "malformed_status_line"),
+    (ErrorMsg::INVALID_STATUS_CODE, "invalid_status_code"),
+    (
+        ErrorMsg::UNSUPPORTED_CONTENT_ENCODING,
+        "unsupported_content_encoding",
+    ),
+    (ErrorMsg::REQUEST_TIMEOUT, "request_timeout"),
+    (ErrorMsg::EXPECTATION_FAILED, "expectation_failed"),
+];
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    // per bucket, not cumulative
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|le| seconds <= *le) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+// Counters for the whole process, rendered in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // by method, route and status
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    // by route, from the complete request until the response is sent
+    request_duration: Mutex<BTreeMap<String, Histogram>>,
+    // by backend, until the response head is read
+    upstream_duration: Mutex<BTreeMap<String, Histogram>>,
+    parse_errors: Mutex<BTreeMap<&'static str, u64>>,
+    connections_active: AtomicUsize,
+    connections_total: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    websockets_active: AtomicUsize,
+    // by the side that sent them
+    websocket_messages: Mutex<BTreeMap<&'static str, u64>>,
+    // by route, looked at when rendering
+    backends: Mutex<Vec<(String, Backend)>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn record_request(&self, method: &str, route: &str, status: StatusCode, took: Duration) {
+        let key = (method.to_string(), route.to_string(), status.as_u16());
+        *self.requests.lock().unwrap().entry(key).or_insert(0) += 1;
+        self.request_duration
+            .lock()
+            .unwrap()
+            .entry(route.to_string())
+            .or_default()
+            .observe(took);
+    }
+
+    pub fn record_upstream(&self, backend: &str, took: Duration) {
+        self.upstream_duration
+            .lock()
+            .unwrap()
+            .entry(backend.to_string())
+            .or_default()
+            .observe(took);
+    }
+
+    pub fn record_parse_error(&self, e: &Error) {
+        let message = e.to_string();
+        let reason = PARSE_ERRORS
+            .iter()
+            .find(|(msg, _)| *msg == message)
+            .map(|(_, reason)| *reason)
+            .unwrap_or(match e.kind() {
+                ErrorKind::UnexpectedEof => "connection_closed",
+                _ => "other",
+            });
+        *self.parse_errors.lock().unwrap().entry(reason).or_insert(0) += 1;
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_received(&self, n: u64) {
+        self.bytes_received.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_sent(&self, n: u64) {
+        self.bytes_sent.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn websocket_opened(&self) {
+        self.websockets_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn websocket_closed(&self) {
+        self.websockets_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // `from` is "client" or "upstream"
+    pub fn add_websocket_messages(&self, from: &'static str, n: u64) {
+        *self
+            .websocket_messages
+            .lock()
+            .unwrap()
+            .entry(from)
+            .or_insert(0) += n;
+    }
+
+    // Forgets the backends of a router that was replaced
+    pub fn clear_backends(&self) {
+        self.backends.lock().unwrap().clear();
+    }
+
+    // Their health is reported under the route
+    pub fn register_backends(&self, route: &str, backends: &[Backend]) {
+        let mut registered = self.backends.lock().unwrap();
+        for backend in backends {
+            registered.push((route.to_string(), backend.clone()));
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "lb_http_requests_total",
+            "counter",
+            "Requests answered.",
+        );
+        for ((method, route, status), n) in self.requests.lock().unwrap().iter() {
+            let labels = labels(&[
+                ("method", method),
+                ("route", route),
+                ("status", &status.to_string()),
+            ]);
+            let _ = writeln!(out, "lb_http_requests_total{labels} {n}");
+        }
+
+        header(
+            &mut out,
+            "lb_http_request_duration_seconds",
+            "histogram",
+            "Time from the complete request until the response was sent.",
+        );
+        for (route, histogram) in self.request_duration.lock().unwrap().iter() {
+            render_histogram(
+                &mut out,
+                "lb_http_request_duration_seconds",
+                ("route", route),
+                histogram,
+            );
+        }
+
+        header(
+            &mut out,
+            "lb_upstream_request_duration_seconds",
+            "histogram",
+            "Time until the upstream response head arrived.",
+        );
+        for (backend, histogram) in self.upstream_duration.lock().unwrap().iter() {
+            render_histogram(
+                &mut out,
+                "lb_upstream_request_duration_seconds",
+                ("backend", backend),
+                histogram,
+            );
+        }
+
+        header(
+            &mut out,
+            "lb_parse_errors_total",
+            "counter",
+            "Requests that could not be read, by reason.",
+        );
+        for (reason, n) in self.parse_errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "lb_parse_errors_total{} {n}",
+                labels(&[("reason", reason)])
+            );
+        }
+
+        let gauges = [
+            (
+                "lb_connections_active",
+                "gauge",
+                "Open client connections.",
+                self.connections_active.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "lb_connections_total",
+                "counter",
+                "Client connections accepted.",
+                self.connections_total.load(Ordering::Relaxed),
+            ),
+            (
+                "lb_bytes_received_total",
+                "counter",
+                "Request bytes read from clients.",
+                self.bytes_received.load(Ordering::Relaxed),
+            ),
+            (
+                "lb_bytes_sent_total",
+                "counter",
+                "Response bytes written to clients.",
+                self.bytes_sent.load(Ordering::Relaxed),
+            ),
+            (
+                "lb_websocket_connections_active",
+                "gauge",
+                "Open WebSocket tunnels.",
+                self.websockets_active.load(Ordering::Relaxed) as u64,
+            ),
+        ];
+        for (name, kind, help, value) in gauges {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+
+        header(
+            &mut out,
+            "lb_websocket_messages_total",
+            "counter",
+            "WebSocket messages relayed, by the side that sent them.",
+        );
+        for (from, n) in self.websocket_messages.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "lb_websocket_messages_total{} {n}",
+                labels(&[("from", from)])
+            );
+        }
+
+        self.render_backends(&mut out);
+        out
+    }
+
+    fn render_backends(&self, out: &mut String) {
+        let backends = self.backends.lock().unwrap();
+
+        header(
+            out,
+            "lb_backend_up",
+            "gauge",
+            "Whether the backend takes requests, 0 while its circuit is open or it is unhealthy.",
+        );
+        for (route, backend) in backends.iter() {
+            let up = backend.is_healthy()
+                && backend
+                    .breaker()
+                    .is_none_or(|b| b.state() != CircuitState::Open);
+            let labels = labels(&[("route", route), ("backend", backend.pool().address())]);
+            let _ = writeln!(out, "lb_backend_up{labels} {}", up as u8);
+        }
+
+        header(
+            out,
+            "lb_backend_circuit_state",
+            "gauge",
+            "Circuit breaker state of the backend.",
+        );
+        for (route, backend) in backends.iter() {
+            let Some(breaker) = backend.breaker() else {
+                continue;
+            };
+            let current = breaker.state();
+            for (state, name) in [
+                (CircuitState::Closed, "closed"),
+                (CircuitState::Open, "open"),
+                (CircuitState::HalfOpen, "half_open"),
+            ] {
+                let labels = labels(&[
+                    ("route", route),
+                    ("backend", backend.pool().address()),
+                    ("state", name),
+                ]);
+                let _ = writeln!(
+                    out,
+                    "lb_backend_circuit_state{labels} {}",
+                    (state == current) as u8
+                );
+            }
+        }
+
+        header(
+            out,
+            "lb_backend_connections",
+            "gauge",
+            "Pooled upstream connections, by state.",
+        );
+        for (route, backend) in backends.iter() {
+            let pool = backend.pool();
+            for (state, n) in [("idle", pool.idle()), ("in_flight", pool.in_flight())] {
+                let labels = labels(&[
+                    ("route", route),
+                    ("backend", pool.address()),
+                    ("state", state),
+                ]);
+                let _ = writeln!(out, "lb_backend_connections{labels} {n}");
+            }
+        }
+    }
+
+    pub fn response(&self) -> Response {
+        Response::builder(StatusCode::OK)
+            .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+            .header("Cache-Control", "no-store")
+            .body(self.render())
+            .build()
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn render_histogram(out: &mut String, name: &str, label: (&str, &str), histogram: &Histogram) {
+    let mut cumulative = 0;
+    for (le, n) in BUCKETS.iter().zip(histogram.counts) {
+        cumulative += n;
+        let labels = labels(&[label, ("le", &le.to_string())]);
+        let _ = writeln!(out, "{name}_bucket{labels} {cumulative}");
+    }
+    let labels = labels(&[label, ("le", "+Inf")]);
+    let _ = writeln!(out, "{name}_bucket{labels} {}", histogram.count);
+
+    let labels = self::labels(&[label]);
+    let _ = writeln!(out, "{name}_sum{labels} {}", histogram.sum);
+    let _ = writeln!(out, "{name}_count{labels} {}", histogram.count);
+}
+
+// Counts the bytes written through it
+pub struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, written: 0 }
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_request("GET", "/api", StatusCode::OK, Duration::from_millis(30));
+        metrics.record_request("GET", "/api", StatusCode::OK, Duration::from_secs(20));
+        metrics.record_parse_error(&Error::new(
+            ErrorKind::InvalidData,
+            ErrorMsg::TOO_MANY_HEADERS,
+        ));
+        metrics.connection_opened();
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE lb_http_requests_total counter\n"));
+        assert!(
+            text.contains(
+                "lb_http_requests_total{method=\"GET\",route=\"/api\",status=\"200\"} 2\n"
+            )
+        );
+        assert!(
+            text.contains(
+                "lb_http_request_duration_seconds_bucket{route=\"/api\",le=\"0.025\"} 0\n"
+            )
+        );
+        assert!(
+            text.contains(
+                "lb_http_request_duration_seconds_bucket{route=\"/api\",le=\"0.05\"} 1\n"
+            )
+        );
+        assert!(
+            text.contains(
+                "lb_http_request_duration_seconds_bucket{route=\"/api\",le=\"+Inf\"} 2\n"
+            )
+        );
+        assert!(text.contains("lb_http_request_duration_seconds_count{route=\"/api\"} 2\n"));
+        assert!(text.contains("lb_parse_errors_total{reason=\"too_many_headers\"} 1\n"));
+        assert!(text.contains("lb_connections_active 1\n"));
+        assert_eq!(labels(&[("a", "x\"y")]), "{a=\"x\\\"y\"}");
+    }
+}
@@ -1,4 +1,29 @@
-pub mod request;
-pub mod response;
+pub mod accesslog;
+pub mod admin;
 pub mod body;
+pub mod breaker;
+pub mod cache;
+pub mod compression;
+pub mod config;
+pub mod connection;
+pub mod fileserver;
+pub mod grpc;
 pub mod headers;
+pub mod http2;
+pub mod httpdate;
+pub mod limits;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod pool;
+pub mod proxy;
+pub mod ratelimit;
+pub mod reload;
+pub mod request;
+pub mod response;
+pub mod retry;
+pub mod rewrite;
+pub mod router;
+pub mod tls;
+pub mod tracing;
+pub mod websocket;
@@ -0,0 +1,333 @@
+use std::{
+    io::{self, Error, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::internal::request::ErrorMsg;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    // idle connections kept per backend, more are closed when they come back
+    pub max_idle: usize,
+    pub idle_timeout: Duration,
+    // connections are not reused past this age, so backends can rebalance
+    pub max_lifetime: Duration,
+    // connections in use at once, further requests wait up to `acquire_timeout`
+    pub max_in_flight: usize,
+    pub acquire_timeout: Duration,
+    pub connect_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_idle: 16,
+            idle_timeout: Duration::from_secs(60),
+            max_lifetime: Duration::from_secs(10 * 60),
+            max_in_flight: 256,
+            acquire_timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Idle {
+    stream: TcpStream,
+    created: Instant,
+    since: Instant,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    idle: Vec<Idle>,
+    in_flight: usize,
+}
+
+// Keep-alive connections to one backend
+#[derive(Debug)]
+pub struct Pool {
+    address: String,
+    config: PoolConfig,
+    state: Mutex<PoolState>,
+    released: Condvar,
+}
+
+impl Pool {
+    pub fn new(address: impl Into<String>, config: PoolConfig) -> Arc<Pool> {
+        Arc::new(Pool {
+            address: address.into(),
+            config,
+            state: Mutex::new(PoolState::default()),
+            released: Condvar::new(),
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    pub fn idle(&self) -> usize {
+        self.state.lock().unwrap().idle.len()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    // Hands out a live idle connection when there is one, a new one otherwise
+    pub fn acquire(self: &Arc<Self>) -> Result<Connection, Error> {
+        self.checkout(true)
+    }
+
+    // Skips the idle connections, for a retry after a reused one turned out dead
+    pub fn connect(self: &Arc<Self>) -> Result<Connection, Error> {
+        self.checkout(false)
+    }
+
+    fn checkout(self: &Arc<Self>, reuse: bool) -> Result<Connection, Error> {
+        let deadline = Instant::now() + self.config.acquire_timeout;
+        let mut state = self.state.lock().unwrap();
+
+        while state.in_flight >= self.config.max_in_flight {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(Error::new(
+                    ErrorKind::ResourceBusy,
+                    ErrorMsg::POOL_EXHAUSTED,
+                ));
+            }
+            state = self.released.wait_timeout(state, left).unwrap().0;
+        }
+        state.in_flight += 1;
+
+        // from here on the slot is given back by dropping the connection, or
+        // right away when no connection comes of it
+        let now = Instant::now();
+        while reuse && let Some(idle) = state.idle.pop() {
+            if now.duration_since(idle.since) >= self.config.idle_timeout
+                || now.duration_since(idle.created) >= self.config.max_lifetime
+                || !is_alive(&idle.stream)
+            {
+                continue;
+            }
+            return Ok(Connection {
+                pool: Arc::clone(self),
+                stream: Some(idle.stream),
+                created: idle.created,
+                reused: true,
+            });
+        }
+        drop(state);
+
+        let stream = self.open().inspect_err(|_| self.give_back(None))?;
+        Ok(Connection {
+            pool: Arc::clone(self),
+            stream: Some(stream),
+            created: Instant::now(),
+            reused: false,
+        })
+    }
+
+    fn open(&self) -> Result<TcpStream, Error> {
+        let mut last_error = Error::new(ErrorKind::NotFound, "Upstream address did not resolve");
+
+        for addr in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.config.connect_timeout) {
+                Ok(stream) => {
+                    let _ = stream.set_nodelay(true);
+                    return Ok(stream);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn give_back(&self, stream: Option<(TcpStream, Instant)>) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight -= 1;
+
+        if let Some((stream, created)) = stream {
+            let now = Instant::now();
+            if state.idle.len() < self.config.max_idle
+                && now.duration_since(created) < self.config.max_lifetime
+            {
+                state.idle.push(Idle {
+                    stream,
+                    created,
+                    since: now,
+                });
+            }
+        }
+
+        drop(state);
+        self.released.notify_one();
+    }
+}
+
+// An idle connection should have nothing to read, EOF or stray bytes mean the
+// backend closed it or is out of sync with us
+fn is_alive(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let alive = matches!(
+        stream.peek(&mut [0u8; 1]),
+        Err(e) if e.kind() == ErrorKind::WouldBlock
+    );
+    alive && stream.set_nonblocking(false).is_ok()
+}
+
+// A connection checked out of a pool. It only goes back to the pool through
+// `release`, after a complete response, dropping it closes the connection.
+#[derive(Debug)]
+pub struct Connection {
+    pool: Arc<Pool>,
+    stream: Option<TcpStream>,
+    created: Instant,
+    reused: bool,
+}
+
+impl Connection {
+    // Whether it came from the idle list, a failure on it may just mean the
+    // backend closed it in the meantime
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+
+    // The backend it leads to
+    pub fn address(&self) -> &str {
+        self.pool.address()
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        self.stream.as_ref().expect("connection already released")
+    }
+
+    pub fn release(mut self) {
+        let stream = self.stream.take().map(|s| (s, self.created));
+        self.pool.give_back(stream);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+            self.pool.give_back(None);
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream().read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream().flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    fn pool(config: PoolConfig) -> (Arc<Pool>, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        (Pool::new(address, config), listener)
+    }
+
+    #[test]
+    fn test_pool_reuse() {
+        let (pool, listener) = pool(PoolConfig::default());
+
+        let first = pool.acquire().unwrap();
+        let (server_side, _) = listener.accept().unwrap();
+        let local = first.stream().local_addr().unwrap();
+        assert!(!first.is_reused());
+        assert_eq!(pool.in_flight(), 1);
+        first.release();
+        assert_eq!((pool.in_flight(), pool.idle()), (0, 1));
+
+        let second = pool.acquire().unwrap();
+        assert!(second.is_reused());
+        assert_eq!(second.stream().local_addr().unwrap(), local);
+        // dropped without release, it is closed instead of going back
+        drop(second);
+        assert_eq!((pool.in_flight(), pool.idle()), (0, 0));
+        drop(server_side);
+    }
+
+    #[test]
+    fn test_pool_liveness() {
+        let (pool, listener) = pool(PoolConfig::default());
+
+        pool.acquire().unwrap().release();
+        let (server_side, _) = listener.accept().unwrap();
+        drop(server_side);
+        thread::sleep(Duration::from_millis(50));
+
+        // the backend closed the idle connection, a new one is opened
+        let connection = pool.acquire().unwrap();
+        assert!(!connection.is_reused());
+    }
+
+    #[test]
+    fn test_pool_limits() {
+        let (pool, _listener) = pool(PoolConfig {
+            max_in_flight: 1,
+            acquire_timeout: Duration::from_millis(50),
+            max_idle: 0,
+            ..PoolConfig::default()
+        });
+
+        let first = pool.acquire().unwrap();
+        let error = pool.acquire().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ResourceBusy);
+
+        // a waiting request gets the slot as soon as it is released
+        let waiter = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || pool.acquire().map(|c| c.is_reused()))
+        };
+        thread::sleep(Duration::from_millis(10));
+        first.release();
+        assert!(!waiter.join().unwrap().unwrap());
+        // max_idle 0 keeps nothing
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_pool_failed_connect() {
+        let (pool, listener) = pool(PoolConfig {
+            max_in_flight: 2,
+            acquire_timeout: Duration::from_millis(50),
+            ..PoolConfig::default()
+        });
+        drop(listener);
+
+        // a connect that fails does not keep its slot
+        for _ in 0..5 {
+            let error = pool.acquire().unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+        }
+        assert_eq!(pool.in_flight(), 0);
+    }
+}
@@ -0,0 +1,2157 @@
+use std::{
+    borrow::Cow,
+    io::{self, Error, ErrorKind, Read, Write},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::internal::{
+    body::{
+        BodyLength,
+        stream::{BodyReader, IncomingBody},
+    },
+    breaker::{self, BreakerConfig, CircuitBreaker, Permit},
+    cache::{self, Cache, Entry, Lookup},
+    grpc::{self, HealthCheck},
+    http2::client::{self as http2, ClientBody, ClientResponse},
+    limits::Limits,
+    metrics::Metrics,
+    pool::{Connection, Pool, PoolConfig},
+    request::{self, ErrorMsg, Request, RequestMethod},
+    response::{
+        self, LateTrailers, Response, ResponseBody, StatusCode, Upstream, response_body_length,
+    },
+    retry::{Outcome, RetryPolicy, RetryTracker},
+    tracing::{Span, SpanKind, TraceContext},
+    websocket::{self, Upgraded},
+};
+use crate::{info, warn};
+
+// Fields that only apply to a single connection, RFC 9110 section 7.6.1
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+    "trailer",
+    "upgrade",
+    "proxy-authorization",
+];
+
+// How long a request that expects 100-continue waits for the upstream's
+// answer before its body is sent anyway, RFC 9110 section 10.1.1
+const EXPECT_WAIT: Duration = Duration::from_secs(1);
+
+// One upstream server of a proxy
+#[derive(Debug, Clone)]
+pub struct Backend {
+    pool: Arc<Pool>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    // changed at runtime through the admin API, shared by the clones
+    draining: Arc<AtomicBool>,
+    weight: Arc<AtomicU32>,
+    // set by the health checks, when there are any
+    healthy: Arc<AtomicBool>,
+}
+
+impl Backend {
+    fn new(pool: Arc<Pool>, breaker: Option<Arc<CircuitBreaker>>) -> Backend {
+        Backend {
+            pool,
+            breaker,
+            draining: Arc::new(AtomicBool::new(false)),
+            weight: Arc::new(AtomicU32::new(1)),
+            healthy: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub fn pool(&self) -> &Arc<Pool> {
+        &self.pool
+    }
+
+    pub fn breaker(&self) -> Option<&Arc<CircuitBreaker>> {
+        self.breaker.as_ref()
+    }
+
+    pub fn address(&self) -> &str {
+        self.pool.address()
+    }
+
+    // A draining backend gets no new requests, the ones it has go on
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    // Its share of the requests relative to the other backends, 0 takes it
+    // out like draining does
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::Relaxed)
+    }
+
+    pub fn set_weight(&self, weight: u32) {
+        self.weight.store(weight, Ordering::Relaxed);
+    }
+
+    // False while the backend fails its health checks
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    fn allows(&self) -> bool {
+        !self.is_draining()
+            && self.weight() > 0
+            && self.is_healthy()
+            && self.breaker.as_ref().is_none_or(|b| b.allows())
+    }
+}
+
+// Probes the backends of a proxy in a thread of its own, which ends with the
+// last clone of the proxy
+#[derive(Debug)]
+struct HealthChecker {
+    check: HealthCheck,
+    // the address and health of each backend, replaced when a reload
+    // carries backends over
+    backends: Mutex<Vec<(String, Arc<AtomicBool>)>>,
+}
+
+impl HealthChecker {
+    fn start(check: HealthCheck, backends: &[Backend]) -> Arc<HealthChecker> {
+        let checker = Arc::new(HealthChecker {
+            check,
+            backends: Mutex::new(Vec::new()),
+        });
+        checker.watch(backends);
+
+        let weak = Arc::downgrade(&checker);
+        thread::spawn(move || {
+            while let Some(checker) = weak.upgrade() {
+                let backends = checker.backends.lock().unwrap().clone();
+                for (address, healthy) in backends {
+                    let result = grpc::probe(&address, &checker.check);
+                    let was_healthy = healthy.swap(result.is_ok(), Ordering::Relaxed);
+                    match result {
+                        Err(e) if was_healthy => warn!("backend {address} is down: {e}"),
+                        Ok(()) if !was_healthy => info!("backend {address} is up again"),
+                        _ => {}
+                    }
+                }
+                let interval = checker.check.interval;
+                drop(checker);
+                thread::sleep(interval);
+            }
+        });
+        checker
+    }
+
+    fn watch(&self, backends: &[Backend]) {
+        *self.backends.lock().unwrap() = backends
+            .iter()
+            .map(|b| (b.address().to_string(), Arc::clone(&b.healthy)))
+            .collect();
+    }
+}
+
+// Forwards requests to upstream servers, optionally through a shared cache
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    // shared by the clones, so every worker thread reuses the same connections
+    backends: Vec<Backend>,
+    // round robin over the backends
+    next: Arc<AtomicUsize>,
+    retry: RetryPolicy,
+    retries: Arc<RetryTracker>,
+    // for the backends as a whole
+    breaker: Option<Arc<CircuitBreaker>>,
+    // takes the requests while every circuit is open
+    fallback: Option<Box<Proxy>>,
+    limits: Limits,
+    cache: Option<Arc<Cache>>,
+    metrics: Option<Arc<Metrics>>,
+    // the backends speak HTTP/2 with prior knowledge
+    http2: bool,
+    health: Option<Arc<HealthChecker>>,
+}
+
+impl Proxy {
+    // `upstream` is host:port
+    pub fn new(upstream: impl Into<String>) -> Self {
+        let retry = RetryPolicy::default();
+        Proxy {
+            backends: vec![Backend::new(
+                Pool::new(upstream, PoolConfig::default()),
+                None,
+            )],
+            next: Arc::new(AtomicUsize::new(0)),
+            retries: Arc::new(RetryTracker::new(retry.budget.clone())),
+            retry,
+            breaker: None,
+            fallback: None,
+            limits: Limits::default(),
+            cache: None,
+            metrics: None,
+            http2: false,
+            health: None,
+        }
+    }
+
+    // Another server for the same content, requests are spread over all of
+    // them and a retry goes to the next one
+    pub fn with_backend(mut self, upstream: impl Into<String>) -> Self {
+        let first = &self.backends[0];
+        let backend = Backend::new(
+            Pool::new(upstream, first.pool.config().clone()),
+            first
+                .breaker
+                .as_ref()
+                .map(|b| CircuitBreaker::new(b.config().clone())),
+        );
+        self.backends.push(backend);
+        if let Some(health) = &self.health {
+            health.watch(&self.backends);
+        }
+        self
+    }
+
+    pub fn with_pool_config(mut self, config: PoolConfig) -> Self {
+        for backend in &mut self.backends {
+            backend.pool = Pool::new(backend.pool.address(), config.clone());
+        }
+        self
+    }
+
+    // A breaker for each backend, the others keep taking requests while one is open
+    pub fn with_backend_breaker(mut self, config: BreakerConfig) -> Self {
+        for backend in &mut self.backends {
+            backend.breaker = Some(CircuitBreaker::new(config.clone()));
+        }
+        self
+    }
+
+    // A breaker for all the backends together
+    pub fn with_breaker(mut self, config: BreakerConfig) -> Self {
+        self.breaker = Some(CircuitBreaker::new(config));
+        self
+    }
+
+    // The backends speak HTTP/2 with prior knowledge (h2c) as gRPC servers do,
+    // every request goes on a connection of its own
+    pub fn with_http2(mut self) -> Self {
+        self.http2 = true;
+        self
+    }
+
+    // Probes the backends with the gRPC health checking protocol, one that
+    // fails takes no requests until a probe passes again
+    pub fn with_health_check(mut self, check: HealthCheck) -> Self {
+        self.health = Some(HealthChecker::start(check, &self.backends));
+        self
+    }
+
+    pub fn with_fallback(mut self, fallback: Proxy) -> Self {
+        self.fallback = Some(Box::new(fallback));
+        self
+    }
+
+    pub fn breaker(&self) -> Option<&Arc<CircuitBreaker>> {
+        self.breaker.as_ref()
+    }
+
+    pub fn fallback(&self) -> Option<&Proxy> {
+        self.fallback.as_deref()
+    }
+
+    // Records the upstream time of each attempt, the fallback's as well
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.fallback = self
+            .fallback
+            .take()
+            .map(|f| Box::new(f.with_metrics(Arc::clone(&metrics))));
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retries = Arc::new(RetryTracker::new(retry.budget.clone()));
+        self.retry = retry;
+        self
+    }
+
+    pub fn backends(&self) -> &[Backend] {
+        &self.backends
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Arc<Cache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    // The backends and options, for reports. Retries, limits and pools come
+    // from the shared config.
+    pub fn describe(&self) -> String {
+        let addresses: Vec<&str> = self.backends.iter().map(|b| b.address()).collect();
+        let mut out = format!("proxy {}", addresses.join(","));
+        if self.http2 {
+            out.push_str(" http2");
+        }
+        if let Some(health) = &self.health {
+            out.push_str(&format!(" health check {:?}", health.check));
+        }
+        if let Some(breaker) = self.backends[0].breaker() {
+            out.push_str(&format!(" backend breaker {:?}", breaker.config()));
+        }
+        if let Some(breaker) = &self.breaker {
+            out.push_str(&format!(" breaker {:?}", breaker.config()));
+        }
+        if let Some(cache) = &self.cache {
+            out.push_str(&format!(" cache {:?}", cache.config()));
+        }
+        if let Some(fallback) = &self.fallback {
+            out.push_str(&format!(" fallback ({})", fallback.describe()));
+        }
+        out
+    }
+
+    // Takes over the state of the proxy this one replaces where the settings
+    // are the same, so upstream connections, circuits, drained backends,
+    // weights and cached responses survive a reload
+    pub fn carry_over(mut self, old: &Proxy) -> Self {
+        let breaker_config =
+            |b: &Option<Arc<CircuitBreaker>>| b.as_ref().map(|b| b.config().clone());
+        for backend in &mut self.backends {
+            if let Some(same) = old.backends.iter().find(|b| {
+                b.address() == backend.address()
+                    && b.pool.config() == backend.pool.config()
+                    && breaker_config(&b.breaker) == breaker_config(&backend.breaker)
+            }) {
+                *backend = same.clone();
+            }
+        }
+        match &self.health {
+            Some(health) => health.watch(&self.backends),
+            // nothing takes a kept backend out any more
+            None => {
+                for backend in &self.backends {
+                    backend.healthy.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+        if breaker_config(&self.breaker) == breaker_config(&old.breaker) {
+            self.breaker = old.breaker.clone();
+        }
+        if self.retry.budget == old.retry.budget {
+            self.retries = Arc::clone(&old.retries);
+        }
+        if let (Some(cache), Some(previous)) = (&self.cache, &old.cache)
+            && cache.config() == previous.config()
+        {
+            self.cache = Some(Arc::clone(previous));
+        }
+        if let Some(previous) = &old.fallback {
+            self.fallback = self
+                .fallback
+                .take()
+                .map(|f| Box::new(f.carry_over(previous)));
+        }
+        self
+    }
+
+    pub fn serve(&self, request: &Request) -> Response {
+        self.serve_body(request, &mut OutgoingBody::buffered(&request.body))
+    }
+
+    // Serves a request whose body is still coming from the client, it goes
+    // upstream as it arrives instead of being read whole first. As much of it
+    // as max_body_size is kept for the retries, a longer one is sent once.
+    pub fn serve_streaming(&self, request: &Request, body: IncomingBody) -> Response {
+        let keep = match self.retry.max_attempts > 1 {
+            true => self.limits.max_body_size,
+            false => 0,
+        };
+        let mut body = OutgoingBody::streamed(body, keep);
+        let response = self.serve_body(request, &mut body);
+        // the client is told about its own body, not about the upstream
+        match body.failed {
+            Some(e) => {
+                let status = request::error_status(&e);
+                Response::builder(status).body(e.to_string()).build()
+            }
+            None => response,
+        }
+    }
+
+    fn serve_body(&self, request: &Request, body: &mut OutgoingBody) -> Response {
+        // a WebSocket handshake bypasses the cache, its 101 brings the
+        // upstream connection along for the tunnel
+        if websocket::is_upgrade(request) {
+            return match websocket::check_handshake(request) {
+                Some(rejected) => rejected,
+                None => self.pass(request, body),
+            };
+        }
+
+        // gRPC calls bypass the cache, and are told about failures with a
+        // gRPC status
+        if grpc::is_grpc(request) {
+            return match self.forward(request, &[], body) {
+                Ok(response) => grpc::answer(response),
+                Err(e) => grpc::failure(&e),
+            };
+        }
+
+        let method = request.method.clone().unwrap_or(RequestMethod::Get);
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None if method == RequestMethod::Purge => {
+                return Response::builder(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(StatusCode::METHOD_NOT_ALLOWED.reason())
+                    .build();
+            }
+            None => return self.pass(request, body),
+        };
+
+        if method == RequestMethod::Purge {
+            return cache.purge(request);
+        }
+
+        if !method.is_safe() {
+            // unsafe methods invalidate what is stored for the target, RFC 9111 section 4.4
+            let response = self.pass(request, body);
+            if response.status.is_success() || response.status.is_redirection() {
+                let mut get = request.clone();
+                get.method = Some(RequestMethod::Get);
+                cache.invalidate(&get);
+            }
+            return response;
+        }
+
+        let now = SystemTime::now();
+        match cache.lookup(request, now) {
+            Lookup::Hit(entry) => from_cache(request, &entry, now, "HIT"),
+            Lookup::Revalidate(entry) => {
+                self.revalidate_in_background(request, cache, entry.clone());
+                from_cache(request, &entry, now, "STALE")
+            }
+            Lookup::Stale(entry) => self.validate(request, cache, entry, body),
+            Lookup::Miss => {
+                let only_if_cached = request
+                    .headers
+                    .as_ref()
+                    .and_then(|h| h.get("Cache-Control"))
+                    .is_some_and(|cc| cache::CacheControl::parse(cc).only_if_cached);
+                if only_if_cached {
+                    return Response::builder(StatusCode::GATEWAY_TIMEOUT)
+                        .body(StatusCode::GATEWAY_TIMEOUT.reason())
+                        .build();
+                }
+
+                match self.forward(request, &[], body) {
+                    Ok(response) => {
+                        let mut response = store_on_read(request, cache, response, now);
+                        response.set_header("X-Cache", "MISS");
+                        response
+                    }
+                    Err(e) => bad_gateway(&e),
+                }
+            }
+        }
+    }
+
+    // Forwards without looking at the cache
+    fn pass(&self, request: &Request, body: &mut OutgoingBody) -> Response {
+        self.forward(request, &[], body)
+            .unwrap_or_else(|e| bad_gateway(&e))
+    }
+
+    // Sends a conditional request for a stale entry, a 304 makes it fresh again
+    fn validate(
+        &self,
+        request: &Request,
+        cache: &Arc<Cache>,
+        mut entry: Entry,
+        body: &mut OutgoingBody,
+    ) -> Response {
+        let request_time = SystemTime::now();
+
+        match self.forward(request, &cache::validators(&entry), body) {
+            Ok(response) if response.status == StatusCode::NOT_MODIFIED => {
+                let now = SystemTime::now();
+                entry.freshen(&response.headers, request_time, now);
+                cache.store(request, entry.clone());
+                from_cache(request, &entry, now, "REVALIDATED")
+            }
+            Ok(response)
+                if response.status.is_server_error()
+                    && cache.can_serve_stale_on_error(request, &entry, request_time) =>
+            {
+                from_cache(request, &entry, request_time, "STALE")
+            }
+            Ok(response) => {
+                let mut response = store_on_read(request, cache, response, request_time);
+                response.set_header("X-Cache", "EXPIRED");
+                response
+            }
+            Err(_) if cache.can_serve_stale_on_error(request, &entry, request_time) => {
+                from_cache(request, &entry, request_time, "STALE")
+            }
+            Err(e) => bad_gateway(&e),
+        }
+    }
+
+    fn revalidate_in_background(&self, request: &Request, cache: &Arc<Cache>, mut entry: Entry) {
+        if !cache.begin_revalidation(request) {
+            return;
+        }
+
+        let proxy = self.clone();
+        let cache = Arc::clone(cache);
+        let request = request.clone();
+        thread::spawn(move || {
+            let request_time = SystemTime::now();
+            let validators = cache::validators(&entry);
+            let body = &mut OutgoingBody::buffered(&request.body);
+            match proxy.forward(&request, &validators, body) {
+                Ok(response) if response.status == StatusCode::NOT_MODIFIED => {
+                    entry.freshen(&response.headers, request_time, SystemTime::now());
+                    cache.store(&request, entry);
+                }
+                Ok(response) => {
+                    // reading the body through is what stores it
+                    let response = store_on_read(&request, &cache, response, request_time);
+                    let _ = drain(response.body);
+                }
+                Err(_) => {}
+            }
+            cache.end_revalidation(&request);
+        });
+    }
+
+    // Sends the request upstream and reads the response head, the body is left
+    // on the connection and streamed when the response is sent. `extra` fields
+    // replace the ones of the request with the same name. Failed attempts are
+    // retried on the next backend as the retry policy allows and the `body`
+    // can be sent again.
+    fn forward(
+        &self,
+        request: &Request,
+        extra: &[(String, String)],
+        body: &mut OutgoingBody,
+    ) -> Result<Response, Error> {
+        let method = request
+            .method
+            .clone()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, ErrorMsg::MALFROMED_START_LINE))?;
+        let fields = self.request_fields(request, &method, extra, body.length());
+        // the spans of the upstream calls are children of the one the listener
+        // put on the request
+        let trace = request
+            .headers
+            .as_ref()
+            .and_then(TraceContext::from_headers);
+
+        let outbound = Outbound {
+            request,
+            method: &method,
+            fields: &fields,
+            trace: trace.as_ref(),
+        };
+        let result = self.try_backends(&outbound, body);
+        if let Err(e) = &result
+            && e.to_string() == ErrorMsg::CIRCUIT_OPEN
+            && let Some(fallback) = &self.fallback
+            && body.rewind()
+        {
+            return fallback.forward(request, extra, body);
+        }
+        result
+    }
+
+    fn try_backends(
+        &self,
+        outbound: &Outbound,
+        body: &mut OutgoingBody,
+    ) -> Result<Response, Error> {
+        let method = outbound.method;
+        let group = self.breaker.as_ref().map(|b| b.try_acquire()).transpose()?;
+        let started = Instant::now();
+
+        self.retries.record_request();
+        let first = self.pick();
+        let mut attempt = 0;
+        loop {
+            let index = first + attempt as usize;
+            let result = self.attempt(index, outbound, &group, body);
+            attempt += 1;
+
+            let outcome = match &result {
+                Ok(response) => Outcome::Status(response.status),
+                Err((true, e)) => Outcome::ConnectFailed(e),
+                Err((false, e)) => Outcome::Failed(e),
+            };
+            let retry = attempt < self.retry.max_attempts
+                && self.retry.should_retry(method, &outcome)
+                && body.rewind()
+                && self.retries.try_retry();
+            if !retry {
+                if let Some(group) = &group {
+                    match &result {
+                        Ok(response) => {
+                            group.record(!response.status.is_server_error(), started.elapsed())
+                        }
+                        Err((_, e)) if !breaker::is_rejection(e) => {
+                            group.record(false, started.elapsed())
+                        }
+                        Err(_) => {}
+                    }
+                }
+                return result.map_err(|(_, e)| e);
+            }
+            // an unwanted response is dropped along with its connection
+            drop(result);
+            thread::sleep(self.retry.backoff(attempt));
+        }
+    }
+
+    // Weighted round robin, a backend with weight 3 takes three requests in a
+    // row for every one of a backend with weight 1
+    fn pick(&self) -> usize {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        let weights: Vec<usize> = self.backends.iter().map(|b| b.weight() as usize).collect();
+        let total: usize = weights.iter().sum();
+        if total == 0 {
+            return next % self.backends.len();
+        }
+
+        let mut n = next % total;
+        for (i, weight) in weights.into_iter().enumerate() {
+            if n < weight {
+                return i;
+            }
+            n -= weight;
+        }
+        0
+    }
+
+    // One attempt against the first backend from `index` on that takes
+    // requests, the error tells whether nothing was sent
+    fn attempt(
+        &self,
+        index: usize,
+        outbound: &Outbound,
+        group: &Option<Permit>,
+        body: &mut OutgoingBody,
+    ) -> Result<Response, (bool, Error)> {
+        let n = self.backends.len();
+        let backend = (0..n)
+            .map(|i| &self.backends[(index + i) % n])
+            .find(|b| b.allows())
+            .ok_or_else(|| (true, breaker::circuit_open()))?;
+        let permit = backend
+            .breaker
+            .as_ref()
+            .map(|b| b.try_acquire())
+            .transpose()
+            .map_err(|e| (true, e))?;
+
+        let started = Instant::now();
+        let result = self.send_to(&backend.pool, outbound, body);
+        let took = started.elapsed();
+        if let Some(metrics) = &self.metrics {
+            metrics.record_upstream(backend.pool.address(), took);
+        }
+        if let Some(permit) = &permit {
+            let success = matches!(&result, Ok(exchanged) if !exchanged.status().is_server_error());
+            permit.record(success, took);
+        }
+
+        // the slots are held until the body is read through
+        let permits = permit.into_iter().chain(group.clone()).collect();
+        let mut response = match result? {
+            Exchanged::Http1(connection, head, leftover)
+                if head.status == StatusCode::SWITCHING_PROTOCOLS =>
+            {
+                self.switched(connection, &head, leftover)
+            }
+            Exchanged::Http1(connection, head, leftover) => {
+                self.response(connection, &head, &leftover, outbound.method, permits)
+            }
+            Exchanged::Http2(status, upstream) => Ok(http2_response(status, upstream, permits)),
+        }
+        .map_err(|e| (false, e))?;
+        response.upstream = Some(Upstream {
+            address: backend.pool.address().to_string(),
+            took,
+        });
+        Ok(response)
+    }
+
+    // Sends the request on a pooled connection and reads the response head.
+    // HTTP/2 connections are not pooled.
+    fn send_to(
+        &self,
+        pool: &Arc<Pool>,
+        outbound: &Outbound,
+        body: &mut OutgoingBody,
+    ) -> Result<Exchanged, (bool, Error)> {
+        let trace = outbound.trace;
+        if self.http2 {
+            let connection = connect(pool, trace, Pool::connect).map_err(|e| (true, e))?;
+            return self
+                .exchange_http2(connection, outbound, body)
+                .map_err(|e| (false, e));
+        }
+        let connection = connect(pool, trace, Pool::acquire).map_err(|e| (true, e))?;
+        match self.exchange(connection, outbound, body) {
+            Ok(exchanged) => Ok(exchanged),
+            // the backend may have closed the idle connection just as it was
+            // picked, that is only safe to try again when the method is idempotent
+            Err((true, _)) if outbound.method.is_idempotent() && body.rewind() => {
+                let connection = connect(pool, trace, Pool::connect).map_err(|e| (true, e))?;
+                self.exchange(connection, outbound, body)
+                    .map_err(|(_, e)| (false, e))
+            }
+            Err((_, e)) => Err((false, e)),
+        }
+    }
+
+    fn response(
+        &self,
+        connection: Connection,
+        head: &response::ParsedResponse,
+        leftover: &[u8],
+        method: &RequestMethod,
+        permits: Vec<Permit>,
+    ) -> Result<Response, Error> {
+        let length = response_body_length(method, head.status, &head.headers)?;
+
+        let mut response = Response::new(head.status);
+        if head.headers.get("Server").is_some() {
+            response.remove_header("Server");
+        }
+        for (name, value) in end_to_end(&head.fields) {
+            if name == "content-length" && length != BodyLength::Empty {
+                continue;
+            }
+            response.append_header(name, value);
+        }
+
+        let reusable = length != BodyLength::UntilClose && upstream_keep_alive(head);
+        let body = BodyReader::for_response(head, method, leftover, connection, &self.limits)?;
+        let trailers = (length == BodyLength::Chunked).then(LateTrailers::default);
+        response.late_trailers = trailers.clone();
+        let body = PooledBody {
+            body: Some(body),
+            reusable,
+            permits,
+            trailers,
+        };
+        response.body = match length {
+            BodyLength::Empty => {
+                body.finish();
+                ResponseBody::Empty
+            }
+            BodyLength::Fixed(len) => ResponseBody::Fixed(Box::new(body), len as u64),
+            BodyLength::Chunked | BodyLength::UntilClose => ResponseBody::Stream(Box::new(body)),
+        };
+        Ok(response)
+    }
+
+    // A 101 to a WebSocket handshake, the connection leaves the pool with it.
+    // The circuit breaker slots are given back, a tunnel can stay open for hours.
+    fn switched(
+        &self,
+        connection: Connection,
+        head: &response::ParsedResponse,
+        leftover: Vec<u8>,
+    ) -> Result<Response, Error> {
+        let websocket = head
+            .headers
+            .get("Upgrade")
+            .is_some_and(|u| u.trim().eq_ignore_ascii_case("websocket"));
+        if !websocket {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Upstream switched to a protocol other than WebSocket",
+            ));
+        }
+
+        let mut response = Response::new(head.status);
+        response.remove_header("Server");
+        for (name, value) in end_to_end(&head.fields) {
+            response.append_header(name, value);
+        }
+        response.set_header("Upgrade", "websocket");
+        response.upgrade = Some(Upgraded::new(connection, leftover));
+        Ok(response)
+    }
+
+    // Writes the request and reads the response head. On failure tells whether
+    // the connection was a reused one.
+    fn exchange(
+        &self,
+        mut connection: Connection,
+        outbound: &Outbound,
+        body: &mut OutgoingBody,
+    ) -> Result<Exchanged, (bool, Error)> {
+        let Outbound {
+            request,
+            method,
+            fields,
+            trace,
+        } = *outbound;
+        let span = upstream_span(&connection, method, trace);
+        // the upstream continues the trace from this span
+        let head = request_head(request, method, fields, &span.context().traceparent());
+
+        let reused = connection.is_reused();
+        let timeout = self.retry.per_try_timeout;
+        // the expectation of the client is passed on, the upstream may turn
+        // the body down before it is sent
+        let expects_continue = request.expects_continue() && !body.is_empty();
+        let result = set_timeouts(&connection, timeout)
+            .and_then(|_| connection.write_all(&head))
+            .and_then(|_| match expects_continue {
+                true => self.await_continue(&mut connection, method, timeout),
+                false => Ok(Continue::Send(Vec::new())),
+            })
+            .and_then(|answer| match answer {
+                Continue::Refused(head, leftover) => Ok((head, leftover)),
+                Continue::Send(received) => send_body(&mut connection, body).and_then(|_| {
+                    let upgrade = websocket::is_upgrade(request);
+                    self.read_response_head(&mut connection, received, method, upgrade)
+                }),
+            })
+            // the body is streamed at the pace of the client
+            .and_then(|r| set_timeouts(&connection, None).map(|_| r));
+        end_span(span, result.as_ref().map(|(head, _)| head.status));
+
+        match result {
+            Ok((head, leftover)) => Ok(Exchanged::Http1(connection, head, leftover)),
+            Err(e) => Err((reused, e)),
+        }
+    }
+
+    // The request over HTTP/2 with prior knowledge, on a connection of its
+    // own. The body is left on the connection as with HTTP/1.1.
+    fn exchange_http2(
+        &self,
+        connection: Connection,
+        outbound: &Outbound,
+        body: &mut OutgoingBody,
+    ) -> Result<Exchanged, Error> {
+        let Outbound {
+            request,
+            method,
+            fields,
+            trace,
+        } = *outbound;
+        let span = upstream_span(&connection, method, trace);
+        let authority = request.host().unwrap_or(connection.address()).to_string();
+        let mut list = vec![
+            (":method".to_string(), method.as_str().to_string()),
+            (":scheme".to_string(), "http".to_string()),
+            (
+                ":path".to_string(),
+                request.path.as_deref().unwrap_or("/").to_string(),
+            ),
+            (":authority".to_string(), authority),
+        ];
+        for (name, value) in fields {
+            let name = name.to_ascii_lowercase();
+            if !matches!(
+                name.as_str(),
+                "host" | "connection" | "upgrade" | "transfer-encoding"
+            ) {
+                list.push((name, value.clone()));
+            }
+        }
+        list.push(("traceparent".to_string(), span.context().traceparent()));
+        // gRPC servers want it, it went with the hop-by-hop fields
+        if grpc::is_grpc(request) {
+            list.push(("te".to_string(), "trailers".to_string()));
+        }
+
+        let result = set_timeouts(&connection, self.retry.per_try_timeout)
+            .and_then(|_| {
+                let body: Option<&mut dyn Read> = match body.is_empty() {
+                    true => None,
+                    false => Some(body),
+                };
+                http2::send(connection, &list, body, self.limits.max_header_bytes)
+            })
+            .and_then(|upstream| {
+                set_timeouts(upstream.body.get_ref(), None)?;
+                Ok((StatusCode::from_u16(upstream.status)?, upstream))
+            });
+        end_span(span, result.as_ref().map(|(status, _)| *status));
+        result.map(|(status, upstream)| Exchanged::Http2(status, upstream))
+    }
+
+    // The fields sent upstream, the same for every attempt. The body goes with
+    // its `length`, chunked when that is not known.
+    fn request_fields(
+        &self,
+        request: &Request,
+        method: &RequestMethod,
+        extra: &[(String, String)],
+        length: Option<u64>,
+    ) -> Vec<(String, String)> {
+        let mut fields = Vec::new();
+
+        // fields named in Connection are hop-by-hop as well
+        let connection: Vec<String> = request
+            .headers
+            .as_ref()
+            .and_then(|h| h.get("Connection"))
+            .map(|c| {
+                c.split(',')
+                    .map(|f| f.trim().to_ascii_lowercase())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut forwarded_for = None;
+
+        if let Some(headers) = &request.headers {
+            for (name, value) in headers.iter() {
+                if HOP_BY_HOP.contains(&name.as_str())
+                    || connection.contains(name)
+                    || name == "content-length"
+                    || name == "traceparent"
+                    || extra.iter().any(|(k, _)| k.eq_ignore_ascii_case(name))
+                {
+                    continue;
+                }
+                if name == "x-forwarded-for" {
+                    forwarded_for = Some(value.clone());
+                    continue;
+                }
+                fields.push((name.clone(), value.clone()));
+            }
+        }
+
+        // the fields are shared by all attempts, the backends serve the same site
+        if request.host().is_none() {
+            let address = self.backends[0].pool.address();
+            fields.push(("host".to_string(), address.to_string()));
+        }
+        if let Some(peer) = request.peer_addr {
+            let chain = match forwarded_for {
+                Some(chain) => format!("{chain}, {}", peer.ip()),
+                None => peer.ip().to_string(),
+            };
+            fields.push(("x-forwarded-for".to_string(), chain));
+        }
+        fields.extend(extra.iter().cloned());
+        // the one upgrade that is passed on, the Sec-WebSocket fields are end-to-end
+        if websocket::is_upgrade(request) {
+            fields.push(("connection".to_string(), "upgrade".to_string()));
+            fields.push(("upgrade".to_string(), "websocket".to_string()));
+        }
+        // the framing the client used is not passed on
+        match length {
+            None => fields.push(("transfer-encoding".to_string(), "chunked".to_string())),
+            Some(length)
+                if length > 0
+                    || matches!(
+                        method,
+                        RequestMethod::Post | RequestMethod::Put | RequestMethod::Patch
+                    ) =>
+            {
+                fields.push(("content-length".to_string(), length.to_string()));
+            }
+            Some(_) => {}
+        }
+        fields
+    }
+
+    // Waits for the 100 Continue to a request whose body was held back. A
+    // final response that comes first is returned, the body is not sent then.
+    // An upstream that says nothing in EXPECT_WAIT gets the body anyway.
+    fn await_continue(
+        &self,
+        connection: &mut Connection,
+        method: &RequestMethod,
+        timeout: Option<Duration>,
+    ) -> Result<Continue, Error> {
+        let wait = timeout.map_or(EXPECT_WAIT, |t| t.min(EXPECT_WAIT));
+        connection.stream().set_read_timeout(Some(wait))?;
+        let mut received: Vec<u8> = Vec::new();
+        let mut buf = [0u8; 1024];
+
+        let result = loop {
+            match response::parse_head_with_limits(&received, method, &self.limits) {
+                Ok((head, read)) if head.status == StatusCode::CONTINUE => {
+                    received.drain(..read);
+                    break Ok(Continue::Send(received));
+                }
+                Ok((head, read)) if head.is_informational() => {
+                    received.drain(..read);
+                    continue;
+                }
+                Ok((mut head, read)) => {
+                    // the upstream still waits for the body, the connection
+                    // cannot take another request
+                    head.headers.insert("Connection", "close");
+                    break Ok(Continue::Refused(head, received.split_off(read)));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => break Err(e),
+            }
+
+            match connection.read(&mut buf) {
+                Ok(0) => {
+                    break Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Upstream closed the connection before the response was complete",
+                    ));
+                }
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(e)
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                        && received.is_empty() =>
+                {
+                    break Ok(Continue::Send(received));
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        connection.stream().set_read_timeout(timeout)?;
+        result
+    }
+
+    // Reads until a final response head is complete, starting with what was
+    // `received` already. Interim 1xx responses are skipped, a 101 is final
+    // when the request asked to `upgrade`.
+    fn read_response_head(
+        &self,
+        stream: &mut impl Read,
+        mut received: Vec<u8>,
+        method: &RequestMethod,
+        upgrade: bool,
+    ) -> Result<(response::ParsedResponse, Vec<u8>), Error> {
+        let mut buf = [0u8; 8 * 1024];
+
+        loop {
+            match response::parse_head_with_limits(&received, method, &self.limits) {
+                Ok((head, read))
+                    if head.is_informational()
+                        && !(upgrade && head.status == StatusCode::SWITCHING_PROTOCOLS) =>
+                {
+                    received.drain(..read);
+                    continue;
+                }
+                Ok((head, read)) => return Ok((head, received.split_off(read))),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Upstream closed the connection before the response was complete",
+                ));
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+    }
+}
+
+// Takes a connection from the pool, or opens a new one, within a span
+fn connect(
+    pool: &Arc<Pool>,
+    trace: Option<&TraceContext>,
+    open: fn(&Arc<Pool>) -> Result<Connection, Error>,
+) -> Result<Connection, Error> {
+    let mut span = Span::start("upstream connect", SpanKind::Internal, trace);
+    span.set_attribute("server.address", pool.address());
+    let result = open(pool);
+    match &result {
+        Ok(connection) => span.set_attribute("lb.connection.reused", connection.is_reused()),
+        Err(e) => {
+            span.set_attribute("error.message", e);
+            span.set_error();
+        }
+    }
+    span.end();
+    result
+}
+
+fn upstream_span(
+    connection: &Connection,
+    method: &RequestMethod,
+    trace: Option<&TraceContext>,
+) -> Span {
+    let mut span = Span::start("upstream response", SpanKind::Client, trace);
+    span.set_attribute("server.address", connection.address());
+    span.set_attribute("http.request.method", method.as_str());
+    span
+}
+
+// Ends the span of an upstream call with the status it got or its error
+fn end_span(mut span: Span, result: Result<StatusCode, &Error>) {
+    match result {
+        Ok(status) => {
+            span.set_attribute("http.response.status_code", status.as_u16());
+            if status.is_server_error() {
+                span.set_error();
+            }
+        }
+        Err(e) => {
+            span.set_attribute("error.message", e);
+            span.set_error();
+        }
+    }
+    span.end();
+}
+
+// The HTTP/1.1 request line and fields
+fn request_head(
+    request: &Request,
+    method: &RequestMethod,
+    fields: &[(String, String)],
+    traceparent: &str,
+) -> Vec<u8> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\n",
+        method.as_str(),
+        request.path.as_deref().unwrap_or("/")
+    );
+    for (name, value) in fields {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!("traceparent: {traceparent}\r\n\r\n"));
+    head.into_bytes()
+}
+
+// Writes the request body, as chunks when its length is not known
+fn send_body(connection: &mut Connection, body: &mut OutgoingBody) -> io::Result<()> {
+    let chunked = body.length().is_none();
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let n = body.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        match chunked {
+            true => {
+                let mut chunk = format!("{n:x}\r\n").into_bytes();
+                chunk.extend_from_slice(&buf[..n]);
+                chunk.extend_from_slice(b"\r\n");
+                connection.write_all(&chunk)?;
+            }
+            false => connection.write_all(&buf[..n])?,
+        }
+    }
+    match chunked {
+        true => connection.write_all(b"0\r\n\r\n"),
+        false => Ok(()),
+    }
+}
+
+fn set_timeouts(connection: &Connection, timeout: Option<Duration>) -> io::Result<()> {
+    connection.stream().set_read_timeout(timeout)?;
+    connection.stream().set_write_timeout(timeout)
+}
+
+// The fields of an upstream response in the order they came, without the
+// hop-by-hop ones and those the upstream named in Connection
+fn end_to_end(fields: &[(String, String)]) -> impl Iterator<Item = (&str, &str)> {
+    let connection: Vec<String> = fields
+        .iter()
+        .filter(|(name, _)| name == "connection")
+        .flat_map(|(_, value)| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .collect();
+    fields
+        .iter()
+        .filter(move |(name, _)| !HOP_BY_HOP.contains(&name.as_str()) && !connection.contains(name))
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+}
+
+// HTTP/1.1 upstreams keep the connection unless they say close
+fn upstream_keep_alive(head: &response::ParsedResponse) -> bool {
+    let close = head
+        .headers
+        .get("Connection")
+        .is_some_and(|c| c.split(',').any(|o| o.trim().eq_ignore_ascii_case("close")));
+    head.version == "HTTP/1.1" && !close
+}
+
+// What an upstream said to a request that holds back its body
+enum Continue {
+    // the body is wanted, what came after the 100 is the start of the response
+    Send(Vec<u8>),
+    // the final response, the body is not sent
+    Refused(response::ParsedResponse, Vec<u8>),
+}
+
+// What every attempt at a request sends alike
+#[derive(Clone, Copy)]
+struct Outbound<'a> {
+    request: &'a Request,
+    method: &'a RequestMethod,
+    fields: &'a [(String, String)],
+    // the context the upstream spans continue
+    trace: Option<&'a TraceContext>,
+}
+
+// The request body as it goes upstream: the one the request holds, or one
+// still coming from the client. What is read of the latter is kept, up to
+// `keep` bytes, so that another attempt can send it again.
+struct OutgoingBody<'a> {
+    kept: Cow<'a, [u8]>,
+    source: Option<&'a mut dyn Read>,
+    length: Option<u64>,
+    keep: usize,
+    // how far the current attempt is into `kept`
+    position: usize,
+    // more came than could be kept, the body can only be sent once
+    spilled: bool,
+    // reading the client's body failed, with this error
+    failed: Option<Error>,
+}
+
+impl<'a> OutgoingBody<'a> {
+    fn buffered(body: &'a [u8]) -> Self {
+        OutgoingBody {
+            length: Some(body.len() as u64),
+            kept: Cow::Borrowed(body),
+            source: None,
+            keep: body.len(),
+            position: 0,
+            spilled: false,
+            failed: None,
+        }
+    }
+
+    fn streamed(body: IncomingBody<'a>, keep: usize) -> Self {
+        OutgoingBody {
+            kept: Cow::Owned(Vec::new()),
+            source: Some(body.reader),
+            length: body.length,
+            keep,
+            position: 0,
+            spilled: false,
+            failed: None,
+        }
+    }
+
+    // None when the body goes chunked
+    fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    fn is_empty(&self) -> bool {
+        self.length == Some(0)
+    }
+
+    // Starts the body over for another attempt, false when it cannot be sent
+    // again
+    fn rewind(&mut self) -> bool {
+        if self.spilled || self.failed.is_some() {
+            return false;
+        }
+        self.position = 0;
+        true
+    }
+}
+
+impl Read for OutgoingBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position < self.kept.len() {
+            let n = buf.len().min(self.kept.len() - self.position);
+            buf[..n].copy_from_slice(&self.kept[self.position..self.position + n]);
+            self.position += n;
+            return Ok(n);
+        }
+        let Some(source) = self.source.as_mut() else {
+            return Ok(0);
+        };
+
+        let n = match source.read(buf) {
+            Ok(n) => n,
+            Err(e) => {
+                let error = Error::new(e.kind(), e.to_string());
+                self.failed = Some(e);
+                return Err(error);
+            }
+        };
+        if !self.spilled {
+            match self.kept.len() + n <= self.keep {
+                true => {
+                    self.kept.to_mut().extend_from_slice(&buf[..n]);
+                    self.position += n;
+                }
+                false => {
+                    self.spilled = true;
+                    self.kept = Cow::Owned(Vec::new());
+                    self.position = 0;
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+// What a backend answered, the body still to be read
+enum Exchanged {
+    Http1(Connection, response::ParsedResponse, Vec<u8>),
+    Http2(StatusCode, ClientResponse<Connection>),
+}
+
+impl Exchanged {
+    fn status(&self) -> StatusCode {
+        match self {
+            Exchanged::Http1(_, head, _) => head.status,
+            Exchanged::Http2(status, _) => *status,
+        }
+    }
+}
+
+// Reads the response body off a pooled connection, which goes back to the
+// pool once the body is complete and nothing was sent past it
+struct PooledBody {
+    body: Option<BodyReader<Connection>>,
+    reusable: bool,
+    permits: Vec<Permit>,
+    // the trailers of a chunked body
+    trailers: Option<LateTrailers>,
+}
+
+impl PooledBody {
+    fn finish(mut self) {
+        if let Some(body) = self.body.take()
+            && self.reusable
+            && body.is_done()
+            && body.leftover().is_empty()
+        {
+            body.into_inner().release();
+        }
+    }
+}
+
+impl Read for PooledBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let body = match self.body.as_mut() {
+            Some(body) => body,
+            None => return Ok(0),
+        };
+
+        let n = body.read(buf)?;
+        if body.is_done() {
+            if let (Some(trailers), Some(received)) = (&self.trailers, body.trailers()) {
+                *trailers.lock().unwrap() = received
+                    .iter()
+                    .filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_str()))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect();
+            }
+            let done = PooledBody {
+                body: self.body.take(),
+                reusable: self.reusable,
+                permits: std::mem::take(&mut self.permits),
+                trailers: None,
+            };
+            done.finish();
+        }
+        Ok(n)
+    }
+}
+
+// The response read over HTTP/2. A response without a body may have its gRPC
+// status among the headers, it is passed on in the trailers.
+fn http2_response(
+    status: StatusCode,
+    upstream: ClientResponse<Connection>,
+    permits: Vec<Permit>,
+) -> Response {
+    let mut response = Response::new(status);
+    let ended = upstream.body.is_ended();
+    for (name, value) in end_to_end(&upstream.headers) {
+        if name == "server" {
+            response.remove_header("Server");
+        }
+        if name == "content-length" {
+            continue;
+        }
+        match ended && name.starts_with("grpc-") {
+            true => response
+                .trailers
+                .push((name.to_string(), value.to_string())),
+            false => response.append_header(name, value),
+        }
+    }
+    if !ended {
+        let trailers = LateTrailers::default();
+        response.late_trailers = Some(Arc::clone(&trailers));
+        response.body = ResponseBody::Stream(Box::new(Http2Body {
+            body: upstream.body,
+            trailers,
+            permits,
+        }));
+    }
+    response
+}
+
+// Reads the body of an HTTP/2 response, the trailers are handed on at its end
+struct Http2Body {
+    body: ClientBody<Connection>,
+    trailers: LateTrailers,
+    permits: Vec<Permit>,
+}
+
+impl Read for Http2Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.body.read(buf)?;
+        if self.body.is_ended() {
+            *self.trailers.lock().unwrap() = self.body.trailers().to_vec();
+            self.permits.clear();
+        }
+        Ok(n)
+    }
+}
+
+fn bad_gateway(e: &Error) -> Response {
+    let status = match e.kind() {
+        ErrorKind::ResourceBusy => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorKind::TimedOut | ErrorKind::WouldBlock => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    Response::builder(status).body(status.reason()).build()
+}
+
+fn from_cache(request: &Request, entry: &Entry, now: SystemTime, outcome: &str) -> Response {
+    let method = request.method.clone().unwrap_or(RequestMethod::Get);
+    let mut response = match &request.headers {
+        Some(headers) if cache::not_modified(headers, entry) => {
+            let mut response = Response::new(StatusCode::NOT_MODIFIED);
+            for name in [
+                "ETag",
+                "Last-Modified",
+                "Cache-Control",
+                "Expires",
+                "Vary",
+                "Date",
+            ] {
+                if let Some(value) = entry.header(name) {
+                    response.append_header(name, value);
+                }
+            }
+            response
+        }
+        _ => entry.to_response(&method, now),
+    };
+    response.set_header("X-Cache", outcome);
+    response
+}
+
+fn drain(body: ResponseBody) -> io::Result<u64> {
+    match body {
+        ResponseBody::Empty | ResponseBody::Bytes(_) => Ok(0),
+        ResponseBody::Fixed(reader, len) => io::copy(&mut reader.take(len), &mut io::sink()),
+        ResponseBody::Stream(mut reader) => io::copy(&mut reader, &mut io::sink()),
+    }
+}
+
+// Stores a storable response once its body has been read through, responses
+// without a body are stored right away
+fn store_on_read(
+    request: &Request,
+    cache: &Arc<Cache>,
+    mut response: Response,
+    request_time: SystemTime,
+) -> Response {
+    if !cache::is_storable(request, response.status, &response.headers) {
+        return response;
+    }
+    let entry = Entry::new(
+        request,
+        response.status,
+        &response.headers,
+        request_time,
+        SystemTime::now(),
+    );
+
+    response.body = match std::mem::replace(&mut response.body, ResponseBody::Empty) {
+        ResponseBody::Empty | ResponseBody::Fixed(_, 0) => {
+            cache.store(request, entry);
+            ResponseBody::Empty
+        }
+        ResponseBody::Bytes(body) => {
+            let mut entry = entry;
+            entry.body = body.clone();
+            cache.store(request, entry);
+            ResponseBody::Bytes(body)
+        }
+        ResponseBody::Fixed(reader, len) => ResponseBody::Fixed(
+            Box::new(StoringReader::new(reader, Some(len), request, cache, entry)),
+            len,
+        ),
+        ResponseBody::Stream(reader) => ResponseBody::Stream(Box::new(StoringReader::new(
+            reader, None, request, cache, entry,
+        ))),
+    };
+    response
+}
+
+// Copies the body into the entry as it is sent to the client, the entry is
+// given up on when the body turns out too large for the cache
+struct StoringReader {
+    inner: Box<dyn Read + Send>,
+    // a Fixed body is not read past its length, so EOF is never seen
+    expected: Option<u64>,
+    request: Request,
+    cache: Arc<Cache>,
+    entry: Option<Entry>,
+}
+
+impl StoringReader {
+    fn new(
+        inner: Box<dyn Read + Send>,
+        expected: Option<u64>,
+        request: &Request,
+        cache: &Arc<Cache>,
+        entry: Entry,
+    ) -> Self {
+        StoringReader {
+            inner,
+            expected,
+            request: request.clone(),
+            cache: Arc::clone(cache),
+            entry: Some(entry),
+        }
+    }
+}
+
+impl Read for StoringReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf).inspect_err(|_| self.entry = None)?;
+
+        if let Some(entry) = self.entry.as_mut() {
+            entry.body.extend_from_slice(&buf[..n]);
+            let complete = match self.expected {
+                Some(len) => entry.body.len() as u64 >= len,
+                None => n == 0,
+            };
+
+            if entry.size() > self.cache.config().max_entry_bytes {
+                self.entry = None;
+            } else if complete && let Some(entry) = self.entry.take() {
+                self.cache.store(&self.request, entry);
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::request::parse;
+    use crate::internal::{
+        breaker::CircuitState,
+        cache::CacheConfig,
+        config::Config,
+        connection::{ClientStream, ConnectionLimits, ConnectionTracker},
+        http2,
+    };
+    use std::{
+        net::TcpListener,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    // Answers every connection with the next canned response, returns the
+    // upstream address and how many requests it got
+    fn upstream(
+        responses: Vec<&'static str>,
+    ) -> (String, Arc<AtomicUsize>, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&hits);
+
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let mut received = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8(received).unwrap());
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        (addr, hits, handle)
+    }
+
+    fn send(response: Response) -> response::ParsedResponse {
+        let mut out = Vec::new();
+        response.send(&mut out).unwrap();
+        response::parse(&out, &RequestMethod::Get).unwrap()
+    }
+
+    #[test]
+    fn test_forward() {
+        let (addr, _, handle) = upstream(vec![
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nServer: backend\r\nConnection: close\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        ]);
+
+        let mut request = parse(
+            b"POST /items?x=1 HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, X-Secret\r\nX-Secret: 1\r\nX-Forwarded-For: 10.0.0.1\r\ntraceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\r\ntracestate: a=1\r\nContent-Length: 2\r\n\r\nhi",
+        )
+        .unwrap();
+        request.peer_addr = Some("192.168.1.2:5000".parse().unwrap());
+
+        let response = send(Proxy::new(addr).serve(&request));
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(response.headers.get("server"), Some("backend"));
+        assert_eq!(response.body, b"hello");
+
+        let forwarded = handle.join().unwrap().remove(0);
+        assert!(forwarded.starts_with("POST /items?x=1 HTTP/1.1\r\n"));
+        assert!(forwarded.contains("host: example.com\r\n"));
+        assert!(forwarded.contains("x-forwarded-for: 10.0.0.1, 192.168.1.2\r\n"));
+        assert!(forwarded.contains("content-length: 2\r\n"));
+        assert!(!forwarded.contains("x-secret"));
+        assert!(!forwarded.contains("keep-alive"));
+        // the trace goes on from the span of the upstream call
+        assert!(forwarded.contains("traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(!forwarded.contains("00f067aa0ba902b7"));
+        assert!(forwarded.contains("tracestate: a=1\r\n"));
+    }
+
+    #[test]
+    fn test_response_fields() {
+        let (addr, _, handle) = upstream(vec![
+            "HTTP/1.1 200 OK\r\nSet-Cookie: a=1; Path=/\r\nConnection: close, X-Hop\r\nX-Hop: 1\r\nVary: Accept\r\nSet-Cookie: b=2, c=3\r\nContent-Length: 0\r\n\r\n",
+        ]);
+        let request = parse(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let response = send(Proxy::new(addr).serve(&request));
+        handle.join().unwrap();
+
+        // each cookie stays a field of its own, in the order the upstream sent them
+        let fields: Vec<(&str, &str)> = response
+            .fields
+            .iter()
+            .filter(|(name, _)| {
+                !["server", "connection", "content-length"].contains(&name.as_str())
+            })
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("set-cookie", "a=1; Path=/"),
+                ("vary", "Accept"),
+                ("set-cookie", "b=2, c=3"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // what the upstream writes after the head, and after the body if it reads one
+        let cases = [
+            // turned down from the head, the connection is closed without the body
+            (
+                "HTTP/1.1 413 Content Too Large\r\nContent-Length: 0\r\n\r\n",
+                None,
+            ),
+            (
+                "HTTP/1.1 100 Continue\r\n\r\n",
+                Some("HTTP/1.1 201 Created\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"),
+            ),
+            // the final response comes in the same write as the 100
+            (
+                "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n",
+                Some(""),
+            ),
+        ];
+        let handle = thread::spawn(move || {
+            let mut sent = Vec::new();
+            for (before, after) in cases {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut received = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                }
+                assert!(String::from_utf8_lossy(&received).contains("expect: 100-continue\r\n"));
+                stream.write_all(before.as_bytes()).unwrap();
+                let end = received.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+                let mut body = received.split_off(end);
+                match after {
+                    Some(after) => {
+                        while body.len() < 5 {
+                            let n = stream.read(&mut buf).unwrap();
+                            body.extend_from_slice(&buf[..n]);
+                        }
+                        stream.write_all(after.as_bytes()).unwrap();
+                    }
+                    None => {
+                        stream.read_to_end(&mut body).unwrap();
+                    }
+                }
+                sent.push(body);
+            }
+            sent
+        });
+
+        let proxy = Proxy::new(addr);
+        let request = parse(
+            b"PUT /upload HTTP/1.1\r\nHost: h\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .unwrap();
+        assert_eq!(proxy.serve(&request).status, StatusCode::CONTENT_TOO_LARGE);
+        assert_eq!(proxy.serve(&request).status, StatusCode::CREATED);
+        assert_eq!(proxy.serve(&request).status, StatusCode::ACCEPTED);
+        assert_eq!(
+            handle.join().unwrap(),
+            [b"".to_vec(), b"hello".to_vec(), b"hello".to_vec()]
+        );
+    }
+
+    // A client body that goes ahead once the upstream asked for it, as a
+    // client waiting for 100 Continue would
+    struct Held<'a> {
+        data: &'a [u8],
+        continued: &'a AtomicBool,
+        read: bool,
+    }
+
+    impl Read for Held<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            assert!(self.continued.load(Ordering::SeqCst));
+            self.read = true;
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_expect_continue_streamed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let continued = Arc::new(AtomicBool::new(false));
+        let go_ahead = Arc::clone(&continued);
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for refuse in [true, false] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut received = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                }
+                let end = received.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+                let mut body = received.split_off(end);
+                if refuse {
+                    let response = "HTTP/1.1 413 Content Too Large\r\nContent-Length: 0\r\n\r\n";
+                    stream.write_all(response.as_bytes()).unwrap();
+                    stream.read_to_end(&mut body).unwrap();
+                } else {
+                    go_ahead.store(true, Ordering::SeqCst);
+                    stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+                    while body.len() < 5 {
+                        let n = stream.read(&mut buf).unwrap();
+                        body.extend_from_slice(&buf[..n]);
+                    }
+                    let response = "HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n";
+                    stream.write_all(response.as_bytes()).unwrap();
+                }
+                bodies.push(body);
+            }
+            bodies
+        });
+
+        let proxy = Proxy::new(addr);
+        let head =
+            b"PUT /upload HTTP/1.1\r\nHost: h\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n";
+        let (request, _) = request::parse_head_with_limits(head, &Limits::default()).unwrap();
+        // the body is not asked for when the upstream turns it down
+        let mut held = Held {
+            data: b"hello",
+            continued: &continued,
+            read: false,
+        };
+        let body = IncomingBody {
+            reader: &mut held,
+            length: Some(5),
+        };
+        let status = proxy.serve_streaming(&request, body).status;
+        assert_eq!(status, StatusCode::CONTENT_TOO_LARGE);
+        assert!(!held.read);
+
+        let mut held = Held {
+            data: b"hello",
+            continued: &continued,
+            read: false,
+        };
+        let body = IncomingBody {
+            reader: &mut held,
+            length: Some(5),
+        };
+        let status = proxy.serve_streaming(&request, body).status;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(held.read);
+        assert_eq!(handle.join().unwrap(), [b"".to_vec(), b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn test_pooled_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // one connection answering two requests before it goes away
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            for body in ["one", "two"] {
+                let mut received = Vec::new();
+                while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                }
+                assert!(
+                    !String::from_utf8(received)
+                        .unwrap()
+                        .contains("connection: close")
+                );
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n{body}");
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            drop(stream);
+            listener.accept().unwrap()
+        });
+
+        let proxy = Proxy::new(addr);
+        let request = parse(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        assert_eq!(send(proxy.serve(&request)).body, b"one");
+        assert_eq!(
+            (
+                proxy.backends()[0].pool().idle(),
+                proxy.backends()[0].pool().in_flight()
+            ),
+            (1, 0)
+        );
+        assert_eq!(send(proxy.serve(&request)).body, b"two");
+
+        // the backend closed the idle connection, the next request opens a new one
+        let client = thread::spawn(move || send(proxy.serve(&request)).body);
+        let (mut stream, _) = handle.join().unwrap();
+        let _ = stream.read(&mut [0u8; 1024]).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nthree")
+            .unwrap();
+        assert_eq!(client.join().unwrap(), b"three");
+    }
+
+    #[test]
+    fn test_failover() {
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead_addr = dead.local_addr().unwrap().to_string();
+        drop(dead);
+        let unavailable =
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+        let (failing, _, failing_handle) = upstream(vec![unavailable; 3]);
+        let (working, hits, working_handle) = upstream(vec![ok; 3]);
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            retry_on: "connect,503".parse().unwrap(),
+            backoff_base: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        };
+        let proxy = Proxy::new(dead_addr)
+            .with_backend(failing)
+            .with_backend(working)
+            .with_retry(retry);
+
+        // refused, then 503, then served by the third backend
+        let get = parse(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let response = send(proxy.serve(&get));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body, b"ok");
+
+        // the buffered body is sent again on the retry
+        let put = parse(b"PUT / HTTP/1.1\r\nHost: h\r\nContent-Length: 1\r\n\r\nx").unwrap();
+        assert_eq!(send(proxy.serve(&put)).status, StatusCode::OK);
+        assert_eq!(send(proxy.serve(&get)).status, StatusCode::OK);
+
+        // POST moves on after the connection failure, but not after the 503
+        let post = parse(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 1\r\n\r\nx").unwrap();
+        let response = send(proxy.serve(&post));
+        assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+        let forwarded = working_handle.join().unwrap();
+        assert!(forwarded[1].starts_with("PUT / HTTP/1.1\r\n"));
+        assert_eq!(failing_handle.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_weights() {
+        let proxy = Proxy::new("127.0.0.1:1").with_backend("127.0.0.1:2");
+        proxy.backends()[0].set_weight(3);
+        let picks: Vec<usize> = (0..8).map(|_| proxy.pick()).collect();
+        assert_eq!(picks, [0, 0, 0, 1, 0, 0, 0, 1]);
+
+        // a draining backend is passed over, even when it is picked
+        proxy.backends()[0].set_draining(true);
+        let request = parse(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let outbound = Outbound {
+            request: &request,
+            method: &RequestMethod::Get,
+            fields: &[],
+            trace: None,
+        };
+        let body = &mut OutgoingBody::buffered(&request.body);
+        let error = proxy.attempt(0, &outbound, &None, body).unwrap_err();
+        assert!(error.0);
+        assert_eq!(error.1.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead_addr = dead.local_addr().unwrap().to_string();
+        drop(dead);
+        let (fallback, _, fallback_handle) = upstream(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\nfallback",
+        ]);
+        let config = BreakerConfig {
+            min_requests: 2,
+            ..BreakerConfig::default()
+        };
+        let request = parse(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+
+        let proxy = Proxy::new(&dead_addr).with_backend_breaker(config.clone());
+        for _ in 0..2 {
+            assert_eq!(proxy.serve(&request).status, StatusCode::BAD_GATEWAY);
+        }
+        let breaker = proxy.backends()[0].breaker().unwrap();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        // fails fast without trying the backend
+        assert_eq!(
+            proxy.serve(&request).status,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let proxy = Proxy::new(dead_addr)
+            .with_breaker(config)
+            .with_fallback(Proxy::new(fallback));
+        for _ in 0..2 {
+            assert_eq!(proxy.serve(&request).status, StatusCode::BAD_GATEWAY);
+        }
+        let response = send(proxy.serve(&request));
+        assert_eq!(response.body, b"fallback");
+        fallback_handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_unreachable_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let request = parse(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let response = Proxy::new(addr).serve(&request);
+        assert_eq!(response.status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn test_trailers_passed_on() {
+        let (addr, _, handle) = upstream(vec![
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: x-sum\r\n\r\n3\r\nabc\r\n0\r\nx-sum: 6\r\n\r\n",
+        ]);
+        let request = parse(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let parsed = send(Proxy::new(addr).serve(&request));
+        assert_eq!(parsed.body, b"abc");
+        assert_eq!(parsed.trailers.unwrap().get("x-sum"), Some("6"));
+        handle.join().unwrap();
+    }
+
+    // An HTTP/2 server answering every connection with `handler`
+    fn http2_upstream(handler: fn(Result<Request, Error>, &mut http2::Responder)) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = ClientStream::from(stream.unwrap());
+                thread::spawn(move || {
+                    let tracker = ConnectionTracker::new(ConnectionLimits::default());
+                    let guard = tracker.acquire(None).unwrap();
+                    http2::serve(&stream, &[], None, &Config::default(), &guard, &handler);
+                });
+            }
+        });
+        addr
+    }
+
+    fn grpc_request(path: &str) -> Request {
+        let head = format!(
+            "POST {path} HTTP/1.1\r\nHost: h\r\nContent-Type: application/grpc\r\nTE: trailers\r\nContent-Length: 5\r\n\r\n\0\0\0\0\0"
+        );
+        parse(head.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_grpc() {
+        let addr = http2_upstream(|request, responder| {
+            let request = request.unwrap();
+            let headers = request.headers.unwrap();
+            let response = match request.path.as_deref() {
+                Some("/echo.Echo/Say") if headers.get("te") == Some("trailers") => {
+                    Response::builder(StatusCode::OK)
+                        .header("Content-Type", "application/grpc")
+                        .stream(io::Cursor::new(request.body))
+                        .trailer("grpc-status", "0")
+                        .build()
+                }
+                // trailers-only, the status comes with the headers
+                Some("/echo.Echo/Fail") => Response::builder(StatusCode::OK)
+                    .header("Content-Type", "application/grpc")
+                    .header("grpc-status", "5")
+                    .build(),
+                _ => Response::new(StatusCode::NOT_FOUND),
+            };
+            let _ = responder.send(response);
+        });
+        let proxy = Proxy::new(&addr).with_http2();
+
+        let mut response = proxy.serve(&grpc_request("/echo.Echo/Say"));
+        assert_eq!(response.status, StatusCode::OK);
+        let mut body = Vec::new();
+        let ResponseBody::Stream(mut reader) =
+            std::mem::replace(&mut response.body, ResponseBody::Empty)
+        else {
+            panic!("body not streamed");
+        };
+        reader.read_to_end(&mut body).unwrap();
+        assert_eq!(body, [0; 5]);
+        let trailers = response.late_trailers.unwrap();
+        assert_eq!(
+            *trailers.lock().unwrap(),
+            [("grpc-status".to_string(), "0".to_string())]
+        );
+
+        let response = proxy.serve(&grpc_request("/echo.Echo/Fail"));
+        assert_eq!(
+            response.trailers,
+            [("grpc-status".to_string(), "5".to_string())]
+        );
+
+        // a plain HTTP error becomes the status a gRPC client would make of it
+        let response = proxy.serve(&grpc_request("/echo.Missing/Say"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.trailers[0].1, grpc::UNIMPLEMENTED.to_string());
+
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead_addr = dead.local_addr().unwrap().to_string();
+        drop(dead);
+        let response = Proxy::new(dead_addr).serve(&grpc_request("/echo.Echo/Say"));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.trailers[0].1, grpc::UNAVAILABLE.to_string());
+    }
+
+    #[test]
+    fn test_health_check() {
+        let serving = http2_upstream(|_, responder| {
+            let _ = responder.send(
+                Response::builder(StatusCode::OK)
+                    .header("Content-Type", "application/grpc")
+                    .body([0, 0, 0, 0, 2, 0x08, 1])
+                    .trailer("grpc-status", "0")
+                    .build(),
+            );
+        });
+        let not_serving = http2_upstream(|_, responder| {
+            let _ = responder.send(
+                Response::builder(StatusCode::OK)
+                    .header("Content-Type", "application/grpc")
+                    .body([0, 0, 0, 0, 2, 0x08, 2])
+                    .trailer("grpc-status", "0")
+                    .build(),
+            );
+        });
+        let proxy = Proxy::new(serving)
+            .with_backend(not_serving)
+            .with_http2()
+            .with_health_check(HealthCheck {
+                interval: Duration::from_millis(20),
+                ..HealthCheck::default()
+            });
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while proxy.backends()[1].is_healthy() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(proxy.backends()[0].is_healthy());
+        assert!(!proxy.backends()[1].is_healthy());
+        assert!(!proxy.backends()[1].allows());
+    }
+
+    #[test]
+    fn test_cached_proxy() {
+        let (addr, hits, handle) = upstream(vec![
+            "HTTP/1.1 200 OK\r\nCache-Control: max-age=0\r\nETag: \"v1\"\r\nContent-Length: 5\r\n\r\nfirst",
+            "HTTP/1.1 304 Not Modified\r\nCache-Control: max-age=60\r\nETag: \"v1\"\r\n\r\n",
+            "HTTP/1.1 204 No Content\r\n\r\n",
+        ]);
+        let cache = Arc::new(Cache::new(CacheConfig::default()));
+        let proxy = Proxy::new(addr).with_cache(Arc::clone(&cache));
+        let get = parse(b"GET /doc HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+
+        let miss = proxy.serve(&get);
+        assert_eq!(miss.header("X-Cache"), Some("MISS"));
+        assert_eq!(send(miss).body, b"first");
+
+        // max-age=0 makes it stale right away, the 304 freshens it
+        let revalidated = send(proxy.serve(&get));
+        assert_eq!(revalidated.headers.get("x-cache"), Some("REVALIDATED"));
+        assert_eq!(revalidated.body, b"first");
+
+        let hit = send(proxy.serve(&get));
+        assert_eq!(hit.headers.get("x-cache"), Some("HIT"));
+        assert_eq!(hit.headers.get("cache-control"), Some("max-age=60"));
+        assert_eq!(hit.body, b"first");
+
+        let conditional =
+            parse(b"GET /doc HTTP/1.1\r\nHost: h\r\nIf-None-Match: \"v1\"\r\n\r\n").unwrap();
+        assert_eq!(proxy.serve(&conditional).status, StatusCode::NOT_MODIFIED);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // a successful DELETE invalidates the stored GET
+        let delete = parse(b"DELETE /doc HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        assert_eq!(proxy.serve(&delete).status, StatusCode::NO_CONTENT);
+        assert_eq!(cache.lookup(&get, SystemTime::now()), Lookup::Miss);
+
+        let requests = handle.join().unwrap();
+        assert!(requests[1].contains("If-None-Match: \"v1\"\r\n"));
+    }
+}
@@ -0,0 +1,478 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::internal::{
+    request::Request,
+    response::{Response, StatusCode},
+};
+
+// What requests are counted together
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateLimitKey {
+    ClientIp,
+    // e.g. an API key, requests without the field are counted by client IP
+    Header(String),
+    // every client of a route shares one limit
+    Route,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    // `limit` requests per `window` on average, bursts of up to `burst` at once
+    TokenBucket,
+    // at most `limit` requests in any `window`, approximated from the counts
+    // of the current and the previous fixed window
+    SlidingWindow,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitRule {
+    pub key: RateLimitKey,
+    pub algorithm: Algorithm,
+    pub limit: u64,
+    pub window: Duration,
+    // token bucket capacity, defaults to `limit`
+    pub burst: u64,
+}
+
+impl RateLimitRule {
+    pub fn new(key: RateLimitKey, limit: u64, window: Duration) -> Self {
+        RateLimitRule {
+            key,
+            algorithm: Algorithm::TokenBucket,
+            limit,
+            window,
+            burst: limit,
+        }
+    }
+
+    pub fn with_burst(mut self, burst: u64) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    pub fn sliding(mut self) -> Self {
+        self.algorithm = Algorithm::SlidingWindow;
+        self
+    }
+}
+
+// <key>=<limit>/<seconds>[,burst=<n>][,sliding] where key is ip, route or header:<name>
+impl FromStr for RateLimitRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid rate limit {s}");
+        let mut parts = s.split(',');
+
+        let (key, rate) = parts
+            .next()
+            .and_then(|p| p.split_once('='))
+            .ok_or_else(invalid)?;
+        let key = match key {
+            "ip" => RateLimitKey::ClientIp,
+            "route" => RateLimitKey::Route,
+            _ => match key.strip_prefix("header:") {
+                Some(name) if !name.is_empty() => RateLimitKey::Header(name.to_string()),
+                _ => return Err(invalid()),
+            },
+        };
+        let (limit, seconds) = rate.split_once('/').ok_or_else(invalid)?;
+        let limit = limit.parse::<u64>().map_err(|_| invalid())?;
+        let seconds = seconds.parse::<u64>().map_err(|_| invalid())?;
+        if limit == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+
+        let mut rule = RateLimitRule::new(key, limit, Duration::from_secs(seconds));
+        for option in parts {
+            match option.split_once('=') {
+                // an empty bucket would turn every request away
+                Some(("burst", n)) => match n.parse() {
+                    Ok(0) | Err(_) => return Err(invalid()),
+                    Ok(burst) => rule.burst = burst,
+                },
+                None if option == "sliding" => rule = rule.sliding(),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Allowed {
+        limit: u64,
+        remaining: u64,
+        // until the quota is back to full
+        reset: Duration,
+    },
+    Limited {
+        limit: u64,
+        retry_after: Duration,
+    },
+}
+
+impl Decision {
+    fn remaining(&self) -> u64 {
+        match self {
+            Decision::Allowed { remaining, .. } => *remaining,
+            Decision::Limited { .. } => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Bucket {
+        tokens: f64,
+        updated: Instant,
+    },
+    Window {
+        start: Instant,
+        current: u64,
+        previous: u64,
+    },
+}
+
+// Keys that have not been seen for this many windows are dropped once the
+// table grows past MAX_KEYS
+const MAX_KEYS: usize = 100_000;
+const SHARDS: usize = 16;
+
+// A limiter can be put in an Arc and shared by every worker thread, the keys
+// are spread over a few locks so they do not all wait on each other
+#[derive(Debug)]
+pub struct RateLimiter {
+    rule: RateLimitRule,
+    shards: Vec<Mutex<HashMap<String, State>>>,
+}
+
+impl RateLimiter {
+    pub fn new(rule: RateLimitRule) -> Self {
+        RateLimiter {
+            rule,
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    pub fn rule(&self) -> &RateLimitRule {
+        &self.rule
+    }
+
+    // `route` is the prefix of the route the request matched
+    pub fn key(&self, request: &Request, route: &str) -> String {
+        let ip = || {
+            request
+                .peer_addr
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default()
+        };
+
+        match &self.rule.key {
+            RateLimitKey::ClientIp => format!("ip:{}", ip()),
+            RateLimitKey::Header(name) => {
+                match request.headers.as_ref().and_then(|h| h.get(name)) {
+                    Some(value) => format!("header:{value}"),
+                    None => format!("ip:{}", ip()),
+                }
+            }
+            RateLimitKey::Route => format!("route:{route}"),
+        }
+    }
+
+    pub fn check(&self, request: &Request, route: &str, now: Instant) -> Decision {
+        self.check_key(&self.key(request, route), now)
+    }
+
+    pub fn check_key(&self, key: &str, now: Instant) -> Decision {
+        let shard = key
+            .bytes()
+            .fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize));
+        let mut states = self.shards[shard % SHARDS].lock().unwrap();
+
+        if states.len() >= MAX_KEYS / SHARDS {
+            let idle = self.rule.window * 2;
+            states.retain(|_, state| match state {
+                State::Bucket { updated, .. } => now.duration_since(*updated) < idle,
+                State::Window { start, .. } => now.duration_since(*start) < idle,
+            });
+        }
+
+        let state = states
+            .entry(key.to_string())
+            .or_insert_with(|| match self.rule.algorithm {
+                Algorithm::TokenBucket => State::Bucket {
+                    tokens: self.rule.burst as f64,
+                    updated: now,
+                },
+                Algorithm::SlidingWindow => State::Window {
+                    start: now,
+                    current: 0,
+                    previous: 0,
+                },
+            });
+
+        match state {
+            State::Bucket { tokens, updated } => self.take_token(tokens, updated, now),
+            State::Window {
+                start,
+                current,
+                previous,
+            } => self.count_in_window(start, current, previous, now),
+        }
+    }
+
+    fn take_token(&self, tokens: &mut f64, updated: &mut Instant, now: Instant) -> Decision {
+        let rule = &self.rule;
+        let per_second = rule.limit as f64 / rule.window.as_secs_f64();
+        let capacity = rule.burst as f64;
+
+        let elapsed = now.saturating_duration_since(*updated).as_secs_f64();
+        *tokens = (*tokens + elapsed * per_second).min(capacity);
+        *updated = now;
+
+        if *tokens < 1.0 {
+            return Decision::Limited {
+                limit: rule.limit,
+                retry_after: Duration::from_secs_f64((1.0 - *tokens) / per_second),
+            };
+        }
+
+        *tokens -= 1.0;
+        Decision::Allowed {
+            limit: rule.limit,
+            remaining: *tokens as u64,
+            reset: Duration::from_secs_f64((capacity - *tokens) / per_second),
+        }
+    }
+
+    fn count_in_window(
+        &self,
+        start: &mut Instant,
+        current: &mut u64,
+        previous: &mut u64,
+        now: Instant,
+    ) -> Decision {
+        let window = self.rule.window;
+
+        let mut elapsed = now.saturating_duration_since(*start);
+        if elapsed >= window {
+            let windows = elapsed.as_nanos() / window.as_nanos();
+            *previous = if windows == 1 { *current } else { 0 };
+            *current = 0;
+            *start += window * windows as u32;
+            elapsed = now.saturating_duration_since(*start);
+        }
+
+        // the previous window counts for the part of it the sliding window still covers
+        let overlap = 1.0 - elapsed.as_secs_f64() / window.as_secs_f64();
+        let estimated = (*previous as f64 * overlap) as u64 + *current;
+        let reset = window - elapsed;
+
+        if estimated >= self.rule.limit {
+            // how long until the previous window's share has dropped by one request,
+            // or the current window is over when that is not enough
+            let retry_after = if *previous > 0 && *current < self.rule.limit {
+                let needed = (estimated + 1 - self.rule.limit) as f64 / *previous as f64;
+                window.mul_f64(needed).min(reset)
+            } else {
+                reset
+            };
+            return Decision::Limited {
+                limit: self.rule.limit,
+                retry_after,
+            };
+        }
+
+        *current += 1;
+        Decision::Allowed {
+            limit: self.rule.limit,
+            remaining: self.rule.limit - estimated - 1,
+            reset,
+        }
+    }
+}
+
+// Runs every limiter, the request is rejected when any of them says so.
+// Otherwise the decision with the least remaining quota is the one reported.
+pub fn check_all<'a>(
+    limiters: impl IntoIterator<Item = &'a RateLimiter>,
+    request: &Request,
+    route: &str,
+) -> Option<Decision> {
+    let now = Instant::now();
+    let mut tightest: Option<Decision> = None;
+
+    for limiter in limiters {
+        let decision = limiter.check(request, route, now);
+        if let Decision::Limited { .. } = decision {
+            return Some(decision);
+        }
+        if tightest
+            .as_ref()
+            .is_none_or(|t| decision.remaining() < t.remaining())
+        {
+            tightest = Some(decision);
+        }
+    }
+
+    tightest
+}
+
+fn ceil_secs(d: Duration) -> u64 {
+    d.as_secs() + u64::from(d.subsec_nanos() > 0)
+}
+
+// RateLimit-* fields from draft-ietf-httpapi-ratelimit-headers
+pub fn add_headers(response: &mut Response, decision: &Decision) {
+    match decision {
+        Decision::Allowed {
+            limit,
+            remaining,
+            reset,
+        } => {
+            response.set_header("RateLimit-Limit", &limit.to_string());
+            response.set_header("RateLimit-Remaining", &remaining.to_string());
+            response.set_header("RateLimit-Reset", &ceil_secs(*reset).to_string());
+        }
+        Decision::Limited { limit, retry_after } => {
+            let seconds = ceil_secs(*retry_after).to_string();
+            response.set_header("RateLimit-Limit", &limit.to_string());
+            response.set_header("RateLimit-Remaining", "0");
+            response.set_header("RateLimit-Reset", &seconds);
+        }
+    }
+}
+
+pub fn too_many_requests(decision: &Decision) -> Response {
+    let mut response = Response::builder(StatusCode::TOO_MANY_REQUESTS)
+        .body(StatusCode::TOO_MANY_REQUESTS.reason())
+        .build();
+    if let Decision::Limited { retry_after, .. } = decision {
+        response.set_header("Retry-After", &ceil_secs(*retry_after).to_string());
+    }
+    add_headers(&mut response, decision);
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::request::parse;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(
+            RateLimitRule::new(RateLimitKey::ClientIp, 2, Duration::from_secs(1)).with_burst(3),
+        );
+        let start = Instant::now();
+
+        for remaining in [2, 1, 0] {
+            match limiter.check_key("a", start) {
+                Decision::Allowed { remaining: r, .. } => assert_eq!(r, remaining),
+                d => panic!("{d:?}"),
+            }
+        }
+        assert_eq!(
+            limiter.check_key("a", start),
+            Decision::Limited {
+                limit: 2,
+                retry_after: Duration::from_millis(500)
+            }
+        );
+        // other keys have their own bucket
+        assert!(matches!(
+            limiter.check_key("b", start),
+            Decision::Allowed { .. }
+        ));
+
+        // two tokens a second come back
+        let later = start + Duration::from_millis(500);
+        assert!(matches!(
+            limiter.check_key("a", later),
+            Decision::Allowed { remaining: 0, .. }
+        ));
+        assert!(matches!(
+            limiter.check_key("a", later),
+            Decision::Limited { .. }
+        ));
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let limiter = RateLimiter::new(
+            RateLimitRule::new(RateLimitKey::Route, 4, Duration::from_secs(10)).sliding(),
+        );
+        let start = Instant::now();
+
+        for _ in 0..4 {
+            assert!(matches!(
+                limiter.check_key("r", start),
+                Decision::Allowed { .. }
+            ));
+        }
+        assert!(matches!(
+            limiter.check_key("r", start + Duration::from_secs(5)),
+            Decision::Limited { .. }
+        ));
+
+        // 7.5s into the next window a quarter of the previous one still counts
+        let next = start + Duration::from_millis(17_500);
+        for _ in 0..3 {
+            assert!(matches!(
+                limiter.check_key("r", next),
+                Decision::Allowed { .. }
+            ));
+        }
+        assert!(matches!(
+            limiter.check_key("r", next),
+            Decision::Limited { .. }
+        ));
+
+        // nothing from two windows back is remembered
+        assert!(matches!(
+            limiter.check_key("r", start + Duration::from_secs(40)),
+            Decision::Allowed { remaining: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn test_rate_limit_keys() {
+        let mut request = parse(b"GET / HTTP/1.1\r\nX-Api-Key: k1\r\n\r\n").unwrap();
+        request.peer_addr = Some("10.0.0.1:1234".parse().unwrap());
+        let mut anonymous = parse(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        anonymous.peer_addr = Some("10.0.0.2:1234".parse().unwrap());
+
+        let rule: RateLimitRule = "header:X-Api-Key=10/60,burst=20".parse().unwrap();
+        assert_eq!(rule.burst, 20);
+        let limiter = RateLimiter::new(rule);
+        assert_eq!(limiter.key(&request, "/api"), "header:k1");
+        assert_eq!(limiter.key(&anonymous, "/api"), "ip:10.0.0.2");
+
+        let limiter = RateLimiter::new("route=1/1,sliding".parse().unwrap());
+        assert_eq!(limiter.rule().algorithm, Algorithm::SlidingWindow);
+        assert_eq!(limiter.key(&request, "/api"), "route:/api");
+
+        assert!("ip=0/60".parse::<RateLimitRule>().is_err());
+        assert!("cookie=1/60".parse::<RateLimitRule>().is_err());
+        assert!("ip=1/60,fast".parse::<RateLimitRule>().is_err());
+        assert!("ip=1/60,burst=0".parse::<RateLimitRule>().is_err());
+    }
+
+    #[test]
+    fn test_too_many_requests() {
+        let response = too_many_requests(&Decision::Limited {
+            limit: 10,
+            retry_after: Duration::from_millis(1500),
+        });
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.header("Retry-After"), Some("2"));
+        assert_eq!(response.header("RateLimit-Limit"), Some("10"));
+        assert_eq!(response.header("RateLimit-Remaining"), Some("0"));
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::{Arc, RwLock};
+
+use crate::internal::{config::Config, router::Router};
+
+// The router and config requests are served with, replaced as a whole on reload
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub router: Router,
+    pub config: Config,
+}
+
+// The current snapshot. A request keeps the one it started with until it is
+// answered, the next request on the connection gets the new one.
+#[derive(Debug)]
+pub struct Live {
+    current: RwLock<Arc<Snapshot>>,
+}
+
+impl Live {
+    pub fn new(snapshot: Snapshot) -> Live {
+        Live {
+            current: RwLock::new(Arc::new(snapshot)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<Snapshot> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    // Returns the snapshot that was current until now
+    pub fn swap(&self, snapshot: Snapshot) -> Arc<Snapshot> {
+        std::mem::replace(&mut *self.current.write().unwrap(), Arc::new(snapshot))
+    }
+}
+
+// What differs between two snapshots, one line per change
+pub fn changes(old: &Snapshot, new: &Snapshot) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    let routes = |router: &Router| -> Vec<(String, String)> {
+        router
+            .routes()
+            .iter()
+            .map(|r| (r.name().to_string(), r.describe()))
+            .collect()
+    };
+    let (before, after) = (routes(&old.router), routes(&new.router));
+    for (prefix, describe) in &after {
+        match before.iter().find(|(p, _)| p == prefix) {
+            None => changes.push(format!("route {prefix} added: {describe}")),
+            Some((_, previous)) if previous != describe => {
+                changes.push(format!("route {prefix} changed: {previous} => {describe}"))
+            }
+            Some(_) => {}
+        }
+    }
+    for (prefix, _) in &before {
+        if !after.iter().any(|(p, _)| p == prefix) {
+            changes.push(format!("route {prefix} removed"));
+        }
+    }
+
+    let rules = |router: &Router| -> Vec<String> {
+        router
+            .rate_limits()
+            .iter()
+            .map(|l| format!("{:?}", l.rule()))
+            .collect()
+    };
+    if rules(&old.router) != rules(&new.router) {
+        changes.push("rate limits changed".to_string());
+    }
+    let access_log = |router: &Router| router.access_log().map(|l| l.config().clone());
+    if access_log(&old.router) != access_log(&new.router) {
+        changes.push("access log changed".to_string());
+    }
+    if old.router.redirects() != new.router.redirects() {
+        changes.push("redirect map changed".to_string());
+    }
+
+    let (a, b) = (&old.config, &new.config);
+    for (name, changed) in [
+        ("limits", a.limits != b.limits),
+        ("compression", a.compression != b.compression),
+        ("timeouts", a.timeouts != b.timeouts),
+        ("connection limits", a.connections != b.connections),
+        ("upstream pools", a.upstream != b.upstream),
+        ("retries", a.retry != b.retry),
+        ("websockets", a.websocket != b.websocket),
+        ("HTTP/2", a.http2 != b.http2),
+    ] {
+        if changed {
+            changes.push(format!("{name} changed"));
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::{proxy::Proxy, request::parse, router::RouteAction};
+
+    fn snapshot(router: Router) -> Snapshot {
+        Snapshot {
+            router,
+            config: Config::default(),
+        }
+    }
+
+    #[test]
+    fn test_live_swap() {
+        let live = Live::new(snapshot(Router::new()));
+        let in_flight = live.load();
+
+        let mut config = Config::default();
+        config.compression.enabled = true;
+        let proxy = Proxy::new("127.0.0.1:1");
+        let old = live.swap(Snapshot {
+            router: Router::new().route("/api", RouteAction::Proxy(proxy)),
+            config,
+        });
+
+        // the request that started before keeps its router
+        assert!(Arc::ptr_eq(&old, &in_flight));
+        assert!(in_flight.router.routes().is_empty());
+        let request = parse(b"GET /api/x HTTP/1.1\r\n\r\n").unwrap();
+        assert!(live.load().router.route_name(&request).is_some());
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = snapshot(
+            Router::new()
+                .route("/api", RouteAction::Proxy(Proxy::new("127.0.0.1:1")))
+                .route("/old", RouteAction::Proxy(Proxy::new("127.0.0.1:2")))
+                .route("/same", RouteAction::Proxy(Proxy::new("127.0.0.1:3"))),
+        );
+        let mut new = snapshot(
+            Router::new()
+                .route(
+                    "/api",
+                    RouteAction::Proxy(Proxy::new("127.0.0.1:1").with_backend("127.0.0.1:4")),
+                )
+                .route("/", RouteAction::Proxy(Proxy::new("127.0.0.1:5")))
+                .route("/same", RouteAction::Proxy(Proxy::new("127.0.0.1:3"))),
+        );
+        new.config.timeouts.keep_alive = std::time::Duration::from_secs(1);
+
+        assert_eq!(
+            changes(&old, &new),
+            [
+                "route /api changed: proxy 127.0.0.1:1 => proxy 127.0.0.1:1,127.0.0.1:4",
+                "route / added: proxy 127.0.0.1:5",
+                "route /old removed",
+                "timeouts changed",
+            ]
+        );
+        assert!(changes(&new, &new).is_empty());
+    }
+}
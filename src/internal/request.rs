@@ -1,8 +1,12 @@
 use crate::internal::body::parse_request_body;
+use crate::{debug, trace};
 
-use super::headers::{Headers, parse_field_lines};
+use super::headers::{Headers, field_section_len, parse_field_lines};
+use super::limits::Limits;
+use super::response::StatusCode;
 use core::str;
 use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
 use std::str::FromStr;
 
 const CRLF: &[u8; 2] = b"\r\n";
@@ -16,15 +20,56 @@ impl ErrorMsg {
     pub const INVALID_HTTP_SPECIFICATION: &str = "Invalid HTTP specification.";
     pub const INVALID_HTTP_VERSION: &str = "Invalid HTTP version.";
     pub const INVALID_FIELD_LINE: &str = "Invalid field line.";
+    pub const INCOMPLETE_MESSAGE: &str = "Incomplete message: waiting for data";
+    pub const REQUEST_LINE_TOO_LONG: &str = "Request line exceeds the maximum length.";
+    pub const HEADERS_TOO_LARGE: &str = "Header section exceeds the maximum size.";
+    pub const TOO_MANY_HEADERS: &str = "Header section exceeds the maximum field count.";
+    pub const HEADER_TOO_LARGE: &str = "Header field exceeds the maximum size.";
+    pub const BODY_TOO_LARGE: &str = "Message body exceeds the maximum size.";
+    pub const MALFORMED_STATUS_LINE: &str = "malformed status line";
+    pub const INVALID_STATUS_CODE: &str = "Invalid status code.";
+    pub const UNSUPPORTED_CONTENT_ENCODING: &str = "Unsupported content coding.";
+    pub const REQUEST_TIMEOUT: &str = "Timed out waiting for the request.";
+    pub const POOL_EXHAUSTED: &str = "No upstream connection available.";
+    pub const CIRCUIT_OPEN: &str = "Upstream circuit is open.";
+    pub const TOO_MANY_PENDING: &str = "Too many requests waiting for the upstream.";
+    pub const EXPECTATION_FAILED: &str = "Expectation not supported.";
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RequestMethod {
     Get,
+    Head,
     Post,
     Patch,
     Delete,
     Put,
+    // cache invalidation, only accepted from the addresses the cache allows
+    Purge,
+}
+
+impl RequestMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestMethod::Get => "GET",
+            RequestMethod::Head => "HEAD",
+            RequestMethod::Post => "POST",
+            RequestMethod::Patch => "PATCH",
+            RequestMethod::Delete => "DELETE",
+            RequestMethod::Put => "PUT",
+            RequestMethod::Purge => "PURGE",
+        }
+    }
+
+    // RFC 9110 section 9.2.1
+    pub fn is_safe(&self) -> bool {
+        matches!(self, RequestMethod::Get | RequestMethod::Head)
+    }
+
+    // RFC 9110 section 9.2.2, these can be sent again when a connection fails
+    pub fn is_idempotent(&self) -> bool {
+        self.is_safe() || matches!(self, RequestMethod::Put | RequestMethod::Delete)
+    }
 }
 
 impl FromStr for RequestMethod {
@@ -32,17 +77,19 @@ impl FromStr for RequestMethod {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "GET" => Ok(RequestMethod::Get),
+            "HEAD" => Ok(RequestMethod::Head),
             "POST" => Ok(RequestMethod::Post),
             "PATCH" => Ok(RequestMethod::Patch),
             "PUT" => Ok(RequestMethod::Put),
             "DELETE" => Ok(RequestMethod::Delete),
+            "PURGE" => Ok(RequestMethod::Purge),
 
             _ => Err(Error::new(ErrorKind::Unsupported, ErrorMsg::UNSUPPORTED_METHOD).to_string()),
         }
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 enum ParsingState {
     Init,
     Header,
@@ -64,6 +111,7 @@ impl ParsingState {
     // }
 }
 
+#[derive(Clone)]
 pub struct Request {
     state: ParsingState,
     pub method: Option<RequestMethod>,
@@ -71,6 +119,15 @@ pub struct Request {
     pub headers: Option<Headers>,
     pub path: Option<String>,
     pub body: Vec<u8>,
+    pub trailers: Option<Headers>,
+    // set by the listener, None for requests that did not come off a socket
+    pub peer_addr: Option<SocketAddr>,
+}
+
+impl Default for Request {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Request {
@@ -82,16 +139,96 @@ impl Request {
             path: None,
             headers: None,
             body: vec![],
+            trailers: None,
+            peer_addr: None,
         }
     }
 
     fn is_done(&self) -> bool {
         self.state == ParsingState::Error || self.state == ParsingState::Done
     }
+
+    // request-target without the query, origin-form: absolute-path [ "?" query ]
+    pub fn target_path(&self) -> Option<&str> {
+        self.path
+            .as_deref()
+            .map(|p| p.split_once('?').map_or(p, |(path, _)| path))
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.headers.as_ref().and_then(|h| h.get("Host"))
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.path
+            .as_deref()
+            .and_then(|p| p.split_once('?'))
+            .map(|(_, query)| query)
+    }
+
+    // The client waits for a 100 Continue before it sends the body, RFC 9110
+    // section 10.1.1. HTTP/1.0 clients cannot know the status, theirs is ignored.
+    pub fn expects_continue(&self) -> bool {
+        self.version.as_deref() != Some("HTTP/1.0")
+            && self
+                .headers
+                .as_ref()
+                .and_then(|h| h.get("Expect"))
+                .is_some_and(|e| e.trim().eq_ignore_ascii_case("100-continue"))
+    }
+}
+
+// 100-continue is the only expectation there is, any other one fails
+pub(crate) fn check_expectation(headers: &Headers) -> Result<(), Error> {
+    match headers.get("Expect") {
+        Some(e) if !e.trim().eq_ignore_ascii_case("100-continue") => Err(Error::new(
+            ErrorKind::Unsupported,
+            ErrorMsg::EXPECTATION_FAILED,
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Picks the status to answer a request that could not be read with
+pub fn error_status(e: &Error) -> StatusCode {
+    match e.to_string().as_str() {
+        ErrorMsg::REQUEST_LINE_TOO_LONG => StatusCode::URI_TOO_LONG,
+        ErrorMsg::HEADERS_TOO_LARGE | ErrorMsg::TOO_MANY_HEADERS | ErrorMsg::HEADER_TOO_LARGE => {
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        }
+        ErrorMsg::BODY_TOO_LARGE => StatusCode::CONTENT_TOO_LARGE,
+        ErrorMsg::UNSUPPORTED_METHOD => StatusCode::NOT_IMPLEMENTED,
+        ErrorMsg::INVALID_HTTP_VERSION => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+        ErrorMsg::UNSUPPORTED_CONTENT_ENCODING => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ErrorMsg::REQUEST_TIMEOUT => StatusCode::REQUEST_TIMEOUT,
+        ErrorMsg::EXPECTATION_FAILED => StatusCode::EXPECTATION_FAILED,
+        _ => StatusCode::BAD_REQUEST,
+    }
 }
 
-// Following the RFC 9112
 pub fn parse(request_data: &[u8]) -> Result<Request, Error> {
+    parse_with_limits(request_data, &Limits::default())
+}
+
+pub fn parse_with_limits(request_data: &[u8], limits: &Limits) -> Result<Request, Error> {
+    parse_message(request_data, limits, true).map(|(request, _)| request)
+}
+
+// Parses the request line and the headers only, returns the request together with
+// the number of bytes consumed so the body can be streamed from the rest
+pub fn parse_head_with_limits(
+    request_data: &[u8],
+    limits: &Limits,
+) -> Result<(Request, usize), Error> {
+    parse_message(request_data, limits, false)
+}
+
+// Following the RFC 9112
+fn parse_message(
+    request_data: &[u8],
+    limits: &Limits,
+    with_body: bool,
+) -> Result<(Request, usize), Error> {
     // cursor
     let mut read: usize = 0;
 
@@ -102,65 +239,75 @@ pub fn parse(request_data: &[u8]) -> Result<Request, Error> {
 
     let mut request = Request::new();
     while !request.is_done() {
-        let idx = match request_data[read..]
-            .windows(CRLF.len())
-            .position(|r| r == CRLF)
-        {
-            Some(i) => i,
-            None => break,
-        };
-
-        let end_of_line = read + idx;
-        let curr_data = &request_data[read..end_of_line];
-
         match request.state {
             ParsingState::Init => {
+                let line = &request_data[read..];
+                let idx = match line.windows(CRLF.len()).position(|r| r == CRLF) {
+                    Some(i) if i > limits.max_request_line => None,
+                    Some(i) => Some(i),
+                    None if line.len() > limits.max_request_line => None,
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::WouldBlock,
+                            ErrorMsg::INCOMPLETE_MESSAGE,
+                        ));
+                    }
+                };
+                let idx = idx.ok_or_else(|| {
+                    request.state = ParsingState::Error;
+                    Error::new(ErrorKind::InvalidData, ErrorMsg::REQUEST_LINE_TOO_LONG)
+                })?;
+
+                let end_of_line = read + idx;
+                let curr_data = &request_data[read..end_of_line];
+
                 match parse_request_line(curr_data) {
                     Ok((m, t, v, bytes_read)) => {
-                        println!("==== Request line ==== ");
+                        trace!("request line: {} {t} {v}", m.as_str());
 
                         request.method = Some(m);
                         request.path = Some(t);
                         request.version = Some(v);
                         request.state = ParsingState::Header;
                         read += bytes_read;
-
-                        println!("- Method: {:?}", request.method);
-                        println!("- Path: {:?}", request.path);
-                        println!("- Version: {:?}", request.version);
                     }
                     Err(e) => {
-                        eprintln!("!! Error: {}", e);
+                        debug!("invalid request line: {e}");
                         request.state = ParsingState::Error;
                         return Err(e);
                     }
                 };
             }
             ParsingState::Header => {
-                println!("XXX");
+                let section = &request_data[read..];
                 let (headers, bytes_read) =
-                    parse_field_lines(&request_data[read..]).map_err(|e| {
+                    parse_field_lines(section, limits).inspect_err(|_| {
                         request.state = ParsingState::Error;
-                        e
                     })?;
 
-                println!("Headers");
-                for x in headers.iter() {
-                    println!(" - {}: {}", x.0, x.1);
+                if field_section_len(section).is_none() {
+                    return Err(Error::new(
+                        ErrorKind::WouldBlock,
+                        ErrorMsg::INCOMPLETE_MESSAGE,
+                    ));
                 }
 
+                for (name, value) in headers.iter() {
+                    trace!("header: {name}: {value}");
+                }
+                check_expectation(&headers).inspect_err(|_| {
+                    request.state = ParsingState::Error;
+                })?;
+
                 request.headers = Some(headers);
                 request.state = ParsingState::Body;
-
-                println!("===========");
                 read += bytes_read;
             }
+            ParsingState::Body if !with_body => break,
             ParsingState::Body => {
-                println!("Incoming");
-                let bytes_read =
-                    parse_request_body(&request_data[read..], &mut request).map_err(|e| {
+                let bytes_read = parse_request_body(&request_data[read..], &mut request, limits)
+                    .inspect_err(|_| {
                         request.state = ParsingState::Error;
-                        e
                     })?;
 
                 read += bytes_read;
@@ -168,7 +315,6 @@ pub fn parse(request_data: &[u8]) -> Result<Request, Error> {
             }
             ParsingState::Error => todo!(),
             ParsingState::Done => {
-                println!("Processing request");
                 request.state = ParsingState::Done;
                 break;
             }
@@ -177,8 +323,7 @@ pub fn parse(request_data: &[u8]) -> Result<Request, Error> {
         // read the \r\n
     }
 
-    println!("DONE");
-    Ok(request)
+    Ok((request, read))
 }
 
 fn parse_request_line(b: &[u8]) -> Result<(RequestMethod, String, String, usize), Error> {
@@ -190,7 +335,6 @@ fn parse_request_line(b: &[u8]) -> Result<(RequestMethod, String, String, usize)
     let mut read: usize = 0;
 
     let x: Vec<&[u8]> = b.split(|e| *e == SP).collect();
-    println!("after split => {x:?}");
     if x.len() != 3 {
         return Err(Error::new(
             ErrorKind::InvalidInput,
@@ -208,11 +352,20 @@ fn parse_request_line(b: &[u8]) -> Result<(RequestMethod, String, String, usize)
     let target = bytes_to_strings(x[1], "target")?;
     let version = bytes_to_strings(x[2], "version")?;
 
-    let request_method = match RequestMethod::from_str(&method.as_str()) {
+    let request_method = match RequestMethod::from_str(method.as_str()) {
         Ok(mtd) => mtd,
         Err(e) => return Err(Error::new(ErrorKind::Unsupported, e)),
     };
 
+    parse_http_version(&version)?;
+
+    read += b.len();
+    read += 2;
+    Ok((request_method, target, version, read))
+}
+
+// HTTP-version = HTTP-name "/" DIGIT "." DIGIT
+pub(crate) fn parse_http_version(version: &str) -> Result<(), Error> {
     let p_v: (&str, &str) = match version.split_once("/") {
         Some(p) => p,
         None => {
@@ -237,10 +390,7 @@ fn parse_request_line(b: &[u8]) -> Result<(RequestMethod, String, String, usize)
         ));
     }
 
-    read += b.len();
-    read += 2;
-    println!("total read in start line => {}", read);
-    Ok((request_method, target, version, read))
+    Ok(())
 }
 
 #[cfg(test)]
@@ -251,7 +401,7 @@ mod test {
     fn test_parse_request_line() {
         let mut input: &[u8] = b"GET / HTTP/1.1";
 
-        let (m, t, v, bytes_read) = parse_request_line(&input).unwrap();
+        let (m, t, v, bytes_read) = parse_request_line(input).unwrap();
 
         assert_eq!(m, RequestMethod::Get);
         assert_eq!(t, "/");
@@ -259,45 +409,114 @@ mod test {
         assert_eq!(bytes_read, 16);
 
         input = b"HOST /helllo HTTP/1.1";
-        let mut result = parse_request_line(&input);
+        let mut result = parse_request_line(input);
         let mut error = result.unwrap_err();
 
         assert_eq!(error.kind(), ErrorKind::Unsupported);
         assert_eq!(error.to_string(), "This request method is not implemented.");
 
         input = b"POST HTTP/1.1";
-        result = parse_request_line(&input);
+        result = parse_request_line(input);
         error = result.unwrap_err();
 
         assert_eq!(error.kind(), ErrorKind::InvalidInput);
         assert_eq!(error.to_string(), ErrorMsg::MALFROMED_START_LINE);
 
         input = b"PATCH /hello Http/1.1";
-        result = parse_request_line(&input);
+        result = parse_request_line(input);
         error = result.unwrap_err();
 
         assert_eq!(error.kind(), ErrorKind::InvalidInput);
         assert_eq!(error.to_string(), ErrorMsg::INVALID_HTTP_SPECIFICATION);
 
         input = b"PATCH /hello Http 1.1";
-        result = parse_request_line(&input);
+        result = parse_request_line(input);
         error = result.unwrap_err();
 
         assert_eq!(error.kind(), ErrorKind::InvalidInput);
         assert_eq!(error.to_string(), ErrorMsg::MALFROMED_START_LINE);
 
         input = b"PATCH /hello HTTP/2.1";
-        result = parse_request_line(&input);
+        result = parse_request_line(input);
         error = result.unwrap_err();
 
         assert_eq!(error.kind(), ErrorKind::InvalidInput);
         assert_eq!(error.to_string(), ErrorMsg::INVALID_HTTP_VERSION);
 
         input = b"PATCH /hello HTTP/1.1";
-        let result = parse_request_line(&input).unwrap();
+        let result = parse_request_line(input).unwrap();
 
         assert_eq!(result.0, RequestMethod::Patch);
         assert_eq!(result.1, "/hello");
         assert_eq!(result.2, "HTTP/1.1");
     }
+
+    #[test]
+    fn test_parse_chunked_request() {
+        let input: &[u8] = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTrailer: grpc-status\r\n\r\n5;ext=1\r\nHello\r\n0\r\ngrpc-status: 0\r\n\r\n";
+
+        let request = parse(input).unwrap();
+
+        assert_eq!(request.method, Some(RequestMethod::Post));
+        assert_eq!(request.body, b"Hello");
+        let trailers = request.trailers.unwrap();
+        assert_eq!(trailers.get("grpc-status"), Some("0"));
+
+        let input: &[u8] = b"POST /upload HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let request = parse(input).unwrap();
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let input: &[u8] = b"POST /upload HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let mut limits = Limits {
+            max_request_line: 10,
+            ..Limits::default()
+        };
+
+        let error = parse_with_limits(input, &limits).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), ErrorMsg::REQUEST_LINE_TOO_LONG);
+
+        // a request line that never ends is rejected as well
+        let error = parse_with_limits(b"GET /aaaaaaaaaaaaaaaaa", &limits)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), ErrorMsg::REQUEST_LINE_TOO_LONG);
+
+        limits = Limits {
+            max_body_size: 4,
+            ..Limits::default()
+        };
+        let error = parse_with_limits(input, &limits).err().unwrap();
+        assert_eq!(error.to_string(), ErrorMsg::BODY_TOO_LARGE);
+
+        let error = parse(b"POST /upload HTTP/1.1\r\nContent-Length: 5\r\n")
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::WouldBlock);
+
+        let error = parse(b"POST /upl").err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_expect() {
+        let input: &[u8] =
+            b"PUT /upload HTTP/1.1\r\nExpect: 100-Continue\r\nContent-Length: 5\r\n\r\n";
+        let (request, read) = parse_head_with_limits(input, &Limits::default()).unwrap();
+        assert_eq!(read, input.len());
+        assert!(request.expects_continue());
+
+        let input: &[u8] =
+            b"PUT /upload HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n";
+        let (request, _) = parse_head_with_limits(input, &Limits::default()).unwrap();
+        assert!(!request.expects_continue());
+
+        let error = parse(b"PUT /upload HTTP/1.1\r\nExpect: 200-ok\r\n\r\n")
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), ErrorMsg::EXPECTATION_FAILED);
+    }
 }
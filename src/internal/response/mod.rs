@@ -0,0 +1,568 @@
+use std::{
+    fmt,
+    io::{self, BufWriter, Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+mod parser;
+mod status;
+
+pub use parser::{
+    ParsedResponse, ResponseParser, parse, parse_head_with_limits, parse_with_limits,
+    response_body_length,
+};
+pub use status::StatusCode;
+
+use crate::internal::websocket::Upgraded;
+
+const CHUNK_SIZE: usize = 8 * 1024;
+
+pub enum ResponseBody {
+    // a Content-Length or Transfer-Encoding set on the response is kept, e.g. for HEAD
+    Empty,
+    Bytes(Vec<u8>),
+    // a reader of known length such as a file, sent with Content-Length
+    Fixed(Box<dyn Read + Send>, u64),
+    // written with chunked transfer coding as it is read
+    Stream(Box<dyn Read + Send>),
+}
+
+impl fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseBody::Empty => write!(f, "Empty"),
+            ResponseBody::Bytes(b) => write!(f, "Bytes({} bytes)", b.len()),
+            ResponseBody::Fixed(_, len) => write!(f, "Fixed({len} bytes)"),
+            ResponseBody::Stream(_) => write!(f, "Stream"),
+        }
+    }
+}
+
+// Trailers only known once a streamed body was read through, like those of a
+// proxied response. The reader of the body fills them in.
+pub type LateTrailers = Arc<Mutex<Vec<(String, String)>>>;
+
+// The upstream a proxied response came from, for logging
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upstream {
+    pub address: String,
+    // until the response head arrived
+    pub took: Duration,
+}
+
+//RESPONSE SCHEMATICS -> [start-line]CRLF[headers]CRLF[message-body]
+#[derive(Debug)]
+pub struct Response {
+    pub protocol: String,
+    pub status: StatusCode,
+    // kept in insertion order so `send` writes them out deterministically
+    pub headers: Vec<(String, String)>,
+    pub body: ResponseBody,
+    pub trailers: Vec<(String, String)>,
+    // sent after `trailers`
+    pub late_trailers: Option<LateTrailers>,
+    // not sent
+    pub upstream: Option<Upstream>,
+    // set on a 101 from an upstream that switched to WebSocket
+    pub upgrade: Option<Upgraded>,
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            protocol: "HTTP/1.1".to_string(),
+            status,
+            headers: vec![
+                ("Server".to_string(), "X-B-O-X".to_string()),
+                ("Connection".to_string(), "close".to_string()),
+            ],
+            body: ResponseBody::Empty,
+            trailers: Vec::new(),
+            late_trailers: None,
+            upstream: None,
+            upgrade: None,
+        }
+    }
+
+    pub fn builder(status: StatusCode) -> ResponseBuilder {
+        ResponseBuilder {
+            response: Response::new(status),
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // Replaces every field with the same name, keeping the position of the first one
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        let mut seen = false;
+        self.headers.retain_mut(|(k, v)| {
+            if !k.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            if seen {
+                return false;
+            }
+            seen = true;
+            *v = value.to_string();
+            true
+        });
+
+        if !seen {
+            self.append_header(name, value);
+        }
+    }
+
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    fn is_chunked(&self) -> bool {
+        matches!(self.body, ResponseBody::Stream(_)) || !self.trailers.is_empty()
+    }
+
+    // An HTTP/1.0 client knows no chunked coding, a body that would be chunked
+    // is ended by closing the connection instead and its trailers are dropped
+    pub fn ends_with_close(&self) -> bool {
+        self.protocol == "HTTP/1.0" && self.status.allows_body() && self.is_chunked()
+    }
+
+    // The answer to a HEAD request: the header section a GET gets, Content-Length
+    // included, without the body. A streamed body has no length to give, so it
+    // is announced as chunked like it would be for GET.
+    pub fn without_body(mut self) -> Response {
+        let length = match &self.body {
+            ResponseBody::Empty => return self,
+            ResponseBody::Bytes(b) => Some(b.len() as u64),
+            ResponseBody::Fixed(_, len) => Some(*len),
+            ResponseBody::Stream(_) => None,
+        };
+        self.body = ResponseBody::Empty;
+        self.trailers.clear();
+        self.late_trailers = None;
+        match length {
+            Some(length) => self.set_header("Content-Length", &length.to_string()),
+            None => {
+                self.remove_header("Content-Length");
+                self.set_header("Transfer-Encoding", "chunked");
+            }
+        }
+        self
+    }
+
+    pub fn send(self, stream: &mut impl Write) -> io::Result<()> {
+        let mut stream = BufWriter::new(stream);
+        let with_body = self.status.allows_body();
+        let close_delimited = self.ends_with_close();
+        let chunked = with_body && self.is_chunked() && !close_delimited;
+
+        // append the startline
+        write!(
+            stream,
+            "{} {} {}\r\n",
+            self.protocol,
+            self.status.as_u16(),
+            self.status.reason()
+        )?;
+
+        // the framing headers are derived from the body, whatever was set is dropped
+        let keep_framing = matches!(self.body, ResponseBody::Empty) && !chunked;
+        let set_length = self.header("Content-Length").filter(|_| keep_framing);
+        let set_coding = self.header("Transfer-Encoding").filter(|_| keep_framing);
+        for (key, value) in &self.headers {
+            if key.eq_ignore_ascii_case("Content-Length")
+                || key.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                continue;
+            }
+            write!(stream, "{}: {}\r\n", key, value)?;
+        }
+
+        if close_delimited {
+            // no length, the body runs until the connection closes
+        } else if chunked {
+            write!(stream, "Transfer-Encoding: chunked\r\n")?;
+            if !self.trailers.is_empty() {
+                let names: Vec<&str> = self.trailers.iter().map(|(k, _)| k.as_str()).collect();
+                write!(stream, "Trailer: {}\r\n", names.join(", "))?;
+            }
+        } else if let Some(coding) = set_coding {
+            // a GET from an HTTP/1.0 client would get the body until the close instead
+            if self.protocol != "HTTP/1.0" {
+                write!(stream, "Transfer-Encoding: {}\r\n", coding)?;
+            }
+        } else if let Some(length) = set_length {
+            write!(stream, "Content-Length: {}\r\n", length)?;
+        } else if with_body {
+            let length = match &self.body {
+                ResponseBody::Bytes(b) => b.len() as u64,
+                ResponseBody::Fixed(_, len) => *len,
+                _ => 0,
+            };
+            write!(stream, "Content-Length: {}\r\n", length)?;
+        }
+        // append the CRLF after the headers
+        write!(stream, "\r\n")?;
+
+        if !with_body {
+            return stream.flush();
+        }
+
+        if close_delimited {
+            match self.body {
+                ResponseBody::Empty => {}
+                ResponseBody::Bytes(body) => stream.write_all(&body)?,
+                ResponseBody::Fixed(reader, len) => {
+                    io::copy(&mut reader.take(len), &mut stream)?;
+                }
+                ResponseBody::Stream(mut reader) => {
+                    io::copy(&mut reader, &mut stream)?;
+                }
+            }
+            return stream.flush();
+        }
+
+        match self.body {
+            ResponseBody::Empty if chunked => {}
+            ResponseBody::Empty => return stream.flush(),
+            ResponseBody::Bytes(body) if chunked => write_chunk(&mut stream, &body)?,
+            ResponseBody::Bytes(body) => {
+                stream.write_all(&body)?;
+                return stream.flush();
+            }
+            ResponseBody::Fixed(reader, len) if chunked => {
+                write_chunks(&mut stream, &mut reader.take(len))?
+            }
+            ResponseBody::Fixed(reader, len) => {
+                let copied = io::copy(&mut reader.take(len), &mut stream)?;
+                if copied != len {
+                    // the length was already promised, the client has to notice
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Response body shorter than its Content-Length",
+                    ));
+                }
+                return stream.flush();
+            }
+            ResponseBody::Stream(mut reader) => write_chunks(&mut stream, &mut reader)?,
+        }
+
+        // last-chunk, trailer-section and the final CRLF
+        write!(stream, "0\r\n")?;
+        for (key, value) in &self.trailers {
+            write!(stream, "{}: {}\r\n", key, value)?;
+        }
+        if let Some(late) = &self.late_trailers {
+            for (key, value) in late.lock().unwrap().iter() {
+                write!(stream, "{}: {}\r\n", key, value)?;
+            }
+        }
+        write!(stream, "\r\n")?;
+
+        //send the data in buffer to stream
+        stream.flush()
+    }
+
+    pub fn respond(
+        stream: &mut impl Write,
+        status: StatusCode,
+        message: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let entity = match message {
+            Some(s) => s.to_vec(),
+            None => status.reason().as_bytes().to_vec(),
+        };
+
+        Response::builder(status).body(entity).build().send(stream)
+    }
+
+    pub fn ok(stream: &mut impl Write, message: Option<&[u8]>) -> io::Result<()> {
+        Response::respond(stream, StatusCode::OK, message)
+    }
+
+    pub fn not_found(stream: &mut impl Write, message: Option<&[u8]>) -> io::Result<()> {
+        let message = message.unwrap_or(b"Resource Not Found");
+
+        Response::respond(stream, StatusCode::NOT_FOUND, Some(message))
+    }
+
+    pub fn bad_request(stream: &mut impl Write, message: Option<&[u8]>) -> io::Result<()> {
+        Response::respond(stream, StatusCode::BAD_REQUEST, message)
+    }
+}
+
+fn write_chunks(stream: &mut impl Write, reader: &mut impl Read) -> io::Result<()> {
+    let mut buf = [0u8; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        write_chunk(stream, &buf[..n])?;
+        // get every chunk out as soon as it is read
+        stream.flush()?;
+    }
+}
+
+fn write_chunk(stream: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        // a zero sized chunk would end the body
+        return Ok(());
+    }
+
+    write!(stream, "{:x}\r\n", data.len())?;
+    stream.write_all(data)?;
+    write!(stream, "\r\n")
+}
+
+pub struct ResponseBuilder {
+    response: Response,
+}
+
+impl ResponseBuilder {
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.response.status = status;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: &str) -> Self {
+        self.response.protocol = protocol.to_string();
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.response.set_header(name, value);
+        self
+    }
+
+    pub fn append_header(mut self, name: &str, value: &str) -> Self {
+        self.response.append_header(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.response.body = ResponseBody::Bytes(body.into());
+        self
+    }
+
+    pub fn sized(mut self, reader: impl Read + Send + 'static, length: u64) -> Self {
+        self.response.body = ResponseBody::Fixed(Box::new(reader), length);
+        self
+    }
+
+    pub fn stream(mut self, reader: impl Read + Send + 'static) -> Self {
+        self.response.body = ResponseBody::Stream(Box::new(reader));
+        self
+    }
+
+    pub fn trailer(mut self, name: &str, value: &str) -> Self {
+        self.response
+            .trailers
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn build(self) -> Response {
+        self.response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ok_response() {
+        let mut mock_socket = Vec::new();
+
+        Response::ok(&mut mock_socket, Some(b"Test is OK.")).unwrap();
+
+        let output = String::from_utf8_lossy(&mock_socket);
+
+        assert!(output.contains("Test is OK."));
+        assert!(output.contains("HTTP/1.1"));
+        assert!(output.contains("200 OK"));
+    }
+
+    #[test]
+    fn test_not_found() {
+        let mut mock_socket = Vec::new();
+
+        Response::not_found(&mut mock_socket, Some(b"Test is Not Found.")).unwrap();
+
+        let output = String::from_utf8_lossy(&mock_socket);
+
+        assert!(output.contains("Test is Not Found."));
+        assert!(output.contains("HTTP/1.1"));
+        assert!(output.contains("404 Not Found"));
+    }
+
+    #[test]
+    fn test_bad_request() {
+        let mut mock_socket = Vec::new();
+
+        Response::bad_request(&mut mock_socket, Some(b"Test is Bad Request.")).unwrap();
+
+        let output = String::from_utf8_lossy(&mock_socket);
+        // println!("{output}");
+
+        assert!(output.contains("Test is Bad Request."));
+        assert!(output.contains("HTTP/1.1"));
+        assert!(output.contains("400 Bad Request"));
+    }
+
+    #[test]
+    fn test_builder_header_order() {
+        let mut mock_socket = Vec::new();
+
+        Response::builder(StatusCode::CREATED)
+            .header("Location", "/items/1")
+            .header("server", "lb")
+            .append_header("Set-Cookie", "a=1")
+            .append_header("Set-Cookie", "b=2")
+            .header("Content-Length", "999")
+            .body("created")
+            .build()
+            .send(&mut mock_socket)
+            .unwrap();
+
+        let output = String::from_utf8(mock_socket).unwrap();
+        assert_eq!(
+            output,
+            "HTTP/1.1 201 Created\r\nServer: lb\r\nConnection: close\r\nLocation: /items/1\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nContent-Length: 7\r\n\r\ncreated"
+        );
+
+        let mut mock_socket = Vec::new();
+        Response::builder(StatusCode::NO_CONTENT)
+            .body("ignored")
+            .build()
+            .send(&mut mock_socket)
+            .unwrap();
+
+        let output = String::from_utf8(mock_socket).unwrap();
+        assert!(output.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(output.ends_with("close\r\n\r\n"));
+        assert!(!output.contains("Content-Length"));
+    }
+
+    #[test]
+    fn test_streamed_response_with_trailers() {
+        let mut mock_socket = Vec::new();
+        let body: &'static [u8] = b"streamed body";
+
+        Response::builder(StatusCode::OK)
+            .header("Content-Type", "application/grpc")
+            .stream(body)
+            .trailer("grpc-status", "0")
+            .trailer("grpc-message", "")
+            .build()
+            .send(&mut mock_socket)
+            .unwrap();
+
+        let output = String::from_utf8(mock_socket.clone()).unwrap();
+        assert!(output.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(output.contains("Trailer: grpc-status, grpc-message\r\n"));
+        assert!(!output.contains("Content-Length"));
+
+        // our own response parser reads it back
+        let parsed = parse(&mock_socket, &crate::internal::request::RequestMethod::Post).unwrap();
+        assert_eq!(parsed.status, StatusCode::OK);
+        assert_eq!(parsed.body, body);
+        assert_eq!(parsed.trailers.unwrap().get("grpc-status"), Some("0"));
+    }
+
+    #[test]
+    fn test_streamed_response_to_http10() {
+        let mut mock_socket = Vec::new();
+        let body: &'static [u8] = b"streamed body";
+
+        let response = Response::builder(StatusCode::OK)
+            .protocol("HTTP/1.0")
+            .stream(body)
+            .trailer("grpc-status", "0")
+            .build();
+        assert!(response.ends_with_close());
+        response.send(&mut mock_socket).unwrap();
+
+        let output = String::from_utf8(mock_socket).unwrap();
+        assert!(output.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(!output.contains("Transfer-Encoding"));
+        assert!(!output.contains("Trailer"));
+        assert!(!output.contains("Content-Length"));
+        assert!(output.ends_with("\r\n\r\nstreamed body"));
+
+        // a body of known length still gets one
+        let response = Response::builder(StatusCode::OK)
+            .protocol("HTTP/1.0")
+            .body("sized")
+            .build();
+        assert!(!response.ends_with_close());
+    }
+
+    #[test]
+    fn test_without_body() {
+        let body: &'static [u8] = b"streamed body";
+        let mut mock_socket = Vec::new();
+        Response::builder(StatusCode::OK)
+            .stream(body)
+            .trailer("grpc-status", "0")
+            .build()
+            .without_body()
+            .send(&mut mock_socket)
+            .unwrap();
+
+        // announced like the GET response, with nothing after the head
+        let output = String::from_utf8(mock_socket).unwrap();
+        assert!(output.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!output.contains("Trailer"));
+        assert!(output.ends_with("\r\n\r\n"));
+
+        let mut mock_socket = Vec::new();
+        Response::builder(StatusCode::OK)
+            .body("sized")
+            .build()
+            .without_body()
+            .send(&mut mock_socket)
+            .unwrap();
+        let output = String::from_utf8(mock_socket).unwrap();
+        assert!(output.ends_with("Content-Length: 5\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_late_trailers() {
+        // the trailers turn up as the body reaches its end
+        struct Body(LateTrailers, bool);
+        impl Read for Body {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.1 {
+                    self.0.lock().unwrap().push(("x-sum".into(), "3".into()));
+                    return Ok(0);
+                }
+                self.1 = true;
+                buf[..3].copy_from_slice(b"abc");
+                Ok(3)
+            }
+        }
+
+        let late = LateTrailers::default();
+        let mut response = Response::builder(StatusCode::OK)
+            .stream(Body(Arc::clone(&late), false))
+            .build();
+        response.late_trailers = Some(late);
+        let mut mock_socket = Vec::new();
+        response.send(&mut mock_socket).unwrap();
+
+        let parsed = parse(&mock_socket, &crate::internal::request::RequestMethod::Get).unwrap();
+        assert_eq!(parsed.body, b"abc");
+        assert_eq!(parsed.trailers.unwrap().get("x-sum"), Some("3"));
+    }
+}
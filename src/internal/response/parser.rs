@@ -0,0 +1,386 @@
+use crate::internal::{
+    body::{BodyLength, body_length, stream::BodyDecoder},
+    headers::{Headers, field_section_len, parse_field_list},
+    limits::Limits,
+    request::{ErrorMsg, RequestMethod, parse_http_version},
+    response::StatusCode,
+};
+use core::str;
+use std::io::{Error, ErrorKind};
+
+const CRLF: &[u8; 2] = b"\r\n";
+const SP: u8 = b' ';
+
+// A response as read from an upstream server
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedResponse {
+    pub version: String,
+    pub status: StatusCode,
+    // as received, which is not necessarily the canonical one
+    pub reason: String,
+    pub headers: Headers,
+    // the same fields in the order they came, with a repeated one such as
+    // Set-Cookie kept apart instead of joined
+    pub fields: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub trailers: Option<Headers>,
+}
+
+impl ParsedResponse {
+    pub fn is_informational(&self) -> bool {
+        self.status.is_informational()
+    }
+}
+
+#[derive(Debug)]
+enum ParsingState {
+    Init,
+    Header,
+    Body(BodyDecoder),
+    Done,
+}
+
+// Incremental response parser, fed with bytes as they come off the upstream
+// connection. The request method is needed since a response to HEAD never has a body.
+#[derive(Debug)]
+pub struct ResponseParser {
+    state: ParsingState,
+    method: RequestMethod,
+    limits: Limits,
+    response: ParsedResponse,
+}
+
+impl ResponseParser {
+    pub fn new(method: RequestMethod) -> Self {
+        Self::with_limits(method, Limits::default())
+    }
+
+    pub fn with_limits(method: RequestMethod, limits: Limits) -> Self {
+        ResponseParser {
+            state: ParsingState::Init,
+            method,
+            limits,
+            response: ParsedResponse {
+                version: String::new(),
+                status: StatusCode::OK,
+                reason: String::new(),
+                headers: Headers::default(),
+                fields: Vec::new(),
+                body: Vec::new(),
+                trailers: None,
+            },
+        }
+    }
+
+    pub fn is_head_done(&self) -> bool {
+        matches!(self.state, ParsingState::Body(_) | ParsingState::Done)
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, ParsingState::Done)
+    }
+
+    pub fn response(&self) -> &ParsedResponse {
+        &self.response
+    }
+
+    pub fn into_response(self) -> ParsedResponse {
+        self.response
+    }
+
+    // Returns the number of bytes consumed, the rest has to be fed again once
+    // more data has arrived
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<usize, Error> {
+        self.advance(bytes, true)
+    }
+
+    fn advance(&mut self, bytes: &[u8], with_body: bool) -> Result<usize, Error> {
+        let mut read: usize = 0;
+
+        loop {
+            match &mut self.state {
+                ParsingState::Init => {
+                    let line = &bytes[read..];
+                    let idx = match line.windows(CRLF.len()).position(|r| r == CRLF) {
+                        Some(i) if i > self.limits.max_request_line => None,
+                        Some(i) => Some(i),
+                        None if line.len() > self.limits.max_request_line => None,
+                        None => return Ok(read),
+                    };
+                    let idx = idx.ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidData, ErrorMsg::REQUEST_LINE_TOO_LONG)
+                    })?;
+
+                    let (version, status, reason) = parse_status_line(&line[..idx])?;
+                    self.response.version = version;
+                    self.response.status = status;
+                    self.response.reason = reason;
+
+                    read += idx + CRLF.len();
+                    self.state = ParsingState::Header;
+                }
+                ParsingState::Header => {
+                    let section = &bytes[read..];
+                    let (fields, bytes_read) = parse_field_list(section, &self.limits)?;
+                    if field_section_len(section).is_none() {
+                        return Ok(read);
+                    }
+                    let headers = Headers::from_fields(&fields);
+
+                    let length =
+                        response_body_length(&self.method, self.response.status, &headers)?;
+                    self.response.headers = headers;
+                    self.response.fields = fields;
+
+                    read += bytes_read;
+                    self.state = ParsingState::Body(BodyDecoder::new(length, &self.limits)?);
+                }
+                ParsingState::Body(_) if !with_body => return Ok(read),
+                ParsingState::Body(decoder) => {
+                    if decoder.is_done() {
+                        self.response.trailers = decoder.trailers().cloned();
+                        self.state = ParsingState::Done;
+                        continue;
+                    }
+
+                    let bytes_read = decoder.decode(&bytes[read..], &mut self.response.body)?;
+                    read += bytes_read;
+
+                    if !decoder.is_done() {
+                        return Ok(read);
+                    }
+                }
+                ParsingState::Done => return Ok(read),
+            }
+        }
+    }
+
+    // Called once the upstream closed the connection
+    pub fn finish(&mut self) -> Result<(), Error> {
+        match &mut self.state {
+            ParsingState::Body(decoder) => {
+                decoder.finish()?;
+                self.response.trailers = decoder.trailers().cloned();
+                self.state = ParsingState::Done;
+                Ok(())
+            }
+            ParsingState::Done => Ok(()),
+            _ => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed before the response head was complete",
+            )),
+        }
+    }
+}
+
+// Parses a complete response. `response_data` is taken as everything the upstream
+// has sent so far, a body without a length runs up to the end of it.
+pub fn parse(response_data: &[u8], method: &RequestMethod) -> Result<ParsedResponse, Error> {
+    parse_with_limits(response_data, method, &Limits::default())
+}
+
+pub fn parse_with_limits(
+    response_data: &[u8],
+    method: &RequestMethod,
+    limits: &Limits,
+) -> Result<ParsedResponse, Error> {
+    let mut parser = ResponseParser::with_limits(method.clone(), limits.clone());
+    parser.feed(response_data)?;
+
+    if let ParsingState::Body(decoder) = &parser.state
+        && !decoder.is_done()
+    {
+        let length =
+            response_body_length(method, parser.response.status, &parser.response.headers)?;
+        if length == BodyLength::UntilClose {
+            parser.finish()?;
+        }
+    }
+
+    if !parser.is_done() {
+        return Err(Error::new(
+            ErrorKind::WouldBlock,
+            ErrorMsg::INCOMPLETE_MESSAGE,
+        ));
+    }
+
+    Ok(parser.into_response())
+}
+
+// Parses the status line and headers only, returns the response together with
+// the number of bytes consumed so the body can be streamed from the rest
+pub fn parse_head_with_limits(
+    response_data: &[u8],
+    method: &RequestMethod,
+    limits: &Limits,
+) -> Result<(ParsedResponse, usize), Error> {
+    let mut parser = ResponseParser::with_limits(method.clone(), limits.clone());
+    let read = parser.advance(response_data, false)?;
+
+    if !parser.is_head_done() {
+        return Err(Error::new(
+            ErrorKind::WouldBlock,
+            ErrorMsg::INCOMPLETE_MESSAGE,
+        ));
+    }
+
+    Ok((parser.into_response(), read))
+}
+
+// RFC 9112 section 6.3
+pub fn response_body_length(
+    method: &RequestMethod,
+    status: StatusCode,
+    headers: &Headers,
+) -> Result<BodyLength, Error> {
+    if *method == RequestMethod::Head || !status.allows_body() {
+        return Ok(BodyLength::Empty);
+    }
+
+    match body_length(headers)? {
+        BodyLength::Empty => Ok(BodyLength::UntilClose),
+        length => Ok(length),
+    }
+}
+
+fn parse_status_line(b: &[u8]) -> Result<(String, StatusCode, String), Error> {
+    // status-line = HTTP-version SP status-code SP [ reason-phrase ]
+    // the reason phrase can contain spaces, so only split twice
+    let malformed = || Error::new(ErrorKind::InvalidInput, ErrorMsg::MALFORMED_STATUS_LINE);
+
+    let mut x = b.splitn(3, |e| *e == SP);
+    let version = x.next().ok_or_else(malformed)?;
+    let code = x.next().ok_or_else(malformed)?;
+    // some servers leave out the SP when there is no reason phrase
+    let reason = x.next().unwrap_or_default();
+
+    let version = str::from_utf8(version).map_err(|_| malformed())?;
+    parse_http_version(version)?;
+
+    if code.len() != 3 || !code.iter().all(|b| b.is_ascii_digit()) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            ErrorMsg::INVALID_STATUS_CODE,
+        ));
+    }
+    let status = StatusCode::from_u16(
+        code.iter()
+            .fold(0u16, |acc, d| acc * 10 + (d - b'0') as u16),
+    )?;
+
+    // reason-phrase can carry obs-text, keep it lossy instead of failing
+    let reason = String::from_utf8_lossy(reason).to_string();
+
+    Ok((version.to_string(), status, reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_status_line() {
+        let (v, c, r) = parse_status_line(b"HTTP/1.1 404 Not Found").unwrap();
+        assert_eq!(v, "HTTP/1.1");
+        assert_eq!(c, StatusCode::NOT_FOUND);
+        assert_eq!(r, "Not Found");
+
+        let (_, c, r) = parse_status_line(b"HTTP/1.0 204").unwrap();
+        assert_eq!(c, StatusCode::NO_CONTENT);
+        assert_eq!(r, "");
+
+        let error = parse_status_line(b"HTTP/1.1 20 OK").unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::INVALID_STATUS_CODE);
+
+        let error = parse_status_line(b"HTTP/1.1 099 OK").unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::INVALID_STATUS_CODE);
+
+        let error = parse_status_line(b"HTTP/2.0 200 OK").unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::INVALID_HTTP_VERSION);
+
+        let error = parse_status_line(b"HTTP/1.1").unwrap_err();
+        assert_eq!(error.to_string(), ErrorMsg::MALFORMED_STATUS_LINE);
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let input: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nServer: up\r\n\r\nhello";
+        let response = parse(input, &RequestMethod::Get).unwrap();
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.reason, "OK");
+        assert_eq!(response.headers.get("server"), Some("up"));
+        assert_eq!(response.body, b"hello");
+
+        // same head, but HEAD responses never carry a body
+        let response = parse(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n",
+            &RequestMethod::Head,
+        )
+        .unwrap();
+        assert!(response.body.is_empty());
+
+        for status in [
+            "101 Switching Protocols",
+            "204 No Content",
+            "304 Not Modified",
+        ] {
+            let input = format!("HTTP/1.1 {status}\r\nContent-Length: 5\r\n\r\n");
+            let response = parse(input.as_bytes(), &RequestMethod::Get).unwrap();
+            assert!(response.body.is_empty());
+        }
+
+        // no length at all: the body runs until the connection closes
+        let input: &[u8] = b"HTTP/1.0 200 OK\r\nServer: old\r\n\r\nuntil the end";
+        let response = parse(input, &RequestMethod::Get).unwrap();
+        assert_eq!(response.body, b"until the end");
+
+        let input: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\n";
+        let response = parse(input, &RequestMethod::Post).unwrap();
+        assert_eq!(response.body, b"hello");
+        assert_eq!(response.trailers.unwrap().get("grpc-status"), Some("0"));
+
+        let error = parse(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhel",
+            &RequestMethod::Get,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_response_parser_incremental() {
+        let input: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nServer: up\r\n\r\nbody without length";
+        let mut parser = ResponseParser::new(RequestMethod::Post);
+
+        let read = parser.feed(input).unwrap();
+        assert!(parser.is_done());
+        assert!(parser.response().is_informational());
+
+        // the final response follows the interim one
+        let mut parser = ResponseParser::new(RequestMethod::Post);
+        let mut pending = input[read..].to_vec();
+        let mut consumed = 0;
+        for piece in pending.split_off(0).chunks(5) {
+            pending.extend_from_slice(piece);
+            let n = parser.feed(&pending).unwrap();
+            consumed += n;
+            pending.drain(..n);
+        }
+        assert!(parser.is_head_done());
+        assert!(!parser.is_done());
+        assert_eq!(consumed, input.len() - read);
+
+        parser.finish().unwrap();
+        let response = parser.into_response();
+        assert_eq!(response.status, StatusCode::CREATED);
+        assert_eq!(response.body, b"body without length");
+
+        let mut parser = ResponseParser::new(RequestMethod::Get);
+        parser
+            .feed(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort")
+            .unwrap();
+        let error = parser.finish().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+};
+
+use crate::internal::request::ErrorMsg;
+
+// status-code = 3DIGIT, any value in 100..=999 is accepted so unknown codes
+// from upstreams can be passed through, the registered ones have a constant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StatusCode(u16);
+
+macro_rules! status_codes {
+    ($(($code:expr, $name:ident, $reason:expr),)+) => {
+        impl StatusCode {
+            $(pub const $name: StatusCode = StatusCode($code);)+
+
+            // Canonical reason phrase from the IANA HTTP Status Code Registry
+            pub fn canonical_reason(&self) -> Option<&'static str> {
+                match self.0 {
+                    $($code => Some($reason),)+
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    (100, CONTINUE, "Continue"),
+    (101, SWITCHING_PROTOCOLS, "Switching Protocols"),
+    (102, PROCESSING, "Processing"),
+    (103, EARLY_HINTS, "Early Hints"),
+    (200, OK, "OK"),
+    (201, CREATED, "Created"),
+    (202, ACCEPTED, "Accepted"),
+    (203, NON_AUTHORITATIVE_INFORMATION, "Non-Authoritative Information"),
+    (204, NO_CONTENT, "No Content"),
+    (205, RESET_CONTENT, "Reset Content"),
+    (206, PARTIAL_CONTENT, "Partial Content"),
+    (207, MULTI_STATUS, "Multi-Status"),
+    (208, ALREADY_REPORTED, "Already Reported"),
+    (226, IM_USED, "IM Used"),
+    (300, MULTIPLE_CHOICES, "Multiple Choices"),
+    (301, MOVED_PERMANENTLY, "Moved Permanently"),
+    (302, FOUND, "Found"),
+    (303, SEE_OTHER, "See Other"),
+    (304, NOT_MODIFIED, "Not Modified"),
+    (305, USE_PROXY, "Use Proxy"),
+    (307, TEMPORARY_REDIRECT, "Temporary Redirect"),
+    (308, PERMANENT_REDIRECT, "Permanent Redirect"),
+    (400, BAD_REQUEST, "Bad Request"),
+    (401, UNAUTHORIZED, "Unauthorized"),
+    (402, PAYMENT_REQUIRED, "Payment Required"),
+    (403, FORBIDDEN, "Forbidden"),
+    (404, NOT_FOUND, "Not Found"),
+    (405, METHOD_NOT_ALLOWED, "Method Not Allowed"),
+    (406, NOT_ACCEPTABLE, "Not Acceptable"),
+    (407, PROXY_AUTHENTICATION_REQUIRED, "Proxy Authentication Required"),
+    (408, REQUEST_TIMEOUT, "Request Timeout"),
+    (409, CONFLICT, "Conflict"),
+    (410, GONE, "Gone"),
+    (411, LENGTH_REQUIRED, "Length Required"),
+    (412, PRECONDITION_FAILED, "Precondition Failed"),
+    (413, CONTENT_TOO_LARGE, "Content Too Large"),
+    (414, URI_TOO_LONG, "URI Too Long"),
+    (415, UNSUPPORTED_MEDIA_TYPE, "Unsupported Media Type"),
+    (416, RANGE_NOT_SATISFIABLE, "Range Not Satisfiable"),
+    (417, EXPECTATION_FAILED, "Expectation Failed"),
+    (421, MISDIRECTED_REQUEST, "Misdirected Request"),
+    (422, UNPROCESSABLE_CONTENT, "Unprocessable Content"),
+    (423, LOCKED, "Locked"),
+    (424, FAILED_DEPENDENCY, "Failed Dependency"),
+    (425, TOO_EARLY, "Too Early"),
+    (426, UPGRADE_REQUIRED, "Upgrade Required"),
+    (428, PRECONDITION_REQUIRED, "Precondition Required"),
+    (429, TOO_MANY_REQUESTS, "Too Many Requests"),
+    (431, REQUEST_HEADER_FIELDS_TOO_LARGE, "Request Header Fields Too Large"),
+    (451, UNAVAILABLE_FOR_LEGAL_REASONS, "Unavailable For Legal Reasons"),
+    (500, INTERNAL_SERVER_ERROR, "Internal Server Error"),
+    (501, NOT_IMPLEMENTED, "Not Implemented"),
+    (502, BAD_GATEWAY, "Bad Gateway"),
+    (503, SERVICE_UNAVAILABLE, "Service Unavailable"),
+    (504, GATEWAY_TIMEOUT, "Gateway Timeout"),
+    (505, HTTP_VERSION_NOT_SUPPORTED, "HTTP Version Not Supported"),
+    (506, VARIANT_ALSO_NEGOTIATES, "Variant Also Negotiates"),
+    (507, INSUFFICIENT_STORAGE, "Insufficient Storage"),
+    (508, LOOP_DETECTED, "Loop Detected"),
+    (510, NOT_EXTENDED, "Not Extended"),
+    (511, NETWORK_AUTHENTICATION_REQUIRED, "Network Authentication Required"),
+}
+
+impl StatusCode {
+    pub fn from_u16(code: u16) -> Result<StatusCode, Error> {
+        if !(100..=999).contains(&code) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                ErrorMsg::INVALID_STATUS_CODE,
+            ));
+        }
+
+        Ok(StatusCode(code))
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    // Falls back to an empty phrase for unregistered codes, which the
+    // status-line grammar allows
+    pub fn reason(&self) -> &'static str {
+        self.canonical_reason().unwrap_or("")
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.0)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.0)
+    }
+
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.0)
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.0)
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.0)
+    }
+
+    // 1xx, 204 and 304 responses never carry content, RFC 9110 section 6.4.1
+    pub fn allows_body(&self) -> bool {
+        !self.is_informational()
+            && *self != StatusCode::NO_CONTENT
+            && *self != StatusCode::NOT_MODIFIED
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.0, self.reason())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status_code() {
+        assert_eq!(StatusCode::NOT_FOUND.as_u16(), 404);
+        assert_eq!(StatusCode::NOT_FOUND.reason(), "Not Found");
+        assert_eq!(
+            StatusCode::TOO_MANY_REQUESTS.to_string(),
+            "429 Too Many Requests"
+        );
+        assert_eq!(
+            StatusCode::from_u16(308).unwrap(),
+            StatusCode::PERMANENT_REDIRECT
+        );
+
+        let unknown = StatusCode::from_u16(599).unwrap();
+        assert_eq!(unknown.canonical_reason(), None);
+        assert!(unknown.is_server_error());
+
+        assert!(StatusCode::from_u16(99).is_err());
+        assert!(StatusCode::from_u16(1000).is_err());
+
+        assert!(!StatusCode::NO_CONTENT.allows_body());
+        assert!(!StatusCode::CONTINUE.allows_body());
+        assert!(StatusCode::CREATED.allows_body());
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{Error, ErrorKind},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::internal::{request::RequestMethod, response::StatusCode};
+
+// What a failed attempt may be retried on
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryOn {
+    // the connection could not be opened, nothing reached the backend
+    pub connect_failure: bool,
+    // the backend did not answer within the per-try timeout
+    pub timeout: bool,
+    // the backend answered with one of these
+    pub statuses: Vec<StatusCode>,
+}
+
+impl Default for RetryOn {
+    fn default() -> Self {
+        RetryOn {
+            connect_failure: true,
+            timeout: false,
+            statuses: Vec::new(),
+        }
+    }
+}
+
+// `connect,timeout,502,503`
+impl FromStr for RetryOn {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut retry_on = RetryOn {
+            connect_failure: false,
+            ..RetryOn::default()
+        };
+
+        for condition in s.split(',').map(str::trim) {
+            match condition {
+                "connect" => retry_on.connect_failure = true,
+                "timeout" => retry_on.timeout = true,
+                _ => match condition.parse().map(StatusCode::from_u16) {
+                    Ok(Ok(status)) if status.is_server_error() => retry_on.statuses.push(status),
+                    _ => return Err(format!("Invalid retry condition {condition}")),
+                },
+            }
+        }
+        Ok(retry_on)
+    }
+}
+
+// Retries may only make up this share of the requests, so a struggling backend
+// does not get several times its usual load
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryBudget {
+    pub ratio: f64,
+    // allowed per window whatever the ratio, so a quiet proxy can still retry
+    pub min_retries: u64,
+    pub window: Duration,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget {
+            ratio: 0.2,
+            min_retries: 10,
+            window: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    // including the first one, 1 turns retries off
+    pub max_attempts: u32,
+    pub retry_on: RetryOn,
+    // only idempotent methods are retried after the request was sent, unless set
+    pub retry_non_idempotent: bool,
+    // for sending the request and reading the response head
+    pub per_try_timeout: Option<Duration>,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    pub budget: RetryBudget,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            retry_on: RetryOn::default(),
+            retry_non_idempotent: false,
+            per_try_timeout: None,
+            backoff_base: Duration::from_millis(25),
+            backoff_max: Duration::from_secs(1),
+            budget: RetryBudget::default(),
+        }
+    }
+}
+
+// How an attempt went, as far as retrying is concerned
+#[derive(Debug)]
+pub enum Outcome<'a> {
+    ConnectFailed(&'a Error),
+    Failed(&'a Error),
+    Status(StatusCode),
+}
+
+impl RetryPolicy {
+    pub fn should_retry(&self, method: &RequestMethod, outcome: &Outcome) -> bool {
+        let sent_safely = method.is_idempotent() || self.retry_non_idempotent;
+
+        match outcome {
+            // the request never left, retrying cannot repeat it
+            Outcome::ConnectFailed(_) => self.retry_on.connect_failure,
+            Outcome::Failed(e) => {
+                sent_safely
+                    && self.retry_on.timeout
+                    && matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+            }
+            Outcome::Status(status) => sent_safely && self.retry_on.statuses.contains(status),
+        }
+    }
+
+    // Exponential backoff with full jitter before the given retry, counting from 1
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self
+            .backoff_base
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+            .min(self.backoff_max);
+        exponential.mul_f64(random_fraction())
+    }
+}
+
+// In [0, 1), good enough to spread retries apart
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(Instant::now().elapsed().as_nanos() as u64);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Requests and retries seen in the current budget window
+#[derive(Debug)]
+pub struct RetryTracker {
+    budget: RetryBudget,
+    window: Mutex<(Instant, u64, u64)>,
+}
+
+impl RetryTracker {
+    pub fn new(budget: RetryBudget) -> Self {
+        RetryTracker {
+            budget,
+            window: Mutex::new((Instant::now(), 0, 0)),
+        }
+    }
+
+    pub fn record_request(&self) {
+        let mut window = self.current();
+        window.1 += 1;
+    }
+
+    // Takes a retry from the budget, false once it is spent
+    pub fn try_retry(&self) -> bool {
+        let mut window = self.current();
+        let (_, requests, retries) = &mut *window;
+
+        let allowed = (*requests as f64 * self.budget.ratio) as u64;
+        if *retries >= allowed.max(self.budget.min_retries) {
+            return false;
+        }
+        *retries += 1;
+        true
+    }
+
+    fn current(&self) -> std::sync::MutexGuard<'_, (Instant, u64, u64)> {
+        let mut window = self.window.lock().unwrap();
+        if window.0.elapsed() >= self.budget.window {
+            *window = (Instant::now(), 0, 0);
+        }
+        window
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry_conditions() {
+        let policy = RetryPolicy {
+            retry_on: "connect,timeout,503".parse().unwrap(),
+            ..RetryPolicy::default()
+        };
+        let refused = Error::from(ErrorKind::ConnectionRefused);
+        let timed_out = Error::from(ErrorKind::TimedOut);
+        let reset = Error::from(ErrorKind::ConnectionReset);
+        let unavailable = Outcome::Status(StatusCode::SERVICE_UNAVAILABLE);
+
+        assert!(policy.should_retry(&RequestMethod::Post, &Outcome::ConnectFailed(&refused)));
+        assert!(policy.should_retry(&RequestMethod::Get, &Outcome::Failed(&timed_out)));
+        assert!(!policy.should_retry(&RequestMethod::Get, &Outcome::Failed(&reset)));
+        assert!(policy.should_retry(&RequestMethod::Put, &unavailable));
+        assert!(!policy.should_retry(&RequestMethod::Post, &unavailable));
+        assert!(!policy.should_retry(
+            &RequestMethod::Get,
+            &Outcome::Status(StatusCode::BAD_GATEWAY)
+        ));
+
+        let opted_in = RetryPolicy {
+            retry_non_idempotent: true,
+            ..policy
+        };
+        assert!(opted_in.should_retry(&RequestMethod::Post, &unavailable));
+
+        assert!("connect,404".parse::<RetryOn>().is_err());
+        assert!("sometimes".parse::<RetryOn>().is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            backoff_base: Duration::from_millis(100),
+            backoff_max: Duration::from_millis(300),
+            ..RetryPolicy::default()
+        };
+
+        for _ in 0..20 {
+            assert!(policy.backoff(1) < Duration::from_millis(100));
+            assert!(policy.backoff(2) < Duration::from_millis(200));
+            assert!(policy.backoff(10) < Duration::from_millis(300));
+        }
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let tracker = RetryTracker::new(RetryBudget {
+            ratio: 0.5,
+            min_retries: 1,
+            window: Duration::from_secs(60),
+        });
+
+        tracker.record_request();
+        assert!(tracker.try_retry());
+        assert!(!tracker.try_retry());
+
+        for _ in 0..5 {
+            tracker.record_request();
+        }
+        // 6 requests allow 3 retries, 1 is already used
+        assert!(tracker.try_retry());
+        assert!(tracker.try_retry());
+        assert!(!tracker.try_retry());
+    }
+}
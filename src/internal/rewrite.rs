@@ -0,0 +1,495 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{Error, ErrorKind},
+};
+
+use regex::Regex;
+
+use crate::internal::{
+    request::Request,
+    response::{Response, StatusCode},
+};
+
+// A piece of a Location template
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    // `http` or `https`, from X-Forwarded-Proto set by the listener
+    Scheme,
+    // the Host field as sent, with the port if there is one
+    Host,
+    // the Host field without the port
+    Hostname,
+    // the request path, without the query
+    Path,
+    // the path below the route prefix
+    Rest,
+    // the query without the `?`, empty when there is none
+    Query,
+    // `?` and the query, or nothing
+    QueryMark,
+    // path and query as requested
+    Target,
+}
+
+// A Location built from the request, like `https://{hostname}{target}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    // Fails with ErrorKind::InvalidInput on an unknown or unclosed variable
+    pub fn parse(source: &str) -> Result<Template, Error> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidInput, msg);
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                return Err(invalid(format!("{source}: unclosed {{")));
+            };
+            let name = &rest[start + 1..start + end];
+            parts.push(match name {
+                "scheme" => Part::Scheme,
+                "host" => Part::Host,
+                "hostname" => Part::Hostname,
+                "path" => Part::Path,
+                "rest" => Part::Rest,
+                "query" => Part::Query,
+                "?query" => Part::QueryMark,
+                "target" => Part::Target,
+                _ => return Err(invalid(format!("{source}: unknown variable {{{name}}}"))),
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        if source.chars().any(|c| c.is_ascii_control() || c == ' ') {
+            return Err(invalid(format!("{source}: not a valid Location")));
+        }
+        Ok(Template {
+            source: source.to_string(),
+            parts,
+        })
+    }
+
+    // None when the template needs a host and the request has no usable one
+    pub fn render(&self, request: &Request, rest: &str) -> Option<String> {
+        let path = request.target_path().unwrap_or("/");
+        let query = request.query();
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => out.push_str(text),
+                Part::Scheme => out.push_str(scheme(request)),
+                Part::Host => out.push_str(host(request)?),
+                Part::Hostname => out.push_str(hostname(host(request)?)),
+                Part::Path => out.push_str(path),
+                Part::Rest => out.push_str(match rest {
+                    "" => "/",
+                    rest => rest,
+                }),
+                Part::Query => out.push_str(query.unwrap_or("")),
+                Part::QueryMark => {
+                    if let Some(query) = query {
+                        out.push('?');
+                        out.push_str(query);
+                    }
+                }
+                Part::Target => out.push_str(request.path.as_deref().unwrap_or("/")),
+            }
+        }
+        Some(out)
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn scheme(request: &Request) -> &'static str {
+    let proto = request
+        .headers
+        .as_ref()
+        .and_then(|h| h.get("X-Forwarded-Proto"));
+    match proto {
+        Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+        _ => "http",
+    }
+}
+
+// The Host field when it is a plausible authority, so a client cannot put
+// a path or userinfo into the Location it gets back
+fn host(request: &Request) -> Option<&str> {
+    let host = request.host()?.trim();
+    let valid = !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._:[]".contains(&b));
+    valid.then_some(host)
+}
+
+fn hostname(host: &str) -> &str {
+    if host.starts_with('[') {
+        // an IPv6 literal keeps its brackets
+        return host.find(']').map_or(host, |end| &host[..=end]);
+    }
+    host.split_once(':').map_or(host, |(name, _)| name)
+}
+
+// Answers every request of a route with a redirect
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    status: StatusCode,
+    location: Template,
+}
+
+impl Redirect {
+    // Only the redirect statuses that carry a Location are accepted:
+    // 301, 302, 303, 307 and 308
+    pub fn new(status: StatusCode, location: Template) -> Result<Redirect, Error> {
+        match status.as_u16() {
+            301 | 302 | 303 | 307 | 308 => Ok(Redirect { status, location }),
+            code => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{code} is not a redirect status"),
+            )),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn describe(&self) -> String {
+        format!("redirect {} {}", self.status.as_u16(), self.location)
+    }
+
+    // `rest` is the part of the request path below the route prefix
+    pub fn serve(&self, request: &Request, rest: &str) -> Response {
+        let Some(location) = self.location.render(request, rest) else {
+            return Response::builder(StatusCode::BAD_REQUEST)
+                .body(StatusCode::BAD_REQUEST.reason())
+                .build();
+        };
+        Response::builder(self.status)
+            .header("Location", &location)
+            .header("Content-Type", "text/plain")
+            .body(format!("Redirecting to {location}\n"))
+            .build()
+    }
+}
+
+// Legacy paths answered with a redirect of their own, looked up exactly,
+// before any route
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RedirectMap {
+    entries: HashMap<String, Redirect>,
+}
+
+impl RedirectMap {
+    pub fn new() -> Self {
+        RedirectMap::default()
+    }
+
+    // One mapping per line, `<path> <location> [status]`, 301 when no status
+    // is given. Blank lines and lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<RedirectMap, Error> {
+        let mut map = RedirectMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |msg: &dyn fmt::Display| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("line {}: {msg}", number + 1),
+                )
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (path, location, status) = match fields[..] {
+                [path, location] => (path, location, StatusCode::MOVED_PERMANENTLY),
+                [path, location, status] => {
+                    let status = status
+                        .parse()
+                        .map_err(|_| invalid(&format!("{status} is not a status")))
+                        .and_then(|code| StatusCode::from_u16(code).map_err(|e| invalid(&e)))?;
+                    (path, location, status)
+                }
+                _ => return Err(invalid(&"expected <path> <location> [status]")),
+            };
+            if !path.starts_with('/') {
+                return Err(invalid(&format!("{path} does not start with /")));
+            }
+            let location = Template::parse(location).map_err(|e| invalid(&e))?;
+            let redirect = Redirect::new(status, location).map_err(|e| invalid(&e))?;
+            map = map.insert(path, redirect);
+        }
+        Ok(map)
+    }
+
+    pub fn insert(mut self, path: &str, redirect: Redirect) -> Self {
+        self.entries.insert(normalize(path).to_string(), redirect);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // None when the path is not mapped
+    pub fn serve(&self, request: &Request) -> Option<Response> {
+        let path = request.target_path()?;
+        let redirect = self.entries.get(normalize(path))?;
+        Some(redirect.serve(request, path))
+    }
+}
+
+// `/old/` and `/old` are the same legacy URL
+fn normalize(path: &str) -> &str {
+    match path.trim_end_matches('/') {
+        "" => "/",
+        path => path,
+    }
+}
+
+// Changes the request path before the route acts on it. The query is kept.
+#[derive(Debug, Clone)]
+pub enum Rewrite {
+    // `from` and whole segments below it, an empty `to` strips the prefix
+    Prefix { from: String, to: String },
+    // the first match is replaced, `$1` or `${name}` refer to the groups
+    Regex { pattern: Regex, replacement: String },
+}
+
+impl Rewrite {
+    pub fn prefix(from: &str, to: &str) -> Rewrite {
+        Rewrite::Prefix {
+            from: from.trim_end_matches('/').to_string(),
+            to: to.trim_end_matches('/').to_string(),
+        }
+    }
+
+    // Fails with ErrorKind::InvalidInput when the pattern does not compile
+    pub fn regex(pattern: &str, replacement: &str) -> Result<Rewrite, Error> {
+        let pattern =
+            Regex::new(pattern).map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(Rewrite::Regex {
+            pattern,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Rewrite::Prefix { from, to } => format!("rewrite {}* => {}*", or_root(from), to),
+            Rewrite::Regex {
+                pattern,
+                replacement,
+            } => format!("rewrite {pattern} => {replacement}"),
+        }
+    }
+
+    // None when the rule does not match the path
+    pub fn apply(&self, path: &str) -> Option<String> {
+        let rewritten = match self {
+            Rewrite::Prefix { from, to } => {
+                let rest = path.strip_prefix(from.as_str())?;
+                if !rest.is_empty() && !rest.starts_with('/') {
+                    return None;
+                }
+                format!("{to}{rest}")
+            }
+            Rewrite::Regex {
+                pattern,
+                replacement,
+            } => {
+                if !pattern.is_match(path) {
+                    return None;
+                }
+                pattern.replace(path, replacement.as_str()).into_owned()
+            }
+        };
+        Some(match rewritten {
+            path if path.starts_with('/') => path,
+            path => format!("/{path}"),
+        })
+    }
+}
+
+fn or_root(prefix: &str) -> &str {
+    match prefix {
+        "" => "/",
+        prefix => prefix,
+    }
+}
+
+// The target with every rule applied in order, each to the path the one
+// before produced. None when no rule matched.
+pub fn rewrite_target(rules: &[Rewrite], target: &str) -> Option<String> {
+    let (mut path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query)),
+        None => (target.to_string(), None),
+    };
+    let mut matched = false;
+    for rule in rules {
+        if let Some(rewritten) = rule.apply(&path) {
+            path = rewritten;
+            matched = true;
+        }
+    }
+    if !matched {
+        return None;
+    }
+    if let Some(query) = query {
+        path.push('?');
+        path.push_str(query);
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::request::parse;
+
+    #[test]
+    fn test_template() {
+        let mut request =
+            parse(b"GET /shop/cart?id=1 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n").unwrap();
+        let render = |template: &str, request: &Request| {
+            Template::parse(template)
+                .unwrap()
+                .render(request, "/cart")
+                .unwrap()
+        };
+        assert_eq!(
+            render("https://{hostname}{target}", &request),
+            "https://example.com/shop/cart?id=1"
+        );
+        assert_eq!(
+            render("{scheme}://{host}/new{rest}{?query}", &request),
+            "http://example.com:8080/new/cart?id=1"
+        );
+        assert_eq!(
+            render("/p{path}?q={query}", &request),
+            "/p/shop/cart?q=id=1"
+        );
+
+        request
+            .headers
+            .as_mut()
+            .unwrap()
+            .insert("host", "[::1]:8080");
+        request
+            .headers
+            .as_mut()
+            .unwrap()
+            .insert("x-forwarded-proto", "https");
+        assert_eq!(render("{scheme}://{hostname}/", &request), "https://[::1]/");
+
+        // a Host that is not an authority gives no Location
+        request
+            .headers
+            .as_mut()
+            .unwrap()
+            .insert("host", "evil.com/x?");
+        let template = Template::parse("https://{host}/").unwrap();
+        assert!(template.render(&request, "").is_none());
+
+        assert!(Template::parse("https://{nope}/").is_err());
+        assert!(Template::parse("https://{host").is_err());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let location = Template::parse("https://{hostname}{target}").unwrap();
+        assert!(Redirect::new(StatusCode::OK, location.clone()).is_err());
+        let redirect = Redirect::new(StatusCode::PERMANENT_REDIRECT, location).unwrap();
+
+        let request = parse(b"GET /a?b HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        let response = redirect.serve(&request, "/a");
+        assert_eq!(response.status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.header("Location"), Some("https://example.com/a?b"));
+
+        let request = parse(b"GET /a HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(
+            redirect.serve(&request, "/a").status,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn test_redirect_map() {
+        let map = RedirectMap::parse(
+            "# legacy pages\n\
+             /old.html /new\n\
+             /blog/ https://blog.example.com{?query} 302\n",
+        )
+        .unwrap();
+        assert_eq!(map.len(), 2);
+
+        let request = parse(b"GET /blog?p=2 HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+        let response = map.serve(&request).unwrap();
+        assert_eq!(response.status, StatusCode::FOUND);
+        assert_eq!(
+            response.header("Location"),
+            Some("https://blog.example.com?p=2")
+        );
+        let request = parse(b"GET /old.html HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(
+            map.serve(&request).unwrap().header("Location"),
+            Some("/new")
+        );
+        let request = parse(b"GET /old.htm HTTP/1.1\r\n\r\n").unwrap();
+        assert!(map.serve(&request).is_none());
+
+        let error = RedirectMap::parse("/a /b\n/c /d 200\n").unwrap_err();
+        assert!(error.to_string().starts_with("line 2:"));
+        assert!(RedirectMap::parse("a /b\n").is_err());
+        assert!(RedirectMap::parse("/a\n").is_err());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let strip = Rewrite::prefix("/api/", "");
+        assert_eq!(strip.apply("/api/users").as_deref(), Some("/users"));
+        assert_eq!(strip.apply("/api").as_deref(), Some("/"));
+        assert_eq!(strip.apply("/apix"), None);
+
+        let replace = Rewrite::prefix("/v1", "/v2");
+        assert_eq!(replace.apply("/v1/items/3").as_deref(), Some("/v2/items/3"));
+
+        let legacy = Rewrite::regex(r"^/product\.php/(\d+)$", "/products/$1").unwrap();
+        assert_eq!(
+            legacy.apply("/product.php/42").as_deref(),
+            Some("/products/42")
+        );
+        assert_eq!(legacy.apply("/product.php/x"), None);
+        assert!(Rewrite::regex("(", "x").is_err());
+
+        // rules chain, the query is kept
+        let rules = [
+            strip,
+            Rewrite::regex("^/users/(.+)$", "/people/$1").unwrap(),
+        ];
+        assert_eq!(
+            rewrite_target(&rules, "/api/users/7?full=1").as_deref(),
+            Some("/people/7?full=1")
+        );
+        assert_eq!(rewrite_target(&rules, "/other"), None);
+    }
+}
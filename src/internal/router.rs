@@ -0,0 +1,568 @@
+use std::sync::Arc;
+
+use crate::internal::{
+    accesslog::AccessLog,
+    admin::Admin,
+    body::stream::IncomingBody,
+    fileserver::FileServer,
+    metrics::Metrics,
+    proxy::Proxy,
+    ratelimit::{self, RateLimiter},
+    request::Request,
+    response::Response,
+    rewrite::{self, Redirect, RedirectMap, Rewrite},
+};
+
+#[derive(Debug, Clone)]
+pub enum RouteAction {
+    Static(FileServer),
+    // the whole request target is forwarded, the prefix is not stripped
+    Proxy(Proxy),
+    // the Prometheus text rendering, meant for an admin listener
+    Metrics(Arc<Metrics>),
+    // the admin API, gets the path below the prefix
+    Admin(Arc<Admin>),
+    // every request gets a redirect to the rendered Location
+    Redirect(Redirect),
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    // matched against whole path segments, "/assets" takes "/assets/x" but not "/assetsx"
+    pub prefix: String,
+    pub action: RouteAction,
+    pub rate_limits: Vec<Arc<RateLimiter>>,
+    // applied in order to the request path before the action
+    pub rewrites: Vec<Rewrite>,
+}
+
+impl Route {
+    // The prefix, "/" for the one that takes every path
+    pub fn name(&self) -> &str {
+        match self.prefix.as_str() {
+            "" => "/",
+            prefix => prefix,
+        }
+    }
+
+    // What the route does, two routes with the same description behave the same
+    pub fn describe(&self) -> String {
+        let mut out = match &self.action {
+            RouteAction::Static(files) => files.describe(),
+            RouteAction::Proxy(proxy) => proxy.describe(),
+            RouteAction::Metrics(_) => "metrics".to_string(),
+            RouteAction::Admin(_) => "admin".to_string(),
+            RouteAction::Redirect(redirect) => redirect.describe(),
+        };
+        for rule in &self.rewrites {
+            out.push_str(&format!(" {}", rule.describe()));
+        }
+        for limiter in &self.rate_limits {
+            out.push_str(&format!(" rate limit {:?}", limiter.rule()));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    // checked for every request, before the ones of the route
+    rate_limits: Vec<Arc<RateLimiter>>,
+    metrics: Option<Arc<Metrics>>,
+    access_log: Option<Arc<AccessLog>>,
+    // looked up before the routes
+    redirects: Arc<RedirectMap>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            routes: Vec::new(),
+            rate_limits: Vec::new(),
+            metrics: None,
+            access_log: None,
+            redirects: Arc::new(RedirectMap::new()),
+        }
+    }
+
+    pub fn route(mut self, prefix: &str, action: RouteAction) -> Self {
+        let mut route = Route {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            action,
+            rate_limits: Vec::new(),
+            rewrites: Vec::new(),
+        };
+        if let Some(metrics) = &self.metrics {
+            instrument(&mut route, metrics);
+        }
+        self.routes.push(route);
+        self
+    }
+
+    // Counts the traffic of the listener serving this router, proxied routes
+    // report their upstream time and backend health
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        for route in &mut self.routes {
+            instrument(route, &metrics);
+        }
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn metrics(&self) -> Option<&Arc<Metrics>> {
+        self.metrics.as_ref()
+    }
+
+    // Every request answered through this router is written to the log
+    pub fn with_access_log(mut self, access_log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    pub fn access_log(&self) -> Option<&Arc<AccessLog>> {
+        self.access_log.as_ref()
+    }
+
+    // The label requests are counted under
+    pub fn route_name(&self, request: &Request) -> Option<&str> {
+        let (route, _) = self.find(request.target_path()?)?;
+        Some(route.name())
+    }
+
+    // The limiter is shared, not copied, when the router is cloned
+    pub fn rate_limit(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limits.push(limiter);
+        self
+    }
+
+    pub fn rate_limits(&self) -> &[Arc<RateLimiter>] {
+        &self.rate_limits
+    }
+
+    // Keeps what the same routes of the router this one replaces built up:
+    // upstream connections, circuits, drained backends, cached responses and
+    // rate limit counters
+    pub fn carry_over(mut self, old: &Router) -> Self {
+        let keep = |limiters: &mut Vec<Arc<RateLimiter>>, old: &[Arc<RateLimiter>]| {
+            for limiter in limiters {
+                if let Some(same) = old.iter().find(|o| o.rule() == limiter.rule()) {
+                    *limiter = Arc::clone(same);
+                }
+            }
+        };
+        keep(&mut self.rate_limits, &old.rate_limits);
+
+        for route in &mut self.routes {
+            let Some(previous) = old.routes.iter().find(|r| r.prefix == route.prefix) else {
+                continue;
+            };
+            keep(&mut route.rate_limits, &previous.rate_limits);
+            if let (RouteAction::Proxy(proxy), RouteAction::Proxy(old)) =
+                (&mut route.action, &previous.action)
+            {
+                *proxy = proxy.clone().carry_over(old);
+            }
+        }
+        self
+    }
+
+    // Limits the route added with the same prefix
+    pub fn route_rate_limit(mut self, prefix: &str, limiter: Arc<RateLimiter>) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        if let Some(route) = self.routes.iter_mut().find(|r| r.prefix == prefix) {
+            route.rate_limits.push(limiter);
+        }
+        self
+    }
+
+    // Rewrites the path of requests to the route added with the same prefix
+    pub fn route_rewrite(mut self, prefix: &str, rule: Rewrite) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        if let Some(route) = self.routes.iter_mut().find(|r| r.prefix == prefix) {
+            route.rewrites.push(rule);
+        }
+        self
+    }
+
+    pub fn with_redirects(mut self, redirects: RedirectMap) -> Self {
+        self.redirects = Arc::new(redirects);
+        self
+    }
+
+    pub fn redirects(&self) -> &RedirectMap {
+        &self.redirects
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    // Longest matching prefix wins, returns the route and the rest of the path below it
+    pub fn find<'a>(&self, path: &'a str) -> Option<(&Route, &'a str)> {
+        self.routes
+            .iter()
+            .filter_map(|route| {
+                let rest = path.strip_prefix(route.prefix.as_str())?;
+                (rest.is_empty() || rest.starts_with('/')).then_some((route, rest))
+            })
+            .max_by_key(|(route, _)| route.prefix.len())
+    }
+
+    // The answer to a request that is known from its head, for a client that
+    // waits for 100 Continue before it sends the body. None when the body is
+    // wanted, which is what it takes to apply rate limits, or when a proxy
+    // leaves the answer to its upstream.
+    pub fn precheck(&self, request: &Request) -> Option<Response> {
+        if let Some(response) = self.redirects.serve(request) {
+            return Some(response);
+        }
+        let (route, rest) = self.find(request.target_path()?)?;
+        match &route.action {
+            RouteAction::Admin(admin) => admin.unauthorized(request),
+            RouteAction::Redirect(redirect) if route.rewrites.is_empty() => {
+                Some(redirect.serve(request, rest))
+            }
+            _ => None,
+        }
+    }
+
+    // Whether the request goes to a proxy, which takes its body as it comes
+    // instead of read whole
+    pub fn streams(&self, request: &Request) -> bool {
+        self.redirects.serve(request).is_none()
+            && request
+                .target_path()
+                .and_then(|path| self.find(path))
+                .is_some_and(|(route, _)| matches!(route.action, RouteAction::Proxy(_)))
+    }
+
+    // None when no route matches the request
+    pub fn handle(&self, request: &Request) -> Option<Response> {
+        self.dispatch(request, None)
+    }
+
+    // As handle, for a request whose body is still to be read. A proxy sends
+    // it upstream as it arrives, the other answers leave it unread.
+    pub fn handle_streaming(&self, request: &Request, body: IncomingBody) -> Option<Response> {
+        self.dispatch(request, Some(body))
+    }
+
+    fn dispatch(&self, request: &Request, body: Option<IncomingBody>) -> Option<Response> {
+        if let Some(response) = self.redirects.serve(request) {
+            return Some(response);
+        }
+        let (route, rest) = self.find(request.target_path()?)?;
+
+        let limiters = self.rate_limits.iter().chain(&route.rate_limits);
+        let decision = ratelimit::check_all(limiters.map(|l| l.as_ref()), request, &route.prefix);
+        if let Some(decision @ ratelimit::Decision::Limited { .. }) = &decision {
+            return Some(ratelimit::too_many_requests(decision));
+        }
+
+        // the route stays the one the original path matched, the rest is
+        // taken from the new path when it is still below the prefix
+        let rewritten = request
+            .path
+            .as_deref()
+            .and_then(|target| rewrite::rewrite_target(&route.rewrites, target))
+            .map(|target| {
+                let mut rewritten = request.clone();
+                rewritten.path = Some(target);
+                rewritten
+            });
+        let (request, rest) = match &rewritten {
+            Some(rewritten) => {
+                let path = rewritten.target_path().unwrap_or("/");
+                let rest = path
+                    .strip_prefix(route.prefix.as_str())
+                    .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+                    .unwrap_or(path);
+                (rewritten, rest)
+            }
+            None => (request, rest),
+        };
+
+        let mut response = match &route.action {
+            RouteAction::Static(files) => files.serve(request, rest),
+            RouteAction::Proxy(proxy) => match body {
+                Some(body) => proxy.serve_streaming(request, body),
+                None => proxy.serve(request),
+            },
+            RouteAction::Metrics(metrics) => metrics.response(),
+            RouteAction::Admin(admin) => admin.serve(request, rest),
+            RouteAction::Redirect(redirect) => redirect.serve(request, rest),
+        };
+        if let Some(decision) = &decision {
+            ratelimit::add_headers(&mut response, decision);
+        }
+        Some(response)
+    }
+}
+
+fn instrument(route: &mut Route, metrics: &Arc<Metrics>) {
+    let name = route.name().to_string();
+    if let RouteAction::Proxy(proxy) = &mut route.action {
+        *proxy = proxy.clone().with_metrics(Arc::clone(metrics));
+
+        metrics.register_backends(&name, proxy.backends());
+        if let Some(fallback) = proxy.fallback() {
+            metrics.register_backends(&name, fallback.backends());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::{
+        body::stream::BodyReader,
+        config::Config,
+        connection::{ConnectionLimits, ConnectionTracker},
+        limits::Limits,
+        ratelimit::RateLimitRule,
+        reload::{Live, Snapshot},
+        request::{self, parse},
+        response::StatusCode,
+        rewrite::Template,
+    };
+    use std::{
+        io::{self, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    #[test]
+    fn test_router_find() {
+        let router = Router::new()
+            .route(
+                "/assets/",
+                RouteAction::Static(FileServer::new("/srv/assets")),
+            )
+            .route(
+                "/assets/img",
+                RouteAction::Static(FileServer::new("/srv/img")),
+            )
+            .route("/", RouteAction::Static(FileServer::new("/srv/www")));
+
+        let (route, rest) = router.find("/assets/app.js").unwrap();
+        assert_eq!(route.prefix, "/assets");
+        assert_eq!(rest, "/app.js");
+
+        let (route, rest) = router.find("/assets/img/logo.png").unwrap();
+        assert_eq!(route.prefix, "/assets/img");
+        assert_eq!(rest, "/logo.png");
+
+        let (route, rest) = router.find("/assetsx").unwrap();
+        assert_eq!(route.prefix, "");
+        assert_eq!(rest, "/assetsx");
+
+        assert!(Router::new().find("/").is_none());
+    }
+
+    #[test]
+    fn test_router_rate_limit() {
+        let dir = std::env::temp_dir();
+        let per_route: RateLimitRule = "ip=1/60".parse().unwrap();
+        let router = Router::new()
+            .route("/a", RouteAction::Static(FileServer::new(&dir)))
+            .route("/b", RouteAction::Static(FileServer::new(&dir)))
+            .rate_limit(Arc::new(RateLimiter::new("ip=3/60".parse().unwrap())))
+            .route_rate_limit("/a/", Arc::new(RateLimiter::new(per_route)));
+
+        let mut request = parse(b"GET /a/x HTTP/1.1\r\n\r\n").unwrap();
+        request.peer_addr = Some("10.0.0.1:1000".parse().unwrap());
+
+        let response = router.handle(&request).unwrap();
+        assert_ne!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.header("RateLimit-Remaining"), Some("0"));
+
+        let response = router.handle(&request).unwrap();
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.header("Retry-After").is_some());
+
+        // /b only has the global limit, which the rejected request still counted against
+        request.path = Some("/b/x".to_string());
+        let response = router.handle(&request).unwrap();
+        assert_eq!(response.header("RateLimit-Remaining"), Some("0"));
+        assert_eq!(
+            router.handle(&request).unwrap().status,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[test]
+    fn test_router_rewrite() {
+        let location = Template::parse("https://{hostname}{target}").unwrap();
+        let redirect = Redirect::new(StatusCode::PERMANENT_REDIRECT, location).unwrap();
+        let legacy = Redirect::new(
+            StatusCode::MOVED_PERMANENTLY,
+            Template::parse("/docs/").unwrap(),
+        )
+        .unwrap();
+        let router = Router::new()
+            .route("/", RouteAction::Redirect(redirect))
+            .route("/old", RouteAction::Redirect(legacy.clone()))
+            .route_rewrite("/", Rewrite::regex("^/shop/(.*)$", "/store/$1").unwrap())
+            .with_redirects(RedirectMap::new().insert("/faq.html", legacy));
+
+        let request = parse(b"GET /shop/a?x=1 HTTP/1.1\r\nHost: example.com:80\r\n\r\n").unwrap();
+        let response = router.handle(&request).unwrap();
+        assert_eq!(
+            response.header("Location"),
+            Some("https://example.com/store/a?x=1")
+        );
+        // the map is looked up before the routes
+        let request = parse(b"GET /faq.html HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.handle(&request).unwrap();
+        assert_eq!(response.status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.header("Location"), Some("/docs/"));
+        // HEAD is redirected alike, what the listener sends of it has no body
+        let request = parse(b"HEAD /faq.html HTTP/1.1\r\n\r\n").unwrap();
+        let response = router.handle(&request).unwrap();
+        assert_eq!(response.header("Location"), Some("/docs/"));
+        let mut out = Vec::new();
+        response.without_body().send(&mut out).unwrap();
+        let output = String::from_utf8(out).unwrap();
+        assert!(output.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(output.ends_with("Content-Length: 22\r\n\r\n"));
+
+        // a stripped prefix leaves the rest for the file server
+        let dir = std::env::temp_dir().join(format!("lb-rewrite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        let router = Router::new()
+            .route("/files", RouteAction::Static(FileServer::new(&dir)))
+            .route_rewrite("/files", Rewrite::prefix("/files/v1", "/files"));
+        let request = parse(b"GET /files/v1/a.txt HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.handle(&request).unwrap().status, StatusCode::OK);
+        assert!(
+            router.routes()[0]
+                .describe()
+                .contains("rewrite /files/v1* => /files*")
+        );
+    }
+
+    #[test]
+    fn test_router_precheck() {
+        let moved = Redirect::new(
+            StatusCode::MOVED_PERMANENTLY,
+            Template::parse("/v2{rest}").unwrap(),
+        )
+        .unwrap();
+        let live = Arc::new(Live::new(Snapshot {
+            router: Router::new(),
+            config: Config::default(),
+        }));
+        let connections = Arc::new(ConnectionTracker::new(ConnectionLimits::default()));
+        let admin = Admin::new("127.0.0.1:8080", live, connections).with_token("secret");
+        let router = Router::new()
+            .route("/upload", RouteAction::Redirect(moved))
+            .route("/admin", RouteAction::Admin(Arc::new(admin)))
+            .route("/", RouteAction::Proxy(Proxy::new("127.0.0.1:1")));
+
+        let put = |head: &str| {
+            let (request, _) =
+                request::parse_head_with_limits(head.as_bytes(), &Default::default()).unwrap();
+            router.precheck(&request)
+        };
+        let response = put("PUT /upload/a HTTP/1.1\r\nContent-Length: 5\r\n\r\n").unwrap();
+        assert_eq!(response.header("Location"), Some("/v2/a"));
+        let response = put("PUT /admin/reload HTTP/1.1\r\nContent-Length: 5\r\n\r\n").unwrap();
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert!(
+            put("PUT /admin/reload HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: 5\r\n\r\n")
+                .is_none()
+        );
+        // the proxy wants the body
+        assert!(put("PUT /files/a HTTP/1.1\r\nContent-Length: 5\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn test_router_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        // routes added before and after the metrics are both instrumented
+        let router = Router::new()
+            .route("/a", RouteAction::Proxy(Proxy::new("127.0.0.1:1")))
+            .with_metrics(Arc::clone(&metrics))
+            .route("/", RouteAction::Proxy(Proxy::new("127.0.0.1:2")));
+
+        let request = parse(b"GET /a/x HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.route_name(&request), Some("/a"));
+        let request = parse(b"GET /x HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(router.route_name(&request), Some("/"));
+
+        let text = metrics.render();
+        assert!(text.contains("lb_backend_up{route=\"/a\",backend=\"127.0.0.1:1\"} 1\n"));
+        assert!(text.contains("lb_backend_up{route=\"/\",backend=\"127.0.0.1:2\"} 1\n"));
+    }
+
+    #[test]
+    fn test_router_streaming() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let unlimited = Limits {
+            max_body_size: usize::MAX,
+            ..Limits::default()
+        };
+        // counts the body of each request, whatever its framing
+        let handle = thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut received = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    received.extend_from_slice(&buf[..n]);
+                }
+                let (request, read) =
+                    request::parse_head_with_limits(&received, &unlimited).unwrap();
+                let mut body =
+                    BodyReader::for_request(&request, &received[read..], &stream, &unlimited)
+                        .unwrap();
+                let length = io::copy(&mut body, &mut io::sink()).unwrap();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nX-Received: {length}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let router = Router::new()
+            .route("/files", RouteAction::Static(FileServer::new("/srv/files")))
+            .route("/", RouteAction::Proxy(Proxy::new(addr)));
+        // past what the listener would read whole
+        let size = Limits::default().max_body_size as u64 + 1024 * 1024;
+        let heads = [
+            (
+                format!("PUT /upload HTTP/1.1\r\nHost: h\r\nContent-Length: {size}\r\n\r\n"),
+                Some(size),
+            ),
+            (
+                "POST /upload HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\n"
+                    .to_string(),
+                None,
+            ),
+        ];
+        for (head, length) in heads {
+            let (request, _) =
+                request::parse_head_with_limits(head.as_bytes(), &Limits::default()).unwrap();
+            assert!(router.streams(&request));
+            let mut reader = io::repeat(b'x').take(size);
+            let body = IncomingBody {
+                reader: &mut reader,
+                length,
+            };
+            let response = router.handle_streaming(&request, body).unwrap();
+            assert_eq!(response.status, StatusCode::OK);
+            assert_eq!(
+                response.header("X-Received"),
+                Some(size.to_string().as_str())
+            );
+        }
+        handle.join().unwrap();
+
+        let request = parse(b"PUT /files/a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        assert!(!router.streams(&request));
+    }
+}
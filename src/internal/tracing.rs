@@ -0,0 +1,549 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Write as _,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    internal::{accesslog::json_escape, headers::Headers},
+    warn,
+};
+
+// Longest X-Request-Id taken from a client, longer ones are replaced
+const MAX_REQUEST_ID: usize = 128;
+
+// Spans waiting for the exporter, more are dropped rather than slowing requests down
+const QUEUE_SIZE: usize = 4096;
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Not cryptographic, only has to make collisions unlikely. Every RandomState
+// gets fresh keys.
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{b:02x}");
+    }
+    out
+}
+
+fn unhex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    // only lowercase is valid in traceparent
+    if s.len() != N * 2 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut out = [0u8; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+// The X-Request-Id of the client when it is usable, a new one otherwise
+pub fn request_id(headers: Option<&Headers>) -> String {
+    match headers.and_then(|h| h.get("X-Request-Id")) {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID
+                && id.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            id.to_string()
+        }
+        _ => new_request_id(),
+    }
+}
+
+// A random UUID, version 4
+pub fn new_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&random_u64().to_be_bytes());
+    bytes[8..].copy_from_slice(&random_u64().to_be_bytes());
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// Where a span sits in a trace, carried between services by the W3C
+// traceparent and tracestate fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    // the span the next one is a child of
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+    // vendor data, passed on untouched
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    // Starts a new trace
+    pub fn new() -> TraceContext {
+        let mut trace_id = [0u8; 16];
+        trace_id[..8].copy_from_slice(&random_u64().to_be_bytes());
+        trace_id[8..].copy_from_slice(&random_u64().to_be_bytes());
+        TraceContext {
+            trace_id,
+            span_id: new_span_id(),
+            sampled: true,
+            state: None,
+        }
+    }
+
+    // The context of the caller, None when there is none or it is invalid
+    pub fn from_headers(headers: &Headers) -> Option<TraceContext> {
+        let mut context = TraceContext::parse(headers.get("traceparent")?)?;
+        context.state = headers
+            .get("tracestate")
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.to_string());
+        Some(context)
+    }
+
+    // version-trace_id-parent_id-flags, W3C Trace Context section 3.2
+    pub fn parse(value: &str) -> Option<TraceContext> {
+        let value = value.trim();
+        let version = unhex::<1>(value.get(..2)?)?[0];
+        // later versions may append fields, the known ones keep their place
+        let valid_length = match version {
+            0xff => false,
+            0 => value.len() == 55,
+            _ => value.len() == 55 || value.as_bytes().get(55) == Some(&b'-'),
+        };
+        if !valid_length || value.as_bytes()[2] != b'-' {
+            return None;
+        }
+
+        let mut parts = value[3..55].split('-');
+        let trace_id = unhex::<16>(parts.next()?)?;
+        let span_id = unhex::<8>(parts.next()?)?;
+        let flags = unhex::<1>(parts.next()?)?[0];
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+            state: None,
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+
+    pub fn trace_id(&self) -> String {
+        hex(&self.trace_id)
+    }
+
+    // Puts this context on a request that is sent on
+    pub fn set_headers(&self, headers: &mut Headers) {
+        headers.insert("traceparent", &self.traceparent());
+        match &self.state {
+            Some(state) => headers.insert("tracestate", state),
+            None => {
+                headers.remove("tracestate");
+            }
+        }
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        TraceContext::new()
+    }
+}
+
+fn new_span_id() -> [u8; 8] {
+    loop {
+        let id = random_u64();
+        if id != 0 {
+            return id.to_be_bytes();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpanKind {
+    Internal = 1,
+    // handling a request of a client
+    Server = 2,
+    // a request to an upstream
+    Client = 3,
+}
+
+// A timed operation of a trace, exported when it ends
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub name: String,
+    pub kind: SpanKind,
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_id: Option<[u8; 8]>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(String, String)>,
+    pub error: bool,
+    sampled: bool,
+    state: Option<String>,
+}
+
+impl Span {
+    // A child of `parent`, or the root of a new trace
+    pub fn start(name: &str, kind: SpanKind, parent: Option<&TraceContext>) -> Span {
+        let (trace_id, parent_id, sampled, state) = match parent {
+            Some(parent) => (
+                parent.trace_id,
+                Some(parent.span_id),
+                parent.sampled,
+                parent.state.clone(),
+            ),
+            None => (TraceContext::new().trace_id, None, true, None),
+        };
+        let now = SystemTime::now();
+        Span {
+            name: name.to_string(),
+            kind,
+            trace_id,
+            span_id: new_span_id(),
+            parent_id,
+            start: now,
+            end: now,
+            attributes: Vec::new(),
+            error: false,
+            sampled,
+            state,
+        }
+    }
+
+    // For work that already happened
+    pub fn started_at(mut self, start: SystemTime) -> Span {
+        self.start = start;
+        self
+    }
+
+    // What children of this span, here or upstream, are started from
+    pub fn context(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id,
+            span_id: self.span_id,
+            sampled: self.sampled,
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: impl ToString) {
+        self.attributes.push((key.to_string(), value.to_string()));
+    }
+
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+
+    // Hands the span to the exporter, when one was installed and the trace is sampled
+    pub fn end(mut self) {
+        self.end = SystemTime::now();
+        if self.sampled
+            && let Some(exporter) = EXPORTER.get()
+        {
+            exporter.export(self);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtlpConfig {
+    // host:port of the collector
+    pub address: String,
+    pub path: String,
+    pub service_name: String,
+    pub batch_size: usize,
+    // spans are sent at least this often while there are any
+    pub flush_interval: Duration,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        OtlpConfig {
+            address: "127.0.0.1:4318".to_string(),
+            path: "/v1/traces".to_string(),
+            service_name: "lb".to_string(),
+            batch_size: 512,
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl OtlpConfig {
+    // http://host:port/path, the path defaults to /v1/traces
+    pub fn from_url(url: &str) -> Result<OtlpConfig, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Invalid OTLP endpoint {url}, only http:// is supported"))?;
+        let (address, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/v1/traces"),
+        };
+        if address.is_empty() {
+            return Err(format!("Invalid OTLP endpoint {url}"));
+        }
+        Ok(OtlpConfig {
+            address: address.to_string(),
+            path: path.to_string(),
+            ..OtlpConfig::default()
+        })
+    }
+}
+
+// Sends ended spans to an OpenTelemetry collector as OTLP/JSON over HTTP, in
+// batches from a thread of its own
+#[derive(Debug)]
+pub struct Exporter {
+    queue: SyncSender<Span>,
+}
+
+impl Exporter {
+    pub fn spawn(config: OtlpConfig) -> Exporter {
+        let (queue, spans) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::spawn(move || run(config, spans));
+        Exporter { queue }
+    }
+
+    pub fn export(&self, span: Span) {
+        let _ = self.queue.try_send(span);
+    }
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+// Spans of every trace go to this exporter from now on
+pub fn install(exporter: Exporter) {
+    let _ = EXPORTER.set(exporter);
+}
+
+fn run(config: OtlpConfig, spans: Receiver<Span>) {
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + config.flush_interval;
+    loop {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        let closed = match spans.recv_timeout(wait) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        if batch.len() >= config.batch_size || Instant::now() >= deadline || closed {
+            if !batch.is_empty()
+                && let Err(e) = post(&config, &otlp_json(&config.service_name, &batch))
+            {
+                warn!("exporting {} spans failed: {e}", batch.len());
+            }
+            batch.clear();
+            deadline = Instant::now() + config.flush_interval;
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+fn post(config: &OtlpConfig, body: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect(&config.address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        config.path,
+        config.address,
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+
+    let mut status = [0u8; 12];
+    stream.read_exact(&mut status)?;
+    match &status[9..10] {
+        b"2" => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "collector answered {}",
+            String::from_utf8_lossy(&status[9..])
+        ))),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+// The OTLP/JSON encoding of ExportTraceServiceRequest, ids are hex and
+// 64 bit integers strings
+pub fn otlp_json(service_name: &str, spans: &[Span]) -> String {
+    let mut out = format!(
+        "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{{\"key\":\"service.name\",\"value\":{{\"stringValue\":\"{}\"}}}}]}},\"scopeSpans\":[{{\"scope\":{{\"name\":\"lb\"}},\"spans\":[",
+        json_escape(service_name)
+    );
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"traceId\":\"{}\",\"spanId\":\"{}\",",
+            hex(&span.trace_id),
+            hex(&span.span_id)
+        );
+        if let Some(parent) = span.parent_id {
+            let _ = write!(out, "\"parentSpanId\":\"{}\",", hex(&parent));
+        }
+        if let Some(state) = &span.state {
+            let _ = write!(out, "\"traceState\":\"{}\",", json_escape(state));
+        }
+        let _ = write!(
+            out,
+            "\"name\":\"{}\",\"kind\":{},\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[",
+            json_escape(&span.name),
+            span.kind as u8,
+            unix_nanos(span.start),
+            unix_nanos(span.end)
+        );
+        for (j, (key, value)) in span.attributes.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"key\":\"{}\",\"value\":{{\"stringValue\":\"{}\"}}}}",
+                json_escape(key),
+                json_escape(value)
+            );
+        }
+        // 1 is ok, 2 error
+        let _ = write!(
+            out,
+            "],\"status\":{{\"code\":{}}}}}",
+            if span.error { 2 } else { 1 }
+        );
+    }
+    out.push_str("]}]}]}");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_traceparent() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(value).unwrap();
+        assert_eq!(context.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(context.sampled);
+        assert_eq!(context.traceparent(), value);
+
+        // a later version may carry more fields
+        let future = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra";
+        assert!(!TraceContext::parse(future).unwrap().sampled);
+
+        for invalid in [
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None, "{invalid}");
+        }
+
+        let span = Span::start("route", SpanKind::Internal, Some(&context));
+        assert_eq!(span.trace_id, context.trace_id);
+        assert_eq!(span.parent_id, Some(context.span_id));
+        assert_ne!(span.span_id, context.span_id);
+    }
+
+    #[test]
+    fn test_request_id() {
+        let mut headers = Headers::default();
+        headers.insert("X-Request-Id", "abc-123");
+        assert_eq!(request_id(Some(&headers)), "abc-123");
+
+        headers.insert("X-Request-Id", "with space");
+        let id = request_id(Some(&headers));
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert_ne!(request_id(None), request_id(None));
+    }
+
+    #[test]
+    fn test_otlp_export() {
+        let collector = TcpListener::bind("127.0.0.1:0").unwrap();
+        let exporter = Exporter::spawn(OtlpConfig {
+            address: collector.local_addr().unwrap().to_string(),
+            batch_size: 2,
+            ..OtlpConfig::default()
+        });
+
+        let parent = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        let mut span = Span::start("upstream response", SpanKind::Client, parent.as_ref());
+        span.set_attribute("server.address", "10.0.0.1:80");
+        span.end = SystemTime::now();
+        exporter.export(span.clone());
+        exporter.export(Span::start("parse", SpanKind::Internal, None));
+
+        let (mut stream, _) = collector.accept().unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !received.ends_with(b"}]}]}]}") {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0);
+            received.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+
+        let request = String::from_utf8(received).unwrap();
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains("\"traceId\":\"4bf92f3577b34da6a3ce929d0e0e4736\""));
+        assert!(request.contains("\"parentSpanId\":\"00f067aa0ba902b7\""));
+        assert!(
+            request.contains(
+                "{\"key\":\"server.address\",\"value\":{\"stringValue\":\"10.0.0.1:80\"}}"
+            )
+        );
+        assert!(request.contains("\"name\":\"parse\",\"kind\":1"));
+    }
+}
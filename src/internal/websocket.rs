@@ -0,0 +1,556 @@
+use std::{
+    io::{Error, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::internal::{
+    config::Config,
+    connection::{ClientStream, ConnectionGuard},
+    metrics::Metrics,
+    pool::Connection,
+    request::{Request, RequestMethod},
+    response::{Response, StatusCode},
+};
+
+// The only version of RFC 6455
+pub const VERSION: &str = "13";
+
+// How often a tunnel looks at its idle time and at a shutdown
+const TUNNEL_TICK: Duration = Duration::from_millis(500);
+
+const MESSAGE_TOO_BIG: &str = "WebSocket message too big";
+
+// Close codes, RFC 6455 section 7.4.1
+const GOING_AWAY: u16 = 1001;
+const TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebSocketConfig {
+    // a tunnel without traffic either way for this long is closed
+    pub idle_timeout: Duration,
+    // largest message a client may send, in payload bytes, 0 for no limit
+    pub max_message_size: u64,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            idle_timeout: Duration::from_secs(60),
+            max_message_size: 0,
+        }
+    }
+}
+
+fn has_token(request: &Request, field: &str, token: &str) -> bool {
+    request
+        .headers
+        .as_ref()
+        .and_then(|h| h.get(field))
+        .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+// A GET over HTTP/1.1 with `Connection: Upgrade` and `Upgrade: websocket`
+pub fn is_upgrade(request: &Request) -> bool {
+    request.method == Some(RequestMethod::Get)
+        && request.version.as_deref() == Some("HTTP/1.1")
+        && has_token(request, "Connection", "upgrade")
+        && has_token(request, "Upgrade", "websocket")
+}
+
+// The answer to an opening handshake that cannot be passed on, RFC 6455
+// section 4.2.2: a missing key is a bad request, an unknown version gets the
+// one that is supported
+pub fn check_handshake(request: &Request) -> Option<Response> {
+    let headers = request.headers.as_ref()?;
+    if headers
+        .get("Sec-WebSocket-Key")
+        .is_none_or(|key| key.trim().is_empty())
+    {
+        return Some(
+            Response::builder(StatusCode::BAD_REQUEST)
+                .body("Missing Sec-WebSocket-Key")
+                .build(),
+        );
+    }
+    if headers.get("Sec-WebSocket-Version").map(str::trim) != Some(VERSION) {
+        return Some(
+            Response::builder(StatusCode::UPGRADE_REQUIRED)
+                .header("Sec-WebSocket-Version", VERSION)
+                .body(StatusCode::UPGRADE_REQUIRED.reason())
+                .build(),
+        );
+    }
+    None
+}
+
+// The upstream end of a connection that switched to WebSocket, the listener
+// relays between it and the client once the 101 is sent
+#[derive(Debug)]
+pub struct Upgraded {
+    connection: Connection,
+    // what the upstream sent right behind its 101
+    leftover: Vec<u8>,
+}
+
+impl Upgraded {
+    pub fn new(connection: Connection, leftover: Vec<u8>) -> Self {
+        Upgraded {
+            connection,
+            leftover,
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        self.connection.address()
+    }
+}
+
+// Follows the frames of one direction as the bytes go by, RFC 6455 section
+// 5.2, without buffering or unmasking payloads
+#[derive(Debug, Default)]
+pub struct FrameParser {
+    header: Vec<u8>,
+    // payload bytes left of the current frame
+    remaining: u64,
+    // payload of the data frames of the message so far
+    message: u64,
+    max_message: u64,
+}
+
+impl FrameParser {
+    // 0 for no limit on the message size
+    pub fn new(max_message: u64) -> Self {
+        FrameParser {
+            max_message,
+            ..FrameParser::default()
+        }
+    }
+
+    // Returns how many messages were completed in `data`. Fails with
+    // ErrorKind::InvalidData once a message grows over the limit.
+    pub fn feed(&mut self, mut data: &[u8]) -> Result<u64, Error> {
+        let mut messages = 0;
+        loop {
+            if self.remaining > 0 {
+                if data.is_empty() {
+                    break;
+                }
+                let n = self.remaining.min(data.len() as u64);
+                self.remaining -= n;
+                data = &data[n as usize..];
+                continue;
+            }
+
+            let want = header_len(&self.header);
+            if self.header.len() < want {
+                if data.is_empty() {
+                    break;
+                }
+                let n = (want - self.header.len()).min(data.len());
+                self.header.extend_from_slice(&data[..n]);
+                data = &data[n..];
+                continue;
+            }
+
+            let fin = self.header[0] & 0x80 != 0;
+            let opcode = self.header[0] & 0x0f;
+            let len = match self.header[1] & 0x7f {
+                126 => u16::from_be_bytes([self.header[2], self.header[3]]) as u64,
+                127 => u64::from_be_bytes(self.header[2..10].try_into().unwrap()),
+                len => len as u64,
+            };
+            self.header.clear();
+            self.remaining = len;
+
+            // control frames (close, ping, pong) come between the fragments
+            // of a message and do not count towards it
+            if opcode < 8 {
+                self.message += len;
+                if self.max_message > 0 && self.message > self.max_message {
+                    return Err(Error::new(ErrorKind::InvalidData, MESSAGE_TOO_BIG));
+                }
+                if fin {
+                    messages += 1;
+                    self.message = 0;
+                }
+            }
+        }
+        Ok(messages)
+    }
+}
+
+// Length of the frame header that starts with `header`, known once the
+// first two bytes are
+fn header_len(header: &[u8]) -> usize {
+    if header.len() < 2 {
+        return 2;
+    }
+    let extended = match header[1] & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask = if header[1] & 0x80 != 0 { 4 } else { 0 };
+    2 + extended + mask
+}
+
+// A close frame from the server side, which is not masked
+fn close_frame(code: u16) -> [u8; 4] {
+    let [high, low] = code.to_be_bytes();
+    [0x88, 0x02, high, low]
+}
+
+// What went through a tunnel
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TunnelStats {
+    // bytes and messages from the client
+    pub received: u64,
+    pub messages_received: u64,
+    // bytes and messages to the client
+    pub sent: u64,
+    pub messages_sent: u64,
+}
+
+// Shared by the two directions of a tunnel
+struct Tunnel<'a> {
+    client: &'a ClientStream,
+    upstream: &'a TcpStream,
+    // writes to the client from both directions go one at a time, a close
+    // frame is not spliced into a relayed chunk
+    client_writes: Mutex<()>,
+    started: Instant,
+    // milliseconds from `started` until the last byte either way
+    last_activity: AtomicU64,
+    closed: AtomicBool,
+    idle_timeout: Duration,
+}
+
+impl Tunnel<'_> {
+    fn touch(&self) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last_activity.store(now, Ordering::Relaxed);
+    }
+
+    fn is_idle(&self) -> bool {
+        let last = Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last) >= self.idle_timeout
+    }
+
+    fn to_client(&self, data: &[u8]) -> Result<(), Error> {
+        let _writing = self.client_writes.lock().unwrap();
+        let mut client = self.client;
+        client.write_all(data)?;
+        client.flush()
+    }
+
+    // Tells the client why, when there is a reason, and closes both ends,
+    // which also wakes the other direction
+    fn close(&self, code: Option<u16>) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(code) = code {
+            let _ = self.to_client(&close_frame(code));
+        }
+        let _ = self.client.tcp().shutdown(Shutdown::Both);
+        let _ = self.upstream.shutdown(Shutdown::Both);
+    }
+
+    // Whether the direction that just timed out waiting should give up
+    fn should_stop(&self, connection: &ConnectionGuard) -> bool {
+        if self.closed.load(Ordering::SeqCst) {
+            return true;
+        }
+        if connection.is_stopping() {
+            self.close(Some(GOING_AWAY));
+            return true;
+        }
+        if self.is_idle() {
+            self.close(None);
+            return true;
+        }
+        false
+    }
+}
+
+fn is_timeout(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// Relays bytes between the client and the upstream until either side closes,
+// nothing moved for the idle timeout, a client message is over the limit or
+// the server stops. `pending` is what the client sent behind its handshake.
+pub fn tunnel(
+    client: &ClientStream,
+    upgraded: Upgraded,
+    pending: &[u8],
+    config: &Config,
+    connection: &ConnectionGuard,
+    metrics: Option<&Arc<Metrics>>,
+) -> TunnelStats {
+    let settings = &config.websocket;
+    let upstream = upgraded.connection.stream();
+    let _ = client.tcp().set_read_timeout(Some(TUNNEL_TICK));
+    let _ = upstream.set_read_timeout(Some(TUNNEL_TICK));
+    let _ = upstream.set_write_timeout(Some(config.timeouts.write));
+
+    let tunnel = Tunnel {
+        client,
+        upstream,
+        client_writes: Mutex::new(()),
+        started: Instant::now(),
+        last_activity: AtomicU64::new(0),
+        closed: AtomicBool::new(false),
+        idle_timeout: settings.idle_timeout,
+    };
+    if let Some(metrics) = metrics {
+        metrics.websocket_opened();
+    }
+
+    let (received, sent) = thread::scope(|scope| {
+        let from_upstream = scope.spawn(|| {
+            let mut frames = FrameParser::new(0);
+            let mut stats = (0, 0);
+            let mut relay = |data: &[u8]| -> Result<(), Error> {
+                stats.1 += frames.feed(data).unwrap_or(0);
+                tunnel.to_client(data)?;
+                stats.0 += data.len() as u64;
+                if let Some(metrics) = metrics {
+                    metrics.add_bytes_sent(data.len() as u64);
+                }
+                Ok(())
+            };
+            if relay(&upgraded.leftover).is_ok() {
+                pump(&tunnel, upstream, connection, &mut relay);
+            }
+            tunnel.close(None);
+            stats
+        });
+
+        let mut frames = FrameParser::new(settings.max_message_size);
+        let mut stats = (0, 0);
+        let mut relay = |data: &[u8]| -> Result<(), Error> {
+            match frames.feed(data) {
+                Ok(n) => stats.1 += n,
+                Err(e) => {
+                    tunnel.close(Some(TOO_BIG));
+                    return Err(e);
+                }
+            }
+            let mut upstream = upstream;
+            upstream.write_all(data)?;
+            stats.0 += data.len() as u64;
+            if let Some(metrics) = metrics {
+                metrics.add_bytes_received(data.len() as u64);
+            }
+            Ok(())
+        };
+        if relay(pending).is_ok() {
+            pump(&tunnel, client, connection, &mut relay);
+        }
+        tunnel.close(None);
+
+        (stats, from_upstream.join().unwrap_or_default())
+    });
+
+    let stats = TunnelStats {
+        received: received.0,
+        messages_received: received.1,
+        sent: sent.0,
+        messages_sent: sent.1,
+    };
+    if let Some(metrics) = metrics {
+        metrics.add_websocket_messages("client", stats.messages_received);
+        metrics.add_websocket_messages("upstream", stats.messages_sent);
+        metrics.websocket_closed();
+    }
+    stats
+}
+
+// Reads one direction until it ends, handing each chunk to `relay`
+fn pump(
+    tunnel: &Tunnel,
+    mut from: impl Read,
+    connection: &ConnectionGuard,
+    relay: &mut impl FnMut(&[u8]) -> Result<(), Error>,
+) {
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        match from.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => {
+                tunnel.touch();
+                if relay(&buf[..n]).is_err() {
+                    return;
+                }
+            }
+            Err(e) if is_timeout(&e) => {
+                if tunnel.should_stop(connection) {
+                    return;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::internal::{
+        connection::{ConnectionLimits, ConnectionTracker},
+        proxy::Proxy,
+        request::parse,
+    };
+    use std::net::TcpListener;
+
+    // a frame as a client sends it, masked
+    fn frame(fin: bool, opcode: u8, payload_len: usize) -> Vec<u8> {
+        let mut out = vec![(fin as u8) << 7 | opcode];
+        match payload_len {
+            0..=125 => out.push(0x80 | payload_len as u8),
+            126..=65535 => {
+                out.push(0x80 | 126);
+                out.extend_from_slice(&(payload_len as u16).to_be_bytes());
+            }
+            _ => {
+                out.push(0x80 | 127);
+                out.extend_from_slice(&(payload_len as u64).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&[1, 2, 3, 4]);
+        out.extend(std::iter::repeat_n(b'x', payload_len));
+        out
+    }
+
+    #[test]
+    fn test_is_upgrade() {
+        let request = parse(
+            b"GET /chat HTTP/1.1\r\nHost: a\r\nConnection: keep-alive, Upgrade\r\n\
+              Upgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+        assert!(is_upgrade(&request));
+        assert!(check_handshake(&request).is_none());
+
+        let request = parse(b"GET /chat HTTP/1.1\r\nUpgrade: websocket\r\n\r\n").unwrap();
+        assert!(!is_upgrade(&request));
+
+        let request = parse(
+            b"GET /chat HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Key: x\r\nSec-WebSocket-Version: 8\r\n\r\n",
+        )
+        .unwrap();
+        let response = check_handshake(&request).unwrap();
+        assert_eq!(response.status, StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(response.header("Sec-WebSocket-Version"), Some("13"));
+    }
+
+    #[test]
+    fn test_frame_parser() {
+        let mut parser = FrameParser::new(0);
+        // a fragmented message with a ping in between, fed a byte at a time
+        let mut data = frame(false, 1, 10);
+        data.extend(frame(true, 9, 4));
+        data.extend(frame(true, 0, 300));
+        data.extend(frame(true, 2, 70_000));
+        let mut messages = 0;
+        for chunk in data.chunks(1) {
+            messages += parser.feed(chunk).unwrap();
+        }
+        assert_eq!(messages, 2);
+
+        // an empty frame completes a message at the end of the data
+        assert_eq!(parser.feed(&frame(true, 1, 0)).unwrap(), 1);
+
+        let mut limited = FrameParser::new(100);
+        assert_eq!(limited.feed(&frame(false, 2, 60)).unwrap(), 0);
+        let error = limited.feed(&frame(true, 0, 60)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_tunnel() {
+        // an upstream that takes the handshake and echoes what follows
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = upstream.local_addr().unwrap().to_string();
+        let echo = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0u8; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+                      Upgrade: websocket\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+                )
+                .unwrap();
+            let mut buf = [0u8; 1024];
+            while let Ok(n @ 1..) = stream.read(&mut buf) {
+                stream.write_all(&buf[..n]).unwrap();
+            }
+            String::from_utf8(head).unwrap()
+        });
+
+        let request = parse(
+            b"GET /chat HTTP/1.1\r\nHost: a\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+        let mut response = Proxy::new(&address).serve(&request);
+        assert_eq!(response.status, StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(
+            response.header("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        let upgraded = response.upgrade.take().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server_side = ClientStream::from(listener.accept().unwrap().0);
+        let tracker = ConnectionTracker::new(ConnectionLimits::default());
+        let guard = tracker.acquire(None).unwrap();
+        let config = Config {
+            websocket: WebSocketConfig {
+                max_message_size: 100,
+                ..WebSocketConfig::default()
+            },
+            ..Config::default()
+        };
+
+        // a message sent right behind the handshake comes back, one over the
+        // limit closes the tunnel with 1009
+        let pending = frame(true, 1, 5);
+        let stats = thread::scope(|scope| {
+            let relay =
+                scope.spawn(|| tunnel(&server_side, upgraded, &pending, &config, &guard, None));
+            let mut echoed = vec![0u8; pending.len()];
+            client.read_exact(&mut echoed).unwrap();
+            assert_eq!(echoed, pending);
+
+            client.write_all(&frame(true, 2, 200)).unwrap();
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, close_frame(TOO_BIG));
+            relay.join().unwrap()
+        });
+        assert_eq!(stats.messages_received, 1);
+        assert_eq!(stats.messages_sent, 1);
+        assert_eq!(stats.sent, pending.len() as u64);
+
+        let head = echo.join().unwrap();
+        assert!(head.contains("connection: upgrade\r\nupgrade: websocket\r\n"));
+        assert!(head.contains("sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n"));
+    }
+}
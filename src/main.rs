@@ -1,9 +1,893 @@
-use cmd::tcplistener;
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use lb::cmd::tcplistener;
+use lb::internal::{
+    accesslog::{AccessLog, AccessLogConfig, LogOutput},
+    admin::Admin,
+    breaker::BreakerConfig,
+    cache::{Cache, CacheConfig, Cidr},
+    config::Config,
+    connection::ConnectionTracker,
+    fileserver::FileServer,
+    grpc::HealthCheck,
+    listener::{self, Listener, ListenerConfig},
+    logging::{self, Level},
+    metrics::Metrics,
+    proxy::Proxy,
+    ratelimit::{RateLimitRule, RateLimiter},
+    reload::{self, Live, Snapshot},
+    response::StatusCode,
+    rewrite::{Redirect, RedirectMap, Rewrite, Template},
+    router::{RouteAction, Router},
+    tls::TlsConfig,
+    tracing::{self, Exporter, OtlpConfig},
+};
+use lb::{error, info, warn};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
+
+// How often the config file is checked for changes
+const CONFIG_POLL: Duration = Duration::from_secs(2);
+
+// lb [--config <file>] [<options>] [--listen <address> [--tls-cert <file> --tls-key <file>]
+//    [--accept-threads <n>] [--http2] [<options>]]...
+//
+// options: [--listing] [--compress] [--decompress-requests] [--cache] [--cache-dir <dir>]
+//    [--cache-purge-allow <cidr>]...
+//    [--max-connections <n>] [--max-connections-per-ip <n>] [--max-body-size <bytes>]
+//    [--max-request-line <bytes>] [--max-header-size <bytes>] [--max-header-bytes <bytes>]
+//    [--max-header-count <n>] [--upstream-max-idle <n>]
+//    [--retries <n>] [--retry-on <conditions>] [--retry-non-idempotent] [--try-timeout <secs>]
+//    [--drain-timeout <secs>] [--request-line-timeout <secs>] [--header-timeout <secs>]
+//    [--body-timeout <secs>] [--keep-alive-timeout <secs>] [--min-body-rate <bytes/s>]
+//    [--min-body-rate-grace <secs>]
+//    [--websocket-idle-timeout <secs>] [--websocket-max-message <bytes>]
+//    [--http2-max-streams <n>] [--http2-window <bytes>]
+//    [--circuit-breaker] [--max-concurrent <n>] [--max-pending <n>]
+//    [--fallback <prefix>=<host:port>[,<host:port>]...]...
+//    [--static <prefix>=<dir>]... [--proxy <prefix>=<host:port>[,<host:port>]...]...
+//    [--grpc <prefix>=<host:port>[,<host:port>]...]... [--grpc-health-check <secs>]
+//    [--grpc-health-service <name>]
+//    [--redirect <prefix>=<status>:<location>]... [--redirect-map <file>]
+//    [--rewrite-prefix <prefix>=<replacement>]... [--rewrite <prefix> <regex> <replacement>]...
+//    [--rate-limit <rule>]... [--admin <host:port>] [--admin-token <token>]
+//    [--access-log <path|->] [--log-format <common|combined|json>] [--log-fields <fields>]
+//    [--log-sample <share>] [--log-level <level>]
+//    [--otlp-endpoint <http://host:port/path>] [--service-name <name>]
+//
+// Without --listen there is one listener on 127.0.0.1:8080. The options after
+// a --listen apply to that listener, the ones before the first one to every
+// listener; --admin, --admin-token, --log-level, --otlp-endpoint and
+// --service-name only go there.
+//
+// The config file holds the same options, one or more per line, # starts a
+// comment. It is read again on SIGHUP, on POST /reload of the admin API and
+// when it changes.
+//
+// The admin token can also be given in LB_ADMIN_TOKEN, out of sight of ps.
+struct Settings {
+    listeners: Vec<ListenerSettings>,
+    log_level: Option<Level>,
+    // only read at startup
+    admin: Option<String>,
+    admin_token: Option<String>,
+    otlp: Option<OtlpConfig>,
+}
+
+// What one listener serves
+struct ListenerSettings {
+    listener: ListenerConfig,
+    router: Router,
+    config: Config,
+    access_log: Option<AccessLogConfig>,
+}
+
+const DEFAULT_LISTENER: &str = "127.0.0.1:8080";
+
+fn value(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+    what: &str,
+) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{flag} needs {what}"))
+}
+
+fn number<T: FromStr>(value: &str, flag: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid {flag} {value}"))
+}
+
+// Whole seconds, at least one: a deadline of 0 would refuse every request
+fn timeout(value: &str, flag: &str) -> Result<Duration, String> {
+    match number(value, flag)? {
+        0 => Err(format!("{flag} needs at least 1")),
+        secs => Ok(Duration::from_secs(secs)),
+    }
+}
+
+// prefix=value
+fn mapping(value: &str, flag: &str, what: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(prefix, rest)| (prefix.to_string(), rest.to_string()))
+        .ok_or_else(|| format!("{flag} needs <prefix>={what}"))
+}
+
+// host:port[,host:port]...
+fn upstreams(value: &str) -> Result<Vec<String>, String> {
+    value
+        .split(',')
+        .map(|upstream| match upstream.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(upstream.to_string())
+            }
+            _ => Err(format!("Invalid upstream {upstream}, expected <host:port>")),
+        })
+        .collect()
+}
+
+// status:location, 301:https://{hostname}{target}
+fn redirect(value: &str) -> Result<Redirect, String> {
+    let invalid = |e: &dyn std::fmt::Display| format!("Invalid --redirect {value}: {e}");
+    let (status, location) = value
+        .split_once(':')
+        .ok_or_else(|| invalid(&"expected <status>:<location>"))?;
+    let status = number(status, "--redirect status")
+        .and_then(|code| StatusCode::from_u16(code).map_err(|e| e.to_string()))?;
+    let location = Template::parse(location).map_err(|e| invalid(&e))?;
+    Redirect::new(status, location).map_err(|e| invalid(&e))
+}
+
+// The arguments with the options of the config file in place of --config
+fn args() -> Result<Vec<String>, String> {
+    let mut expanded = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                let path = value(&mut args, "--config", "<file>")?;
+                let text = fs::read_to_string(&path)
+                    .map_err(|e| format!("Cannot read the config file {path}: {e}"))?;
+                for line in text.lines() {
+                    let line = line.split_once('#').map_or(line, |(options, _)| options);
+                    expanded.extend(line.split_whitespace().map(|s| s.to_string()));
+                }
+            }
+            _ => expanded.push(arg),
+        }
+    }
+    Ok(expanded)
+}
+
+fn config_path() -> Option<PathBuf> {
+    let mut args = env::args().skip_while(|a| a != "--config");
+    args.next()?;
+    args.next().map(PathBuf::from)
+}
+
+fn parse(args: Vec<String>) -> Result<Settings, String> {
+    let mut sections = args.split(|arg| arg == "--listen");
+    let common = sections.next().unwrap_or_default();
+    let mut settings = Settings {
+        listeners: Vec::new(),
+        log_level: None,
+        admin: None,
+        admin_token: env::var("LB_ADMIN_TOKEN").ok(),
+        otlp: None,
+    };
+
+    // the options for the whole server
+    let mut service_name: Option<String> = None;
+    let mut shared = Vec::new();
+    let mut args = common.iter().cloned();
+    while let Some(arg) = args.next() {
+        let flag = arg.as_str();
+        match flag {
+            "--admin" => settings.admin = Some(value(&mut args, flag, "<host:port>")?),
+            "--admin-token" => settings.admin_token = Some(value(&mut args, flag, "<token>")?),
+            "--log-level" => {
+                settings.log_level = Some(value(&mut args, flag, "<level>")?.parse()?);
+            }
+            "--otlp-endpoint" => {
+                settings.otlp = Some(OtlpConfig::from_url(&value(&mut args, flag, "<url>")?)?);
+            }
+            "--service-name" => service_name = Some(value(&mut args, flag, "<name>")?),
+            _ => shared.push(arg),
+        }
+    }
+    if let (Some(otlp), Some(name)) = (&mut settings.otlp, service_name) {
+        otlp.service_name = name;
+    }
+
+    let sections: Vec<&[String]> = sections.collect();
+    if sections.is_empty() {
+        let (listener, options) = listener_options(DEFAULT_LISTENER, shared.clone())?;
+        settings.listeners.push(routes(listener, options)?);
+    }
+    for section in sections {
+        let (address, options) = section
+            .split_first()
+            .ok_or("--listen needs <address>".to_string())?;
+        let (listener, options) = listener_options(address, options.to_vec())?;
+        if settings
+            .listeners
+            .iter()
+            .any(|l| l.listener.address == listener.address)
+        {
+            return Err(format!("--listen {address} is given twice"));
+        }
+        settings
+            .listeners
+            .push(routes(listener, [shared.clone(), options].concat())?);
+    }
+    Ok(settings)
+}
+
+// Takes out the options about the listener itself
+fn listener_options(
+    address: &str,
+    args: Vec<String>,
+) -> Result<(ListenerConfig, Vec<String>), String> {
+    let address =
+        listener::parse_address(address).map_err(|e| format!("Invalid --listen {address}: {e}"))?;
+    let mut listener = ListenerConfig::new(address);
+    let (mut cert, mut key) = (None, None);
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let flag = arg.as_str();
+        match flag {
+            "--tls-cert" => cert = Some(PathBuf::from(value(&mut args, flag, "<file>")?)),
+            "--tls-key" => key = Some(PathBuf::from(value(&mut args, flag, "<file>")?)),
+            "--http2" => listener.http2 = true,
+            "--accept-threads" => {
+                listener.accept_threads = number(&value(&mut args, flag, "<n>")?, flag)?;
+                if listener.accept_threads == 0 {
+                    return Err(format!("{flag} needs at least 1"));
+                }
+            }
+            _ => rest.push(arg),
+        }
+    }
+    listener.tls = match (cert, key) {
+        (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+        (None, None) => None,
+        _ => return Err(format!("{address} needs both --tls-cert and --tls-key")),
+    };
+    Ok((listener, rest))
+}
+
+// The routes and limits of one listener
+fn routes(listener: ListenerConfig, args: Vec<String>) -> Result<ListenerSettings, String> {
+    let mut router = Router::new();
+    let mut config = Config::default();
+    let mut listing = false;
+    let mut cache: Option<CacheConfig> = None;
+    let mut purge_allowed: Vec<Cidr> = Vec::new();
+    let mut statics: Vec<(String, String)> = Vec::new();
+    // the bool is for gRPC routes, whose backends speak HTTP/2
+    let mut proxies: Vec<(String, Vec<String>, bool)> = Vec::new();
+    let mut health: Option<HealthCheck> = None;
+    let mut rate_limits: Vec<RateLimitRule> = Vec::new();
+    let mut breaker: Option<BreakerConfig> = None;
+    let mut fallbacks: HashMap<String, Vec<String>> = HashMap::new();
+    let mut access_log: Option<AccessLogConfig> = None;
+    let mut redirects: Vec<(String, Redirect)> = Vec::new();
+    let mut redirect_map: Option<RedirectMap> = None;
+    let mut rewrites: Vec<(String, Rewrite)> = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let flag = arg.as_str();
+        match flag {
+            "--listing" => listing = true,
+            "--compress" => config.compression.enabled = true,
+            "--decompress-requests" => config.compression.decompress_requests = true,
+            "--static" => {
+                let (prefix, dir) =
+                    mapping(&value(&mut args, flag, "<prefix>=<dir>")?, flag, "<dir>")?;
+                if !Path::new(&dir).is_dir() {
+                    return Err(format!("{flag} {prefix}={dir}: {dir} is not a directory"));
+                }
+                statics.push((prefix, dir));
+            }
+            "--proxy" => {
+                let (prefix, list) = mapping(
+                    &value(&mut args, flag, "<prefix>=<host:port>")?,
+                    flag,
+                    "<host:port>",
+                )?;
+                proxies.push((prefix, upstreams(&list)?, false));
+            }
+            "--grpc" => {
+                let (prefix, list) = mapping(
+                    &value(&mut args, flag, "<prefix>=<host:port>")?,
+                    flag,
+                    "<host:port>",
+                )?;
+                proxies.push((prefix, upstreams(&list)?, true));
+            }
+            "--grpc-health-check" => {
+                let secs: u64 = number(&value(&mut args, flag, "<secs>")?, flag)?;
+                if secs == 0 {
+                    return Err(format!("{flag} needs at least 1"));
+                }
+                health = Some(HealthCheck {
+                    interval: Duration::from_secs(secs),
+                    ..health.unwrap_or_default()
+                });
+            }
+            "--grpc-health-service" => {
+                health = Some(HealthCheck {
+                    service: value(&mut args, flag, "<name>")?,
+                    ..health.unwrap_or_default()
+                });
+            }
+            "--redirect" => {
+                let (prefix, target) = mapping(
+                    &value(&mut args, flag, "<prefix>=<status>:<location>")?,
+                    flag,
+                    "<status>:<location>",
+                )?;
+                redirects.push((prefix, redirect(&target)?));
+            }
+            "--redirect-map" => {
+                let path = value(&mut args, flag, "<file>")?;
+                let text = fs::read_to_string(&path).map_err(|e| format!("{flag} {path}: {e}"))?;
+                redirect_map =
+                    Some(RedirectMap::parse(&text).map_err(|e| format!("{flag} {path}: {e}"))?);
+            }
+            "--rewrite-prefix" => {
+                let (prefix, to) = mapping(
+                    &value(&mut args, flag, "<prefix>=<replacement>")?,
+                    flag,
+                    "<replacement>",
+                )?;
+                let rule = Rewrite::prefix(&prefix, &to);
+                rewrites.push((prefix, rule));
+            }
+            "--rewrite" => {
+                let prefix = value(&mut args, flag, "<prefix> <regex> <replacement>")?;
+                let pattern = value(&mut args, flag, "<prefix> <regex> <replacement>")?;
+                let replacement = value(&mut args, flag, "<prefix> <regex> <replacement>")?;
+                let rule = Rewrite::regex(&pattern, &replacement)
+                    .map_err(|e| format!("{flag} {prefix} {pattern}: {e}"))?;
+                rewrites.push((prefix, rule));
+            }
+            "--max-connections" => {
+                config.connections.max_connections = number(&value(&mut args, flag, "<n>")?, flag)?;
+            }
+            "--max-connections-per-ip" => {
+                config.connections.max_connections_per_ip =
+                    number(&value(&mut args, flag, "<n>")?, flag)?;
+            }
+            "--max-body-size" => {
+                config.limits.max_body_size = number(&value(&mut args, flag, "<bytes>")?, flag)?;
+            }
+            "--max-request-line" | "--max-header-size" | "--max-header-bytes"
+            | "--max-header-count" => {
+                let n = number(&value(&mut args, flag, "<n>")?, flag)?;
+                // no request gets past a limit of 0
+                if n == 0 {
+                    return Err(format!("{flag} needs at least 1"));
+                }
+                let limit = match flag {
+                    "--max-request-line" => &mut config.limits.max_request_line,
+                    "--max-header-size" => &mut config.limits.max_header_size,
+                    "--max-header-bytes" => &mut config.limits.max_header_bytes,
+                    _ => &mut config.limits.max_header_count,
+                };
+                *limit = n;
+            }
+            "--upstream-max-idle" => {
+                config.upstream.max_idle = number(&value(&mut args, flag, "<n>")?, flag)?;
+            }
+            "--retries" => {
+                let n: u32 = number(&value(&mut args, flag, "<n>")?, flag)?;
+                config.retry.max_attempts = n + 1;
+            }
+            "--retry-on" => {
+                config.retry.retry_on = value(&mut args, flag, "<conditions>")?.parse()?;
+            }
+            "--retry-non-idempotent" => config.retry.retry_non_idempotent = true,
+            "--try-timeout" => {
+                let secs = number(&value(&mut args, flag, "<secs>")?, flag)?;
+                config.retry.per_try_timeout = Some(Duration::from_secs(secs));
+            }
+            "--drain-timeout" => {
+                let secs = number(&value(&mut args, flag, "<secs>")?, flag)?;
+                config.timeouts.drain = Duration::from_secs(secs);
+            }
+            "--request-line-timeout" => {
+                config.timeouts.request_line = timeout(&value(&mut args, flag, "<secs>")?, flag)?;
+            }
+            "--header-timeout" => {
+                config.timeouts.headers = timeout(&value(&mut args, flag, "<secs>")?, flag)?;
+            }
+            "--body-timeout" => {
+                config.timeouts.body = timeout(&value(&mut args, flag, "<secs>")?, flag)?;
+            }
+            "--keep-alive-timeout" => {
+                config.timeouts.keep_alive = timeout(&value(&mut args, flag, "<secs>")?, flag)?;
+            }
+            "--min-body-rate" => {
+                config.timeouts.min_body_rate =
+                    number(&value(&mut args, flag, "<bytes/s>")?, flag)?;
+            }
+            "--min-body-rate-grace" => {
+                let secs = number(&value(&mut args, flag, "<secs>")?, flag)?;
+                config.timeouts.min_body_rate_grace = Duration::from_secs(secs);
+            }
+            "--websocket-idle-timeout" => {
+                let secs = number(&value(&mut args, flag, "<secs>")?, flag)?;
+                config.websocket.idle_timeout = Duration::from_secs(secs);
+            }
+            "--websocket-max-message" => {
+                config.websocket.max_message_size =
+                    number(&value(&mut args, flag, "<bytes>")?, flag)?;
+            }
+            "--http2-max-streams" => {
+                config.http2.max_concurrent_streams =
+                    number(&value(&mut args, flag, "<n>")?, flag)?;
+                if config.http2.max_concurrent_streams == 0 {
+                    return Err(format!("{flag} needs at least 1"));
+                }
+            }
+            "--http2-window" => {
+                config.http2.initial_window_size =
+                    number(&value(&mut args, flag, "<bytes>")?, flag)?;
+            }
+            "--circuit-breaker" => breaker = Some(breaker.unwrap_or_default()),
+            "--max-concurrent" => {
+                breaker = Some(BreakerConfig {
+                    max_concurrent: number(&value(&mut args, flag, "<n>")?, flag)?,
+                    ..breaker.unwrap_or_default()
+                });
+            }
+            "--max-pending" => {
+                breaker = Some(BreakerConfig {
+                    max_pending: number(&value(&mut args, flag, "<n>")?, flag)?,
+                    ..breaker.unwrap_or_default()
+                });
+            }
+            "--fallback" => {
+                let (prefix, list) = mapping(
+                    &value(&mut args, flag, "<prefix>=<host:port>")?,
+                    flag,
+                    "<host:port>",
+                )?;
+                fallbacks.insert(prefix, upstreams(&list)?);
+            }
+            "--rate-limit" => rate_limits.push(value(&mut args, flag, "<rule>")?.parse()?),
+            "--access-log" => {
+                let path = value(&mut args, flag, "<path>")?;
+                access_log = Some(AccessLogConfig {
+                    output: match path.as_str() {
+                        "-" => LogOutput::Stdout,
+                        _ => LogOutput::File(path.into()),
+                    },
+                    ..access_log.unwrap_or_default()
+                });
+            }
+            "--log-format" => {
+                access_log = Some(AccessLogConfig {
+                    format: value(&mut args, flag, "<format>")?.parse()?,
+                    ..access_log.unwrap_or_default()
+                });
+            }
+            "--log-fields" => {
+                let fields = value(&mut args, flag, "<fields>")?;
+                access_log = Some(AccessLogConfig {
+                    fields: fields
+                        .split(',')
+                        .map(|f| f.trim().parse())
+                        .collect::<Result<_, _>>()?,
+                    ..access_log.unwrap_or_default()
+                });
+            }
+            "--log-sample" => {
+                access_log = Some(AccessLogConfig {
+                    sample: number(&value(&mut args, flag, "<share>")?, flag)?,
+                    ..access_log.unwrap_or_default()
+                });
+            }
+            "--cache" => cache = Some(cache.unwrap_or_default()),
+            "--cache-dir" => {
+                cache = Some(CacheConfig {
+                    disk_dir: Some(value(&mut args, flag, "<dir>")?.into()),
+                    ..cache.unwrap_or_default()
+                });
+            }
+            "--cache-purge-allow" => purge_allowed.push(value(&mut args, flag, "<cidr>")?.parse()?),
+            "--admin" | "--admin-token" | "--log-level" | "--otlp-endpoint" | "--service-name" => {
+                return Err(format!(
+                    "{flag} applies to the whole server, give it before the first --listen"
+                ));
+            }
+            other => return Err(format!("Unknown argument {other}")),
+        }
+    }
+
+    for (prefix, dir) in statics {
+        let files = FileServer::new(dir).with_listing(listing);
+        router = router.route(&prefix, RouteAction::Static(files));
+    }
+
+    // the blocks given replace the loopback addresses PURGE is allowed from
+    if !purge_allowed.is_empty() {
+        cache = Some(CacheConfig {
+            purge_allowed,
+            ..cache.unwrap_or_default()
+        });
+    }
+    // one cache shared by every proxied route
+    let cache = cache.map(|c| Arc::new(Cache::new(c)));
+    let upstream = |upstreams: &[String]| {
+        let mut proxy = Proxy::new(&upstreams[0]);
+        for upstream in &upstreams[1..] {
+            proxy = proxy.with_backend(upstream);
+        }
+        proxy
+            .with_limits(config.limits.clone())
+            .with_pool_config(config.upstream.clone())
+            .with_retry(config.retry.clone())
+    };
+    for (prefix, upstreams, grpc) in proxies {
+        let mut proxy = upstream(&upstreams);
+        if grpc {
+            proxy = proxy.with_http2();
+            if let Some(health) = &health {
+                proxy = proxy.with_health_check(health.clone());
+            }
+        }
+        if let Some(breaker) = &breaker {
+            proxy = proxy
+                .with_backend_breaker(breaker.clone())
+                .with_breaker(breaker.clone());
+        }
+        if let Some(fallback) = fallbacks.get(&prefix) {
+            let fallback = upstream(fallback);
+            proxy = proxy.with_fallback(match grpc {
+                true => fallback.with_http2(),
+                false => fallback,
+            });
+        }
+        if let Some(cache) = &cache {
+            proxy = proxy.with_cache(Arc::clone(cache));
+        }
+        router = router.route(&prefix, RouteAction::Proxy(proxy));
+    }
+
+    for (prefix, redirect) in redirects {
+        router = router.route(&prefix, RouteAction::Redirect(redirect));
+    }
+    if let Some(map) = redirect_map {
+        router = router.with_redirects(map);
+    }
+    for (prefix, rule) in rewrites {
+        let trimmed = prefix.trim_end_matches('/');
+        if !router.routes().iter().any(|r| r.prefix == trimmed) {
+            return Err(format!("Rewrite for {prefix}, which is not a route"));
+        }
+        router = router.route_rewrite(&prefix, rule);
+    }
+
+    for rule in rate_limits {
+        router = router.rate_limit(Arc::new(RateLimiter::new(rule)));
+    }
+
+    Ok(ListenerSettings {
+        listener,
+        router,
+        config,
+        access_log,
+    })
+}
+
+// Builds what a listener serves with. `previous` is the access log in use,
+// kept when its settings did not change.
+fn snapshot(
+    settings: &ListenerSettings,
+    previous: Option<&Arc<AccessLog>>,
+    metrics: Option<&Arc<Metrics>>,
+) -> Result<Snapshot, String> {
+    let mut router = settings.router.clone();
+    if let Some(config) = &settings.access_log {
+        let access_log = match previous {
+            Some(previous) if previous.config() == config => Arc::clone(previous),
+            _ => Arc::new(
+                AccessLog::new(config.clone())
+                    .map_err(|e| format!("Cannot open the access log: {e}"))?,
+            ),
+        };
+        router = router.with_access_log(access_log);
+    }
+    if let Some(metrics) = metrics {
+        router = router.with_metrics(Arc::clone(metrics));
+    }
+    Ok(Snapshot {
+        router,
+        config: settings.config.clone(),
+    })
+}
+
+// A listener and what it serves with
+struct Served {
+    listener: ListenerConfig,
+    live: Arc<Live>,
+    connections: Arc<ConnectionTracker>,
+}
+
+// Swaps in the configuration read again, or keeps the current one when the new
+// one is invalid
+struct Reloader {
+    served: Vec<Served>,
+    metrics: Option<Arc<Metrics>>,
+    // the settings read at startup, for the ones a reload cannot change
+    startup: (Option<String>, Option<String>, Option<OtlpConfig>),
+    log_level: Mutex<Option<Level>>,
+}
+
+impl Reloader {
+    fn reload(&self) -> Result<String, String> {
+        // one reload at a time, so the change report matches what was swapped
+        let mut log_level = self.log_level.lock().unwrap();
+        let result = self.try_reload(&mut log_level);
+        match &result {
+            Ok(report) => {
+                for line in report.lines() {
+                    info!("reload: {line}");
+                }
+            }
+            Err(e) => error!("reload failed, the old config stays: {e}"),
+        }
+        result
+    }
+
+    fn try_reload(&self, log_level: &mut Option<Level>) -> Result<String, String> {
+        let settings = parse(args()?)?;
+        let mut changes = Vec::new();
+
+        // every listener gets its new snapshot ready before any is swapped
+        let mut swaps = Vec::new();
+        for served in &self.served {
+            let address = served.listener.address;
+            let Some(new) = settings
+                .listeners
+                .iter()
+                .find(|l| l.listener.address == address)
+            else {
+                changes.push(format!("listener {address} removed, needs a restart"));
+                continue;
+            };
+            if new.listener != served.listener {
+                changes.push(format!("listener {address} changed, needs a restart"));
+            }
+            let current = served.live.load();
+            let snapshot = snapshot(new, current.router.access_log(), None)?;
+            changes.extend(
+                reload::changes(&current, &snapshot)
+                    .into_iter()
+                    .map(|change| match self.served.len() {
+                        1 => change,
+                        _ => format!("{address}: {change}"),
+                    }),
+            );
+            swaps.push((served, current, snapshot));
+        }
+        for new in &settings.listeners {
+            let address = new.listener.address;
+            if !self.served.iter().any(|s| s.listener.address == address) {
+                changes.push(format!("listener {address} added, needs a restart"));
+            }
+        }
+        if settings.log_level != *log_level {
+            changes.push("log level changed".to_string());
+        }
+        if (&settings.admin, &settings.admin_token, &settings.otlp)
+            != (&self.startup.0, &self.startup.1, &self.startup.2)
+        {
+            changes.push("admin and tracing settings need a restart".to_string());
+        }
+
+        // the metrics only hear about the new backends once nothing can fail
+        if let Some(metrics) = &self.metrics {
+            metrics.clear_backends();
+        }
+        for (served, current, snapshot) in swaps {
+            let mut router = snapshot.router.carry_over(&current.router);
+            if let Some(metrics) = &self.metrics {
+                router = router.with_metrics(Arc::clone(metrics));
+            }
+            served
+                .connections
+                .set_limits(snapshot.config.connections.clone());
+            served.live.swap(Snapshot {
+                router,
+                config: snapshot.config,
+            });
+        }
+        logging::set_level(settings.log_level.unwrap_or(Level::Info));
+        *log_level = settings.log_level;
+
+        Ok(match changes.is_empty() {
+            true => "nothing changed\n".to_string(),
+            false => changes.join("\n") + "\n",
+        })
+    }
+}
+
+// Reloads when the modification time of the file changes
+fn watch(path: PathBuf, reloader: Arc<Reloader>) {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last: Option<SystemTime> = modified(&path);
+    loop {
+        thread::sleep(CONFIG_POLL);
+        let now = modified(&path);
+        if now != last {
+            last = now;
+            let _ = reloader.reload();
+        }
+    }
+}
 
-mod cmd;
-mod internal;
 fn main() {
-    println!("Hello, world!");
+    let settings = args().and_then(parse).unwrap_or_else(|e| {
+        error!("{e}");
+        process::exit(2);
+    });
+    if let Some(level) = settings.log_level {
+        logging::set_level(level);
+    }
+
+    // spans are only kept when there is somewhere to send them
+    if let Some(otlp) = &settings.otlp {
+        tracing::install(Exporter::spawn(otlp.clone()));
+    }
+
+    // the admin listener serves /metrics, its own traffic is not counted
+    let metrics = settings.admin.as_ref().map(|_| Arc::new(Metrics::new()));
+    let mut listeners = Vec::new();
+    let mut served = Vec::new();
+    for listener in &settings.listeners {
+        let address = listener.listener.address;
+        let bound = Listener::bind(listener.listener.clone()).unwrap_or_else(|e| {
+            error!("cannot listen on {address}: {e}");
+            process::exit(1);
+        });
+        let snapshot = snapshot(listener, None, metrics.as_ref()).unwrap_or_else(|e| {
+            error!("{e}");
+            process::exit(2);
+        });
+        served.push(Served {
+            listener: listener.listener.clone(),
+            connections: Arc::new(ConnectionTracker::new(snapshot.config.connections.clone())),
+            live: Arc::new(Live::new(snapshot)),
+        });
+        listeners.push(bound);
+    }
+    let trackers: Vec<_> = served.iter().map(|s| Arc::clone(&s.connections)).collect();
+    let lives: Vec<_> = served.iter().map(|s| Arc::clone(&s.live)).collect();
+
+    let admin = settings.admin.as_ref().map(|_| {
+        let mut listeners = served.iter().map(|s| {
+            let name = s.listener.address.to_string();
+            (name, Arc::clone(&s.live), Arc::clone(&s.connections))
+        });
+        let (name, live, connections) = listeners.next().expect("There is always a listener");
+        listeners.fold(
+            Admin::new(name, live, connections),
+            |admin, (name, live, connections)| admin.with_listener(name, live, connections),
+        )
+    });
+
+    let reloader = Arc::new(Reloader {
+        served,
+        metrics: metrics.clone(),
+        startup: (
+            settings.admin.clone(),
+            settings.admin_token.clone(),
+            settings.otlp.clone(),
+        ),
+        log_level: Mutex::new(settings.log_level),
+    });
+
+    // SIGHUP reloads the config, SIGUSR1 reopens the access logs after
+    // logrotate moved them away. SIGTERM and SIGINT stop the listeners, a
+    // second one does not wait for the drain.
+    let mut signals =
+        Signals::new([SIGHUP, SIGUSR1, SIGTERM, SIGINT]).expect("Cannot listen for signals");
+    {
+        let reloader = Arc::clone(&reloader);
+        let trackers = trackers.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                let open = || trackers.iter().map(|t| t.open()).sum::<usize>();
+                match signal {
+                    SIGHUP => {
+                        let _ = reloader.reload();
+                    }
+                    SIGTERM | SIGINT if trackers.iter().any(|t| t.is_stopping()) => {
+                        warn!("stopping without waiting for {} connections", open());
+                        process::exit(1);
+                    }
+                    SIGTERM | SIGINT => {
+                        info!("shutting down");
+                        trackers.iter().for_each(|t| t.stop());
+                    }
+                    _ => {
+                        for served in &reloader.served {
+                            if let Some(access_log) = served.live.load().router.access_log()
+                                && let Err(e) = access_log.reopen()
+                            {
+                                error!("reopening the access log failed: {e}");
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+    if let Some(path) = config_path() {
+        let reloader = Arc::clone(&reloader);
+        thread::spawn(move || watch(path, reloader));
+    }
+
+    if let (Some(address), Some(metrics), Some(admin)) = (settings.admin, metrics, admin) {
+        let mut admin = admin
+            .with_metrics(metrics)
+            .with_reload(move || reloader.reload());
+        match settings.admin_token {
+            Some(token) => admin = admin.with_token(token),
+            None => warn!("the admin API on {address} takes requests without a token"),
+        }
+        let admin_router = Router::new().route("/", RouteAction::Admin(Arc::new(admin)));
+        thread::spawn(move || {
+            tcplistener::serve(&address, &admin_router, &Config::default())
+                .expect("An error occured in the admin listener");
+        });
+    }
 
-    tcplistener::listen_for_http().expect("An error occured in TCP listener");
+    // the exit status tells whether every request got its response
+    let clean = thread::scope(|scope| {
+        let serving: Vec<_> = listeners
+            .iter()
+            .zip(lives.iter().zip(&trackers))
+            .map(|(listener, (live, connections))| {
+                let config = listener.config();
+                info!(
+                    "listening on {}{}",
+                    config.address,
+                    match config.tls {
+                        Some(_) => " with TLS",
+                        None => "",
+                    }
+                );
+                scope.spawn(move || tcplistener::serve_live(listener, live, connections))
+            })
+            .collect();
+        serving.into_iter().fold(true, |clean, serving| {
+            match serving.join().expect("A listener thread panicked") {
+                Ok(()) => clean,
+                Err(e) => {
+                    error!("{e}");
+                    false
+                }
+            }
+        })
+    });
+    match clean {
+        true => info!("stopped"),
+        false => process::exit(1),
+    }
 }